@@ -1,5 +1,7 @@
 //! Some helpers for the meta.core language.
 pub mod ids;
+pub mod ordered;
+pub mod validate;
 
 use im::{HashMap, HashSet};
 use itertools::Itertools;
@@ -8,6 +10,15 @@ use meta_store::{Datom, Field, Store};
 
 use crate::ids::*;
 
+/// A problem with `after` attributes detected while ordering atoms.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum OrderAnomaly {
+    /// Atoms (sorted by id) are placed after each other in a loop.
+    Loop(Vec<Field>),
+    /// Atom `id` is placed after `after`, which is not among the ordered atoms.
+    DanglingAfter { id: Field, after: Field },
+}
+
 pub struct MetaCore<'a> {
     pub store: &'a Store,
     // TODO: remove hard-code ids and cache them in struct
@@ -47,42 +58,100 @@ impl<'a> MetaCore<'a> {
 
     /// Order atoms in order determines by `after` attribute. If `after` is not specified, order by
     /// atom id.
-    // Believe me or not, it's actually O(n + m*log(m)), where n is the total number of datoms and m
-    // is the number of atoms without "after" attribute.
+    ///
+    /// All input atoms are returned. See [`MetaCore::order_datoms_with_anomalies`] for how broken
+    /// `after` chains are handled.
     pub fn order_datoms<I>(&'a self, atoms: I) -> Vec<&'a Datom>
     where
         I: IntoIterator<Item = &'a Datom>,
     {
-        let mut no_after = HashSet::new();
-        let mut next = HashMap::<&Field, HashSet<&Datom>>::new();
-        for x in atoms.into_iter() {
-            if let Some(a) = self.after(x) {
-                next.entry(a).or_insert_with(HashSet::new).insert(x);
-            } else {
-                no_after.insert(x);
+        self.order_datoms_with_anomalies(atoms).0
+    }
+
+    /// Same as [`MetaCore::order_datoms`] but also returns problems found in `after` chains.
+    ///
+    /// Atoms placed after an atom that is not in the input are ordered as if they had no `after`.
+    /// Loops are broken at the atom with the lowest id, which is then ordered as if it had no
+    /// `after`. Atoms placed after the same atom are ordered by id.
+    // O(n*log(n)), where n is the number of atoms.
+    pub fn order_datoms_with_anomalies<I>(&'a self, atoms: I) -> (Vec<&'a Datom>, Vec<OrderAnomaly>)
+    where
+        I: IntoIterator<Item = &'a Datom>,
+    {
+        let atoms = atoms
+            .into_iter()
+            .map(|x| (&x.id, x))
+            .collect::<HashMap<&Field, &Datom>>();
+
+        let mut anomalies = Vec::new();
+        let mut roots = Vec::new();
+        let mut prev = HashMap::<&Field, &Field>::new();
+        let mut next = HashMap::<&Field, Vec<&Datom>>::new();
+        for x in atoms.values() {
+            match self.after(x) {
+                Some(a) if atoms.contains_key(a) => {
+                    prev.insert(&x.id, a);
+                    next.entry(a).or_insert_with(Vec::new).push(x);
+                }
+                Some(a) => {
+                    anomalies.push(OrderAnomaly::DanglingAfter {
+                        id: x.id.clone(),
+                        after: a.clone(),
+                    });
+                    roots.push(*x);
+                }
+                None => roots.push(*x),
             }
         }
 
-        // it would be much easier if Rust allowed recursive closures
-        fn traverse_atom<'a>(
-            x: &'a Datom,
+        fn traverse<'a>(
+            root: &'a Datom,
             result: &'_ mut Vec<&'a Datom>,
-            next: &HashMap<&'a Field, HashSet<&'a Datom>>,
+            visited: &'_ mut HashSet<&'a Field>,
+            next: &HashMap<&'a Field, Vec<&'a Datom>>,
         ) {
-            result.push(x);
-            if let Some(next_atoms) = next.get(&x.id) {
-                for a in next_atoms.iter() {
-                    traverse_atom(a, result, next);
+            let mut stack = vec![root];
+            while let Some(x) = stack.pop() {
+                if visited.insert(&x.id).is_some() {
+                    continue;
+                }
+                result.push(x);
+                if let Some(next_atoms) = next.get(&x.id) {
+                    stack.extend(next_atoms.iter().sorted_by_key(|x| &x.id).rev());
                 }
             }
         }
 
-        let mut result = Vec::new();
-        for a in no_after.iter().sorted_by_key(|x| &x.id) {
-            traverse_atom(a, &mut result, &next);
+        let mut result = Vec::with_capacity(atoms.len());
+        let mut visited = HashSet::new();
+        for a in roots.into_iter().sorted_by_key(|x| &x.id) {
+            traverse(a, &mut result, &mut visited, &next);
         }
 
-        result
+        // Everything left unvisited is either a part of a loop or placed after an atom from a
+        // loop. Walk back along `after` until an atom repeats to find the loop, then start from
+        // its lowest id.
+        for x in atoms.values().sorted_by_key(|x| &x.id) {
+            if visited.contains(&x.id) {
+                continue;
+            }
+
+            let mut path = Vec::new();
+            let mut on_path = HashSet::new();
+            let mut id = &x.id;
+            while on_path.insert(id).is_none() {
+                path.push(id);
+                id = prev[id];
+            }
+            let start = path.iter().position(|x| *x == id).unwrap();
+            let loop_ids = path[start..].iter().sorted().cloned().collect::<Vec<_>>();
+
+            traverse(atoms[loop_ids[0]], &mut result, &mut visited, &next);
+            anomalies.push(OrderAnomaly::Loop(loop_ids.into_iter().cloned().collect()));
+        }
+
+        anomalies.sort();
+        (result, anomalies)
     }
 
     pub fn ordered_values(&self, entry: &Field, attr: &Field) -> Vec<&Datom> {
@@ -191,7 +260,6 @@ mod tests {
     }
 
     #[test]
-    #[ignore] // TODO: order silently drops all loops now (a after b, b after a)
     fn test_order_with_after_loop() {
         let store = Store::from_str(
             r#"
@@ -220,4 +288,50 @@ mod tests {
             result
         );
     }
+
+    #[test]
+    fn test_order_anomalies() {
+        let store = Store::from_str(
+            r#"
+              ["10", "0", "1", "2"]
+              ["11", "0", "1", "3"]
+              ["12", "0", "1", "4"]
+              ["13", "0", "1", "5"]
+              ["14", "0", "1", "6"]
+              ["20", "11", "16", "11"]
+              ["21", "12", "16", "missing"]
+              ["22", "14", "16", "13"]
+              ["23", "13", "16", "14"]
+              ["24", "10", "16", "14"]
+            "#,
+        )
+        .unwrap();
+        let core = MetaCore::new(&store);
+
+        let (result, anomalies) = store
+            .eav2(&"0".into(), &"1".into())
+            .map_or_else(Default::default, |x| core.order_datoms_with_anomalies(x));
+
+        assert_eq!(
+            vec![
+                &("12", "0", "1", "4").into(),
+                &("13", "0", "1", "5").into(),
+                &("14", "0", "1", "6").into(),
+                &("10", "0", "1", "2").into(),
+                &("11", "0", "1", "3").into(),
+            ] as Vec<&Datom>,
+            result
+        );
+        assert_eq!(
+            vec![
+                OrderAnomaly::Loop(vec!["11".into()]),
+                OrderAnomaly::Loop(vec!["13".into(), "14".into()]),
+                OrderAnomaly::DanglingAfter {
+                    id: "12".into(),
+                    after: "missing".into()
+                },
+            ],
+            anomalies
+        );
+    }
 }
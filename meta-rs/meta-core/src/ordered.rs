@@ -0,0 +1,228 @@
+//! Editing of ordered attribute values.
+//!
+//! Values of an attribute are ordered by `after` datoms attached to value datoms (see
+//! [`MetaCore::order_datoms`]). Functions in this module compute new order and return datoms that
+//! should be retracted and added to make `after` chain match it. Datoms are not modified, so the
+//! result can be passed directly to [`meta_store::Store::transact`].
+//!
+//! Every edit rewrites the chain into a single explicit list, so applying any of them also repairs
+//! loops and dangling `after` datoms in the list.
+use meta_store::{Datom, Field};
+
+use crate::ids::*;
+use crate::MetaCore;
+
+/// Datoms to retract and add to apply an edit of ordered values.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ListEdit {
+    pub retracted: Vec<Datom>,
+    pub added: Vec<Datom>,
+}
+
+impl<'a> MetaCore<'a> {
+    /// Insert `datom` at `index` among other values of the same entity and attribute. If `index` is
+    /// past the end, `datom` is appended.
+    pub fn insert_at(&self, datom: Datom, index: usize) -> ListEdit {
+        let old = self.ordered_values(&datom.entity, &datom.attribute);
+        let mut new = old.clone();
+        new.insert(index.min(old.len()), &datom);
+
+        let mut edit = self.relink(&old, &new);
+        edit.added.insert(0, datom);
+        edit
+    }
+
+    /// Insert `datom` right after value datom with id `after`, or at the beginning if `after` is
+    /// `None`. If `after` is not found, `datom` is appended.
+    pub fn insert_after(&self, datom: Datom, after: Option<&Field>) -> ListEdit {
+        let index = match after {
+            None => 0,
+            Some(after) => self
+                .ordered_values(&datom.entity, &datom.attribute)
+                .iter()
+                .position(|d| &d.id == after)
+                .map_or(usize::MAX, |i| i + 1),
+        };
+        self.insert_at(datom, index)
+    }
+
+    /// Move value `datom` so it is at `index` after the move. If `index` is past the end, `datom`
+    /// is moved to the end.
+    pub fn move_to(&self, datom: &Datom, index: usize) -> ListEdit {
+        let old = self.ordered_values(&datom.entity, &datom.attribute);
+        let mut new = old
+            .iter()
+            .copied()
+            .filter(|d| d.id != datom.id)
+            .collect::<Vec<_>>();
+        new.insert(index.min(new.len()), datom);
+
+        self.relink(&old, &new)
+    }
+
+    /// Remove value `datom` together with its `after` datoms and relink the following values to
+    /// the previous one.
+    pub fn remove_ordered(&self, datom: &Datom) -> ListEdit {
+        let old = self.ordered_values(&datom.entity, &datom.attribute);
+        let new = old
+            .iter()
+            .copied()
+            .filter(|d| d.id != datom.id)
+            .collect::<Vec<_>>();
+
+        self.relink(&old, &new)
+    }
+
+    /// Reverse order of values of `attribute` of `entity`.
+    pub fn reverse_ordered(&self, entity: &Field, attribute: &Field) -> ListEdit {
+        let old = self.ordered_values(entity, attribute);
+        let new = old.iter().rev().copied().collect::<Vec<_>>();
+
+        self.relink(&old, &new)
+    }
+
+    /// Compute edit that changes values from `old` to `new` order. Values that are absent in `new`
+    /// are retracted.
+    fn relink(&self, old: &[&Datom], new: &[&Datom]) -> ListEdit {
+        let mut edit = ListEdit::default();
+
+        for d in old.iter().filter(|d| !new.iter().any(|n| n.id == d.id)) {
+            edit.retracted.push((*d).clone());
+            edit.retracted.extend(self.after_datoms(&d.id));
+        }
+
+        let mut prev: Option<&Field> = None;
+        for d in new {
+            let current = self.after_datoms(&d.id);
+            let is_linked = matches!(current.as_slice(), [after] if Some(&after.value) == prev);
+            if !is_linked {
+                edit.retracted.extend(current);
+                if let Some(prev) = prev {
+                    edit.added
+                        .push(Datom::eav(d.id.clone(), A_AFTER.clone(), prev.clone()));
+                }
+            }
+            prev = Some(&d.id);
+        }
+
+        edit
+    }
+
+    fn after_datoms(&self, id: &Field) -> Vec<Datom> {
+        self.store
+            .eav2(id, &A_AFTER)
+            .map_or_else(Vec::new, |datoms| datoms.iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use meta_store::Store;
+    use std::str::FromStr;
+
+    static LIST: &str = r#"
+      ["10", "0", "1", "a"]
+      ["11", "0", "1", "b"]
+      ["12", "0", "1", "c"]
+      ["13", "11", "16", "10"]
+      ["14", "12", "16", "11"]
+    "#;
+
+    fn apply(store: &mut Store, f: impl FnOnce(&MetaCore) -> ListEdit) -> Vec<String> {
+        let edit = f(&MetaCore::new(store));
+        store.transact(edit.retracted, edit.added);
+        MetaCore::new(store)
+            .ordered_values(&"0".into(), &"1".into())
+            .iter()
+            .map(|d| d.value.as_ref().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_insert_at() {
+        let mut store = Store::from_str(LIST).unwrap();
+        let d = Datom::new("20".into(), "0".into(), "1".into(), "x".into());
+        assert_eq!(
+            vec!["a", "x", "b", "c"],
+            apply(&mut store, |core| core.insert_at(d, 1))
+        );
+
+        let d = Datom::new("21".into(), "0".into(), "1".into(), "y".into());
+        assert_eq!(
+            vec!["a", "x", "b", "c", "y"],
+            apply(&mut store, |core| core.insert_at(d, 100))
+        );
+    }
+
+    #[test]
+    fn test_insert_after() {
+        let mut store = Store::from_str(LIST).unwrap();
+        let d = Datom::new("20".into(), "0".into(), "1".into(), "x".into());
+        assert_eq!(
+            vec!["x", "a", "b", "c"],
+            apply(&mut store, |core| core.insert_after(d, None))
+        );
+
+        let d = Datom::new("21".into(), "0".into(), "1".into(), "y".into());
+        assert_eq!(
+            vec!["x", "a", "b", "c", "y"],
+            apply(&mut store, |core| core.insert_after(d, Some(&"12".into())))
+        );
+    }
+
+    #[test]
+    fn test_move_to() {
+        let mut store = Store::from_str(LIST).unwrap();
+        let a = ("10", "0", "1", "a").into();
+        assert_eq!(
+            vec!["b", "c", "a"],
+            apply(&mut store, |core| core.move_to(&a, 2))
+        );
+        let c = ("12", "0", "1", "c").into();
+        assert_eq!(
+            vec!["c", "b", "a"],
+            apply(&mut store, |core| core.move_to(&c, 0))
+        );
+    }
+
+    #[test]
+    fn test_remove_ordered() {
+        let mut store = Store::from_str(LIST).unwrap();
+        let b = ("11", "0", "1", "b").into();
+        assert_eq!(
+            vec!["a", "c"],
+            apply(&mut store, |core| core.remove_ordered(&b))
+        );
+        assert_eq!(None, store.atoms().get(&"11".into()));
+        assert_eq!(None, store.atoms().get(&"13".into()));
+    }
+
+    #[test]
+    fn test_reverse_ordered() {
+        let mut store = Store::from_str(LIST).unwrap();
+        assert_eq!(
+            vec!["c", "b", "a"],
+            apply(&mut store, |core| core
+                .reverse_ordered(&"0".into(), &"1".into()))
+        );
+    }
+
+    #[test]
+    fn test_edit_repairs_loop() {
+        let mut store =
+            Store::from_str(&format!("{}\n{}", LIST, r#"["15", "10", "16", "12"]"#)).unwrap();
+        let d = Datom::new("20".into(), "0".into(), "1".into(), "x".into());
+        assert_eq!(
+            vec!["a", "b", "c", "x"],
+            apply(&mut store, |core| core.insert_at(d, 3))
+        );
+        assert_eq!(
+            None,
+            MetaCore::new(&store)
+                .order_datoms_with_anomalies(store.eav2(&"0".into(), &"1".into()).unwrap())
+                .1
+                .first()
+        );
+    }
+}
@@ -0,0 +1,397 @@
+//! Schema validation for meta.core stores.
+//!
+//! Checks that datoms respect attribute declarations: `Attribute.value-type` and
+//! `Attribute.reference-type`.
+use std::fmt::Display;
+
+use im::HashSet;
+use itertools::Itertools;
+
+use meta_store::{ChangeSet, Datom, Field};
+
+use crate::ids::*;
+use crate::{MetaCore, OrderAnomaly};
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Diagnostic {
+    pub datom: Datom,
+    pub kind: DiagnosticKind,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum DiagnosticKind {
+    /// Datom attribute is not an entity of type `Attribute`.
+    UndeclaredAttribute,
+    /// Value of a `Reference` attribute is neither an entity nor a datom id.
+    MissingReference,
+    /// Value of a `Reference` attribute points to an entity that does not have any of the types
+    /// listed in `Attribute.reference-type`.
+    WrongReferenceType {
+        expected: Vec<Field>,
+        actual: Option<Field>,
+    },
+    /// Value of a `NaturalNumber` or `IntegerNumber` attribute is not a valid number.
+    InvalidNumber { value_type: Field },
+    /// Datom is a part of an `after` loop.
+    AfterLoop,
+    /// Datom is placed after a datom that is not a value of the same entity and attribute.
+    DanglingAfter { after: Field },
+}
+
+impl<'a> MetaCore<'a> {
+    /// Validate all datoms in the store. Diagnostics are sorted by datom.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        self.store
+            .atoms()
+            .values()
+            .flat_map(|datom| self.validate_datom(datom))
+            .chain(self.validate_order())
+            .sorted()
+            .collect()
+    }
+
+    /// Validate only datoms whose diagnostics may have changed with `changes`: datoms of the changed
+    /// entities, datoms of the changed attributes, datoms referencing the changed entities or
+    /// datoms, and `after` chains all of these belong to.
+    ///
+    /// Returns ids of all checked datoms (including removed ones) along with diagnostics, so
+    /// diagnostics of other datoms from a previous validation stay valid.
+    pub fn validate_changes(&self, changes: &ChangeSet) -> (HashSet<Field>, Vec<Diagnostic>) {
+        let atoms = self.store.atoms();
+        let changed = changes
+            .datoms()
+            .flat_map(|d| vec![&d.id, &d.entity])
+            .collect::<HashSet<&Field>>();
+
+        let lists = changed
+            .iter()
+            .flat_map(|e| self.store.eav1(e))
+            .chain(changed.iter().flat_map(|a| self.store.aev1(a)))
+            .flat_map(|values| values.values())
+            .flatten()
+            .chain(changed.iter().flat_map(|id| atoms.get(*id)))
+            .chain(atoms.values().filter(|d| changed.contains(&d.value)))
+            .map(|datom| (&datom.entity, &datom.attribute))
+            .unique()
+            .sorted()
+            .collect::<Vec<_>>();
+
+        let datoms = lists
+            .iter()
+            .flat_map(|(entity, attribute)| self.store.eav2(entity, attribute))
+            .flatten()
+            .collect::<HashSet<&Datom>>();
+        let diagnostics = datoms
+            .iter()
+            .flat_map(|datom| self.validate_datom(datom))
+            .chain(self.validate_lists(lists))
+            .sorted()
+            .collect();
+        let ids = datoms
+            .into_iter()
+            .chain(changes.removed.iter())
+            .map(|datom| datom.id.clone())
+            .collect();
+        (ids, diagnostics)
+    }
+
+    /// Check `after` chains of all entity-attribute pairs that have ordered values.
+    fn validate_order(&self) -> Vec<Diagnostic> {
+        let atoms = self.store.atoms();
+        let lists = self
+            .store
+            .aev1(&A_AFTER)
+            .into_iter()
+            .flat_map(|after| after.keys())
+            .flat_map(|id| atoms.get(id))
+            .map(|datom| (&datom.entity, &datom.attribute))
+            .unique()
+            .sorted();
+        self.validate_lists(lists)
+    }
+
+    /// Check `after` chains of values of the entity-attribute pairs.
+    fn validate_lists(
+        &self,
+        lists: impl IntoIterator<Item = (&'a Field, &'a Field)>,
+    ) -> Vec<Diagnostic> {
+        let atoms = self.store.atoms();
+        let mut result = Vec::new();
+        for (entity, attribute) in lists {
+            let values = match self.store.eav2(entity, attribute) {
+                Some(values) => values,
+                None => continue,
+            };
+            let (_, anomalies) = self.order_datoms_with_anomalies(values);
+            for anomaly in anomalies {
+                match anomaly {
+                    OrderAnomaly::Loop(ids) => {
+                        result.extend(ids.iter().map(|id| Diagnostic {
+                            datom: atoms[id].clone(),
+                            kind: DiagnosticKind::AfterLoop,
+                        }));
+                    }
+                    OrderAnomaly::DanglingAfter { id, after } => result.push(Diagnostic {
+                        datom: atoms[&id].clone(),
+                        kind: DiagnosticKind::DanglingAfter { after },
+                    }),
+                }
+            }
+        }
+        result
+    }
+
+    pub fn validate_datom(&self, datom: &Datom) -> Option<Diagnostic> {
+        self.check_datom(datom).map(|kind| Diagnostic {
+            datom: datom.clone(),
+            kind,
+        })
+    }
+
+    fn check_datom(&self, datom: &Datom) -> Option<DiagnosticKind> {
+        let attribute = &datom.attribute;
+        if self.meta_type(attribute).map(|d| &d.value) != Some(&T_ATTRIBUTE) {
+            return Some(DiagnosticKind::UndeclaredAttribute);
+        }
+
+        let value_type = &self.meta_attribute_type(attribute)?.value;
+        let value = &datom.value;
+        if value_type == &V_REFERENCE as &Field {
+            if self.store.eav1(value).is_none() && !self.store.atoms().contains_key(value) {
+                return Some(DiagnosticKind::MissingReference);
+            }
+
+            let expected = self
+                .meta_attribute_reference_type(attribute)
+                .map_or_else(Vec::new, |types| {
+                    types.iter().map(|d| d.value.clone()).sorted().collect()
+                });
+            let actual = self.meta_type(value).map(|d| &d.value);
+            if !expected.is_empty() && !matches!(actual, Some(t) if expected.contains(t)) {
+                return Some(DiagnosticKind::WrongReferenceType {
+                    expected,
+                    actual: actual.cloned(),
+                });
+            }
+        } else if value_type == &V_NATURAL_NUMBER as &Field {
+            if !is_natural_number(value.as_ref()) {
+                return Some(DiagnosticKind::InvalidNumber {
+                    value_type: value_type.clone(),
+                });
+            }
+        } else if value_type == &V_INTEGER_NUMBER as &Field {
+            let value = value.as_ref();
+            if !is_natural_number(value.strip_prefix('-').unwrap_or(value)) {
+                return Some(DiagnosticKind::InvalidNumber {
+                    value_type: value_type.clone(),
+                });
+            }
+        }
+
+        None
+    }
+}
+
+fn is_natural_number(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let Datom {
+            id,
+            entity,
+            attribute,
+            value,
+        } = &self.datom;
+        write!(
+            f,
+            "[{:?}, {:?}, {:?}, {:?}]: ",
+            id.as_ref(),
+            entity.as_ref(),
+            attribute.as_ref(),
+            value.as_ref()
+        )?;
+        match &self.kind {
+            DiagnosticKind::UndeclaredAttribute => write!(f, "attribute is not declared"),
+            DiagnosticKind::MissingReference => write!(f, "reference points to nothing"),
+            DiagnosticKind::WrongReferenceType { expected, actual } => write!(
+                f,
+                "reference points to an entity of type {:?}, expected one of {:?}",
+                actual.as_ref().map(|t| t.as_ref()),
+                expected.iter().map(|t| t.as_ref()).collect::<Vec<_>>()
+            ),
+            DiagnosticKind::InvalidNumber { value_type } => {
+                write!(f, "value is not a valid {:?}", value_type.as_ref())
+            }
+            DiagnosticKind::AfterLoop => write!(f, "datom is a part of an `after` loop"),
+            DiagnosticKind::DanglingAfter { after } => write!(
+                f,
+                "datom is placed after {:?}, which is not a sibling",
+                after.as_ref()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use meta_store::Store;
+    use std::str::FromStr;
+
+    static CORE: &str = include_str!("../../../core.meta");
+
+    fn validate(extra: &str) -> Vec<(Datom, DiagnosticKind)> {
+        let store = Store::from_str(&format!("{}\n{}", CORE, extra)).unwrap();
+        MetaCore::new(&store)
+            .validate()
+            .into_iter()
+            .map(|d| (d.datom, d.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_core_is_valid() {
+        assert_eq!(Vec::<(Datom, DiagnosticKind)>::new(), validate(""));
+    }
+
+    #[test]
+    fn test_undeclared_attribute() {
+        assert_eq!(
+            vec![(
+                ("x1", "0", "unknown", "1").into(),
+                DiagnosticKind::UndeclaredAttribute
+            )],
+            validate(r#"["x1", "0", "unknown", "1"]"#)
+        );
+    }
+
+    #[test]
+    fn test_missing_reference() {
+        assert_eq!(
+            vec![(
+                ("x1", "14", "13", "missing").into(),
+                DiagnosticKind::MissingReference
+            )],
+            validate(r#"["x1", "14", "13", "missing"]"#)
+        );
+    }
+
+    #[test]
+    fn test_wrong_reference_type() {
+        // type(5) must reference a Type(6), but identifier(0) is an Attribute(7)
+        assert_eq!(
+            vec![(
+                ("x1", "100", "5", "0").into(),
+                DiagnosticKind::WrongReferenceType {
+                    expected: vec!["6".into()],
+                    actual: Some("7".into()),
+                }
+            )],
+            validate(r#"["x1", "100", "5", "0"]"#)
+        );
+    }
+
+    #[test]
+    fn test_invalid_numbers() {
+        let result = validate(
+            r#"
+              ["100", "5", "7"]
+              ["100", "1", "9"]
+              ["101", "5", "7"]
+              ["101", "1", "11"]
+              ["x1", "0", "100", "42"]
+              ["x2", "0", "100", "-42"]
+              ["x3", "0", "101", "-42"]
+              ["x4", "0", "101", "4a"]
+            "#,
+        );
+        assert_eq!(
+            vec![
+                (
+                    ("x2", "0", "100", "-42").into(),
+                    DiagnosticKind::InvalidNumber {
+                        value_type: "9".into()
+                    }
+                ),
+                (
+                    ("x4", "0", "101", "4a").into(),
+                    DiagnosticKind::InvalidNumber {
+                        value_type: "11".into()
+                    }
+                ),
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn test_validate_changes() {
+        let mut store = Store::from_str(&format!(
+            "{}\n{}",
+            CORE,
+            r#"
+              ["a1", "100", "5", "7"]
+              ["a2", "100", "1", "9"]
+              ["x1", "0", "100", "-42"]
+              ["x2", "14", "13", "0"]
+              ["x3", "14", "13", "1"]
+              ["y1", "x3", "16", "x2"]
+              ["x4", "15", "13", "x5"]
+              ["x5", "100", "0", "number"]
+            "#
+        ))
+        .unwrap();
+        let before = MetaCore::new(&store).validate();
+
+        // 100 becomes an IntegerNumber, x5 that x4 references is removed, and x2 is placed after x3
+        // in a loop
+        let changes = store.transact_changes(
+            vec![
+                ("x5", "100", "0", "number").into(),
+                ("a2", "100", "1", "9").into(),
+            ],
+            vec![
+                ("a3", "100", "1", "11").into(),
+                ("y2", "x2", "16", "x3").into(),
+            ],
+        );
+        let core = MetaCore::new(&store);
+        let (ids, diagnostics) = core.validate_changes(&changes);
+
+        let result = before
+            .into_iter()
+            .filter(|d| !ids.contains(&d.datom.id))
+            .chain(diagnostics)
+            .sorted()
+            .collect::<Vec<_>>();
+        assert_eq!(core.validate(), result);
+        assert!(!result.is_empty());
+    }
+
+    #[test]
+    fn test_after_anomalies() {
+        let result = validate(
+            r#"
+              ["x1", "14", "13", "0"]
+              ["x2", "14", "13", "1"]
+              ["x3", "14", "13", "4"]
+              ["y1", "x1", "16", "x2"]
+              ["y2", "x2", "16", "x1"]
+              ["y3", "x3", "16", "y1"]
+            "#,
+        );
+        assert_eq!(
+            vec![
+                (("x1", "14", "13", "0").into(), DiagnosticKind::AfterLoop),
+                (("x2", "14", "13", "1").into(), DiagnosticKind::AfterLoop),
+                (
+                    ("x3", "14", "13", "4").into(),
+                    DiagnosticKind::DanglingAfter { after: "y1".into() }
+                ),
+            ],
+            result
+        );
+    }
+}
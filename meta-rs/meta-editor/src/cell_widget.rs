@@ -2,16 +2,25 @@ use druid_shell::{
     kurbo::{Rect, Size},
     piet::{Color, TextLayout},
 };
+use im::HashSet;
 use unicode_segmentation::UnicodeSegmentation;
 
 use meta_gui::widgets::Text;
 use meta_gui::{Constraint, GuiContext, Layout};
 use meta_pretty::SimpleDocKind;
+use meta_store::Field;
 
 use crate::editor::CursorPosition;
 use crate::layout::{CellClass, SDoc};
 
-pub(crate) struct CellWidget<'a>(pub &'a SDoc, pub &'a Option<CursorPosition>);
+pub(crate) struct CellWidget<'a>(
+    pub &'a SDoc,
+    pub &'a Option<CursorPosition>,
+    /// Ids of datoms that fail validation. Their cells are highlighted.
+    pub &'a HashSet<Field>,
+    /// Ids of datoms of the expression the debugger is paused at. Their cells are highlighted.
+    pub &'a HashSet<Field>,
+);
 
 impl<'a> Layout for CellWidget<'a> {
     fn layout(&mut self, ctx: &mut GuiContext, constraint: Constraint) -> Size {
@@ -50,6 +59,21 @@ impl<'a> Layout for CellWidget<'a> {
             .height;
         let size = Size::new(text_size.width, text_size.height.max(min_height));
 
+        let (is_invalid, is_debugged) = match &class {
+            CellClass::Editable(datom) | CellClass::Reference(datom, ..) => {
+                (self.2.contains(&datom.id), self.3.contains(&datom.id))
+            }
+            _ => (false, false),
+        };
+        if is_invalid {
+            let b = ctx.solid_brush(Color::rgba8(0xff, 0x00, 0x00, 0x30));
+            ctx.fill(size.to_rect(), &b);
+        }
+        if is_debugged {
+            let b = ctx.solid_brush(Color::rgba8(0xff, 0xd7, 0x00, 0x60));
+            ctx.fill(size.to_rect(), &b);
+        }
+
         match &self.1 {
             Some(CursorPosition { sdoc, offset }) if sdoc == self.0 => {
                 let b = ctx.solid_brush(Color::rgba8(0, 0, 0, 20));
@@ -0,0 +1,119 @@
+use druid_shell::kurbo::{Insets, Size};
+use druid_shell::piet::Color;
+
+use meta_core::MetaCore;
+use meta_f::{DebugStatus, DebugValue, Debugger};
+use meta_gui::widgets::{Background, Inset, List, Text};
+use meta_gui::{Constraint, GuiContext, Layout};
+use meta_store::{Field, Store};
+
+/// Debugger running a RunTest.
+pub struct DebugSession {
+    pub test: Field,
+    pub debugger: Debugger,
+}
+
+/// Shows debugger status, registers and breakpoints.
+pub(crate) struct DebuggerPanel<'a> {
+    session: &'a DebugSession,
+    store: &'a Store,
+}
+
+impl<'a> DebuggerPanel<'a> {
+    pub fn new(session: &'a DebugSession, store: &'a Store) -> Self {
+        Self { session, store }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let core = MetaCore::new(self.store);
+        let debugger = &self.session.debugger;
+        let mut lines = Vec::new();
+
+        lines.push(format!(
+            "debugging {}",
+            describe_entity(&core, &self.session.test)
+        ));
+        lines.push(match debugger.status() {
+            DebugStatus::Paused => format!("paused at {}", debugger.position()),
+            DebugStatus::Halted(Some(value)) => format!("halted with {}", value),
+            DebugStatus::Halted(None) => "halted".to_string(),
+            DebugStatus::Failed(error) => format!("failed at {}: {:?}", error.position, error.kind),
+        });
+        if let Some(entity) = debugger.entity() {
+            lines.push(format!("in {}", describe_entity(&core, entity)));
+        }
+
+        lines.push(String::new());
+        lines.push("registers:".to_string());
+        for register in debugger.registers() {
+            let mut line = format!("  r{} = {}", register.reg, describe_value(&register.value));
+            if let DebugValue::Pointer(address) = register.value {
+                if let Some(record) = debugger.record(address) {
+                    let fields = record.fields.iter().map(describe_value).collect::<Vec<_>>();
+                    line.push_str(&format!(" [{}] @{}", fields.join(", "), record.offset));
+                }
+            }
+            if let Some((entity, name)) = &register.variable {
+                let name = name.clone().unwrap_or_else(|| entity.to_string());
+                line.push_str(&format!("  ; {}", name));
+            }
+            lines.push(line);
+        }
+
+        lines.push(String::new());
+        lines.push("breakpoints:".to_string());
+        for breakpoint in debugger.breakpoints() {
+            let line = match breakpoint {
+                meta_f::Breakpoint::Position(position) => format!("  at {}", position),
+                meta_f::Breakpoint::Entity(entity) => {
+                    format!("  {}", describe_entity(&core, entity))
+                }
+            };
+            lines.push(line);
+        }
+
+        lines.push(String::new());
+        lines.push("F5 continue, F10 step, F11 step instruction, Shift+F5 stop".to_string());
+        lines
+    }
+}
+
+impl<'a> Layout for DebuggerPanel<'a> {
+    fn layout(&mut self, ctx: &mut GuiContext, constraint: Constraint) -> Size {
+        let lines = self.lines();
+        Background::new(Inset::new(
+            List::new(lines.iter().map(|line| {
+                Text::new(line)
+                    .with_font("Input")
+                    .with_color(Color::rgb8(0x28, 0x28, 0x28))
+            })),
+            Insets::uniform(4.0),
+        ))
+        .with_color(Color::rgb8(0xf0, 0xf0, 0xf0))
+        .layout(ctx, constraint)
+    }
+}
+
+/// Type and identifier of `entity`.
+fn describe_entity(core: &MetaCore, entity: &Field) -> String {
+    let type_ = core
+        .meta_type(entity)
+        .and_then(|type_| core.identifier(&type_.value))
+        .map_or_else(|| "?".to_string(), |datom| datom.value.to_string());
+    let identifier = core
+        .store
+        .value(entity, &meta_f::ids::IDENTIFIER_IDENTIFIER)
+        .or_else(|| core.identifier(entity))
+        .map_or_else(|| entity.to_string(), |datom| datom.value.to_string());
+    format!("{} {}", type_, identifier)
+}
+
+fn describe_value(value: &DebugValue) -> String {
+    match value {
+        DebugValue::Number(n) => n.to_string(),
+        DebugValue::Bool(b) => b.to_string(),
+        DebugValue::String(s) => format!("{:?}", s),
+        DebugValue::Pointer(address) => format!("{:#x}", address),
+        value => format!("{:?}", value),
+    }
+}
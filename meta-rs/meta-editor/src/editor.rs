@@ -1,4 +1,7 @@
 use std::fmt::Debug;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
 
 use druid_shell::kurbo::{Insets, Point, Rect, Size, Vec2};
 use druid_shell::piet::Color;
@@ -12,12 +15,14 @@ use meta_core::MetaCore;
 use meta_gui::widgets::{Direction, List, Scrollable, ScrolledList, Translate};
 use meta_gui::{Constraint, Event, EventType, GuiContext, Layout, SubscriptionId};
 use meta_pretty::{Cell, Path, RichDocRef, SimpleDocKind};
-use meta_store::{Datom, Field, Store};
+use meta_store::{log_path, ChangeSet, Datom, Field, Format, Store, TxId};
 
 use crate::autocomplete::{Autocomplete, AutocompleteEvent};
 use crate::cell_widget::CellWidget;
 use crate::core_layout::core_layout_languages;
+use crate::debugger_panel::{DebugSession, DebuggerPanel};
 use crate::doc_view::DocView;
+use crate::history::{EditKind, History};
 use crate::key::{GlobalKeys, KeyHandler};
 use crate::layout::{CellClass, Doc, EditorCellPayload, RDoc, SDoc};
 
@@ -28,6 +33,8 @@ const INSET: f64 = 10.0;
 
 const SCROLLOFF: f64 = 28.0;
 
+const DEBUGGER_PANEL_WIDTH: f64 = 420.0;
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct CursorPosition {
     pub sdoc: SDoc,
@@ -54,6 +61,26 @@ pub struct Editor {
     scroll: Scrollable,
     autocomplete: Option<Translate<Autocomplete<Field>>>,
     layout_fn: fn(&Store) -> RDoc,
+    /// Ids of datoms that fail schema validation or belong to `error_entities`.
+    invalid_datoms: HashSet<Field>,
+    /// Ids of datoms that fail schema validation. Only datoms affected by store changes are
+    /// revalidated.
+    schema_invalid_datoms: HashSet<Field>,
+    /// Entities reported by the last meta-f type check.
+    error_entities: HashSet<Field>,
+    debug_session: Option<DebugSession>,
+    /// Entities to pause meta-f programs at. Set on all new debug sessions.
+    breakpoints: HashSet<Field>,
+    /// Ids of datoms of the entity the debugger is paused at.
+    debug_datoms: HashSet<Field>,
+    /// Where and in which format the store is saved.
+    save_path: PathBuf,
+    save_format: Format,
+    /// Last store transaction the layout reflects.
+    last_tx: TxId,
+    history: History,
+    /// Kind of the edit being made. Reset after the edit is recorded to history.
+    edit_kind: EditKind,
 }
 
 impl Editor {
@@ -61,6 +88,10 @@ impl Editor {
         let layout_fn = core_layout_languages;
         let doc_view = DocView::new(layout_fn(&store).into());
         let cursor = doc_view.cell_position_to_cursor(CellPosition::new(0, 0));
+        let error_entities = HashSet::new();
+        let schema_invalid_datoms = schema_invalid_datoms(&store);
+        let invalid_datoms = invalid_datoms(&store, &schema_invalid_datoms, &error_entities);
+        let last_tx = store.last_tx();
 
         Editor {
             id,
@@ -71,9 +102,94 @@ impl Editor {
             scroll: Scrollable::new(SubscriptionId::new()),
             autocomplete: None,
             layout_fn,
+            invalid_datoms,
+            schema_invalid_datoms,
+            error_entities,
+            debug_session: None,
+            breakpoints: HashSet::new(),
+            debug_datoms: HashSet::new(),
+            save_path: PathBuf::from("store.meta"),
+            save_format: Format::Json,
+            last_tx,
+            history: History::new(),
+            edit_kind: EditKind::Other,
         }
     }
 
+    /// Highlight all datoms of `entities` as errors.
+    pub fn set_error_entities(&mut self, entities: HashSet<Field>) {
+        self.error_entities = entities;
+        self.invalid_datoms = invalid_datoms(
+            &self.store,
+            &self.schema_invalid_datoms,
+            &self.error_entities,
+        );
+    }
+
+    /// Set or clear breakpoint at `entity` for the current and future debug sessions.
+    pub fn toggle_breakpoint(&mut self, entity: &Field) {
+        let breakpoint = meta_f::Breakpoint::Entity(entity.clone());
+        if self.breakpoints.remove(entity).is_some() {
+            if let Some(session) = &mut self.debug_session {
+                session.debugger.remove_breakpoint(&breakpoint);
+            }
+        } else {
+            self.breakpoints.insert(entity.clone());
+            if let Some(session) = &mut self.debug_session {
+                session.debugger.add_breakpoint(breakpoint);
+            }
+        }
+    }
+
+    pub fn debug_session(&self) -> Option<&DebugSession> {
+        self.debug_session.as_ref()
+    }
+
+    /// Replace the debug session. The session gets all editor breakpoints.
+    pub fn set_debug_session(&mut self, session: Option<DebugSession>) {
+        self.debug_session = session.map(|mut session| {
+            for entity in self.breakpoints.iter() {
+                let breakpoint = meta_f::Breakpoint::Entity(entity.clone());
+                session.debugger.add_breakpoint(breakpoint);
+            }
+            session
+        });
+        self.on_debugger_updated();
+    }
+
+    /// Run `f` on the debugger of the current session (if any) and highlight the entity it
+    /// pauses at.
+    pub fn with_debugger<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut meta_f::Debugger),
+    {
+        if let Some(session) = &mut self.debug_session {
+            f(&mut session.debugger);
+        }
+        self.on_debugger_updated();
+    }
+
+    fn on_debugger_updated(&mut self) {
+        let entity = self
+            .debug_session
+            .as_ref()
+            .and_then(|session| session.debugger.entity().cloned());
+        self.debug_datoms = entity_datoms(&self.store, entity.iter()).collect();
+    }
+
+    pub fn set_save_target(&mut self, path: PathBuf, format: Format) {
+        self.save_path = path;
+        self.save_format = format;
+    }
+
+    /// Save the store along with its transaction log.
+    pub fn save(&self) -> meta_store::Result<()> {
+        let f = File::create(&self.save_path)?;
+        self.store.write(BufWriter::new(f), self.save_format)?;
+        let f = File::create(log_path(&self.save_path))?;
+        self.store.write_log(BufWriter::new(f))
+    }
+
     pub fn doc(&self) -> &Doc {
         &self.doc_view.doc()
     }
@@ -107,7 +223,7 @@ impl Editor {
 
     pub fn set_layout_fn(&mut self, f: fn(&Store) -> RDoc) {
         self.layout_fn = f;
-        self.on_store_updated();
+        self.relayout();
         self.set_cursor(self.cell_position_to_cursor(CellPosition::new(0, 0)));
     }
 
@@ -118,7 +234,81 @@ impl Editor {
         })
     }
 
+    /// Record changes made since the last call to undo history and update layout.
     pub fn on_store_updated(&mut self) {
+        // Store history might have been replaced with an older one, so the transaction id going
+        // back is a change as well.
+        let changes = self.store.changes_since(self.last_tx);
+        let is_replaced = self.store.last_tx() < self.last_tx;
+        let is_changed = is_replaced || !changes.is_empty();
+        self.last_tx = self.store.last_tx();
+
+        if is_replaced {
+            self.schema_invalid_datoms = schema_invalid_datoms(&self.store);
+        } else if is_changed {
+            self.revalidate(&changes);
+        }
+
+        let kind = std::mem::replace(&mut self.edit_kind, EditKind::Other);
+        let position = self.current_position();
+        self.history.record(changes, kind, position);
+
+        if !is_changed {
+            trace!("store has not changed");
+            return;
+        }
+
+        self.relayout();
+    }
+
+    /// Returns `true` if there was something to undo.
+    pub fn undo(&mut self) -> bool {
+        let cursor = self.current_position();
+        match self.history.undo(&mut self.store, cursor) {
+            Some(position) => {
+                self.on_history_step(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns `true` if there was something to redo.
+    pub fn redo(&mut self) -> bool {
+        match self.history.redo(&mut self.store) {
+            Some(position) => {
+                self.on_history_step(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn on_history_step(&mut self, position: Option<CellPosition>) {
+        // changes made by undo/redo should not be recorded
+        let changes = self.store.changes_since(self.last_tx);
+        self.last_tx = self.store.last_tx();
+        self.revalidate(&changes);
+        self.autocomplete = None;
+        self.relayout();
+        if let Some(position) = position {
+            self.set_cursor(self.cell_position_to_cursor(position));
+        }
+    }
+
+    /// Update schema validation results of datoms affected by `changes`.
+    fn revalidate(&mut self, changes: &ChangeSet) {
+        let (checked, diagnostics) = MetaCore::new(&self.store).validate_changes(changes);
+        self.schema_invalid_datoms
+            .retain(|id| !checked.contains(id));
+        self.schema_invalid_datoms.extend(
+            diagnostics
+                .into_iter()
+                .map(|diagnostic| diagnostic.datom.id),
+        );
+    }
+
+    fn relayout(&mut self) {
         let doc_view = DocView::new((self.layout_fn)(&self.store).into());
 
         let cursor = self.cursor.as_ref().and_then(|CursorPosition { sdoc: s, offset }| {
@@ -157,6 +347,12 @@ impl Editor {
         });
 
         self.doc_view = doc_view;
+        self.invalid_datoms = invalid_datoms(
+            &self.store,
+            &self.schema_invalid_datoms,
+            &self.error_entities,
+        );
+        self.on_debugger_updated();
         self.set_cursor(cursor);
     }
 
@@ -271,6 +467,7 @@ impl Editor {
     }
 
     pub fn self_insert(&mut self, text: &str) -> bool {
+        self.edit_kind = EditKind::Insert;
         let edited = self.edit_datom(|datom, offset| {
             let grapheme_offset = datom
                 .value
@@ -287,6 +484,7 @@ impl Editor {
 
             Some(new_datom)
         });
+        self.edit_kind = EditKind::Other;
         if edited {
             self.move_cursor(0, 1);
         }
@@ -351,8 +549,7 @@ impl Editor {
                     if let Some(new_datom) = f(datom, *offset) {
                         debug!("replacing {:?} with {:?}", datom, new_datom);
 
-                        self.store.remove_datom(datom);
-                        self.store.add_datom(&new_datom);
+                        self.store.transact(vec![datom.clone()], vec![new_datom]);
                         self.on_store_updated();
 
                         return true;
@@ -463,8 +660,8 @@ impl Editor {
 
                     trace!("replacing {:?} with {:?}", old_datom, new_datom);
 
-                    self.store.remove_datom(old_datom);
-                    self.store.add_datom(&new_datom);
+                    self.store
+                        .transact(vec![old_datom.clone()], vec![new_datom]);
                     self.on_store_updated();
                 }
             }
@@ -568,6 +765,8 @@ impl Layout for Editor {
         }
 
         let cursor = &self.cursor;
+        let invalid_datoms = &self.invalid_datoms;
+        let debug_datoms = &self.debug_datoms;
         let scroll = &mut self.scroll;
         let layout = self.doc_view.layout();
 
@@ -575,8 +774,11 @@ impl Layout for Editor {
             scroll,
             CHAR_HEIGHT,
             layout.iter().map(|line| {
-                List::new(line.iter().map(|x| CellWidget(x, &cursor)))
-                    .with_direction(Direction::Horizontal)
+                List::new(
+                    line.iter()
+                        .map(|x| CellWidget(x, &cursor, invalid_datoms, debug_datoms)),
+                )
+                .with_direction(Direction::Horizontal)
             }),
         )
         .with_insets(Insets::uniform(INSET))
@@ -588,6 +790,15 @@ impl Layout for Editor {
         )
         .layout(ctx, Constraint::loose(ctx.window_size()));
 
+        if let Some(session) = &self.debug_session {
+            let window_size = ctx.window_size();
+            Translate::new(
+                DebuggerPanel::new(session, &self.store),
+                Vec2::new(window_size.width - DEBUGGER_PANEL_WIDTH, 0.0),
+            )
+            .layout(ctx, Constraint::loose(window_size));
+        }
+
         if let Some(autocomplete) = &mut self.autocomplete {
             for e in autocomplete.child_mut().events() {
                 match e {
@@ -653,6 +864,38 @@ impl Debug for Editor {
     }
 }
 
+fn invalid_datoms(
+    store: &Store,
+    schema_invalid_datoms: &HashSet<Field>,
+    error_entities: &HashSet<Field>,
+) -> HashSet<Field> {
+    schema_invalid_datoms
+        .iter()
+        .cloned()
+        .chain(entity_datoms(store, error_entities.iter()))
+        .collect()
+}
+
+/// Ids of all datoms that fail schema validation.
+fn schema_invalid_datoms(store: &Store) -> HashSet<Field> {
+    MetaCore::new(store)
+        .validate()
+        .into_iter()
+        .map(|diagnostic| diagnostic.datom.id)
+        .collect()
+}
+
+/// Ids of all datoms of `entities`.
+fn entity_datoms<'a>(
+    store: &'a Store,
+    entities: impl Iterator<Item = &'a Field> + 'a,
+) -> impl Iterator<Item = Field> + 'a {
+    entities
+        .filter_map(move |e| store.eav1(e))
+        .flat_map(|attributes| attributes.values())
+        .flat_map(|datoms| datoms.iter().map(|datom| datom.id.clone()))
+}
+
 /// Find meta node with id `id`.
 fn find_id<'a, 'b>(doc: &'a Doc, id: &'b [Field]) -> Option<&'a Doc> {
     match doc.kind() {
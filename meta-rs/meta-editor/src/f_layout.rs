@@ -1,5 +1,7 @@
+use std::time::{Duration, Instant};
+
 use druid_shell::{HotKey, KeyCode, KeyEvent, SysMods};
-use im::HashMap;
+use im::{HashMap, HashSet};
 use itertools::Itertools;
 use lazy_static::lazy_static;
 
@@ -8,6 +10,7 @@ use meta_core::MetaCore;
 use meta_f::ids;
 use meta_store::{Datom, Field, Store};
 
+use crate::debugger_panel::DebugSession;
 use crate::key::KeyHandler;
 use crate::layout::*;
 use crate::reorder_keys::ReorderKeys;
@@ -30,6 +33,9 @@ lazy_static! {
         m.insert(ids::TYPEDEF.clone(), layout_typedef);
         m.insert(ids::CONSTRUCTOR.clone(), layout_constructor);
         m.insert(ids::ACCESS.clone(), layout_access);
+        m.insert(ids::MATCH.clone(), layout_match);
+        m.insert(ids::CASE.clone(), layout_case);
+        m.insert(ids::IF.clone(), layout_if);
         m
     };
 }
@@ -79,7 +85,17 @@ impl KeyHandler for RunTestKeys {
     fn handle_key(&self, key: KeyEvent, editor: &mut crate::editor::Editor) -> bool {
         if HotKey::new(None, KeyCode::F3).matches(key) {
             let test = &self.0;
-            let result = meta_f::interpret(editor.store(), test);
+            let options = meta_f::Options {
+                deadline: Some(Instant::now() + Duration::from_secs(2)),
+                ..run_options()
+            };
+            let result = meta_f::interpret(editor.store(), test, &options);
+
+            let error_entities = match &result {
+                Err(error) => error_entities(error),
+                _ => HashSet::new(),
+            };
+            editor.set_error_entities(error_entities);
 
             editor.with_store(|store| {
                 let id =
@@ -94,17 +110,97 @@ impl KeyHandler for RunTestKeys {
                     id,
                     test.clone(),
                     ids::RUN_TEST_ACTUAL_RESULT.clone(),
-                    Field::from(format!("{:?}", result)),
+                    Field::from(meta_f::describe_result(&result)),
                 ));
             });
 
             return true;
         }
 
+        if HotKey::new(SysMods::Shift, KeyCode::F5).matches(key) {
+            editor.set_debug_session(None);
+            return true;
+        }
+        if HotKey::new(None, KeyCode::F5).matches(key) {
+            if self.start_debugging(editor) {
+                editor.with_debugger(|debugger| {
+                    debugger.resume();
+                });
+            }
+            return true;
+        }
+        if HotKey::new(None, KeyCode::F10).matches(key) {
+            if self.start_debugging(editor) {
+                editor.with_debugger(|debugger| {
+                    debugger.step_entity();
+                });
+            }
+            return true;
+        }
+        if HotKey::new(None, KeyCode::F11).matches(key) {
+            if self.start_debugging(editor) {
+                editor.with_debugger(|debugger| {
+                    debugger.step();
+                });
+            }
+            return true;
+        }
+
         false
     }
 }
 
+impl RunTestKeys {
+    /// Start a debug session for the test unless it is already paused in one. Returns `false` if
+    /// the test fails to compile.
+    fn start_debugging(&self, editor: &mut crate::editor::Editor) -> bool {
+        let test = &self.0;
+        let is_paused = editor.debug_session().map_or(false, |session| {
+            &session.test == test
+                && matches!(session.debugger.status(), meta_f::DebugStatus::Paused)
+        });
+        if is_paused {
+            return true;
+        }
+
+        // There is no deadline, as the program is paused for as long as the user wants.
+        match meta_f::debug(editor.store(), test, &run_options()) {
+            Ok(debugger) => {
+                editor.set_error_entities(HashSet::new());
+                editor.set_debug_session(Some(DebugSession {
+                    test: test.clone(),
+                    debugger,
+                }));
+                true
+            }
+            Err(error) => {
+                editor.set_error_entities(error_entities(&error));
+                editor.set_debug_session(None);
+                false
+            }
+        }
+    }
+}
+
+/// Tests run on the UI thread, so don't let them hang the editor.
+fn run_options() -> meta_f::Options {
+    meta_f::Options {
+        fuel: Some(100_000_000),
+        max_heap_size: Some(256 * 1024 * 1024),
+        ..meta_f::Options::default()
+    }
+}
+
+/// Entities to highlight for a failed test.
+fn error_entities(error: &meta_f::Error) -> HashSet<Field> {
+    match error {
+        meta_f::Error::TypeError(errors) => {
+            errors.iter().map(|error| error.entry().clone()).collect()
+        }
+        _ => HashSet::new(),
+    }
+}
+
 #[derive(Debug)]
 struct BlockKeys(Field);
 impl KeyHandler for BlockKeys {
@@ -266,6 +362,101 @@ impl KeyHandler for ConstructorParamsKeys {
     }
 }
 
+#[derive(Debug)]
+struct MatchKeys(Field);
+impl KeyHandler for MatchKeys {
+    fn handle_key(&self, key: KeyEvent, editor: &mut crate::editor::Editor) -> bool {
+        let m = &self.0;
+        if HotKey::new(SysMods::Cmd, KeyCode::Return).matches(key) {
+            let case = Field::new_id();
+            let constructor = Field::new_id();
+            editor.with_store(|store| {
+                store.add_datom(&Datom::eav(
+                    m.clone(),
+                    ids::MATCH_CASE.clone(),
+                    case.clone(),
+                ));
+                store.add_datom(&Datom::eav(
+                    case.clone(),
+                    core::A_TYPE.clone(),
+                    ids::CASE.clone(),
+                ));
+                store.add_datom(&Datom::eav(
+                    case.clone(),
+                    ids::CASE_CONSTRUCTOR.clone(),
+                    constructor.clone(),
+                ));
+                store.add_datom(&Datom::eav(
+                    constructor.clone(),
+                    core::A_TYPE.clone(),
+                    ids::IDENTIFIER_REFERENCE.clone(),
+                ));
+                store.add_datom(&Datom::eav(
+                    constructor.clone(),
+                    ids::IDENTIFIER_REFERENCE_IDENTIFIER.clone(),
+                    "".into(),
+                ));
+                store.add_datom(&Datom::eav(
+                    case.clone(),
+                    ids::CASE_BODY.clone(),
+                    Field::new_id(),
+                ));
+            });
+
+            editor.goto_cell_id(&[constructor]);
+
+            return true;
+        }
+
+        false
+    }
+}
+
+#[derive(Debug)]
+struct CaseParamsKeys(Field);
+impl KeyHandler for CaseParamsKeys {
+    fn handle_key(&self, key: KeyEvent, editor: &mut crate::editor::Editor) -> bool {
+        let case = &self.0;
+        if HotKey::new(SysMods::Cmd, KeyCode::Return).matches(key) {
+            let param = Field::new_id();
+            let identifier = Field::new_id();
+            editor.with_store(|store| {
+                store.add_datom(&Datom::eav(
+                    case.clone(),
+                    ids::CASE_PARAMETER.clone(),
+                    param.clone(),
+                ));
+                store.add_datom(&Datom::eav(
+                    param.clone(),
+                    core::A_TYPE.clone(),
+                    ids::PARAMETER.clone(),
+                ));
+                store.add_datom(&Datom::eav(
+                    param.clone(),
+                    ids::PARAMETER_IDENTIFIER.clone(),
+                    identifier.clone(),
+                ));
+                store.add_datom(&Datom::eav(
+                    identifier.clone(),
+                    core::A_TYPE.clone(),
+                    ids::IDENTIFIER.clone(),
+                ));
+                store.add_datom(&Datom::eav(
+                    identifier.clone(),
+                    ids::IDENTIFIER_IDENTIFIER.clone(),
+                    "".into(),
+                ));
+            });
+
+            editor.goto_cell_id(&[identifier]);
+
+            return true;
+        }
+
+        false
+    }
+}
+
 #[derive(Debug)]
 struct ApplicationArgsKeys(Field);
 impl KeyHandler for ApplicationArgsKeys {
@@ -294,6 +485,11 @@ impl KeyHandler for ApplicationArgsKeys {
 struct EntityKeys(Datom);
 impl KeyHandler for EntityKeys {
     fn handle_key(&self, key: KeyEvent, editor: &mut crate::editor::Editor) -> bool {
+        if HotKey::new(None, KeyCode::F9).matches(key) {
+            editor.toggle_breakpoint(&self.0.value);
+            return true;
+        }
+
         let core = MetaCore::new(editor.store());
         let id = &self.0.value;
         let my_type = core.meta_type(id).map(|d| &d.value);
@@ -304,6 +500,8 @@ impl KeyHandler for EntityKeys {
             if &self.0.attribute == &ids::FUNCTION_PARAMETER as &Field
                 || &self.0.attribute == &ids::BLOCK_STATEMENT as &Field
                 || &self.0.attribute == &ids::APPLICATION_ARGUMENT as &Field
+                || &self.0.attribute == &ids::MATCH_CASE as &Field
+                || &self.0.attribute == &ids::CASE_PARAMETER as &Field
                 || my_type == Some(&ids::RUN_TEST)
             {
                 // delete completely
@@ -447,6 +645,56 @@ impl KeyHandler for HoleKeys {
             return true;
         }
 
+        if HotKey::new(None, "m").matches(key) {
+            let value = Field::new_id();
+            editor.with_store(|store| {
+                store.add_datom(&Datom::eav(
+                    id.clone(),
+                    core::A_TYPE.clone(),
+                    ids::MATCH.clone(),
+                ));
+                store.add_datom(&Datom::eav(
+                    id.clone(),
+                    ids::MATCH_VALUE.clone(),
+                    value.clone(),
+                ));
+            });
+
+            editor.goto_cell_id(&[value]);
+
+            return true;
+        }
+
+        if HotKey::new(None, "i").matches(key) {
+            let condition = Field::new_id();
+            editor.with_store(|store| {
+                store.add_datom(&Datom::eav(
+                    id.clone(),
+                    core::A_TYPE.clone(),
+                    ids::IF.clone(),
+                ));
+                store.add_datom(&Datom::eav(
+                    id.clone(),
+                    ids::IF_CONDITION.clone(),
+                    condition.clone(),
+                ));
+                store.add_datom(&Datom::eav(
+                    id.clone(),
+                    ids::IF_THEN.clone(),
+                    Field::new_id(),
+                ));
+                store.add_datom(&Datom::eav(
+                    id.clone(),
+                    ids::IF_ELSE.clone(),
+                    Field::new_id(),
+                ));
+            });
+
+            editor.goto_cell_id(&[condition]);
+
+            return true;
+        }
+
         if HotKey::new(None, "(").matches(key) || HotKey::new(SysMods::Shift, "(").matches(key) {
             editor.with_store(|store| {
                 store.add_datom(&Datom::eav(
@@ -794,6 +1042,107 @@ fn layout_access(core: &MetaCore, datom: &Datom) -> RDoc {
     ])
 }
 
+fn layout_match(core: &MetaCore, datom: &Datom) -> RDoc {
+    let entity = &datom.value;
+    let cases = core.ordered_values(entity, &ids::MATCH_CASE);
+
+    with_key_handler(
+        Box::new(MatchKeys(entity.clone())),
+        concat(vec![
+            text("match"), // TODO: keyword
+            whitespace(" "),
+            core.store
+                .value(entity, &ids::MATCH_VALUE)
+                .map_or_else(empty, |d| f_layout(core, d)),
+            whitespace(" "),
+            group(braces(concat(vec![
+                nest(
+                    2,
+                    concat(
+                        cases
+                            .iter()
+                            .map(|c| {
+                                with_key_handler(
+                                    Box::new(ReorderKeys((*c).clone())),
+                                    concat(vec![line(), f_layout(core, c)]),
+                                )
+                                .with_key(c.id.to_string())
+                            })
+                            .intersperse_with(|| punctuation(",")),
+                    ),
+                ),
+                line(),
+            ]))),
+        ]),
+    )
+}
+
+fn layout_case(core: &MetaCore, datom: &Datom) -> RDoc {
+    let entity = &datom.value;
+    let params = core.ordered_values(entity, &ids::CASE_PARAMETER);
+
+    group(concat(vec![
+        core.store
+            .value(entity, &ids::CASE_CONSTRUCTOR)
+            .map_or_else(empty, |d| f_layout(core, d)),
+        with_key_handler(
+            Box::new(CaseParamsKeys(entity.clone())),
+            parentheses(concat(
+                params
+                    .iter()
+                    .map(|p| {
+                        with_key_handler(Box::new(ReorderKeys((*p).clone())), f_layout(core, p))
+                            .with_key(p.id.to_string())
+                    })
+                    .intersperse_with(|| concat(vec![punctuation(","), whitespace(" ")])),
+            )),
+        ),
+        whitespace(" "),
+        punctuation("->"),
+        nest(
+            2,
+            concat(vec![
+                line(),
+                core.store
+                    .value(entity, &ids::CASE_BODY)
+                    .map_or_else(empty, |d| f_layout(core, d)),
+            ]),
+        ),
+    ]))
+}
+
+fn layout_if(core: &MetaCore, datom: &Datom) -> RDoc {
+    let entity = &datom.value;
+    let branch = |attr: &Field| {
+        braces(concat(vec![
+            nest(
+                2,
+                concat(vec![
+                    line(),
+                    core.store
+                        .value(entity, attr)
+                        .map_or_else(empty, |d| f_layout(core, d)),
+                ]),
+            ),
+            line(),
+        ]))
+    };
+
+    group(concat(vec![
+        text("if"), // TODO: keyword
+        whitespace(" "),
+        core.store
+            .value(entity, &ids::IF_CONDITION)
+            .map_or_else(empty, |d| f_layout(core, d)),
+        whitespace(" "),
+        branch(&ids::IF_THEN),
+        whitespace(" "),
+        text("else"),
+        whitespace(" "),
+        branch(&ids::IF_ELSE),
+    ]))
+}
+
 fn layout_hole(_core: &MetaCore, datom: &Datom) -> RDoc {
     with_key_handler(Box::new(HoleKeys(datom.clone())), text("_"))
 }
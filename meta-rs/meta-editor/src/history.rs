@@ -0,0 +1,189 @@
+use meta_store::{ChangeSet, Datom, Field, Store};
+
+use crate::editor::CellPosition;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum EditKind {
+    /// Character insertion. Consecutive insertions into the same datom are undone at once.
+    Insert,
+    Other,
+}
+
+/// A single undoable edit.
+#[derive(Debug, Clone)]
+struct Step {
+    removed: Vec<Datom>,
+    added: Vec<Datom>,
+    kind: EditKind,
+    /// Cursor position before the edit. Restored on undo.
+    cursor_before: Option<CellPosition>,
+    /// Cursor position at the moment of undo. Restored on redo.
+    cursor_after: Option<CellPosition>,
+}
+
+/// Undo/redo stacks of store edits.
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    /// Record an edit. Recording a new edit clears redo stack.
+    pub fn record(
+        &mut self,
+        changes: ChangeSet,
+        kind: EditKind,
+        cursor_before: Option<CellPosition>,
+    ) {
+        if changes.is_empty() {
+            return;
+        }
+        self.redo.clear();
+
+        if let Some(last) = self.undo.last_mut() {
+            if kind == EditKind::Insert && last.kind == EditKind::Insert && last.continues(&changes)
+            {
+                last.compose(changes);
+                return;
+            }
+        }
+
+        self.undo.push(Step {
+            removed: changes.removed,
+            added: changes.added,
+            kind,
+            cursor_before,
+            cursor_after: None,
+        });
+    }
+
+    /// Revert the last edit. `cursor` is the current cursor position that is restored on redo.
+    ///
+    /// Returns `None` if there is nothing to undo, or the cursor position to restore.
+    pub fn undo(
+        &mut self,
+        store: &mut Store,
+        cursor: Option<CellPosition>,
+    ) -> Option<Option<CellPosition>> {
+        let mut step = self.undo.pop()?;
+        store.transact(step.added.clone(), step.removed.clone());
+
+        step.cursor_after = cursor;
+        let result = step.cursor_before;
+        self.redo.push(step);
+        Some(result)
+    }
+
+    /// Reapply the last undone edit.
+    ///
+    /// Returns `None` if there is nothing to redo, or the cursor position to restore.
+    pub fn redo(&mut self, store: &mut Store) -> Option<Option<CellPosition>> {
+        let step = self.redo.pop()?;
+        store.transact(step.removed.clone(), step.added.clone());
+
+        let result = step.cursor_after;
+        self.undo.push(step);
+        Some(result)
+    }
+}
+
+impl Step {
+    /// Whether `changes` edit the same datoms this step has produced.
+    fn continues(&self, changes: &ChangeSet) -> bool {
+        fn ids(datoms: &[Datom]) -> Vec<&Field> {
+            let mut ids = datoms.iter().map(|d| &d.id).collect::<Vec<_>>();
+            ids.sort_unstable();
+            ids
+        }
+        ids(&self.added) == ids(&changes.removed) && ids(&changes.removed) == ids(&changes.added)
+    }
+
+    /// Merge `changes` into this step, so undoing it reverts both.
+    fn compose(&mut self, changes: ChangeSet) {
+        let ChangeSet { removed, added, .. } = changes;
+        let prev_added = std::mem::take(&mut self.added);
+        self.removed
+            .extend(removed.iter().filter(|d| !prev_added.contains(d)).cloned());
+        self.added = prev_added
+            .into_iter()
+            .filter(|d| !removed.contains(d))
+            .chain(added)
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn edit(store: &mut Store, history: &mut History, kind: EditKind, from: &str, to: &str) {
+        let changes = store.transact_changes(
+            vec![("1", "0", "0", from).into()],
+            vec![("1", "0", "0", to).into()],
+        );
+        history.record(changes, kind, Some(CellPosition::new(0, from.len())));
+    }
+
+    fn value(store: &Store) -> &str {
+        store.atoms().get(&"1".into()).unwrap().value.as_ref()
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut store = Store::from_str(r#"["1", "0", "0", "a"]"#).unwrap();
+        let mut history = History::new();
+        edit(&mut store, &mut history, EditKind::Other, "a", "b");
+
+        assert_eq!(
+            Some(Some(CellPosition::new(0, 1))),
+            history.undo(&mut store, Some(CellPosition::new(1, 1)))
+        );
+        assert_eq!("a", value(&store));
+        assert_eq!(None, history.undo(&mut store, None));
+
+        assert_eq!(
+            Some(Some(CellPosition::new(1, 1))),
+            history.redo(&mut store)
+        );
+        assert_eq!("b", value(&store));
+        assert_eq!(None, history.redo(&mut store));
+    }
+
+    #[test]
+    fn test_insertions_are_grouped() {
+        let mut store = Store::from_str(r#"["1", "0", "0", ""]"#).unwrap();
+        let mut history = History::new();
+        edit(&mut store, &mut history, EditKind::Insert, "", "a");
+        edit(&mut store, &mut history, EditKind::Insert, "a", "ab");
+        edit(&mut store, &mut history, EditKind::Other, "ab", "b");
+        edit(&mut store, &mut history, EditKind::Insert, "b", "bc");
+
+        history.undo(&mut store, None);
+        assert_eq!("b", value(&store));
+        history.undo(&mut store, None);
+        assert_eq!("ab", value(&store));
+        assert_eq!(
+            Some(Some(CellPosition::new(0, 0))),
+            history.undo(&mut store, None)
+        );
+        assert_eq!("", value(&store));
+    }
+
+    #[test]
+    fn test_record_clears_redo() {
+        let mut store = Store::from_str(r#"["1", "0", "0", "a"]"#).unwrap();
+        let mut history = History::new();
+        edit(&mut store, &mut history, EditKind::Other, "a", "b");
+        history.undo(&mut store, None);
+        edit(&mut store, &mut history, EditKind::Other, "a", "c");
+
+        assert_eq!(None, history.redo(&mut store));
+        assert_eq!("c", value(&store));
+    }
+}
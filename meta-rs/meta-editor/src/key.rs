@@ -1,4 +1,4 @@
-use std::{fmt::Debug, fs::File, io::BufWriter};
+use std::fmt::Debug;
 
 use druid_shell::{HotKey, KeyCode, KeyEvent, RawMods, SysMods};
 
@@ -81,11 +81,20 @@ impl KeyHandler for GlobalKeys {
             return true;
         }
 
+        if HotKey::new(SysMods::Cmd, KeyCode::KeyZ).matches(key) {
+            editor.undo();
+            return true;
+        }
+
+        if HotKey::new(SysMods::CmdShift, KeyCode::KeyZ).matches(key)
+            || HotKey::new(SysMods::Cmd, KeyCode::KeyY).matches(key)
+        {
+            editor.redo();
+            return true;
+        }
+
         if HotKey::new(SysMods::Cmd, KeyCode::KeyS).matches(key) {
-            let store = editor.store();
-            let f = File::create("store.meta").unwrap();
-            let writer = BufWriter::new(f);
-            serde_json::to_writer_pretty(writer, store).unwrap();
+            editor.save().unwrap();
             return true;
         }
 
@@ -1,23 +1,29 @@
 mod autocomplete;
 mod cell_widget;
 mod core_layout;
+mod debugger_panel;
 mod doc_view;
 mod editor;
 mod f_layout;
+mod history;
 mod key;
 mod layout;
 mod reorder_keys;
 
+use std::path::PathBuf;
+
 use druid_shell::Application;
 
 use meta_gui::{Constraint, Gui, Layout, SubscriptionId};
-use meta_store::Store;
+use meta_store::{Format, Store};
 
 use crate::editor::Editor;
 
-pub fn main(store: Store) {
+/// Run editor. The store is saved to `path` in `format`.
+pub fn main(store: Store, path: PathBuf, format: Format) {
     let app = Application::new().unwrap();
     let mut editor = Editor::new(SubscriptionId::new(), store);
+    editor.set_save_target(path, format);
     Gui::run(app.clone(), move |ctx| {
         editor.layout(ctx, Constraint::UNBOUND);
     });
@@ -1,12 +1,10 @@
 use druid_shell::{HotKey, KeyCode, KeyEvent, SysMods};
 
-use meta_core::ids;
 use meta_core::MetaCore;
-use meta_store::{Datom, Field, Store};
+use meta_store::Datom;
 
 use crate::editor::Editor;
 use crate::key::KeyHandler;
-use im::HashSet;
 use log::{trace, warn};
 
 #[derive(Debug)]
@@ -44,7 +42,7 @@ impl ReorderKeys {
         } = &self.0;
 
         editor.with_store(|store| {
-            let id_other = {
+            let edit = {
                 let core = MetaCore::new(store);
                 let siblings = core.ordered_values(entity, attribute);
                 let idx = siblings.iter().position(|d| &d.id == id);
@@ -73,73 +71,12 @@ impl ReorderKeys {
                         }
                     }
                 };
-                let id_other = &siblings[idx_other].id;
 
-                id_other.clone()
+                trace!("moving {:?} from {} to {}", id, idx, idx_other);
+                core.move_to(&self.0, idx_other)
             };
 
-            match direction {
-                Direction::Up => Self::move_above(store, id, &id_other),
-                Direction::Down => Self::move_above(store, &id_other, id),
-            };
+            store.transact(edit.retracted, edit.added);
         });
     }
-
-    fn move_above(store: &mut Store, ida: &Field, idb: &Field) {
-        trace!("moving {:?} above {:?}", ida, idb);
-
-        if let Some(a_to_b) = store
-            .eav2(ida, &ids::A_AFTER)
-            .and_then(|fields| fields.iter().find(|d| &d.value == idb))
-        {
-            let a_to_b = a_to_b.clone();
-            store.remove_datom(&a_to_b);
-        }
-
-        let from_a = store
-            .aev2(&ids::A_AFTER, ida)
-            .cloned()
-            .unwrap_or_else(HashSet::new);
-        let from_b = store
-            .aev2(&ids::A_AFTER, idb)
-            .cloned()
-            .unwrap_or_else(HashSet::new);
-        let into_a = store
-            .ave2(&ids::A_AFTER, ida)
-            .cloned()
-            .unwrap_or_else(HashSet::new);
-        let into_b = store
-            .ave2(&ids::A_AFTER, idb)
-            .cloned()
-            .unwrap_or_else(HashSet::new);
-
-        // swap all arrows
-        for mut d in from_a {
-            // these are now coming from b
-            store.remove_datom(&d);
-            d.entity = idb.clone();
-            store.add_datom(&d);
-        }
-        for mut d in from_b {
-            // these are now coming from a
-            store.remove_datom(&d);
-            d.entity = ida.clone();
-            store.add_datom(&d);
-        }
-        for mut d in into_a {
-            // these are now coming into b
-            store.remove_datom(&d);
-            d.value = idb.clone();
-            store.add_datom(&d);
-        }
-        for mut d in into_b {
-            // these are now coming into a
-            store.remove_datom(&d);
-            d.value = ida.clone();
-            store.add_datom(&d);
-        }
-
-        // now add the final arrow: b after a
-        store.add_datom(&Datom::eav(idb.clone(), ids::A_AFTER.clone(), ida.clone()))
-    }
 }
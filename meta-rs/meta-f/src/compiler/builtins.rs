@@ -0,0 +1,157 @@
+//! Functions available to all f programs.
+use std::rc::Rc;
+
+use meta_store::Field;
+
+use crate::compiler::cps::*;
+use crate::host::HostFunctions;
+use crate::ids;
+use crate::parser::Identifier;
+use crate::types::Type;
+
+/// Builtin identifiers with primops implementing them. Each primop takes all function arguments
+/// and produces a single result.
+fn primops() -> Vec<(&'static Field, Primop, /* arity: */ usize)> {
+    vec![
+        (&ids::NUMBER_ADD, Primop::Plus, 2),
+        (&ids::NUMBER_SUB, Primop::Minus, 2),
+        (&ids::NUMBER_MUL, Primop::Times, 2),
+        (&ids::NUMBER_DIV, Primop::Divide, 2),
+        (&ids::NUMBER_MOD, Primop::Modulo, 2),
+        (&ids::NUMBER_NEGATE, Primop::Negate, 1),
+        (&ids::NUMBER_EQUAL, Primop::NumberEqual, 2),
+        (&ids::NUMBER_LESS, Primop::Less, 2),
+        (&ids::NUMBER_LESS_EQUAL, Primop::LessEqual, 2),
+        (&ids::NUMBER_GREATER, Primop::Greater, 2),
+        (&ids::NUMBER_GREATER_EQUAL, Primop::GreaterEqual, 2),
+        (&ids::BOOL_NOT, Primop::Not, 1),
+        (&ids::BOOL_AND, Primop::And, 2),
+        (&ids::BOOL_OR, Primop::Or, 2),
+        (&ids::STRING_CONCAT, Primop::StringConcat, 2),
+        (&ids::STRING_LENGTH, Primop::StringLength, 1),
+        (&ids::STRING_EQUAL, Primop::StringEqual, 2),
+        (&ids::STRING_FROM_NUMBER, Primop::NumberToString, 1),
+        (&ids::STRING_TO_NUMBER, Primop::StringToNumber, 1),
+    ]
+}
+
+/// Parameter and result types of builtin functions (see `primops()`).
+fn signatures() -> Vec<(&'static Field, &'static [Type], Type)> {
+    use Type::{Bool, Number, String as Str};
+    vec![
+        (&ids::NUMBER_ADD, &[Number, Number], Number),
+        (&ids::NUMBER_SUB, &[Number, Number], Number),
+        (&ids::NUMBER_MUL, &[Number, Number], Number),
+        (&ids::NUMBER_DIV, &[Number, Number], Number),
+        (&ids::NUMBER_MOD, &[Number, Number], Number),
+        (&ids::NUMBER_NEGATE, &[Number], Number),
+        (&ids::NUMBER_EQUAL, &[Number, Number], Bool),
+        (&ids::NUMBER_LESS, &[Number, Number], Bool),
+        (&ids::NUMBER_LESS_EQUAL, &[Number, Number], Bool),
+        (&ids::NUMBER_GREATER, &[Number, Number], Bool),
+        (&ids::NUMBER_GREATER_EQUAL, &[Number, Number], Bool),
+        (&ids::BOOL_NOT, &[Bool], Bool),
+        (&ids::BOOL_AND, &[Bool, Bool], Bool),
+        (&ids::BOOL_OR, &[Bool, Bool], Bool),
+        (&ids::STRING_CONCAT, &[Str, Str], Str),
+        (&ids::STRING_LENGTH, &[Str], Number),
+        (&ids::STRING_EQUAL, &[Str, Str], Bool),
+        (&ids::STRING_FROM_NUMBER, &[Number], Str),
+        (&ids::STRING_TO_NUMBER, &[Str], Number),
+    ]
+}
+
+/// Types of all builtin identifiers and host functions.
+pub(crate) fn types(host_functions: &HostFunctions) -> Vec<(Identifier, Type)> {
+    let functions = signatures()
+        .into_iter()
+        .map(|(identifier, params, result)| {
+            let type_ = Type::Function(params.to_vec(), Box::new(result));
+            (identifier.clone(), type_)
+        });
+    let host_functions = host_functions.iter().map(|function| {
+        let type_ = Type::Function(function.params.clone(), Box::new(function.result.clone()));
+        (function.identifier.clone(), type_)
+    });
+    // see constants()
+    let values = vec![
+        (ids::TRUE.clone(), Type::Bool),
+        (ids::FALSE.clone(), Type::Bool),
+    ];
+
+    functions
+        .chain(host_functions)
+        .chain(values)
+        .map(|(entry, type_)| (Identifier { entry }, type_))
+        .collect()
+}
+
+/// Builtin identifiers bound to constant values.
+pub(crate) fn constants() -> Vec<(Identifier, Value)> {
+    vec![
+        (
+            Identifier {
+                entry: ids::TRUE.clone(),
+            },
+            Value::Bool(true),
+        ),
+        (
+            Identifier {
+                entry: ids::FALSE.clone(),
+            },
+            Value::Bool(false),
+        ),
+    ]
+}
+
+/// Define functions for all builtins and host functions.
+pub(crate) fn builtins(
+    gen: &mut VarGen,
+    host_functions: &HostFunctions,
+) -> Vec<(Identifier, FnDef)> {
+    // host functions are called with their identifier as the first operand
+    let host_functions = host_functions.iter().map(|function| {
+        let name = Value::String(function.identifier.to_string());
+        (
+            &function.identifier,
+            Primop::CallHost,
+            function.params.len(),
+            Some(name),
+        )
+    });
+    primops()
+        .into_iter()
+        .map(|(identifier, primop, arity)| (identifier, primop, arity, None))
+        .chain(host_functions)
+        .map(|(identifier, primop, arity, name)| {
+            let f = gen.next();
+            let mut params = (0..arity).map(|_| gen.next()).collect::<Vec<_>>();
+            let args = name
+                .into_iter()
+                .chain(params.iter().copied().map(Value::Var))
+                .collect();
+            let k = gen.next(); // return continuation
+            params.push(k);
+            // the result is attributed to the builtin, so errors point to it after inlining
+            let r = gen.next_for(identifier);
+
+            let fndef = FnDef(
+                f,
+                params.into_boxed_slice(),
+                Rc::new(Exp::Primop(
+                    primop,
+                    args,
+                    Box::new([r]),
+                    Box::new([Rc::new(Exp::App(Value::Var(k), Box::new([Value::Var(r)])))]),
+                )),
+            );
+
+            (
+                Identifier {
+                    entry: identifier.clone(),
+                },
+                fndef,
+            )
+        })
+        .collect()
+}
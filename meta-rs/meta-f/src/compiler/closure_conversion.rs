@@ -28,7 +28,14 @@ pub(crate) fn closure_conversion(gen: &mut VarGen, exp: &Rc<Exp>) -> Rc<Exp> {
             }
             Exp::Fix(fns, e) => {
                 let fn_vars = fns.iter().map(|f| f.0).collect::<Vec<Var>>();
-                let wrappers = fns.iter().map(|_| (gen.next())).collect::<Vec<Var>>();
+                let wrappers = fns
+                    .iter()
+                    .map(|f| {
+                        let wrapper = gen.next();
+                        gen.copy_info(f.0, wrapper);
+                        wrapper
+                    })
+                    .collect::<Vec<Var>>();
                 let fn_free_vars = free_variables_fndefs(fns);
 
                 let mut closure_format = wrappers.clone();
@@ -87,7 +94,7 @@ pub(crate) fn closure_conversion(gen: &mut VarGen, exp: &Rc<Exp>) -> Rc<Exp> {
     }
 
     fn build_lifted_fn(gen: &mut VarGen, f: &FnDef) -> (FnDef, Box<[Var]>) {
-        let FnDef(_f_var, params, e) = f;
+        let FnDef(f_var, params, e) = f;
 
         let mut free_vars = free_variables(e);
         for p in params.iter() {
@@ -99,10 +106,15 @@ pub(crate) fn closure_conversion(gen: &mut VarGen, exp: &Rc<Exp>) -> Rc<Exp> {
 
         let extra_vars = free_vars
             .iter()
-            .map(|x| (*x, gen.next()))
+            .map(|x| {
+                let v = gen.next();
+                gen.copy_info(*x, v);
+                (*x, v)
+            })
             .collect::<Vec<(Var, Var)>>();
 
         let new_f_var = gen.next();
+        gen.copy_info(*f_var, new_f_var);
         let mut new_params = Vec::from(&**params);
         new_params.extend(extra_vars.iter().map(|(_old, v)| v));
         let new_params = new_params.into_boxed_slice();
@@ -124,13 +136,24 @@ pub(crate) fn closure_conversion(gen: &mut VarGen, exp: &Rc<Exp>) -> Rc<Exp> {
     ) -> FnDef {
         let mut my_params = lifted_fn.1[..f.1.len()]
             .iter()
-            .map(|_| gen.next())
+            .map(|p| {
+                let v = gen.next();
+                gen.copy_info(*p, v);
+                v
+            })
             .collect::<Vec<_>>();
         let closure_var = gen.next();
         my_params.push(closure_var);
         let my_params = my_params.into_boxed_slice();
 
-        let extra_args_vars = extra_args.iter().map(|_| gen.next()).collect::<Vec<_>>();
+        let extra_args_vars = extra_args
+            .iter()
+            .map(|arg| {
+                let v = gen.next();
+                gen.copy_info(*arg, v);
+                v
+            })
+            .collect::<Vec<_>>();
 
         let mut args = Vec::from(&my_params[..my_params.len() - 1]);
         args.extend(extra_args_vars.iter().copied());
@@ -182,8 +205,9 @@ pub(crate) fn closure_conversion(gen: &mut VarGen, exp: &Rc<Exp>) -> Rc<Exp> {
             Exp::Select(i, val, var, e) => Exp::Select(*i, val.clone(), *var, patch(e)),
             Exp::Offset(i, val, var, e) => Exp::Offset(*i, val.clone(), *var, patch(e)),
             Exp::App(var, vals) => {
-                if matches!(var, Value::Var(_)) {
+                if let Value::Var(f) = var {
                     let v = gen.next();
+                    gen.copy_info(*f, v);
                     let mut args = Vec::from(&**vals);
                     args.push(var.clone());
                     let args = args.into_boxed_slice();
@@ -266,7 +290,7 @@ mod tests {
                 Rc::new(Exp::App(Value::Var(Var(3)), Box::new([]))),
             )),
         ));
-        let mut gen = VarGen { next_var: 4 };
+        let mut gen = VarGen::new(4);
         let result = closure_conversion(&mut gen, &input);
 
         println!("test_closure_convertion:\n{:#?}", result);
@@ -312,7 +336,7 @@ mod tests {
                 Box::new([Value::Int(42), Value::Var(Var(4))]),
             )),
         ));
-        let mut gen = VarGen { next_var: 9 };
+        let mut gen = VarGen::new(9);
         let result = closure_conversion(&mut gen, &input);
 
         println!("test_closure_mutually_recursive:\n{:#?}", result);
@@ -2,6 +2,7 @@ use std::rc::Rc;
 
 use log::{log_enabled, trace, Level};
 
+use crate::host::HostFunctions;
 use crate::parser::RunTest;
 use crate::vm::chunk::Chunk;
 
@@ -9,18 +10,25 @@ use crate::compiler::closure_conversion::closure_conversion;
 use crate::compiler::cps::VarGen;
 use crate::compiler::cps_to_bytecode::cps_to_bytecode;
 use crate::compiler::entry_to_cps::entry_to_cps;
+use crate::compiler::optimize::{optimize, Passes};
 
-pub(crate) fn compile(expr: &RunTest) -> Chunk {
+pub(crate) fn compile(expr: &RunTest, passes: &Passes, host_functions: &HostFunctions) -> Chunk {
     let mut gen = VarGen::new(0);
     trace!("parsed: {:?}", expr);
 
-    let cps = Rc::new(entry_to_cps(&mut gen, expr));
+    let cps = Rc::new(entry_to_cps(&mut gen, expr, host_functions));
     trace!("cps: {:?}", cps);
 
+    let cps = optimize(&cps, passes);
+    trace!("optimized: {:?}", cps);
+
     let cps = closure_conversion(&mut gen, &cps);
     trace!("closure_converted: {:?}", cps);
 
-    let chunk = cps_to_bytecode(&cps);
+    let cps = optimize(&cps, passes);
+    trace!("optimized closure_converted: {:?}", cps);
+
+    let chunk = cps_to_bytecode(&cps, &gen.info);
     if log_enabled!(Level::Trace) {
         chunk.disassemble(&mut std::io::stderr()).unwrap();
     }
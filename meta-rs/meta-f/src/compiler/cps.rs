@@ -2,17 +2,33 @@ use std::rc::Rc;
 
 use im::{HashMap, HashSet};
 
-// TODO: add name for debugging purposes
+use meta_store::Field;
+
+/// Variable. Where it comes from is tracked by [`VarGen`] for debugging purposes.
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Copy, Clone)]
 pub(crate) struct Var(pub u64);
 
+/// Origin of a variable.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub(crate) struct VarInfo {
+    /// Entity of the expression the variable is created for.
+    pub entity: Field,
+    /// Name of the identifier bound to the variable.
+    pub name: Option<String>,
+}
+
 pub(crate) struct VarGen {
     pub next_var: u64,
+    /// Debug information of variables. Not all variables have it.
+    pub info: HashMap<Var, VarInfo>,
 }
 
 impl VarGen {
     pub fn new(next_var: u64) -> Self {
-        Self { next_var }
+        Self {
+            next_var,
+            info: HashMap::new(),
+        }
     }
 
     pub fn next(&mut self) -> Var {
@@ -20,6 +36,37 @@ impl VarGen {
         self.next_var += 1;
         result
     }
+
+    /// Same as [`next`](Self::next), but remembers that the variable comes from `entity`.
+    pub fn next_for(&mut self, entity: &Field) -> Var {
+        let var = self.next();
+        self.describe(var, entity, None);
+        var
+    }
+
+    pub fn describe(&mut self, var: Var, entity: &Field, name: Option<&String>) {
+        self.info.insert(
+            var,
+            VarInfo {
+                entity: entity.clone(),
+                name: name.cloned(),
+            },
+        );
+    }
+
+    /// Name the variable, so it can be found by the name while debugging.
+    pub fn name(&mut self, var: Var, name: Option<&String>) {
+        if let (Some(info), Some(name)) = (self.info.get_mut(&var), name) {
+            info.name = Some(name.clone());
+        }
+    }
+
+    /// Copy debug information of `from` to `to` (e.g., when a variable is renamed).
+    pub fn copy_info(&mut self, from: Var, to: Var) {
+        if let Some(info) = self.info.get(&from).cloned() {
+            self.info.insert(to, info);
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
@@ -27,6 +74,7 @@ pub(crate) enum Value {
     Var(Var),
     Label(Var),
     Int(i32),
+    Bool(bool),
     String(String),
     ConstructorTag(/* type: */ Var, /* constructor: */ u16),
 }
@@ -34,8 +82,30 @@ pub(crate) enum Value {
 #[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
 pub(crate) enum Primop {
     Halt,
-    #[allow(dead_code)]
     Plus,
+    Minus,
+    Times,
+    Divide,
+    Modulo,
+    Negate,
+    NumberEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Not,
+    And,
+    Or,
+    StringConcat,
+    StringLength,
+    StringEqual,
+    NumberToString,
+    StringToNumber,
+    /// No case matches the value.
+    MatchFailure,
+    /// Call a host function. The first operand is the identifier of the function as a string,
+    /// the rest are its arguments.
+    CallHost,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
@@ -48,7 +118,6 @@ pub(crate) enum Exp {
     Offset(isize, Value, Var, Rc<Exp>),
     App(Value, Box<[Value]>),
     Fix(Box<[FnDef]>, Rc<Exp>),
-    #[allow(dead_code)]
     Switch(Value, Box<[Rc<Exp>]>),
     Primop(Primop, Box<[Value]>, Box<[Var]>, Box<[Rc<Exp>]>),
 }
@@ -1,43 +1,156 @@
+use std::collections::VecDeque;
 use std::io::{Cursor, Write};
+use std::rc::Rc;
 
-use im::HashMap;
+use im::{HashMap, HashSet};
+
+use meta_store::Field;
 
 use crate::compiler::cps::*;
+use crate::compiler::liveness::Liveness;
 use crate::vm::bytecode::{Instruction, Reg};
-use crate::vm::chunk::{Chunk, DataRef};
+use crate::vm::chunk::{Chunk, DataRef, Variable};
 use crate::vm::value::Value as VmValue;
 
-pub(crate) fn cps_to_bytecode(exp: &Exp) -> Chunk {
-    let mut compilation = Compilation::new();
-    compilation.compile(exp).unwrap();
+/// Compile `exp` to bytecode. `info` is used to fill chunk debug info.
+pub(crate) fn cps_to_bytecode(exp: &Exp, info: &HashMap<Var, VarInfo>) -> Chunk {
+    let mut next_temporary = u64::MAX;
+    let exp = pass_extra_args_in_frames(exp, &mut next_temporary);
+    let mut compilation = Compilation::new(Liveness::analyze(&exp), info.clone());
+    compilation.next_temporary = next_temporary;
+    compilation.compile(&exp).unwrap();
     compilation.chunk
 }
 
+/// Rewrite functions with more than `MAX_REGISTER_ARGS` parameters to take the extra ones in a
+/// record passed as the last argument, and their applications to allocate it. Temporary variables
+/// for the records are numbered down from `next_temporary`.
+fn pass_extra_args_in_frames(exp: &Exp, next_temporary: &mut u64) -> Exp {
+    fn rewrite(e: &Rc<Exp>, next_temporary: &mut u64) -> Rc<Exp> {
+        Rc::new(pass_extra_args_in_frames(e, next_temporary))
+    }
+
+    match exp {
+        Exp::Record(vals, var, e) => Exp::Record(vals.clone(), *var, rewrite(e, next_temporary)),
+        Exp::Select(i, val, var, e) => {
+            Exp::Select(*i, val.clone(), *var, rewrite(e, next_temporary))
+        }
+        Exp::Offset(i, val, var, e) => {
+            Exp::Offset(*i, val.clone(), *var, rewrite(e, next_temporary))
+        }
+        Exp::App(f, vals) if vals.len() > MAX_REGISTER_ARGS => {
+            let frame = Var(*next_temporary);
+            *next_temporary -= 1;
+            let (in_registers, extra) = vals.split_at(MAX_REGISTER_ARGS - 1);
+            let args = in_registers
+                .iter()
+                .cloned()
+                .chain(std::iter::once(Value::Var(frame)))
+                .collect();
+            Exp::Record(extra.into(), frame, Rc::new(Exp::App(f.clone(), args)))
+        }
+        Exp::App(f, vals) => Exp::App(f.clone(), vals.clone()),
+        Exp::Fix(fns, e) => {
+            let fns = fns
+                .iter()
+                .map(|FnDef(f, params, body)| {
+                    let body = rewrite(body, next_temporary);
+                    if params.len() <= MAX_REGISTER_ARGS {
+                        return FnDef(*f, params.clone(), body);
+                    }
+
+                    let frame = Var(*next_temporary);
+                    *next_temporary -= 1;
+                    let (in_registers, extra) = params.split_at(MAX_REGISTER_ARGS - 1);
+                    let body = extra.iter().enumerate().rev().fold(body, |e, (i, param)| {
+                        Rc::new(Exp::Select(i as isize, Value::Var(frame), *param, e))
+                    });
+                    let params = in_registers
+                        .iter()
+                        .copied()
+                        .chain(std::iter::once(frame))
+                        .collect();
+                    FnDef(*f, params, body)
+                })
+                .collect();
+            Exp::Fix(fns, rewrite(e, next_temporary))
+        }
+        Exp::Switch(val, es) => Exp::Switch(
+            val.clone(),
+            es.iter().map(|e| rewrite(e, next_temporary)).collect(),
+        ),
+        Exp::Primop(op, vals, vars, es) => Exp::Primop(
+            *op,
+            vals.clone(),
+            vars.clone(),
+            es.iter().map(|e| rewrite(e, next_temporary)).collect(),
+        ),
+    }
+}
+
 type RegisterAllocation = [Option<Var>; 256];
 
+/// Register used to allocate spill frames. It is never allocated to variables.
+const SPILL_REGISTER: Reg = Reg(255);
+
+/// Maximum number of variables spilled at once.
+const SPILL_FRAME_SIZE: usize = 32;
+
+/// Maximum number of arguments passed in registers. The rest are passed in a record, see
+/// [`pass_extra_args_in_frames`].
+const MAX_REGISTER_ARGS: usize = 128;
+
 struct Compilation {
     chunk: Chunk,
+    liveness: Liveness,
     registers: RegisterAllocation,
+    /// Registers that hold operands of the instruction being compiled, so must not be spilled.
+    pinned: [bool; 256],
+    /// Variables stored in spill frames: frame variable and offset in the frame. Variables stay
+    /// here after they are loaded back, so they can be dropped from registers without storing.
+    spilled: HashMap<Var, (Var, i16)>,
+    /// Spill frames and constant operands are bound to temporary variables. They are numbered
+    /// down from `u64::MAX`, so they never clash with variables of the program.
+    next_temporary: u64,
     // TODO: we can deduplicate all constant values if we store
     // HashMap<Value, /* instructions: */ HashSet<usize>> first.
     to_patch: HashMap<usize, Var>,
     to_patch_data: HashMap<DataRef, Var>,
     functions: HashMap<Var, usize>,
+    /// Functions to compile after the current one, so code of each function is contiguous.
+    pending: VecDeque<FnDef>,
+    info: HashMap<Var, VarInfo>,
+    /// Entity the code being compiled comes from.
+    entity: Option<Field>,
+    /// Code positions where registers started holding their variables. Variables are moved to
+    /// chunk debug info when registers are released.
+    starts: [usize; 256],
 }
 
 impl Compilation {
-    fn new() -> Self {
+    fn new(liveness: Liveness, info: HashMap<Var, VarInfo>) -> Self {
         Self {
             chunk: Chunk::new(),
+            liveness,
             registers: [None; 256],
+            pinned: [false; 256],
+            spilled: HashMap::new(),
+            next_temporary: u64::MAX,
             to_patch: HashMap::new(),
             to_patch_data: HashMap::new(),
             functions: HashMap::new(),
+            pending: VecDeque::new(),
+            info,
+            entity: None,
+            starts: [0; 256],
         }
     }
 
     fn compile(&mut self, exp: &Exp) -> std::io::Result<()> {
         self.compile_exp(exp)?;
+        while let Some(f) = self.pending.pop_front() {
+            self.compile_fn(&f)?;
+        }
         self.patch()?;
         Ok(())
     }
@@ -49,9 +162,13 @@ impl Compilation {
         let position = self.chunk.code().len();
         self.functions.insert(*f, position);
 
+        let entity = self.info.get(f).map(|info| info.entity.clone());
+        self.set_entity(entity);
+
         self.registers = [None; 256];
+        self.spilled = HashMap::new();
         for (reg, param) in params.iter().enumerate() {
-            self.registers[reg] = Some(*param);
+            self.set_register(Reg(reg as u8), Some(*param));
         }
 
         self.compile_exp(e)?;
@@ -61,9 +178,26 @@ impl Compilation {
 
     fn compile_exp(&mut self, exp: &Exp) -> std::io::Result<usize> {
         let position = self.chunk.code().len();
+
+        let bound_var = match exp {
+            Exp::Record(_, var, _) | Exp::Select(_, _, var, _) | Exp::Offset(_, _, var, _) => {
+                Some(var)
+            }
+            Exp::Primop(_, _, outs, _) => outs.first(),
+            Exp::App(..) | Exp::Fix(..) | Exp::Switch(..) => None,
+        };
+        if let Some(info) = bound_var.and_then(|var| self.info.get(var)) {
+            let entity = info.entity.clone();
+            self.set_entity(Some(entity));
+        }
+
+        self.pinned = [false; 256];
+        let live = self.liveness.live_in(exp).clone();
+        self.release_dead(&live);
+
         match exp {
             Exp::Record(vals, var, e) => {
-                let reg = self.register_for(*var);
+                let reg = self.register_for(*var)?;
                 self.chunk.write(&Instruction::AllocConst {
                     result: reg,
                     cells_to_allocate: vals.len() as u32,
@@ -72,12 +206,14 @@ impl Compilation {
                     let offset = i as i16;
                     match val {
                         Value::Var(var) => {
-                            let val_reg = self.register_of(*var);
+                            let val_reg = self.register_of(*var)?;
                             self.chunk.write(&Instruction::StoreReg {
                                 addr: reg,
                                 offset,
                                 reg_to_store: val_reg,
                             })?;
+                            // the field is stored, so its register can be spilled
+                            self.pinned[val_reg.0 as usize] = false;
                         }
                         Value::Label(var) => {
                             let value = self.chunk.alloc_data(&[VmValue::invalid(var.0 as i32)]);
@@ -97,8 +233,23 @@ impl Compilation {
                                 value,
                             })?;
                         }
+                        Value::Bool(b) => {
+                            let value = self.chunk.alloc_data(&[VmValue::boolean(*b)]);
+                            self.chunk.write(&Instruction::StoreValue {
+                                addr: reg,
+                                offset,
+                                value,
+                            })?;
+                        }
                         Value::String(_) => {
-                            todo!("Strings are not supported");
+                            // strings are not stored inline, so load the pointer first
+                            let tmp = self.scratch_register()?;
+                            self.load_constant(tmp, val)?;
+                            self.chunk.write(&Instruction::StoreReg {
+                                addr: reg,
+                                offset,
+                                reg_to_store: tmp,
+                            })?;
                         }
                         Value::ConstructorTag(var, n_cons) => {
                             let value = self
@@ -115,12 +266,11 @@ impl Compilation {
                 self.compile_exp(e)?;
             }
             Exp::Select(i, val, var, e) => {
-                if let Value::Var(ptr) = val {
-                    let result = self.register_for(*var);
-                    let reg = self.register_of(*ptr);
+                if let Value::Var(_) = val {
+                    let (result, addr) = self.unary_operands(val, *var, e)?;
                     self.chunk.write(&Instruction::Load {
                         result,
-                        addr: reg,
+                        addr,
                         offset: *i as i16,
                     })?;
                 } else {
@@ -129,11 +279,10 @@ impl Compilation {
                 self.compile_exp(e)?;
             }
             Exp::Offset(i, val, var, e) => {
-                if let Value::Var(op1) = val {
-                    let reg = self.register_for(*var);
-                    let op1 = self.register_of(*op1);
+                if let Value::Var(_) = val {
+                    let (result, op1) = self.unary_operands(val, *var, e)?;
                     self.chunk.write(&Instruction::Offset {
-                        result: reg,
+                        result,
                         op1,
                         offset: *i as i32,
                     })?;
@@ -144,110 +293,53 @@ impl Compilation {
             }
 
             Exp::App(f, vals) => {
-                if let Value::Var(v) = f {
-                    let reg = self.register_of(*v);
-
-                    if (reg.0 as usize) < vals.len() {
-                        // save address register from arguments
-
-                        let target = Reg(vals.len() as u8);
-                        if let Some(prev_var) = self.registers[target.0 as usize] {
-                            self.chunk.write(&Instruction::Swap {
-                                from: reg,
-                                to: target,
-                            })?;
-                            self.registers[reg.0 as usize] = Some(prev_var);
-                        } else {
-                            self.chunk.write(&Instruction::Move {
-                                result: target,
-                                from: reg,
-                            })?;
-                            self.registers[reg.0 as usize] = None;
-                        }
-
-                        self.registers[target.0 as usize] = Some(*v);
-                    }
+                // Only the function and arguments are live, so they can be loaded from spill
+                // frames, which are not needed after that.
+                for var in vals
+                    .iter()
+                    .chain(std::iter::once(f))
+                    .filter_map(Value::as_var)
+                {
+                    self.register_of(var)?;
                 }
+                self.spilled = HashMap::new();
+                self.release_dead(&live);
+                self.close_all();
 
-                for (reg, var) in vals.iter().enumerate().filter_map(|(i, val)| {
+                // Arguments are passed in registers 0..n. The function is moved out of their way.
+                let n_args = vals.len();
+                let mut moves = Vec::new();
+                for (i, val) in vals.iter().enumerate() {
                     if let Value::Var(var) = val {
-                        Some((Reg(i as u8), var))
-                    } else {
-                        None
+                        moves.push((self.register_of(*var)?, Reg(i as u8)));
                     }
-                }) {
-                    let reg_from = self.register_of(*var);
-                    if reg_from == reg {
-                        // Nothing to do. Variable is already in place.
-                    } else if reg_from.0 < reg.0 {
-                        // Same argument is passed multiple times. Copy it without invalidating the
-                        // previous register.
-                        if let Some(prev_var) = self.registers[reg.0 as usize] {
-                            // if there was a previous var, stash it somewhere else.
-                            let new_reg = self.register_for(prev_var);
-                            self.chunk.write(&Instruction::Move {
-                                from: reg,
-                                result: new_reg,
-                            })?;
-                        }
-                        self.chunk.write(&Instruction::Move {
-                            from: reg_from,
-                            result: reg,
-                        })?;
-                        self.registers[reg.0 as usize] = Some(*var);
-                    } else {
-                        // Swap/move register in place, forgetting the previous location.
-                        if self.registers[reg.0 as usize].is_some() {
-                            self.chunk.write(&Instruction::Swap {
-                                from: reg_from,
-                                to: reg,
-                            })?;
-                            self.registers.swap(reg_from.0 as usize, reg.0 as usize);
+                }
+                let f_reg = match f {
+                    Value::Var(var) => {
+                        let reg = self.register_of(*var)?;
+                        if (reg.0 as usize) < n_args {
+                            let target = Reg(n_args as u8);
+                            moves.push((reg, target));
+                            Some(target)
                         } else {
-                            self.chunk.write(&Instruction::Move {
-                                from: reg_from,
-                                result: reg,
-                            })?;
-                            self.registers[reg_from.0 as usize] = None;
-                            self.registers[reg.0 as usize] = Some(*var);
+                            Some(reg)
                         }
                     }
-                }
+                    _ => None,
+                };
+                self.parallel_move(moves)?;
 
                 for (reg, val) in vals
                     .iter()
                     .enumerate()
                     .filter(|(_i, val)| !matches!(val, Value::Var(_)))
                 {
-                    let reg = Reg(reg as u8);
-                    match val {
-                        Value::Var(_) => panic!(),
-                        Value::Label(label) => {
-                            let value = self.chunk.alloc_data(&[VmValue::invalid(label.0 as i32)]);
-                            self.chunk
-                                .write(&Instruction::ConstantValue { result: reg, value })?;
-                            self.to_patch_data.insert(value, *label);
-                        }
-                        Value::Int(i) => {
-                            let value = self.chunk.alloc_data(&[VmValue::number(*i)]);
-                            self.chunk
-                                .write(&Instruction::ConstantValue { result: reg, value })?;
-                        }
-                        Value::String(_) => {
-                            todo!("Strings are not supported");
-                        }
-                        Value::ConstructorTag(v, n_cons) => {
-                            let value =
-                                self.chunk.alloc_data(&[VmValue::constructor(v.0, *n_cons)]);
-                            self.chunk
-                                .write(&Instruction::ConstantValue { result: reg, value })?;
-                        }
-                    }
+                    self.load_constant(Reg(reg as u8), val)?;
                 }
 
                 match f {
-                    Value::Var(var) => {
-                        let reg = self.register_of(*var);
+                    Value::Var(_) => {
+                        let reg = f_reg.unwrap();
                         self.chunk.write(&Instruction::JumpReg { reg })?;
                     }
                     Value::Label(var) => {
@@ -261,49 +353,194 @@ impl Compilation {
             }
             Exp::Fix(fns, e) => {
                 self.compile_exp(e)?;
-                for f in fns.iter() {
-                    self.compile_fn(f)?;
-                }
+                self.pending.extend(fns.iter().cloned());
             }
             Exp::Switch(val, es) => {
-                if let Value::Var(var) = val {
-                    let reg = self.register_of(*var);
-                    let offsets = es.iter().map(|_| 0).collect();
-                    let position = self.chunk.write(&Instruction::Switch { reg, offsets })?;
-
-                    let real_positions = es
-                        .iter()
-                        .map(|e| self.compile_exp(e).unwrap())
-                        .collect::<Vec<_>>();
-
-                    let mut code = Cursor::new(self.chunk.code_mut());
-                    code.set_position(position as u64 + 8);
-                    for pos in real_positions.iter() {
-                        let offset = pos - position;
-                        code.write_all(&offset.to_ne_bytes())?;
+                let reg = match val {
+                    Value::Var(var) => self.register_of(*var)?,
+                    _ => {
+                        let reg = self.scratch_register()?;
+                        self.load_constant(reg, val)?;
+                        reg
                     }
-                } else {
-                    panic!("Unsupported value for switch: {:?}", val);
+                };
+                let branches_live =
+                    HashSet::unions(es.iter().map(|e| self.liveness.live_in(e).clone()));
+                self.release_dead(&branches_live);
+                let offsets = es.iter().map(|_| 0).collect();
+                let position = self.chunk.write(&Instruction::Switch { reg, offsets })?;
+                self.close_all();
+
+                // all branches start with the same register allocation
+                let registers = self.registers;
+                let spilled = self.spilled.clone();
+                let entity = self.entity.clone();
+                let real_positions = es
+                    .iter()
+                    .map(|e| {
+                        self.registers = registers;
+                        self.spilled = spilled.clone();
+                        self.starts = [self.chunk.code().len(); 256];
+                        self.set_entity(entity.clone());
+                        self.compile_exp(e).unwrap()
+                    })
+                    .collect::<Vec<_>>();
+
+                let mut code = Cursor::new(self.chunk.code_mut());
+                code.set_position(position as u64 + 8);
+                for pos in real_positions.iter() {
+                    let offset = pos - position;
+                    code.write_all(&offset.to_ne_bytes())?;
                 }
             }
             Exp::Primop(op, ins, outs, es) => match (op, &**ins, &**outs, &**es) {
                 (Primop::Halt, [], [], []) => {
                     self.chunk.write(&Instruction::Halt)?;
+                    self.close_all();
                 }
                 (Primop::Halt, [Value::Var(v)], [], []) => {
-                    let reg = self.register_of(*v);
+                    let reg = self.register_of(*v)?;
                     self.chunk.write(&Instruction::HaltReg { reg })?;
+                    self.close_all();
                 }
                 (Primop::Halt, [Value::Int(constant)], [], []) => {
                     let value = self.chunk.alloc_data(&[VmValue::number(*constant)]);
                     self.chunk.write(&Instruction::HaltValue { value })?;
+                    self.close_all();
+                }
+                (Primop::Halt, [Value::Bool(b)], [], []) => {
+                    let value = self.chunk.alloc_data(&[VmValue::boolean(*b)]);
+                    self.chunk.write(&Instruction::HaltValue { value })?;
+                    self.close_all();
+                }
+                (Primop::Halt, [val @ Value::String(_)], [], []) => {
+                    let reg = self.scratch_register()?;
+                    self.load_constant(reg, val)?;
+                    self.chunk.write(&Instruction::HaltReg { reg })?;
+                    self.close_all();
+                }
+                (Primop::MatchFailure, [], [], []) => {
+                    self.chunk.write(&Instruction::MatchFailure)?;
+                    self.close_all();
+                }
+                (Primop::Plus, [op1, op2], [res], [e]) => {
+                    let (result, op1, op2) = self.binary_operands(op1, op2, *res, e)?;
+                    self.chunk.write(&Instruction::Add { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::Minus, [op1, op2], [res], [e]) => {
+                    let (result, op1, op2) = self.binary_operands(op1, op2, *res, e)?;
+                    self.chunk.write(&Instruction::Sub { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::Times, [op1, op2], [res], [e]) => {
+                    let (result, op1, op2) = self.binary_operands(op1, op2, *res, e)?;
+                    self.chunk.write(&Instruction::Mul { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::Divide, [op1, op2], [res], [e]) => {
+                    let (result, op1, op2) = self.binary_operands(op1, op2, *res, e)?;
+                    self.chunk.write(&Instruction::Div { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::Modulo, [op1, op2], [res], [e]) => {
+                    let (result, op1, op2) = self.binary_operands(op1, op2, *res, e)?;
+                    self.chunk.write(&Instruction::Mod { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::Negate, [op], [res], [e]) => {
+                    let (result, op) = self.unary_operands(op, *res, e)?;
+                    self.chunk.write(&Instruction::Neg { result, op })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::NumberEqual, [op1, op2], [res], [e]) => {
+                    let (result, op1, op2) = self.binary_operands(op1, op2, *res, e)?;
+                    self.chunk
+                        .write(&Instruction::NumberEqual { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::Less, [op1, op2], [res], [e]) => {
+                    let (result, op1, op2) = self.binary_operands(op1, op2, *res, e)?;
+                    self.chunk.write(&Instruction::Less { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::LessEqual, [op1, op2], [res], [e]) => {
+                    let (result, op1, op2) = self.binary_operands(op1, op2, *res, e)?;
+                    self.chunk
+                        .write(&Instruction::LessEqual { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::Greater, [op1, op2], [res], [e]) => {
+                    // a > b is b < a
+                    let (result, op1, op2) = self.binary_operands(op2, op1, *res, e)?;
+                    self.chunk.write(&Instruction::Less { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::GreaterEqual, [op1, op2], [res], [e]) => {
+                    // a >= b is b <= a
+                    let (result, op1, op2) = self.binary_operands(op2, op1, *res, e)?;
+                    self.chunk
+                        .write(&Instruction::LessEqual { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::Not, [op], [res], [e]) => {
+                    let (result, op) = self.unary_operands(op, *res, e)?;
+                    self.chunk.write(&Instruction::Not { result, op })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::And, [op1, op2], [res], [e]) => {
+                    let (result, op1, op2) = self.binary_operands(op1, op2, *res, e)?;
+                    self.chunk.write(&Instruction::And { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::Or, [op1, op2], [res], [e]) => {
+                    let (result, op1, op2) = self.binary_operands(op1, op2, *res, e)?;
+                    self.chunk.write(&Instruction::Or { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::StringConcat, [op1, op2], [res], [e]) => {
+                    let (result, op1, op2) = self.binary_operands(op1, op2, *res, e)?;
+                    self.chunk
+                        .write(&Instruction::StringConcat { result, op1, op2 })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::StringLength, [op], [res], [e]) => {
+                    let (result, op) = self.unary_operands(op, *res, e)?;
+                    self.chunk
+                        .write(&Instruction::StringLength { result, op })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::StringEqual, [op1, op2], [res], [e]) => {
+                    let (result, op1, op2) = self.binary_operands(op1, op2, *res, e)?;
+                    self.chunk
+                        .write(&Instruction::StringEqual { result, op1, op2 })?;
+                    self.compile_exp(e)?;
                 }
-                (Primop::Plus, [Value::Var(op1), Value::Var(op2)], [res], [e]) => {
-                    let result = self.register_for(*res);
-                    self.chunk.write(&Instruction::Add {
+                (Primop::NumberToString, [op], [res], [e]) => {
+                    let (result, op) = self.unary_operands(op, *res, e)?;
+                    self.chunk
+                        .write(&Instruction::NumberToString { result, op })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::StringToNumber, [op], [res], [e]) => {
+                    let (result, op) = self.unary_operands(op, *res, e)?;
+                    self.chunk
+                        .write(&Instruction::StringToNumber { result, op })?;
+                    self.compile_exp(e)?;
+                }
+                (Primop::CallHost, [Value::String(identifier), args @ ..], [res], [e]) => {
+                    let args = args
+                        .iter()
+                        .map(|arg| self.operand(arg))
+                        .collect::<std::io::Result<Vec<_>>>()?;
+                    let live = self.liveness.live_in(e).clone();
+                    self.release_dead(&live);
+                    let result = self.register_for(*res)?;
+                    let function = self.chunk.alloc_string(identifier);
+                    self.chunk.write(&Instruction::CallHost {
                         result,
-                        op1: self.register_of(*op1),
-                        op2: self.register_of(*op2),
+                        function,
+                        args,
                     })?;
                     self.compile_exp(e)?;
                 }
@@ -314,6 +551,14 @@ impl Compilation {
         Ok(position)
     }
 
+    fn set_entity(&mut self, entity: Option<Field>) {
+        let position = self.chunk.code().len();
+        self.chunk
+            .debug_info_mut()
+            .set_entity(position, entity.clone());
+        self.entity = entity;
+    }
+
     fn patch(&mut self) -> std::io::Result<()> {
         let mut code = Cursor::new(self.chunk.code_mut());
         for (pos, var) in self.to_patch.iter() {
@@ -340,38 +585,336 @@ impl Compilation {
         Ok(())
     }
 
-    fn register_of(&self, var: Var) -> Reg {
-        for (i, v) in self.registers.iter().enumerate() {
-            if v == &Some(var) {
-                return Reg(i as u8);
+    /// Load a constant (non-variable) value into the register.
+    fn load_constant(&mut self, reg: Reg, val: &Value) -> std::io::Result<()> {
+        match val {
+            Value::Var(_) => panic!("{:?} is not a constant", val),
+            Value::Label(label) => {
+                let value = self.chunk.alloc_data(&[VmValue::invalid(label.0 as i32)]);
+                self.chunk
+                    .write(&Instruction::ConstantValue { result: reg, value })?;
+                self.to_patch_data.insert(value, *label);
+            }
+            Value::Int(i) => {
+                let value = self.chunk.alloc_data(&[VmValue::number(*i)]);
+                self.chunk
+                    .write(&Instruction::ConstantValue { result: reg, value })?;
+            }
+            Value::Bool(b) => {
+                let value = self.chunk.alloc_data(&[VmValue::boolean(*b)]);
+                self.chunk
+                    .write(&Instruction::ConstantValue { result: reg, value })?;
+            }
+            Value::String(s) => {
+                let value = self.chunk.alloc_string(s);
+                self.chunk
+                    .write(&Instruction::ConstantRef { result: reg, value })?;
+            }
+            Value::ConstructorTag(v, n_cons) => {
+                let value = self.chunk.alloc_data(&[VmValue::constructor(v.0, *n_cons)]);
+                self.chunk
+                    .write(&Instruction::ConstantValue { result: reg, value })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Register holding `var`. Spilled variables are loaded back. The register is pinned until
+    /// the next expression is compiled.
+    fn register_of(&mut self, var: Var) -> std::io::Result<Reg> {
+        if let Some(i) = self.registers.iter().position(|v| v == &Some(var)) {
+            self.pinned[i] = true;
+            return Ok(Reg(i as u8));
+        }
+
+        let (frame, offset) = *self
+            .spilled
+            .get(&var)
+            .unwrap_or_else(|| panic!("Unable to find register of {:?}", var));
+        let addr = self.register_of(frame)?;
+        let result = self.register_for(var)?;
+        self.chunk.write(&Instruction::Load {
+            result,
+            addr,
+            offset,
+        })?;
+        Ok(result)
+    }
+
+    /// Allocate a register for `var`. The register is pinned until the next expression is
+    /// compiled.
+    fn register_for(&mut self, var: Var) -> std::io::Result<Reg> {
+        let reg = self.free_register()?;
+        self.set_register(reg, Some(var));
+        self.pinned[reg.0 as usize] = true;
+        Ok(reg)
+    }
+
+    /// A free register for temporary values. It is not reserved, so must be used immediately.
+    fn scratch_register(&mut self) -> std::io::Result<Reg> {
+        self.free_register()
+    }
+
+    /// Find a free register, spilling variables if there is none.
+    fn free_register(&mut self) -> std::io::Result<Reg> {
+        let free = |registers: &RegisterAllocation| {
+            registers[..SPILL_REGISTER.0 as usize]
+                .iter()
+                .position(|x| x.is_none())
+        };
+        if let Some(i) = free(&self.registers) {
+            return Ok(Reg(i as u8));
+        }
+        self.spill()?;
+        Ok(Reg(
+            free(&self.registers).expect("Unable to find a free register") as u8,
+        ))
+    }
+
+    /// Registers of the operand and the result of an instruction that computes `res` from `op`
+    /// and continues to `e`. The operand register is reused for the result if `op` is not live
+    /// in `e`.
+    fn unary_operands(&mut self, op: &Value, res: Var, e: &Exp) -> std::io::Result<(Reg, Reg)> {
+        let op = self.operand(op)?;
+        let live = self.liveness.live_in(e).clone();
+        self.release_dead(&live);
+        Ok((self.register_for(res)?, op))
+    }
+
+    /// Same as [`unary_operands`](Self::unary_operands), but for two operands.
+    fn binary_operands(
+        &mut self,
+        op1: &Value,
+        op2: &Value,
+        res: Var,
+        e: &Exp,
+    ) -> std::io::Result<(Reg, Reg, Reg)> {
+        let op1 = self.operand(op1)?;
+        let op2 = self.operand(op2)?;
+        let live = self.liveness.live_in(e).clone();
+        self.release_dead(&live);
+        Ok((self.register_for(res)?, op1, op2))
+    }
+
+    /// Register holding `val`. Constants are loaded to a register of a temporary variable, which
+    /// is released after the instruction.
+    fn operand(&mut self, val: &Value) -> std::io::Result<Reg> {
+        match val {
+            Value::Var(var) => self.register_of(*var),
+            _ => {
+                let temporary = self.temporary();
+                let reg = self.register_for(temporary)?;
+                self.load_constant(reg, val)?;
+                Ok(reg)
             }
         }
-        panic!("Unable to find register of {:?}", var);
     }
 
-    fn register_for(&mut self, var: Var) -> Reg {
-        let free = self
-            .registers
-            .iter_mut()
-            .enumerate()
-            .find(|(_i, x)| x.is_none())
-            .expect("Unable to find a free register");
-        *free.1 = Some(var);
-        Reg(free.0 as u8)
+    /// Variable that does not clash with variables of the program.
+    fn temporary(&mut self) -> Var {
+        let var = Var(self.next_temporary);
+        self.next_temporary -= 1;
+        var
+    }
+
+    fn set_register(&mut self, reg: Reg, var: Option<Var>) {
+        self.close_register(reg);
+        self.registers[reg.0 as usize] = var;
     }
 
-    // TODO: implement proper register lifetime analysis
-    // fn drop_variable(&mut self, var: Var) {
-    //     todo!();
-    // }
+    /// Record the variable in `reg` to debug info as held up to the current position.
+    fn close_register(&mut self, reg: Reg) {
+        let position = self.chunk.code().len();
+        let start = std::mem::replace(&mut self.starts[reg.0 as usize], position);
+        if let Some(var) = self.registers[reg.0 as usize] {
+            if let (Some(VarInfo { entity, name }), true) =
+                (self.info.get(&var).cloned(), start < position)
+            {
+                self.chunk.debug_info_mut().add_variable(Variable {
+                    range: start..position,
+                    reg,
+                    entity,
+                    name,
+                });
+            }
+        }
+    }
+
+    /// Record all variables in registers to debug info. Used when control leaves the code.
+    fn close_all(&mut self) {
+        for reg in 0..=u8::MAX {
+            self.close_register(Reg(reg));
+        }
+    }
+
+    /// Free registers and spill slots of variables that are not `live`. Spill frames are kept
+    /// while they hold live variables.
+    fn release_dead(&mut self, live: &HashSet<Var>) {
+        let mut keep = live.clone();
+        loop {
+            let frames = self
+                .spilled
+                .iter()
+                .filter(|(var, (frame, _offset))| keep.contains(var) && !keep.contains(frame))
+                .map(|(_var, (frame, _offset))| *frame)
+                .collect::<Vec<_>>();
+            if frames.is_empty() {
+                break;
+            }
+            keep.extend(frames);
+        }
+
+        self.spilled.retain(|var, _| keep.contains(var));
+        for reg in 0..=u8::MAX {
+            match self.registers[reg as usize] {
+                Some(var) if !keep.contains(&var) => self.set_register(Reg(reg), None),
+                _ => {}
+            }
+        }
+    }
+
+    /// Free registers by storing up to [`SPILL_FRAME_SIZE`] variables to a new heap frame.
+    /// Variables that are already stored in a frame are just dropped from registers.
+    fn spill(&mut self) -> std::io::Result<()> {
+        let victims = (0..SPILL_REGISTER.0)
+            .rev()
+            .map(Reg)
+            .filter(|reg| !self.pinned[reg.0 as usize])
+            .filter_map(|reg| self.registers[reg.0 as usize].map(|var| (reg, var)))
+            .take(SPILL_FRAME_SIZE)
+            .collect::<Vec<_>>();
+        assert!(!victims.is_empty(), "Unable to find a register to spill");
+
+        let to_store = victims
+            .iter()
+            .filter(|(_reg, var)| !self.spilled.contains_key(var))
+            .copied()
+            .collect::<Vec<_>>();
+        if !to_store.is_empty() {
+            let frame = self.temporary();
+
+            self.chunk.write(&Instruction::AllocConst {
+                result: SPILL_REGISTER,
+                cells_to_allocate: to_store.len() as u32,
+            })?;
+            for (offset, (reg, var)) in to_store.iter().enumerate() {
+                let offset = offset as i16;
+                self.chunk.write(&Instruction::StoreReg {
+                    addr: SPILL_REGISTER,
+                    offset,
+                    reg_to_store: *reg,
+                })?;
+                self.spilled.insert(*var, (frame, offset));
+            }
+
+            // move the frame to the register of one of the spilled variables
+            let (frame_reg, _var) = to_store[0];
+            self.chunk.write(&Instruction::Move {
+                result: frame_reg,
+                from: SPILL_REGISTER,
+            })?;
+            self.set_register(frame_reg, Some(frame));
+        }
+
+        for (reg, var) in victims {
+            if self.registers[reg.0 as usize] == Some(var) {
+                self.set_register(reg, None);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move values between registers as if all moves happen at once. `moves` are pairs of source
+    /// and destination registers; destinations must be distinct.
+    fn parallel_move(&mut self, moves: Vec<(Reg, Reg)>) -> std::io::Result<()> {
+        let mut moves = moves
+            .into_iter()
+            .filter(|(from, to)| from != to)
+            .collect::<Vec<_>>();
+        while !moves.is_empty() {
+            // a move can be done once no other move reads its destination
+            let ready = moves
+                .iter()
+                .position(|(_from, to)| moves.iter().all(|(from, _to)| from != to));
+            if let Some(i) = ready {
+                let (from, to) = moves.remove(i);
+                self.chunk.write(&Instruction::Move { result: to, from })?;
+            } else {
+                // Remaining moves form cycles. Swapping puts one value in place, and the
+                // destination value is now read from the source register.
+                let (from, to) = moves.remove(0);
+                self.chunk.write(&Instruction::Swap { from, to })?;
+                for (source, _to) in moves.iter_mut() {
+                    if *source == to {
+                        *source = from;
+                    } else if *source == from {
+                        *source = to;
+                    }
+                }
+                moves.retain(|(from, to)| from != to);
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::compiler::closure_conversion::closure_conversion;
+    use crate::vm::machine::{Options, Vm};
     use std::rc::Rc;
 
+    fn run(exp: &Exp) -> i32 {
+        let chunk = cps_to_bytecode(exp, &HashMap::new());
+        let mut vm = Vm::new(chunk, &Options::default());
+        vm.run().unwrap().unwrap().as_number()
+    }
+
+    /// Run `f` with a stack large enough to compile deeply nested expressions.
+    fn with_large_stack(f: impl FnOnce() + Send + 'static) {
+        std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(f)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    /// `(record [1] v0 (select 0 v0 v1 e))` where `e` is built from `v1`.
+    fn with_one(e: impl FnOnce(Var) -> Exp) -> Exp {
+        Exp::Record(
+            Box::new([Value::Int(1)]),
+            Var(0),
+            Rc::new(Exp::Select(
+                0,
+                Value::Var(Var(0)),
+                Var(1),
+                Rc::new(e(Var(1))),
+            )),
+        )
+    }
+
+    /// Chain of binary primops: `(op v1 v2 res ...)`, ending with `(halt last)`.
+    fn primops(ops: Vec<(Primop, Var, Var, Var)>) -> Exp {
+        let last = ops.last().unwrap().3;
+        let halt = Exp::Primop(
+            Primop::Halt,
+            Box::new([Value::Var(last)]),
+            Box::new([]),
+            Box::new([]),
+        );
+        ops.into_iter().rev().fold(halt, |e, (op, op1, op2, res)| {
+            Exp::Primop(
+                op,
+                Box::new([Value::Var(op1), Value::Var(op2)]),
+                Box::new([res]),
+                Box::new([Rc::new(e)]),
+            )
+        })
+    }
+
     #[test]
     fn test_example() {
         let input = Rc::new(Exp::Fix(
@@ -417,11 +960,149 @@ mod tests {
                 Box::new([Value::Int(42), Value::Var(Var(4))]),
             )),
         ));
-        let mut gen = VarGen { next_var: 9 };
+        let mut gen = VarGen::new(9);
         let result = closure_conversion(&mut gen, &input);
 
-        let chunk = cps_to_bytecode(&result);
+        let chunk = cps_to_bytecode(&result, &gen.info);
 
         chunk.disassemble(&mut std::io::stdout()).unwrap();
     }
+
+    #[test]
+    fn test_reuse_dead_registers() {
+        with_large_stack(|| {
+            // v(i) = v(i - 1) + 1 for i in 1..=1000: more variables than registers, but each dies
+            // right after it is used
+            let result = with_one(|one| {
+                let v = |i: u64| if i == 0 { one } else { Var(1 + i) };
+                primops(
+                    (1..=1000)
+                        .map(|i| (Primop::Plus, v(i - 1), one, v(i)))
+                        .collect(),
+                )
+            });
+
+            assert_eq!(run(&result), 1001);
+        });
+    }
+
+    #[test]
+    fn test_spill() {
+        with_large_stack(|| {
+            // v(i) = v(i - 1) + 1 for i in 1..=1000, then sum all v(i), so they are live at once
+            const N: u64 = 1000;
+            let result = with_one(|one| {
+                let v = |i: u64| if i == 0 { one } else { Var(1 + i) };
+                let sum = |i: u64| Var(1 + N + i);
+                let mut ops = (1..=N)
+                    .map(|i| (Primop::Plus, v(i - 1), one, v(i)))
+                    .collect::<Vec<_>>();
+                ops.push((Primop::Plus, v(1), v(2), sum(2)));
+                ops.extend((3..=N).map(|i| (Primop::Plus, sum(i - 1), v(i), sum(i))));
+                primops(ops)
+            });
+
+            let n = N as i32;
+            assert_eq!(run(&result), n * (n + 1) / 2 + n);
+        });
+    }
+
+    #[test]
+    fn test_permuted_arguments() {
+        // (define (f p0 p1 p2 p3) (halt (- (* (- p0 p1) p2) p3)))
+        let f = FnDef(
+            Var(10),
+            Box::new([Var(11), Var(12), Var(13), Var(14)]),
+            Rc::new(primops(vec![
+                (Primop::Minus, Var(11), Var(12), Var(15)),
+                (Primop::Times, Var(15), Var(13), Var(16)),
+                (Primop::Minus, Var(16), Var(14), Var(17)),
+            ])),
+        );
+        // (record [5 2 3] r0 (select 0 r0 a1 (select 1 r0 b2 (select 2 r0 c3 (f a1 b2 c3 b2)))))
+        let app = Exp::App(
+            Value::Label(Var(10)),
+            Box::new([
+                Value::Var(Var(1)),
+                Value::Var(Var(2)),
+                Value::Var(Var(3)),
+                Value::Var(Var(2)),
+            ]),
+        );
+        let main = (0..3).rev().fold(app, |e, i| {
+            Exp::Select(i, Value::Var(Var(0)), Var(1 + i as u64), Rc::new(e))
+        });
+        let main = Exp::Record(
+            Box::new([Value::Int(5), Value::Int(2), Value::Int(3)]),
+            Var(0),
+            Rc::new(main),
+        );
+        let result = Exp::Fix(Box::new([f]), Rc::new(main));
+
+        assert_eq!(run(&result), 7);
+    }
+
+    #[test]
+    fn test_many_arguments() {
+        // (define (f p0 ... p299) (halt (+ (- p299 p1) p200)))
+        const N: u64 = 300;
+        let param = |i: u64| Var(100 + i);
+        let f = FnDef(
+            Var(10),
+            (0..N).map(param).collect(),
+            Rc::new(primops(vec![
+                (Primop::Minus, param(299), param(1), Var(1)),
+                (Primop::Plus, Var(1), param(200), Var(2)),
+            ])),
+        );
+        // (f 0 ... 299)
+        let app = Exp::App(
+            Value::Label(Var(10)),
+            (0..N).map(|i| Value::Int(i as i32)).collect(),
+        );
+        let result = Exp::Fix(Box::new([f]), Rc::new(app));
+
+        assert_eq!(run(&result), 498);
+    }
+
+    #[test]
+    fn test_parallel_move() {
+        // every assignment of 5 sources to 5 destinations
+        const N: usize = 5;
+        for mut code in 0..N.pow(N as u32) {
+            let sources = (0..N)
+                .map(|_| {
+                    let source = code % N;
+                    code /= N;
+                    source
+                })
+                .collect::<Vec<_>>();
+            let moves = sources
+                .iter()
+                .enumerate()
+                .map(|(to, from)| (Reg(*from as u8), Reg(to as u8)))
+                .collect();
+
+            let halt = Exp::Primop(Primop::Halt, Box::new([]), Box::new([]), Box::new([]));
+            let mut compilation = Compilation::new(Liveness::analyze(&halt), HashMap::new());
+            compilation.parallel_move(moves).unwrap();
+
+            let mut registers = (0..N).collect::<Vec<_>>();
+            let code = compilation.chunk.code();
+            let mut cursor = Cursor::new(code);
+            while (cursor.position() as usize) < code.len() {
+                match Instruction::read(&mut cursor).unwrap() {
+                    Instruction::Move { result, from } => {
+                        registers[result.0 as usize] = registers[from.0 as usize]
+                    }
+                    Instruction::Swap { from, to } => {
+                        registers.swap(from.0 as usize, to.0 as usize)
+                    }
+                    instruction => panic!("unexpected instruction {:?}", instruction),
+                }
+            }
+
+            assert_eq!(registers, sources);
+        }
+    }
 }
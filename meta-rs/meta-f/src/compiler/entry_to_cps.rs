@@ -2,23 +2,42 @@ use std::rc::Rc;
 
 use im::HashMap;
 
+use meta_store::Field;
+
+use crate::compiler::builtins::{builtins, constants};
 use crate::compiler::cps::Exp as CExp;
 use crate::compiler::cps::*;
+use crate::host::HostFunctions;
 use crate::parser::{
-    Binding, Constructor, Expr, Function, Identifier, RunTest, Statement, TypeDef,
+    block_items, Binding, BlockItem, Case, Constructor, Expr, ExprKind, Function, Identifier, If,
+    Match, Parameter, RunTest, Statement, TypeDef,
 };
 
-pub(crate) fn entry_to_cps(gen: &mut VarGen, e: &RunTest) -> CExp {
+pub(crate) fn entry_to_cps(gen: &mut VarGen, e: &RunTest, host_functions: &HostFunctions) -> CExp {
     let mut fields = HashMap::new();
     collect_fields(&mut fields, e);
 
-    compile_expr(
-        gen,
-        Env::new(fields),
-        &e.expr,
-        Box::new(|_gen: &mut _, v| {
-            CExp::Primop(Primop::Halt, Box::new([v]), Box::new([]), Box::new([]))
-        }),
+    let mut env = Env::new(fields, e.names.clone());
+    let mut fndefs = Vec::new();
+    for (identifier, fndef) in builtins(gen, host_functions) {
+        gen.describe(fndef.0, &identifier.entry, env.get_name(&identifier));
+        env.add_variable(identifier, Value::Var(fndef.0));
+        fndefs.push(fndef);
+    }
+    for (identifier, value) in constants() {
+        env.add_variable(identifier, value);
+    }
+
+    CExp::Fix(
+        fndefs.into_boxed_slice(),
+        Rc::new(compile_expr(
+            gen,
+            env,
+            &e.expr,
+            Box::new(|_gen: &mut _, v| {
+                CExp::Primop(Primop::Halt, Box::new([v]), Box::new([]), Box::new([]))
+            }),
+        )),
     )
 }
 
@@ -26,16 +45,22 @@ pub(crate) fn entry_to_cps(gen: &mut VarGen, e: &RunTest) -> CExp {
 struct Env {
     variables: HashMap<Identifier, Value>,
     fields: HashMap<Identifier, /* offset: */ usize>, // constructors and constructor parameters
+    names: HashMap<Identifier, String>,
 }
 
 impl Env {
-    fn new(fields: HashMap<Identifier, usize>) -> Self {
+    fn new(fields: HashMap<Identifier, usize>, names: HashMap<Identifier, String>) -> Self {
         Self {
             variables: HashMap::new(),
             fields,
+            names,
         }
     }
 
+    fn get_name(&self, id: &Identifier) -> Option<&String> {
+        self.names.get(id)
+    }
+
     fn get_variable(&self, id: &Identifier) -> Option<&Value> {
         self.variables.get(id)
     }
@@ -72,21 +97,18 @@ fn compile_expr<'a, F>(gen: &'a mut VarGen, env: Env, e: &'a Expr, and_then: F)
 where
     F: FnOnce(&mut VarGen, Value) -> CExp,
 {
-    match e {
-        Expr::NumberLiteral(i) => and_then(gen, Value::Int(*i)),
-        Expr::StringLiteral(s) => and_then(gen, Value::String(s.clone())),
-        Expr::Reference(identifier) => {
+    match &e.kind {
+        ExprKind::NumberLiteral(i) => and_then(gen, Value::Int(*i)),
+        ExprKind::StringLiteral(s) => and_then(gen, Value::String(s.clone())),
+        ExprKind::Reference(identifier) => {
             let val = env.get_variable(identifier).unwrap();
             and_then(gen, val.clone())
         }
-        Expr::App(f, args) => {
-            let k = gen.next();
-            let kv = gen.next();
-
-            let next = and_then(gen, Value::Var(kv));
-            CExp::Fix(
-                Box::new([FnDef(k, Box::new([kv]), Rc::new(next))]),
-                Rc::new(compile_expr(
+        ExprKind::App(f, args) => {
+            let (k, fndef) = join_continuation(gen, &e.entity, and_then);
+            with_continuation(
+                fndef,
+                compile_expr(
                     gen,
                     env.clone(),
                     f,
@@ -102,34 +124,29 @@ where
                                 as Box<dyn for<'r> FnOnce(&'r mut VarGen, Vec<Value>) -> CExp>,
                         )
                     }) as Box<dyn FnOnce(&mut VarGen, Value) -> CExp>,
-                )),
+                ),
             )
         }
-        Expr::Function(f) => {
-            let f_var = gen.next();
+        ExprKind::Function(f) => {
+            let f_var = gen.next_for(&e.entity);
             let fndef = compile_fndef(gen, env, f, f_var);
             CExp::Fix(Box::new([fndef]), Rc::new(and_then(gen, Value::Var(f_var))))
         }
-        Expr::Block(stmts) => {
-            let k = gen.next();
-            let v = gen.next();
-            CExp::Fix(
-                Box::new([FnDef(
-                    k,
-                    Box::new([v]),
-                    Rc::new(and_then(gen, Value::Var(v))),
-                )]),
-                Rc::new(compile_block(
+        ExprKind::Block(stmts) => {
+            let (k, fndef) = join_continuation(gen, &e.entity, and_then);
+            with_continuation(
+                fndef,
+                compile_block(
                     gen,
                     env,
                     stmts,
                     Box::new(|_gen: &mut _, res| CExp::App(Value::Var(k), Box::new([res])))
                         as Box<dyn FnOnce(&mut _, _) -> _>,
-                )),
+                ),
             )
         }
-        Expr::TypeDef(TypeDef { constructors }) => {
-            let t = gen.next();
+        ExprKind::TypeDef(TypeDef { constructors }) => {
+            let t = gen.next_for(&e.entity);
 
             let fndefs = constructors
                 .iter()
@@ -140,20 +157,20 @@ where
                 .map(|f| Value::Var(f.0))
                 .collect::<Box<[Value]>>();
 
-            let r = gen.next();
+            let r = gen.next_for(&e.entity);
 
             CExp::Fix(
                 fndefs,
                 Rc::new(CExp::Record(vars, r, Rc::new(and_then(gen, Value::Var(r))))),
             )
         }
-        Expr::Access(object, field) => compile_expr(
+        ExprKind::Access(object, field) => compile_expr(
             gen,
             env.clone(),
             object,
             Box::new(move |gen: &mut VarGen, val: _| {
                 let offset = env.get_field(field).expect("unable to get_field()");
-                let r = gen.next();
+                let r = gen.next_for(&e.entity);
                 CExp::Select(
                     *offset as isize,
                     val,
@@ -162,51 +179,219 @@ where
                 )
             }) as Box<dyn FnOnce(&mut VarGen, Value) -> CExp>,
         ),
+        ExprKind::Match(m) => {
+            let Match { value, cases } = &**m;
+
+            let (k, fndef) = join_continuation(gen, &e.entity, and_then);
+            with_continuation(
+                fndef,
+                compile_expr(
+                    gen,
+                    env.clone(),
+                    value,
+                    Box::new(move |gen: &mut VarGen, v: Value| {
+                        compile_cases(gen, env, &e.entity, v, cases, k)
+                    }) as Box<dyn FnOnce(&mut VarGen, Value) -> CExp>,
+                ),
+            )
+        }
+        ExprKind::If(if_) => {
+            let If {
+                condition,
+                then,
+                else_,
+            } = &**if_;
+
+            let (k, fndef) = join_continuation(gen, &e.entity, and_then);
+            with_continuation(
+                fndef,
+                compile_expr(
+                    gen,
+                    env.clone(),
+                    condition,
+                    Box::new(move |gen: &mut VarGen, c: Value| {
+                        let branch = |gen: &mut VarGen, e| {
+                            Rc::new(compile_expr(
+                                gen,
+                                env.clone(),
+                                e,
+                                Box::new(|_gen: &mut _, res| {
+                                    CExp::App(Value::Var(k), Box::new([res]))
+                                })
+                                    as Box<dyn FnOnce(&mut _, _) -> _>,
+                            ))
+                        };
+                        // false is the 0-th constructor of booleans
+                        let branches = Box::new([branch(gen, else_), branch(gen, then)]);
+                        CExp::Switch(c, branches)
+                    }) as Box<dyn FnOnce(&mut VarGen, Value) -> CExp>,
+                ),
+            )
+        }
     }
 }
 
-fn compile_block<F>(gen: &mut VarGen, env: Env, stmts: &[Statement], and_then: F) -> CExp
+/// Continuation passing the result of an expression to `and_then`.
+///
+/// If `and_then` only passes the result to another continuation (the expression is in tail
+/// position), that continuation is returned as is, so tail calls don't allocate continuations.
+/// Otherwise, a new continuation is returned along with its definition.
+fn join_continuation<F>(gen: &mut VarGen, entity: &Field, and_then: F) -> (Var, Option<FnDef>)
 where
     F: FnOnce(&mut VarGen, Value) -> CExp,
 {
-    if stmts.is_empty() {
-        return and_then(gen, Value::Int(0));
+    let k = gen.next_for(entity);
+    let kv = gen.next_for(entity);
+    match and_then(gen, Value::Var(kv)) {
+        CExp::App(Value::Var(next_k), args) if *args == [Value::Var(kv)] => (next_k, None),
+        next => (k, Some(FnDef(k, Box::new([kv]), Rc::new(next)))),
     }
+}
 
-    let stmt = &stmts[0];
-    let rest = &stmts[1..];
+/// Define continuation returned by `join_continuation()` (if any) for `e`.
+fn with_continuation(fndef: Option<FnDef>, e: CExp) -> CExp {
+    match fndef {
+        Some(fndef) => CExp::Fix(Box::new([fndef]), Rc::new(e)),
+        None => e,
+    }
+}
 
-    match stmt {
-        Statement::Binding(binding) => {
-            let Binding { identifier, value } = binding;
-            match value {
-                Expr::Function(f) => {
-                    let f_var = gen.next();
+/// Switch on constructor of `value` and pass the result of the matching case to `k`.
+fn compile_cases(
+    gen: &mut VarGen,
+    env: Env,
+    entity: &Field,
+    value: Value,
+    cases: &[Case],
+    k: Var,
+) -> CExp {
+    // cases indexed by constructor
+    let mut by_constructor: Vec<Option<&Case>> = Vec::new();
+    for case in cases.iter() {
+        let i = *env
+            .get_field(&case.constructor)
+            .expect("unable to get_field()");
+        if by_constructor.len() <= i {
+            by_constructor.resize(i + 1, None);
+        }
+        // first case wins
+        by_constructor[i].get_or_insert(case);
+    }
 
-                    let mut next_env = env;
-                    next_env.add_variable(identifier.clone(), Value::Var(f_var));
+    let branches = by_constructor
+        .into_iter()
+        .map(|case| {
+            Rc::new(match case {
+                Some(Case {
+                    parameters, body, ..
+                }) => {
+                    let vars = parameters
+                        .iter()
+                        .map(|p| parameter_var(gen, &env, p))
+                        .collect::<Vec<_>>();
 
-                    let fndef = compile_fndef(gen, next_env.clone(), f, f_var);
-                    CExp::Fix(
-                        Box::new([fndef]),
-                        Rc::new(compile_block(gen, next_env, rest, and_then)),
-                    )
-                }
-                value => {
                     let mut next_env = env.clone();
-                    compile_expr(
+                    parameters.iter().zip(vars.iter()).for_each(|(p, var)| {
+                        next_env.add_variable(p.id.clone(), Value::Var(*var));
+                    });
+
+                    let body = compile_expr(
                         gen,
-                        env,
-                        value,
-                        Box::new(move |gen: &mut _, v| {
-                            next_env.add_variable(identifier.clone(), v);
-                            compile_block(gen, next_env, rest, and_then)
-                        }) as Box<dyn FnOnce(&mut _, _) -> _>,
-                    )
+                        next_env,
+                        body,
+                        Box::new(|_gen: &mut _, res| CExp::App(Value::Var(k), Box::new([res])))
+                            as Box<dyn FnOnce(&mut _, _) -> _>,
+                    );
+
+                    // offset by 1 because 0-th value is constructor tag
+                    vars.iter().enumerate().rfold(body, |e, (i, var)| {
+                        CExp::Select(i as isize + 1, value.clone(), *var, Rc::new(e))
+                    })
                 }
+                None => CExp::Primop(
+                    Primop::MatchFailure,
+                    Box::new([]),
+                    Box::new([]),
+                    Box::new([]),
+                ),
+            })
+        })
+        .collect();
+
+    let tag = gen.next_for(entity);
+    CExp::Select(
+        0,
+        value,
+        tag,
+        Rc::new(CExp::Switch(Value::Var(tag), branches)),
+    )
+}
+
+fn compile_block<F>(gen: &mut VarGen, env: Env, stmts: &[Statement], and_then: F) -> CExp
+where
+    F: FnOnce(&mut VarGen, Value) -> CExp,
+{
+    compile_block_items(gen, env, &block_items(stmts), and_then)
+}
+
+fn compile_block_items<F>(gen: &mut VarGen, env: Env, items: &[BlockItem<'_>], and_then: F) -> CExp
+where
+    F: FnOnce(&mut VarGen, Value) -> CExp,
+{
+    if items.is_empty() {
+        return and_then(gen, Value::Int(0));
+    }
+
+    let item = &items[0];
+    let rest = &items[1..];
+
+    match item {
+        BlockItem::Functions(functions) => {
+            // all functions of the group are in scope of each other
+            let mut next_env = env;
+            let f_vars = functions
+                .iter()
+                .map(|(binding, _f)| {
+                    let f_var = gen.next();
+                    gen.describe(
+                        f_var,
+                        &binding.value.entity,
+                        next_env.get_name(&binding.identifier),
+                    );
+                    f_var
+                })
+                .collect::<Vec<_>>();
+            for ((binding, _f), f_var) in functions.iter().zip(f_vars.iter()) {
+                next_env.add_variable(binding.identifier.clone(), Value::Var(*f_var));
             }
+
+            let fndefs = functions
+                .iter()
+                .zip(f_vars)
+                .map(|((_binding, f), f_var)| compile_fndef(gen, next_env.clone(), f, f_var))
+                .collect();
+            CExp::Fix(
+                fndefs,
+                Rc::new(compile_block_items(gen, next_env, rest, and_then)),
+            )
         }
-        Statement::Expr(expr) => {
+        BlockItem::Statement(Statement::Binding(binding)) => {
+            let Binding { identifier, value } = binding;
+            let mut next_env = env.clone();
+            compile_expr(
+                gen,
+                env,
+                value,
+                Box::new(move |gen: &mut VarGen, v| {
+                    if let Value::Var(var) = v {
+                        gen.name(var, next_env.get_name(identifier));
+                    }
+                    next_env.add_variable(identifier.clone(), v);
+                    compile_block_items(gen, next_env, rest, and_then)
+                }) as Box<dyn FnOnce(&mut _, _) -> _>,
+            )
+        }
+        BlockItem::Statement(Statement::Expr(expr)) => {
             if rest.is_empty() {
                 compile_expr(gen, env, expr, Box::new(and_then))
             } else {
@@ -215,8 +400,9 @@ where
                     gen,
                     env.clone(),
                     expr,
-                    Box::new(move |gen: &mut _, _: Value| compile_block(gen, env, rest, and_then))
-                        as Box<dyn FnOnce(&mut _, _) -> _>,
+                    Box::new(move |gen: &mut _, _: Value| {
+                        compile_block_items(gen, env, rest, and_then)
+                    }) as Box<dyn FnOnce(&mut _, _) -> _>,
                 )
             }
         }
@@ -226,9 +412,12 @@ where
 fn compile_fndef(gen: &mut VarGen, env: Env, f: &Function, f_var: Var) -> FnDef {
     let Function { parameters, body } = f;
 
-    let mut params = parameters.iter().map(|_| gen.next()).collect::<Vec<_>>();
+    let mut params = parameters
+        .iter()
+        .map(|p| parameter_var(gen, &env, p))
+        .collect::<Vec<_>>();
 
-    let k = gen.next(); // return continuation
+    let k = gen.next_for(&body.entity); // return continuation
     params.push(k);
     let params = params;
 
@@ -257,7 +446,11 @@ fn compile_constructor(gen: &mut VarGen, env: &Env, t: Var, constructor: &Constr
     } = constructor;
 
     let var = gen.next(); // constructor function
-    let parameters = parameters.iter().map(|_| gen.next()).collect::<Vec<_>>();
+    gen.describe(var, &identifier.entry, env.get_name(identifier));
+    let parameters = parameters
+        .iter()
+        .map(|p| parameter_var(gen, env, p))
+        .collect::<Vec<_>>();
 
     let mut constructor_vals = Vec::new();
     constructor_vals.push(Value::ConstructorTag(
@@ -267,10 +460,10 @@ fn compile_constructor(gen: &mut VarGen, env: &Env, t: Var, constructor: &Constr
     constructor_vals.extend(parameters.iter().copied().map(Value::Var));
 
     let mut fn_params = parameters;
-    let k = gen.next(); // return continuation
+    let k = gen.next_for(&identifier.entry); // return continuation
     fn_params.push(k);
 
-    let r = gen.next(); // result record
+    let r = gen.next_for(&identifier.entry); // result record
 
     FnDef(
         var,
@@ -283,27 +476,34 @@ fn compile_constructor(gen: &mut VarGen, env: &Env, t: Var, constructor: &Constr
     )
 }
 
+/// Variable bound to the parameter.
+fn parameter_var(gen: &mut VarGen, env: &Env, parameter: &Parameter) -> Var {
+    let var = gen.next();
+    gen.describe(var, &parameter.id.entry, env.get_name(&parameter.id));
+    var
+}
+
 fn collect_fields(fields: &mut HashMap<Identifier, usize>, test: &RunTest) {
     fn collect_expr(fields: &mut HashMap<Identifier, usize>, e: &Expr) {
-        match e {
-            Expr::NumberLiteral(_) => {}
-            Expr::StringLiteral(_) => {}
-            Expr::Reference(_) => {}
-            Expr::App(f, args) => {
+        match &e.kind {
+            ExprKind::NumberLiteral(_) => {}
+            ExprKind::StringLiteral(_) => {}
+            ExprKind::Reference(_) => {}
+            ExprKind::App(f, args) => {
                 collect_expr(fields, f);
                 for arg in args.iter() {
                     collect_expr(fields, arg);
                 }
             }
-            Expr::Function(f) => {
+            ExprKind::Function(f) => {
                 collect_expr(fields, &f.body);
             }
-            Expr::Block(stmts) => {
+            ExprKind::Block(stmts) => {
                 for stmt in stmts.iter() {
                     collect_stmt(fields, stmt);
                 }
             }
-            Expr::TypeDef(TypeDef { constructors }) => {
+            ExprKind::TypeDef(TypeDef { constructors }) => {
                 for (i, c) in constructors.iter().enumerate() {
                     fields.insert(c.identifier.clone(), i);
 
@@ -313,9 +513,20 @@ fn collect_fields(fields: &mut HashMap<Identifier, usize>, test: &RunTest) {
                     }
                 }
             }
-            Expr::Access(e, _) => {
+            ExprKind::Access(e, _) => {
                 collect_expr(fields, e);
             }
+            ExprKind::Match(m) => {
+                collect_expr(fields, &m.value);
+                for case in m.cases.iter() {
+                    collect_expr(fields, &case.body);
+                }
+            }
+            ExprKind::If(if_) => {
+                collect_expr(fields, &if_.condition);
+                collect_expr(fields, &if_.then);
+                collect_expr(fields, &if_.else_);
+            }
         }
     }
 
@@ -332,3 +543,104 @@ fn collect_fields(fields: &mut HashMap<Identifier, usize>, test: &RunTest) {
 
     collect_expr(fields, &test.expr);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile, Passes};
+    use crate::ids;
+    use crate::test_util::*;
+    use crate::vm::machine::{Options, Vm};
+
+    /// Run `stmts` as a block with a heap too small to hold a continuation per call.
+    fn run_block(stmts: Vec<Statement>, passes: &Passes) -> crate::Value {
+        let test = RunTest {
+            expr: expr("block", ExprKind::Block(stmts)),
+            names: HashMap::new(),
+        };
+        let chunk = compile(&test, passes, &HostFunctions::new());
+        let mut vm = Vm::new(
+            chunk,
+            &Options {
+                heap_size: 16 * 1024,
+                max_heap_size: Some(64 * 1024),
+                ..Options::default()
+            },
+        );
+        let result = vm.run().unwrap().unwrap();
+        vm.to_host(result)
+    }
+
+    #[test]
+    fn test_tail_recursion() {
+        // { count = fn(n, acc) -> if n == 0 then acc else count(n - 1, acc + 2)
+        //   count(100000, 0) }
+        let count = || {
+            vec![
+                binding(
+                    "count",
+                    function(
+                        "count-fn",
+                        &["n", "acc"],
+                        if_(
+                            "if",
+                            builtin(
+                                "equal",
+                                &ids::NUMBER_EQUAL,
+                                vec![reference("n-ref1", "n"), number("0", 0)],
+                            ),
+                            reference("acc-ref1", "acc"),
+                            app(
+                                "rec",
+                                reference("count-ref1", "count"),
+                                vec![
+                                    builtin(
+                                        "sub",
+                                        &ids::NUMBER_SUB,
+                                        vec![reference("n-ref2", "n"), number("1", 1)],
+                                    ),
+                                    builtin(
+                                        "add",
+                                        &ids::NUMBER_ADD,
+                                        vec![reference("acc-ref2", "acc"), number("2", 2)],
+                                    ),
+                                ],
+                            ),
+                        ),
+                    ),
+                ),
+                Statement::Expr(app(
+                    "app",
+                    reference("count-ref2", "count"),
+                    vec![number("100000", 100_000), number("acc-0", 0)],
+                )),
+            ]
+        };
+
+        for passes in [Passes::none(), Passes::default()].iter() {
+            assert_eq!(crate::Value::Number(200_000), run_block(count(), passes));
+        }
+    }
+
+    #[test]
+    fn test_mutual_tail_recursion() {
+        // { is_even = fn(n) -> if n == 0 then true else is_odd(n - 1)
+        //   is_odd = fn(n) -> if n == 0 then false else is_even(n - 1)
+        //   is_even(100001) }
+        let program = || {
+            vec![
+                parity("is_even", &ids::TRUE, "is_odd"),
+                parity("is_odd", &ids::FALSE, "is_even"),
+                Statement::Expr(app(
+                    "app",
+                    reference("is_even-ref", "is_even"),
+                    vec![number("100001", 100_001)],
+                )),
+            ]
+        };
+
+        for passes in [Passes::none(), Passes::default()].iter() {
+            assert_eq!(crate::Value::Bool(false), run_block(program(), passes));
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Liveness analysis of closure-converted CPS.
+//!
+//! In CPS every variable is bound once and control never returns, so the variables live at the
+//! start of an expression are exactly its free variables.
+use std::collections::HashMap as StdHashMap;
+
+use im::HashSet;
+
+use crate::compiler::cps::*;
+
+pub(crate) struct Liveness {
+    /// Variables live at the start of each expression, keyed by expression address.
+    live_in: StdHashMap<*const Exp, HashSet<Var>>,
+}
+
+impl Liveness {
+    /// Analyze `exp` and all functions defined in it. The expressions must not move while the
+    /// analysis is used.
+    pub fn analyze(exp: &Exp) -> Self {
+        let mut liveness = Self {
+            live_in: StdHashMap::new(),
+        };
+        liveness.visit(exp);
+        liveness
+    }
+
+    /// Variables used by `exp` or the code it continues to.
+    pub fn live_in(&self, exp: &Exp) -> &HashSet<Var> {
+        self.live_in
+            .get(&(exp as *const Exp))
+            .expect("expression has not been analyzed")
+    }
+
+    fn visit(&mut self, exp: &Exp) -> HashSet<Var> {
+        fn val_to_var(val: &Value) -> HashSet<Var> {
+            val.as_var().map_or_else(HashSet::new, HashSet::unit)
+        }
+        fn vals_to_vars(vals: &[Value]) -> HashSet<Var> {
+            HashSet::unions(vals.iter().map(val_to_var))
+        }
+
+        let live = match exp {
+            Exp::Record(vals, var, e) => vals_to_vars(vals) + self.visit(e).without(var),
+            Exp::Select(_i, val, var, e) | Exp::Offset(_i, val, var, e) => {
+                val_to_var(val) + self.visit(e).without(var)
+            }
+            Exp::App(f, vals) => vals_to_vars(vals) + val_to_var(f),
+            Exp::Switch(val, es) => {
+                val_to_var(val) + HashSet::unions(es.iter().map(|e| self.visit(e)))
+            }
+            Exp::Fix(fns, e) => {
+                let mut live = self.visit(e);
+                for FnDef(_f, params, body) in fns.iter() {
+                    let mut body_live = self.visit(body);
+                    for param in params.iter() {
+                        body_live.remove(param);
+                    }
+                    live = live + body_live;
+                }
+                for FnDef(f, _params, _body) in fns.iter() {
+                    live.remove(f);
+                }
+                live
+            }
+            Exp::Primop(_op, ins, outs, es) => {
+                let mut es_live = HashSet::unions(es.iter().map(|e| self.visit(e)));
+                for out in outs.iter() {
+                    es_live.remove(out);
+                }
+                vals_to_vars(ins) + es_live
+            }
+        };
+
+        self.live_in.insert(exp as *const Exp, live.clone());
+        live
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_live_in() {
+        // (record [v0 v1] v2
+        //   (select 0 v2 v3
+        //     (app v3 [v1])))
+        let app = Rc::new(Exp::App(Value::Var(Var(3)), Box::new([Value::Var(Var(1))])));
+        let select = Rc::new(Exp::Select(0, Value::Var(Var(2)), Var(3), app.clone()));
+        let record = Exp::Record(
+            Box::new([Value::Var(Var(0)), Value::Var(Var(1))]),
+            Var(2),
+            select.clone(),
+        );
+
+        let liveness = Liveness::analyze(&record);
+
+        assert_eq!(
+            liveness.live_in(&record),
+            &vec![Var(0), Var(1)].into_iter().collect()
+        );
+        assert_eq!(
+            liveness.live_in(&select),
+            &vec![Var(1), Var(2)].into_iter().collect()
+        );
+        assert_eq!(
+            liveness.live_in(&app),
+            &vec![Var(1), Var(3)].into_iter().collect()
+        );
+    }
+}
@@ -1,7 +1,11 @@
+pub(crate) mod builtins;
 pub(crate) mod closure_conversion;
 pub(crate) mod compile;
 pub(crate) mod cps;
 pub(crate) mod cps_to_bytecode;
 pub(crate) mod entry_to_cps;
+pub(crate) mod liveness;
+pub(crate) mod optimize;
 
 pub(crate) use compile::compile;
+pub(crate) use optimize::Passes;
@@ -0,0 +1,620 @@
+//! Optimizations of CPS.
+//!
+//! The optimizer runs before closure conversion to get rid of administrative continuations, and
+//! after it to turn calls of closures with known functions into direct jumps.
+//!
+//! All passes rely on variables being bound only once. Each round of optimizations only shrinks
+//! the expression, so rounds are repeated until nothing changes.
+use std::rc::Rc;
+
+use im::HashMap;
+
+use crate::compiler::cps::*;
+
+/// Optimizations to perform. All are enabled by default.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct Passes {
+    /// Evaluate primops and switches on constants.
+    pub constant_folding: bool,
+    /// Inline functions that are called once and not used otherwise.
+    pub beta_contraction: bool,
+    /// Replace functions that only pass their parameters to another function with that function.
+    pub eta_reduction: bool,
+    /// Drop records, selects, pure primops and functions whose results are not used.
+    pub dead_code: bool,
+    /// Resolve selects from records bound in scope, so calls of functions stored in records
+    /// (constructors, closures) become calls of known functions.
+    pub known_calls: bool,
+}
+
+impl Passes {
+    /// No optimizations: the code closely follows the source, which is better for debugging.
+    pub fn none() -> Self {
+        Self {
+            constant_folding: false,
+            beta_contraction: false,
+            eta_reduction: false,
+            dead_code: false,
+            known_calls: false,
+        }
+    }
+}
+
+impl Default for Passes {
+    fn default() -> Self {
+        Self {
+            constant_folding: true,
+            beta_contraction: true,
+            eta_reduction: true,
+            dead_code: true,
+            known_calls: true,
+        }
+    }
+}
+
+pub(crate) fn optimize(exp: &Rc<Exp>, passes: &Passes) -> Rc<Exp> {
+    let mut exp = exp.clone();
+    loop {
+        let mut uses = HashMap::new();
+        census(&mut uses, &exp);
+
+        let next = Round::new(passes, uses).exp(&exp);
+        if next == exp {
+            return exp;
+        }
+        exp = next;
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Uses {
+    total: usize,
+    /// Uses as a function of [`Exp::App`].
+    calls: usize,
+}
+
+/// Count uses of all variables in `exp`.
+fn census(uses: &mut HashMap<Var, Uses>, exp: &Exp) {
+    fn use_val(uses: &mut HashMap<Var, Uses>, val: &Value, call: bool) {
+        if let Value::Var(var) | Value::Label(var) = val {
+            let entry = uses.entry(*var).or_default();
+            entry.total += 1;
+            if call {
+                entry.calls += 1;
+            }
+        }
+    }
+    fn use_vals(uses: &mut HashMap<Var, Uses>, vals: &[Value]) {
+        vals.iter().for_each(|val| use_val(uses, val, false));
+    }
+
+    match exp {
+        Exp::Record(vals, _var, e) => {
+            use_vals(uses, vals);
+            census(uses, e);
+        }
+        Exp::Select(_i, val, _var, e) | Exp::Offset(_i, val, _var, e) => {
+            use_val(uses, val, false);
+            census(uses, e);
+        }
+        Exp::App(f, vals) => {
+            use_val(uses, f, true);
+            use_vals(uses, vals);
+        }
+        Exp::Fix(fns, e) => {
+            fns.iter()
+                .for_each(|FnDef(_f, _params, body)| census(uses, body));
+            census(uses, e);
+        }
+        Exp::Switch(val, es) => {
+            use_val(uses, val, false);
+            es.iter().for_each(|e| census(uses, e));
+        }
+        Exp::Primop(_op, ins, _outs, es) => {
+            use_vals(uses, ins);
+            es.iter().for_each(|e| census(uses, e));
+        }
+    }
+}
+
+/// Value of a primop applied to constants. Operations that would fail at runtime are not folded.
+fn fold(op: Primop, ins: &[Value]) -> Option<Value> {
+    use Value::{Bool, Int, String as Str};
+    match (op, ins) {
+        (Primop::Plus, [Int(a), Int(b)]) => a.checked_add(*b).map(Int),
+        (Primop::Minus, [Int(a), Int(b)]) => a.checked_sub(*b).map(Int),
+        (Primop::Times, [Int(a), Int(b)]) => a.checked_mul(*b).map(Int),
+        (Primop::Divide, [Int(a), Int(b)]) => a.checked_div(*b).map(Int),
+        (Primop::Modulo, [Int(a), Int(b)]) => a.checked_rem(*b).map(Int),
+        (Primop::Negate, [Int(a)]) => a.checked_neg().map(Int),
+        (Primop::NumberEqual, [Int(a), Int(b)]) => Some(Bool(a == b)),
+        (Primop::Less, [Int(a), Int(b)]) => Some(Bool(a < b)),
+        (Primop::LessEqual, [Int(a), Int(b)]) => Some(Bool(a <= b)),
+        (Primop::Greater, [Int(a), Int(b)]) => Some(Bool(a > b)),
+        (Primop::GreaterEqual, [Int(a), Int(b)]) => Some(Bool(a >= b)),
+        (Primop::Not, [Bool(a)]) => Some(Bool(!a)),
+        (Primop::And, [Bool(a), Bool(b)]) => Some(Bool(*a && *b)),
+        (Primop::Or, [Bool(a), Bool(b)]) => Some(Bool(*a || *b)),
+        (Primop::StringConcat, [Str(a), Str(b)]) => Some(Str(format!("{}{}", a, b))),
+        (Primop::StringLength, [Str(a)]) => Some(Int(a.chars().count() as i32)),
+        (Primop::StringEqual, [Str(a), Str(b)]) => Some(Bool(a == b)),
+        (Primop::NumberToString, [Int(a)]) => Some(Str(a.to_string())),
+        (Primop::StringToNumber, [Str(a)]) => a.parse().ok().map(Int),
+        _ => None,
+    }
+}
+
+/// Primops that never fail, so can be dropped if their results are not used.
+fn is_pure(op: Primop) -> bool {
+    matches!(
+        op,
+        Primop::NumberEqual
+            | Primop::Less
+            | Primop::LessEqual
+            | Primop::Greater
+            | Primop::GreaterEqual
+            | Primop::Not
+            | Primop::And
+            | Primop::Or
+            | Primop::StringConcat
+            | Primop::StringLength
+            | Primop::StringEqual
+            | Primop::NumberToString
+    )
+}
+
+/// A single pass over the expression that performs all enabled optimizations. Decisions are made
+/// with the census taken before the round, so they stay valid as the expression shrinks.
+struct Round<'a> {
+    passes: &'a Passes,
+    uses: HashMap<Var, Uses>,
+    /// Values to replace variables with.
+    substitution: HashMap<Var, Value>,
+    /// Functions to inline at their only call.
+    inline: HashMap<Var, FnDef>,
+    /// Fields of records bound in scope and the field each variable points to.
+    records: HashMap<Var, (Rc<[Value]>, isize)>,
+}
+
+impl<'a> Round<'a> {
+    fn new(passes: &'a Passes, uses: HashMap<Var, Uses>) -> Self {
+        Self {
+            passes,
+            uses,
+            substitution: HashMap::new(),
+            inline: HashMap::new(),
+            records: HashMap::new(),
+        }
+    }
+
+    fn uses(&self, var: Var) -> Uses {
+        self.uses.get(&var).copied().unwrap_or_default()
+    }
+
+    fn is_dead(&self, var: Var) -> bool {
+        self.passes.dead_code && self.uses(var).total == 0
+    }
+
+    fn value(&self, val: &Value) -> Value {
+        let mut val = val.clone();
+        while let Some(next) = val.as_var().and_then(|var| self.substitution.get(&var)) {
+            val = next.clone();
+        }
+        val
+    }
+
+    fn values(&self, vals: &[Value]) -> Box<[Value]> {
+        vals.iter().map(|val| self.value(val)).collect()
+    }
+
+    /// Field `i` of the record `val` points to, if the record is known.
+    fn field(&self, val: &Value, i: isize) -> Option<Value> {
+        let (fields, offset) = self.records.get(&val.as_var()?)?;
+        let i = offset + i;
+        if 0 <= i && (i as usize) < fields.len() {
+            Some(fields[i as usize].clone())
+        } else {
+            None
+        }
+    }
+
+    fn exp(&mut self, exp: &Exp) -> Rc<Exp> {
+        match exp {
+            Exp::Record(vals, var, e) => {
+                if self.is_dead(*var) {
+                    return self.exp(e);
+                }
+                let vals = self.values(vals);
+                if self.passes.known_calls {
+                    self.records
+                        .insert(*var, (vals.iter().cloned().collect(), 0));
+                }
+                Rc::new(Exp::Record(vals, *var, self.exp(e)))
+            }
+            Exp::Select(i, val, var, e) => {
+                if self.is_dead(*var) {
+                    return self.exp(e);
+                }
+                let val = self.value(val);
+                if self.passes.known_calls {
+                    if let Some(field) = self.field(&val, *i) {
+                        self.substitution.insert(*var, field);
+                        return self.exp(e);
+                    }
+                }
+                Rc::new(Exp::Select(*i, val, *var, self.exp(e)))
+            }
+            Exp::Offset(i, val, var, e) => {
+                if self.is_dead(*var) {
+                    return self.exp(e);
+                }
+                let val = self.value(val);
+                if self.passes.known_calls {
+                    if let Some((fields, offset)) = val.as_var().and_then(|v| self.records.get(&v))
+                    {
+                        let record = (fields.clone(), offset + i);
+                        self.records.insert(*var, record);
+                    }
+                }
+                Rc::new(Exp::Offset(*i, val, *var, self.exp(e)))
+            }
+            Exp::App(f, vals) => {
+                let f = self.value(f);
+                let vals = self.values(vals);
+                if let Value::Var(var) | Value::Label(var) = &f {
+                    if let Some(FnDef(_f, params, body)) = self.inline.remove(var) {
+                        assert_eq!(params.len(), vals.len(), "wrong number of arguments");
+                        for (param, val) in params.iter().zip(vals.iter()) {
+                            self.substitution.insert(*param, val.clone());
+                        }
+                        return self.exp(&body);
+                    }
+                }
+                Rc::new(Exp::App(f, vals))
+            }
+            Exp::Fix(fns, e) => {
+                let mut kept = Vec::new();
+                for fndef in fns.iter() {
+                    let FnDef(f, params, body) = fndef;
+                    let uses = self.uses(*f);
+                    let self_uses = || {
+                        let mut body_uses = HashMap::new();
+                        census(&mut body_uses, body);
+                        body_uses.get(f).map_or(0, |uses: &Uses| uses.total)
+                    };
+
+                    if self.passes.dead_code && uses.total == self_uses() {
+                        continue;
+                    }
+                    if self.passes.beta_contraction
+                        && uses.total == 1
+                        && uses.calls == 1
+                        && self_uses() == 0
+                    {
+                        self.inline.insert(*f, fndef.clone());
+                        continue;
+                    }
+                    if self.passes.eta_reduction {
+                        if let Some(g) = self.eta_target(fns, params, body) {
+                            self.substitution.insert(*f, g);
+                            continue;
+                        }
+                    }
+                    kept.push(fndef);
+                }
+
+                let kept = kept
+                    .into_iter()
+                    .map(|FnDef(f, params, body)| FnDef(*f, params.clone(), self.exp(body)))
+                    .collect::<Box<[FnDef]>>();
+                let e = self.exp(e);
+                if kept.is_empty() {
+                    e
+                } else {
+                    Rc::new(Exp::Fix(kept, e))
+                }
+            }
+            Exp::Switch(val, es) => {
+                let val = self.value(val);
+                if self.passes.constant_folding {
+                    let branch = match val {
+                        Value::Int(i) if i >= 0 => Some(i as usize),
+                        Value::Bool(b) => Some(b as usize),
+                        Value::ConstructorTag(_type, constructor) => Some(constructor as usize),
+                        _ => None,
+                    };
+                    if let Some(e) = branch.and_then(|branch| es.get(branch)) {
+                        return self.exp(e);
+                    }
+                }
+                let es = es.iter().map(|e| self.exp(e)).collect();
+                Rc::new(Exp::Switch(val, es))
+            }
+            Exp::Primop(op, ins, outs, es) => {
+                let ins = self.values(ins);
+                if let ([out], [e]) = (&**outs, &**es) {
+                    if self.passes.constant_folding {
+                        if let Some(result) = fold(*op, &ins) {
+                            self.substitution.insert(*out, result);
+                            return self.exp(e);
+                        }
+                    }
+                    if is_pure(*op) && self.is_dead(*out) {
+                        return self.exp(e);
+                    }
+                }
+                let es = es.iter().map(|e| self.exp(e)).collect();
+                Rc::new(Exp::Primop(*op, ins, outs.clone(), es))
+            }
+        }
+    }
+
+    /// Function `body` passes all `params` to, if it does nothing else. Functions of the same
+    /// `Fix` are not considered, as they could be reduced to each other.
+    fn eta_target(&self, fns: &[FnDef], params: &[Var], body: &Exp) -> Option<Value> {
+        if let Exp::App(g, args) = body {
+            let passes_params = args.len() == params.len()
+                && args
+                    .iter()
+                    .zip(params.iter())
+                    .all(|(arg, param)| arg == &Value::Var(*param));
+            let g = self.value(g);
+            let var = match &g {
+                Value::Var(var) | Value::Label(var) => *var,
+                _ => return None,
+            };
+            let is_local =
+                params.contains(&var) || fns.iter().any(|FnDef(f, _params, _body)| *f == var);
+            if passes_params && !is_local && !self.inline.contains_key(&var) {
+                return Some(g);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::HostFunctions;
+    use crate::vm::{Options, Vm};
+
+    fn halt(val: Value) -> Exp {
+        Exp::Primop(Primop::Halt, Box::new([val]), Box::new([]), Box::new([]))
+    }
+
+    fn only(pass: impl FnOnce(&mut Passes)) -> Passes {
+        let mut passes = Passes::none();
+        pass(&mut passes);
+        passes
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        // (+ 1 2 v0 (< v0 4 v1 (switch v1 (halt "no") (halt "yes"))))
+        let input = Rc::new(Exp::Primop(
+            Primop::Plus,
+            Box::new([Value::Int(1), Value::Int(2)]),
+            Box::new([Var(0)]),
+            Box::new([Rc::new(Exp::Primop(
+                Primop::Less,
+                Box::new([Value::Var(Var(0)), Value::Int(4)]),
+                Box::new([Var(1)]),
+                Box::new([Rc::new(Exp::Switch(
+                    Value::Var(Var(1)),
+                    Box::new([
+                        Rc::new(halt(Value::String("no".to_string()))),
+                        Rc::new(halt(Value::String("yes".to_string()))),
+                    ]),
+                ))]),
+            ))]),
+        ));
+
+        let result = optimize(&input, &only(|p| p.constant_folding = true));
+
+        assert_eq!(*result, halt(Value::String("yes".to_string())));
+    }
+
+    #[test]
+    fn test_no_folding_of_failing_primops() {
+        // (/ 1 0 v0 (halt v0))
+        let input = Rc::new(Exp::Primop(
+            Primop::Divide,
+            Box::new([Value::Int(1), Value::Int(0)]),
+            Box::new([Var(0)]),
+            Box::new([Rc::new(halt(Value::Var(Var(0))))]),
+        ));
+
+        let result = optimize(&input, &Passes::default());
+
+        assert_eq!(result, input);
+    }
+
+    #[test]
+    fn test_beta_contraction() {
+        // (fix ((f0 (v1 k2) (k2 v1))) (f0 42 k3))
+        let input = Rc::new(Exp::Fix(
+            Box::new([FnDef(
+                Var(0),
+                Box::new([Var(1), Var(2)]),
+                Rc::new(Exp::App(Value::Var(Var(2)), Box::new([Value::Var(Var(1))]))),
+            )]),
+            Rc::new(Exp::App(
+                Value::Var(Var(0)),
+                Box::new([Value::Int(42), Value::Var(Var(3))]),
+            )),
+        ));
+
+        let result = optimize(&input, &only(|p| p.beta_contraction = true));
+
+        assert_eq!(
+            *result,
+            Exp::App(Value::Var(Var(3)), Box::new([Value::Int(42)]))
+        );
+    }
+
+    #[test]
+    fn test_no_inlining_of_escaping_functions() {
+        // (fix ((f0 (v1) (halt v1))) (f0 f0))
+        let input = Rc::new(Exp::Fix(
+            Box::new([FnDef(
+                Var(0),
+                Box::new([Var(1)]),
+                Rc::new(halt(Value::Var(Var(1)))),
+            )]),
+            Rc::new(Exp::App(Value::Var(Var(0)), Box::new([Value::Var(Var(0))]))),
+        ));
+
+        let result = optimize(&input, &Passes::default());
+
+        assert_eq!(result, input);
+    }
+
+    #[test]
+    fn test_eta_reduction() {
+        // (fix ((k0 (v1) (g2 v1))) (f3 7 k0))
+        let input = Rc::new(Exp::Fix(
+            Box::new([FnDef(
+                Var(0),
+                Box::new([Var(1)]),
+                Rc::new(Exp::App(Value::Var(Var(2)), Box::new([Value::Var(Var(1))]))),
+            )]),
+            Rc::new(Exp::App(
+                Value::Var(Var(3)),
+                Box::new([Value::Int(7), Value::Var(Var(0))]),
+            )),
+        ));
+
+        let result = optimize(&input, &only(|p| p.eta_reduction = true));
+
+        assert_eq!(
+            *result,
+            Exp::App(
+                Value::Var(Var(3)),
+                Box::new([Value::Int(7), Value::Var(Var(2))])
+            )
+        );
+    }
+
+    #[test]
+    fn test_dead_code() {
+        // (fix ((f0 (v1) (f0 v1)))
+        //   (record [1] v2
+        //     (== 1 v3 v4
+        //       (halt 0))))
+        let input = Rc::new(Exp::Fix(
+            Box::new([FnDef(
+                Var(0),
+                Box::new([Var(1)]),
+                Rc::new(Exp::App(Value::Var(Var(0)), Box::new([Value::Var(Var(1))]))),
+            )]),
+            Rc::new(Exp::Record(
+                Box::new([Value::Int(1)]),
+                Var(2),
+                Rc::new(Exp::Primop(
+                    Primop::NumberEqual,
+                    Box::new([Value::Int(1), Value::Var(Var(3))]),
+                    Box::new([Var(4)]),
+                    Box::new([Rc::new(halt(Value::Int(0)))]),
+                )),
+            )),
+        ));
+
+        let result = optimize(&input, &only(|p| p.dead_code = true));
+
+        assert_eq!(*result, halt(Value::Int(0)));
+    }
+
+    #[test]
+    fn test_known_calls() {
+        // (record [L0 v1] v2
+        //   (offset 1 v2 v3
+        //     (select -1 v3 v4
+        //       (v4 v3))))
+        let input = Rc::new(Exp::Record(
+            Box::new([Value::Label(Var(0)), Value::Var(Var(1))]),
+            Var(2),
+            Rc::new(Exp::Offset(
+                1,
+                Value::Var(Var(2)),
+                Var(3),
+                Rc::new(Exp::Select(
+                    -1,
+                    Value::Var(Var(3)),
+                    Var(4),
+                    Rc::new(Exp::App(Value::Var(Var(4)), Box::new([Value::Var(Var(3))]))),
+                )),
+            )),
+        ));
+
+        let result = optimize(&input, &only(|p| p.known_calls = true));
+
+        assert_eq!(
+            *result,
+            Exp::Record(
+                Box::new([Value::Label(Var(0)), Value::Var(Var(1))]),
+                Var(2),
+                Rc::new(Exp::Offset(
+                    1,
+                    Value::Var(Var(2)),
+                    Var(3),
+                    Rc::new(Exp::App(
+                        Value::Label(Var(0)),
+                        Box::new([Value::Var(Var(3))]),
+                    )),
+                )),
+            )
+        );
+    }
+
+    #[test]
+    fn test_programs() -> std::io::Result<()> {
+        use crate::parser::parse;
+        use meta_core::MetaCore;
+        use std::io::Cursor;
+
+        let store = serde_json::from_reader(Cursor::new(include_str!("../../../store.meta")))?;
+        let core = MetaCore::new(&store);
+
+        let all_but = |pass: fn(&mut Passes)| {
+            let mut passes = Passes::default();
+            pass(&mut passes);
+            passes
+        };
+        let passes = [
+            Passes::default(),
+            Passes::none(),
+            all_but(|p| p.constant_folding = false),
+            all_but(|p| p.beta_contraction = false),
+            all_but(|p| p.eta_reduction = false),
+            all_but(|p| p.dead_code = false),
+            all_but(|p| p.known_calls = false),
+        ];
+        let tests = vec![
+            ("ckgzjkf8r0001cjmazi6gmb8x", crate::Value::Number(43)),
+            ("ckh62qzu500009gma6hh890nj", crate::Value::Number(31)),
+            ("ckh62yayg0000x9maiza5ybg2", crate::Value::Number(15)),
+            (
+                "ckhewlqrj000uszmadqoxvksk",
+                crate::Value::String("Hello, world!".to_string()),
+            ),
+            (
+                "ckhhluinu0045uhmalbr1a7e4",
+                crate::Value::String("42".to_string()),
+            ),
+            ("ckhj1zgg90012nbma2in4fsiu", crate::Value::Number(42)),
+        ];
+
+        for (test, expected) in tests {
+            let expr = parse(&core, &test.into()).unwrap();
+            for passes in passes.iter() {
+                let chunk = crate::compiler::compile(&expr, passes, &HostFunctions::new());
+                let mut vm = Vm::new(chunk, &Options::default());
+                let result = vm.run().unwrap().map(|value| vm.to_host(value));
+                assert_eq!(result, Some(expected.clone()), "{} with {:?}", test, passes);
+            }
+        }
+
+        Ok(())
+    }
+}
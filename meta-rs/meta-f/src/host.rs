@@ -0,0 +1,272 @@
+//! Rust functions callable from f programs.
+//!
+//! Host functions are registered under identifiers, just as builtins are. Programs refer to them
+//! by identifier, so a program compiled with one set of host functions can run with another one
+//! as long as it provides the functions the program calls.
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use meta_store::{Field, Store};
+
+use crate::ids;
+use crate::types::Type;
+use crate::value::Value;
+
+/// Rust type that can be passed to and returned from host functions.
+pub trait HostType: Sized {
+    /// f type of the values.
+    fn type_() -> Type;
+    fn from_value(value: &Value) -> Option<Self>;
+    fn into_value(self) -> Value;
+}
+
+impl HostType for i32 {
+    fn type_() -> Type {
+        Type::Number
+    }
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+    fn into_value(self) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl HostType for bool {
+    fn type_() -> Type {
+        Type::Bool
+    }
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl HostType for String {
+    fn type_() -> Type {
+        Type::String
+    }
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+    fn into_value(self) -> Value {
+        Value::String(self)
+    }
+}
+
+type Function = dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync;
+
+/// Rust function with its f type.
+#[derive(Clone)]
+pub struct HostFunction {
+    pub identifier: Field,
+    pub params: Vec<Type>,
+    pub result: Type,
+    function: Arc<Function>,
+}
+
+impl HostFunction {
+    /// Function working with values directly. It is only called with as many arguments as it has
+    /// parameters; errors are reported as `HostFunction` run errors.
+    pub fn new<F>(identifier: Field, params: Vec<Type>, result: Type, function: F) -> Self
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        Self {
+            identifier,
+            params,
+            result,
+            function: Arc::new(function),
+        }
+    }
+
+    pub fn call(&self, args: &[Value]) -> Result<Value, String> {
+        if args.len() != self.params.len() {
+            return Err(format!(
+                "{} expects {} arguments, got {}",
+                self.identifier.as_ref(),
+                self.params.len(),
+                args.len()
+            ));
+        }
+        (self.function)(args)
+    }
+}
+
+impl Debug for HostFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostFunction")
+            .field("identifier", &self.identifier)
+            .field("params", &self.params)
+            .field("result", &self.result)
+            .finish()
+    }
+}
+
+/// Rust function that can be registered with [`HostFunctions::register`]. Implemented for
+/// functions of one to three [`HostType`] arguments returning a `HostType`.
+pub trait IntoHostFunction<Args> {
+    fn into_host_function(self, identifier: Field) -> HostFunction;
+}
+
+fn argument<T: HostType>(value: Option<&Value>) -> Result<T, String> {
+    let value = value.ok_or("missing argument")?;
+    T::from_value(value).ok_or_else(|| format!("expected {}, got {}", T::type_(), value))
+}
+
+macro_rules! impl_into_host_function {
+    ($($arg:ident),+) => {
+        impl<F, R, $($arg),+> IntoHostFunction<($($arg,)+)> for F
+        where
+            F: Fn($($arg),+) -> R + Send + Sync + 'static,
+            R: HostType,
+            $($arg: HostType),+
+        {
+            fn into_host_function(self, identifier: Field) -> HostFunction {
+                HostFunction::new(
+                    identifier,
+                    vec![$($arg::type_()),+],
+                    R::type_(),
+                    move |args| {
+                        let mut args = args.iter();
+                        Ok(self($(argument::<$arg>(args.next())?),+).into_value())
+                    },
+                )
+            }
+        }
+    };
+}
+
+impl_into_host_function!(A);
+impl_into_host_function!(A, B);
+impl_into_host_function!(A, B, C);
+
+/// Registry of host functions available to a program.
+#[derive(Clone, Default)]
+pub struct HostFunctions {
+    functions: Vec<HostFunction>,
+}
+
+impl HostFunctions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `function` under `identifier`. A function registered under the same identifier
+    /// before is replaced.
+    pub fn register<Args>(
+        &mut self,
+        identifier: impl Into<Field>,
+        function: impl IntoHostFunction<Args>,
+    ) -> &mut Self {
+        self.add(function.into_host_function(identifier.into()))
+    }
+
+    /// Same as [`register`](Self::register), but for functions created with [`HostFunction::new`].
+    pub fn add(&mut self, function: HostFunction) -> &mut Self {
+        self.functions
+            .retain(|f| f.identifier != function.identifier);
+        self.functions.push(function);
+        self
+    }
+
+    /// Add all functions of `other`, replacing the ones with the same identifiers.
+    pub fn extend(&mut self, other: &HostFunctions) -> &mut Self {
+        for function in other.iter() {
+            self.add(function.clone());
+        }
+        self
+    }
+
+    pub fn get(&self, identifier: &str) -> Option<&HostFunction> {
+        self.functions
+            .iter()
+            .find(|f| f.identifier.as_ref() == identifier)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &HostFunction> {
+        self.functions.iter()
+    }
+}
+
+impl Debug for HostFunctions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.functions.iter().map(|f| &f.identifier))
+            .finish()
+    }
+}
+
+/// `io/print`: prints a string to stdout and returns it.
+pub fn io() -> HostFunctions {
+    let mut functions = HostFunctions::new();
+    functions.register(ids::IO_PRINT.clone(), |s: String| {
+        println!("{}", s);
+        s
+    });
+    functions
+}
+
+/// [`io`] functions and `store/value`, which returns the value of an attribute of an entity in
+/// `store` (or an empty string if there is none).
+pub fn builtins(store: &Store) -> HostFunctions {
+    let store = store.clone();
+    let mut functions = io();
+    functions.register(
+        ids::STORE_VALUE.clone(),
+        move |entity: String, attribute: String| {
+            store
+                .value(&entity.into(), &attribute.into())
+                .map(|datom| datom.value.to_string())
+                .unwrap_or_default()
+        },
+    );
+    functions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let mut functions = HostFunctions::new();
+        functions.register("add", |a: i32, b: i32| a + b);
+        functions.register("not", |b: bool| !b);
+
+        let add = functions.get("add").unwrap();
+        assert_eq!(add.params, vec![Type::Number, Type::Number]);
+        assert_eq!(add.result, Type::Number);
+        assert_eq!(
+            add.call(&[Value::Number(1), Value::Number(2)]),
+            Ok(Value::Number(3))
+        );
+        assert!(add.call(&[Value::Number(1)]).is_err());
+        assert_eq!(
+            add.call(&[Value::Number(1), Value::Bool(true)]),
+            Err("expected Number, got true".to_string())
+        );
+
+        // registering under the same identifier replaces the function
+        functions.register("add", |a: i32, b: i32| a * b);
+        assert_eq!(functions.iter().count(), 2);
+        assert_eq!(
+            functions
+                .get("add")
+                .unwrap()
+                .call(&[Value::Number(2), Value::Number(3)]),
+            Ok(Value::Number(6))
+        );
+    }
+}
@@ -36,4 +36,46 @@ lazy_static! {
     pub static ref ACCESS: Field = "ckh80o3mz0000bqmaiw3lr7pk".into();
     pub static ref ACCESS_OBJECT: Field = "ckh80oq7u0004bqmafth0c4f4".into();
     pub static ref ACCESS_FIELD: Field = "ckh80scjb000bbqmawe56ul0c".into();
+    pub static ref MATCH: Field = "ckhhlmmki000buhmaatznbce0".into();
+    pub static ref MATCH_VALUE: Field = "ckhhllzq20000uhmam9o0nuht".into();
+    pub static ref MATCH_CASE: Field = "ckhhlmaj30005uhmauuoy15cg".into();
+    pub static ref CASE: Field = "ckhhlo2od0010uhmawvitmoqx".into();
+    pub static ref CASE_CONSTRUCTOR: Field = "ckhhln378000iuhma5pczmnoa".into();
+    pub static ref CASE_PARAMETER: Field = "ckhhlnczd000ouhma6e0nnhcr".into();
+    pub static ref CASE_BODY: Field = "ckhhlnsfs000vuhmalqovopba".into();
+    pub static ref IF: Field = "ckhj1kitg001uefmarv73o67u".into();
+    pub static ref IF_CONDITION: Field = "ckhj1joyv001cefmajjp01se0".into();
+    pub static ref IF_THEN: Field = "ckhj1jxok001iefmadf2rfyte".into();
+    pub static ref IF_ELSE: Field = "ckhj1k7w5001oefmacx2hq75h".into();
+}
+
+// Builtin identifiers
+lazy_static! {
+    pub static ref STRING_CONCAT: Field = "ckhewjwaa0000szmaswcbwub6".into();
+    pub static ref STRING_LENGTH: Field = "ckhewk1yi0003szmascxeiv05".into();
+    pub static ref STRING_EQUAL: Field = "ckhewk7h50006szma0dd3uopq".into();
+    pub static ref STRING_FROM_NUMBER: Field = "ckhewkcwu0009szmacswc8qo7".into();
+    pub static ref STRING_TO_NUMBER: Field = "ckhewkkgs000cszmao445df0n".into();
+    pub static ref NUMBER_ADD: Field = "ckhj1gp8o0000efmas1umiod8".into();
+    pub static ref NUMBER_SUB: Field = "ckhj1guhc0003efmawfkrw13s".into();
+    pub static ref NUMBER_MUL: Field = "ckhj1gzg70006efmaq4ivn1yh".into();
+    pub static ref NUMBER_DIV: Field = "ckhj1h72i0009efmaaoltqc4k".into();
+    pub static ref NUMBER_MOD: Field = "ckhj1hefx000cefmay3teeco9".into();
+    pub static ref NUMBER_NEGATE: Field = "ckhj1hmj6000fefma8ytqcpmo".into();
+    pub static ref NUMBER_EQUAL: Field = "ckhj1hqhf000iefmavkl5o019".into();
+    pub static ref NUMBER_LESS: Field = "ckhj1hv2r000lefmaq5r3c991".into();
+    pub static ref NUMBER_LESS_EQUAL: Field = "ckhj1i3d3000oefma0mkyhxyr".into();
+    pub static ref NUMBER_GREATER: Field = "ckhj1ibk5000refmazkv2kimd".into();
+    pub static ref NUMBER_GREATER_EQUAL: Field = "ckhj1iimd000uefmadpeyu0ml".into();
+    pub static ref BOOL_NOT: Field = "ckhj1io1n000xefma6i39y8ad".into();
+    pub static ref BOOL_AND: Field = "ckhj1ivc10010efmanmh3x5mc".into();
+    pub static ref BOOL_OR: Field = "ckhj1j3680013efma2o4spjva".into();
+    pub static ref TRUE: Field = "ckhj1ja3v0016efmar7y4dj4q".into();
+    pub static ref FALSE: Field = "ckhj1jgu00019efmabeejm11o".into();
+}
+
+// Identifiers of builtin host functions (see `host::builtins()`)
+lazy_static! {
+    pub static ref IO_PRINT: Field = "ckhx02dl6dh03v451kszvyynl".into();
+    pub static ref STORE_VALUE: Field = "ckhxcfefqt63q6ajkuexshfh0".into();
 }
@@ -1,29 +1,117 @@
-use log::trace;
+use std::io::{Read, Write};
+
+use log::{debug, trace};
 
 use meta_core::MetaCore;
 use meta_store::{Field, Store};
 
-use crate::compiler::compile;
+use crate::compiler::{compile, Passes};
+use crate::host::{self, HostFunctions};
 use crate::parser::{parse, Error as ParseError};
-use crate::vm::value::Value;
-use crate::vm::{Error as VmError, Vm};
+use crate::types::{typecheck, Error as TypeError};
+use crate::value::Value;
+use crate::vm::chunk::Chunk;
+use crate::vm::debugger::Debugger;
+use crate::vm::format::{read_chunk, write_chunk, Error as FormatError};
+use crate::vm::memory::GcStats;
+use crate::vm::{Error as VmError, Options, Vm};
 
 #[derive(Debug)]
 pub enum Error {
     ParseError(Vec<ParseError>),
+    TypeError(Vec<TypeError>),
     RunError(VmError),
 }
 
-pub fn interpret(store: &Store, entry: &Field) -> Result<Option<Value>, Error> {
+/// Programs of the store can call builtin host functions (see [`host::builtins`]) and the ones
+/// from `options`, which replace builtin ones with the same identifiers.
+pub fn interpret(store: &Store, entry: &Field, options: &Options) -> Result<Option<Value>, Error> {
+    interpret_with_stats(store, entry, options).map(|(result, _stats)| result)
+}
+
+/// Same as [`interpret`], but also returns garbage collector statistics.
+pub fn interpret_with_stats(
+    store: &Store,
+    entry: &Field,
+    options: &Options,
+) -> Result<(Option<Value>, GcStats), Error> {
+    let options = with_host_functions(host::builtins(store), options);
+    let chunk = compile_entry(store, entry, &Passes::default(), &options.host_functions)?;
+
+    let mut vm = Vm::new(chunk, &options);
+    let result = vm.run()?;
+    let stats = vm.gc_stats();
+    debug!("gc stats: {:?}", stats);
+    Ok((result.map(|value| vm.to_host(value)), stats))
+}
+
+/// Compile `entry` and return a debugger paused before the first instruction. The code is not
+/// optimized, so it follows the source closely.
+pub fn debug(store: &Store, entry: &Field, options: &Options) -> Result<Debugger, Error> {
+    let options = with_host_functions(host::builtins(store), options);
+    let chunk = compile_entry(store, entry, &Passes::none(), &options.host_functions)?;
+    Ok(Debugger::new(Vm::new(chunk, &options)))
+}
+
+/// Compiled program that can be saved and run later without the store.
+pub struct Program {
+    chunk: Chunk,
+}
+
+/// Compile `entry` the same way [`interpret`] does, but don't run it.
+pub fn compile_program(store: &Store, entry: &Field, options: &Options) -> Result<Program, Error> {
+    let options = with_host_functions(host::builtins(store), options);
+    let chunk = compile_entry(store, entry, &Passes::default(), &options.host_functions)?;
+    Ok(Program { chunk })
+}
+
+impl Program {
+    /// Write the program in the versioned on-disk format.
+    pub fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        write_chunk(&self.chunk, w)
+    }
+
+    /// Read a program written by [`write`](Self::write). Code is validated before it is returned.
+    pub fn read<R: Read>(r: &mut R) -> Result<Self, FormatError> {
+        let chunk = read_chunk(r)?;
+        Ok(Program { chunk })
+    }
+
+    /// Run the program with [`host::io`] functions and the ones from `options`. Programs calling
+    /// other functions (e.g., `store/value`) need them to be passed in `options`.
+    pub fn run(self, options: &Options) -> Result<Option<Value>, Error> {
+        let options = with_host_functions(host::io(), options);
+        let mut vm = Vm::new(self.chunk, &options);
+        let result = vm.run()?;
+        Ok(result.map(|value| vm.to_host(value)))
+    }
+}
+
+/// `options` with `host_functions` added to its host functions. Functions from `options` take
+/// precedence.
+fn with_host_functions(mut host_functions: HostFunctions, options: &Options) -> Options {
+    host_functions.extend(&options.host_functions);
+    Options {
+        host_functions,
+        ..options.clone()
+    }
+}
+
+fn compile_entry(
+    store: &Store,
+    entry: &Field,
+    passes: &Passes,
+    host_functions: &HostFunctions,
+) -> Result<Chunk, Error> {
     let core = MetaCore::new(store);
 
     let expr = parse(&core, entry)?;
     trace!("parsed: {:?}", expr);
 
-    let chunk = compile(&expr);
+    let type_ = typecheck(&expr, host_functions)?;
+    debug!("type: {}", type_);
 
-    let mut vm = Vm::new(chunk);
-    Ok(vm.run()?)
+    Ok(compile(&expr, passes, host_functions))
 }
 
 impl From<Vec<ParseError>> for Error {
@@ -32,6 +120,12 @@ impl From<Vec<ParseError>> for Error {
     }
 }
 
+impl From<Vec<TypeError>> for Error {
+    fn from(e: Vec<TypeError>) -> Self {
+        Error::TypeError(e)
+    }
+}
+
 impl From<VmError> for Error {
     fn from(e: VmError) -> Self {
         Error::RunError(e)
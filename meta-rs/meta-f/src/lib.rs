@@ -1,17 +1,38 @@
 mod compiler;
+pub mod host;
 pub mod ids;
 mod interpreter;
 mod parser;
+mod test_runner;
+#[cfg(test)]
+mod test_util;
+mod types;
+mod value;
 mod vm;
 
+pub use interpreter::compile_program;
+pub use interpreter::debug;
 pub use interpreter::interpret;
+pub use interpreter::interpret_with_stats;
 pub use interpreter::Error;
-pub use vm::value::Value;
+pub use interpreter::Program;
+pub use test_runner::{
+    describe_result, run_tests, Outcome as TestOutcome, Report as TestReport, TestResult,
+};
+pub use types::{Error as TypeError, Type};
+pub use value::Value;
+pub use vm::debugger::{Breakpoint, DebugValue, Debugger, Record, Register, Status as DebugStatus};
+pub use vm::format::Error as FormatError;
+pub use vm::memory::GcStats;
+pub use vm::{Error as RunError, ErrorKind as RunErrorKind, Options};
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use meta_store::Field;
     use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
     use test_env_log::test;
 
     static STORE: &str = include_str!("../../store.meta");
@@ -22,21 +43,22 @@ mod tests {
 
         let meta_f_test = "ckgrnl18v000cxama1mpves0c".into();
 
-        let result = interpret(&store, &meta_f_test).unwrap();
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
 
-        assert_eq!(Some(Value::number(42)), result);
+        assert_eq!(Some(Value::Number(42)), result);
 
         Ok(())
     }
 
-    #[ignore] // strings are not currently supported
     #[test]
     fn test_hello_world() -> std::io::Result<()> {
         let store = serde_json::from_reader(Cursor::new(STORE))?;
 
         let meta_f_test = "ckgrnm5bt000ixamakqelhqwg".into();
 
-        interpret(&store, &meta_f_test).unwrap();
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
+
+        assert_eq!(Some(Value::String("Hello, world!".to_string())), result);
 
         Ok(())
     }
@@ -47,9 +69,9 @@ mod tests {
 
         let meta_f_test = "ckgzjkf8r0001cjmazi6gmb8x".into();
 
-        let result = interpret(&store, &meta_f_test).unwrap();
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
 
-        assert_eq!(Some(Value::number(43)), result);
+        assert_eq!(Some(Value::Number(43)), result);
 
         Ok(())
     }
@@ -60,9 +82,9 @@ mod tests {
 
         let meta_f_test = "ckh62qzu500009gma6hh890nj".into();
 
-        let result = interpret(&store, &meta_f_test).unwrap();
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
 
-        assert_eq!(Some(Value::number(31)), result);
+        assert_eq!(Some(Value::Number(31)), result);
 
         Ok(())
     }
@@ -73,9 +95,9 @@ mod tests {
 
         let meta_f_test = "ckh62t1xz0000e8magvtomp2n".into();
 
-        let result = interpret(&store, &meta_f_test).unwrap();
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
 
-        assert_eq!(Some(Value::number(100)), result);
+        assert_eq!(Some(Value::Number(100)), result);
 
         Ok(())
     }
@@ -86,9 +108,317 @@ mod tests {
 
         let meta_f_test = "ckh62yayg0000x9maiza5ybg2".into();
 
-        let result = interpret(&store, &meta_f_test).unwrap();
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
+
+        assert_eq!(Some(Value::Number(15)), result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_concat() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckhewlqrj000uszmadqoxvksk".into();
+
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
+
+        assert_eq!(Some(Value::String("Hello, world!".to_string())), result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_length() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckhewn8ca001gszmadc68f85g".into();
+
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
+
+        assert_eq!(Some(Value::Number(5)), result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_to_number() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckhewnv0i001vszmax6s4z2oa".into();
+
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
+
+        assert_eq!(Some(Value::Number(42)), result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_just() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckhhluinu0045uhmalbr1a7e4".into();
+
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
+
+        assert_eq!(Some(Value::String("42".to_string())), result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_nothing() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckhhm0r6q0073uhmae6f2g4hl".into();
+
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
+
+        assert_eq!(Some(Value::String("nothing".to_string())), result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_if() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckhj1zgg90012nbma2in4fsiu".into();
+
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
+
+        assert_eq!(Some(Value::Number(42)), result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_booleans() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckhj20za6001tnbmacn14w9mx".into();
+
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
+
+        assert_eq!(Some(Value::Bool(true)), result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_division_by_zero() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckhj222nx002dnbmap8bs6eg9".into();
+
+        let result = interpret(&store, &meta_f_test, &Options::default());
+
+        // division happens inside number/div builtin
+        let number_div = Field::from("ckhj1h72i0009efmaaoltqc4k");
+        assert!(matches!(
+            result,
+            Err(Error::RunError(RunError {
+                kind: RunErrorKind::DivisionByZero,
+                entity: Some(entity),
+                ..
+            })) if entity == number_div
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_entity_breakpoint() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckhj222nx002dnbmap8bs6eg9".into();
+        // number/div(1, 0)
+        let application = Field::from("ckhj21oo10027nbmae3gehi07");
+
+        let mut debugger = debug(&store, &meta_f_test, &Options::default()).unwrap();
+        debugger.add_breakpoint(Breakpoint::Entity(application.clone()));
+
+        assert!(matches!(debugger.resume(), DebugStatus::Paused));
+        assert_eq!(debugger.entity(), Some(&application));
+        assert!(!debugger.registers().is_empty());
+
+        assert!(matches!(
+            debugger.resume(),
+            DebugStatus::Failed(RunError {
+                kind: RunErrorKind::DivisionByZero,
+                ..
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_type_error() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckhj530c0000fd0mamel3bw6p".into();
+
+        let result = interpret(&store, &meta_f_test, &Options::default());
+
+        match result {
+            Err(Error::TypeError(errors)) => {
+                assert_eq!(1, errors.len());
+                // the string argument
+                assert_eq!(&Field::from("ckhj52jjt0006d0maipwebywm"), errors[0].entry());
+            }
+            _ => panic!("expected type error, got {:?}", result),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_infinite_recursion_runs_out_of_fuel() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckh7l3dk20000fxmabieparn0".into();
+
+        let options = Options {
+            fuel: Some(10_000),
+            ..Options::default()
+        };
+        let result = interpret(&store, &meta_f_test, &options);
+
+        assert!(matches!(
+            result,
+            Err(Error::RunError(RunError {
+                kind: RunErrorKind::OutOfFuel,
+                ..
+            }))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_infinite_recursion_exceeds_deadline() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckh7l3dk20000fxmabieparn0".into();
+
+        let options = Options {
+            deadline: Some(Instant::now() + Duration::from_millis(10)),
+            ..Options::default()
+        };
+        let result = interpret(&store, &meta_f_test, &options);
+
+        assert!(matches!(
+            result,
+            Err(Error::RunError(RunError {
+                kind: RunErrorKind::DeadlineExceeded,
+                ..
+            }))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_precompiled_program() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        // string concatenation, pattern matching and a runtime error
+        for test in [
+            "ckhewlqrj000uszmadqoxvksk",
+            "ckhhluinu0045uhmalbr1a7e4",
+            "ckhj222nx002dnbmap8bs6eg9",
+        ]
+        .iter()
+        {
+            let entry = Field::from(*test);
+            let mut bytes = Vec::new();
+            compile_program(&store, &entry, &Options::default())
+                .unwrap()
+                .write(&mut bytes)?;
+
+            let program = Program::read(&mut Cursor::new(bytes)).unwrap();
+            let result = program.run(&Options::default());
+
+            let expected = interpret(&store, &entry, &Options::default());
+            assert_eq!(describe_result(&result), describe_result(&expected));
+            if let (Err(Error::RunError(error)), Err(Error::RunError(expected))) =
+                (&result, &expected)
+            {
+                assert_eq!(error.entity, expected.entity);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_value() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let meta_f_test = "ckhxz5k1p0034hfmahvlomvl0".into();
+
+        let result = interpret(&store, &meta_f_test, &Options::default()).unwrap();
+
+        assert_eq!(Some(Value::String("number/add".to_string())), result);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_host_function() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        // io/print("Hello, host!")
+        let meta_f_test = "ckhxz5k1p0015hfma2yxuazb4".into();
+
+        let printed = Arc::new(Mutex::new(Vec::new()));
+        let mut options = Options::default();
+        let output = printed.clone();
+        options
+            .host_functions
+            .register(ids::IO_PRINT.clone(), move |s: String| {
+                output.lock().unwrap().push(s.clone());
+                s.to_uppercase()
+            });
+
+        let result = interpret(&store, &meta_f_test, &options).unwrap();
+
+        assert_eq!(Some(Value::String("HELLO, HOST!".to_string())), result);
+        assert_eq!(*printed.lock().unwrap(), vec!["Hello, host!".to_string()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_host_function() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
 
-        assert_eq!(Some(Value::number(15)), result);
+        let meta_f_test = "ckhxz5k1p0034hfmahvlomvl0".into();
+
+        // store/value is only available while the store is available, so a program run without
+        // the store cannot call it
+        let program = compile_program(&store, &meta_f_test, &Options::default()).unwrap();
+        let result = program.run(&Options::default());
+
+        assert!(matches!(
+            result,
+            Err(Error::RunError(RunError {
+                kind: RunErrorKind::UnknownHostFunction(identifier),
+                ..
+            })) if identifier == ids::STORE_VALUE.as_ref()
+        ));
+
+        // unless the function is passed in options
+        let program = compile_program(&store, &meta_f_test, &Options::default()).unwrap();
+        let options = Options {
+            host_functions: host::builtins(&store),
+            ..Options::default()
+        };
+        let result = program.run(&options).unwrap();
+
+        assert_eq!(Some(Value::String("number/add".to_string())), result);
 
         Ok(())
     }
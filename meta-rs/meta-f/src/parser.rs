@@ -1,4 +1,4 @@
-use im::{hashset, HashSet};
+use im::{hashset, HashMap, HashSet};
 use itertools::Itertools;
 
 use meta_core::MetaCore;
@@ -22,6 +22,8 @@ pub enum Error {
 #[derive(Debug)]
 pub(crate) struct RunTest {
     pub expr: Expr,
+    /// Names of all identifiers the test refers to.
+    pub names: HashMap<Identifier, String>,
 }
 
 #[derive(Debug, Eq, PartialEq, Hash, Clone)]
@@ -57,6 +59,27 @@ pub(crate) struct Constructor {
     pub parameters: Vec<Parameter>,
 }
 
+#[derive(Debug)]
+pub(crate) struct Match {
+    pub value: Expr,
+    pub cases: Vec<Case>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Case {
+    pub constructor: Identifier,
+    /// Bound to constructor parameters in order.
+    pub parameters: Vec<Parameter>,
+    pub body: Expr,
+}
+
+#[derive(Debug)]
+pub(crate) struct If {
+    pub condition: Expr,
+    pub then: Expr,
+    pub else_: Expr,
+}
+
 #[derive(Debug)]
 pub(crate) enum Statement {
     Binding(Binding),
@@ -64,7 +87,14 @@ pub(crate) enum Statement {
 }
 
 #[derive(Debug)]
-pub(crate) enum Expr {
+pub(crate) struct Expr {
+    /// Entity the expression is parsed from.
+    pub entity: Field,
+    pub kind: ExprKind,
+}
+
+#[derive(Debug)]
+pub(crate) enum ExprKind {
     NumberLiteral(i32),
     StringLiteral(String),
     Reference(Identifier),
@@ -73,6 +103,8 @@ pub(crate) enum Expr {
     Block(Vec<Statement>),
     TypeDef(TypeDef),
     Access(Box<Expr>, Identifier),
+    Match(Box<Match>),
+    If(Box<If>),
 }
 
 pub(crate) fn parse(core: &MetaCore, entry: &Field) -> Result<RunTest, Vec<Error>> {
@@ -82,6 +114,7 @@ pub(crate) fn parse(core: &MetaCore, entry: &Field) -> Result<RunTest, Vec<Error
 struct Parser<'a> {
     core: &'a MetaCore<'a>,
     errors: Vec<Error>,
+    names: HashMap<Identifier, String>,
 }
 
 impl<'a> Parser<'a> {
@@ -89,6 +122,7 @@ impl<'a> Parser<'a> {
         Self {
             core,
             errors: Vec::new(),
+            names: HashMap::new(),
         }
     }
 
@@ -130,12 +164,22 @@ impl<'a> Parser<'a> {
     fn parse_entry(&mut self, entry: &Field) -> Result<RunTest, ()> {
         self.expect_type(entry, &hashset! {RUN_TEST.clone()})?;
         let expr = self.required_attribute(&entry, &RUN_TEST_EXPR)?;
+        let expr = self.parse_expr(&expr)?;
         Ok(RunTest {
-            expr: self.parse_expr(&expr)?,
+            expr,
+            names: std::mem::take(&mut self.names),
         })
     }
 
     fn parse_expr(&mut self, entry: &Field) -> Result<Expr, ()> {
+        let kind = self.parse_expr_kind(entry)?;
+        Ok(Expr {
+            entity: entry.clone(),
+            kind,
+        })
+    }
+
+    fn parse_expr_kind(&mut self, entry: &Field) -> Result<ExprKind, ()> {
         let type_ = self.expect_type(
             entry,
             &hashset! {
@@ -147,6 +191,8 @@ impl<'a> Parser<'a> {
                 BLOCK.clone(),
                 TYPEDEF.clone(),
                 ACCESS.clone(),
+                MATCH.clone(),
+                IF.clone(),
             },
         )?;
         if type_ == (&NUMBER_LITERAL as &Field) {
@@ -154,16 +200,16 @@ impl<'a> Parser<'a> {
             // TODO: handle error
             let value = number.as_ref().parse().unwrap();
 
-            Ok(Expr::NumberLiteral(value))
+            Ok(ExprKind::NumberLiteral(value))
         } else if type_ == &STRING_LITERAL as &Field {
             let value = self
                 .required_attribute(entry, &STRING_LITERAL_VALUE)?
                 .to_string();
 
-            Ok(Expr::StringLiteral(value))
+            Ok(ExprKind::StringLiteral(value))
         } else if type_ == &IDENTIFIER_REFERENCE as &Field {
             let identifier = self.required_attribute(entry, &IDENTIFIER_REFERENCE_IDENTIFIER)?;
-            Ok(Expr::Reference(self.parse_identifier(&identifier)?))
+            Ok(ExprKind::Reference(self.parse_identifier(&identifier)?))
         } else if type_ == &FUNCTION as &Field {
             let body = self.required_attribute(entry, &FUNCTION_BODY)?;
             let body = self.parse_expr(&body)?;
@@ -175,7 +221,7 @@ impl<'a> Parser<'a> {
                 .map(|p| self.parse_parameter(&p.value))
                 .try_collect()?;
 
-            Ok(Expr::Function(Box::new(Function { parameters, body })))
+            Ok(ExprKind::Function(Box::new(Function { parameters, body })))
         } else if type_ == &APPLICATION as &Field {
             let f = self.required_attribute(entry, &APPLICATION_FN)?;
             let f = self.parse_expr(&f)?;
@@ -187,7 +233,7 @@ impl<'a> Parser<'a> {
                 .map(|e| self.parse_expr(&e.value))
                 .try_collect()?;
 
-            Ok(Expr::App(Box::new(f), args))
+            Ok(ExprKind::App(Box::new(f), args))
         } else if type_ == &BLOCK as &Field {
             let stmts = self
                 .core
@@ -196,7 +242,7 @@ impl<'a> Parser<'a> {
                 .map(|e| self.parse_statement(&e.value))
                 .try_collect()?;
 
-            Ok(Expr::Block(stmts))
+            Ok(ExprKind::Block(stmts))
         } else if type_ == &TYPEDEF as &Field {
             let constructors = self
                 .core
@@ -205,7 +251,7 @@ impl<'a> Parser<'a> {
                 .map(|e| self.parse_constructor(&e.value))
                 .try_collect()?;
 
-            Ok(Expr::TypeDef(TypeDef { constructors }))
+            Ok(ExprKind::TypeDef(TypeDef { constructors }))
         } else if type_ == &ACCESS as &Field {
             let object = self.required_attribute(entry, &ACCESS_OBJECT)?;
             let object = self.parse_expr(&object)?;
@@ -213,7 +259,32 @@ impl<'a> Parser<'a> {
             let identifier = self.required_attribute(&field, &IDENTIFIER_REFERENCE_IDENTIFIER)?;
             let identifier = self.parse_identifier(&identifier)?;
 
-            Ok(Expr::Access(Box::new(object), identifier))
+            Ok(ExprKind::Access(Box::new(object), identifier))
+        } else if type_ == &MATCH as &Field {
+            let value = self.required_attribute(entry, &MATCH_VALUE)?;
+            let value = self.parse_expr(&value)?;
+
+            let cases = self
+                .core
+                .ordered_values(entry, &MATCH_CASE)
+                .into_iter()
+                .map(|e| self.parse_case(&e.value))
+                .try_collect()?;
+
+            Ok(ExprKind::Match(Box::new(Match { value, cases })))
+        } else if type_ == &IF as &Field {
+            let condition = self.required_attribute(entry, &IF_CONDITION)?;
+            let condition = self.parse_expr(&condition)?;
+            let then = self.required_attribute(entry, &IF_THEN)?;
+            let then = self.parse_expr(&then)?;
+            let else_ = self.required_attribute(entry, &IF_ELSE)?;
+            let else_ = self.parse_expr(&else_)?;
+
+            Ok(ExprKind::If(Box::new(If {
+                condition,
+                then,
+                else_,
+            })))
         } else {
             panic!("Type not covered: {:?}", type_);
         }
@@ -229,6 +300,8 @@ impl<'a> Parser<'a> {
             BLOCK.clone(),
             TYPEDEF.clone(),
                 ACCESS.clone(),
+            MATCH.clone(),
+            IF.clone(),
             BINDING.clone(),
         };
 
@@ -255,9 +328,15 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_identifier(&mut self, entry: &Field) -> Result<Identifier, ()> {
-        Ok(Identifier {
+        let identifier = Identifier {
             entry: entry.clone(),
-        })
+        };
+        // names are only used for debugging, so they are optional
+        if let Some(name) = self.core.store.value(entry, &IDENTIFIER_IDENTIFIER) {
+            self.names
+                .insert(identifier.clone(), name.value.as_ref().to_string());
+        }
+        Ok(identifier)
     }
 
     fn parse_constructor(&mut self, entry: &Field) -> Result<Constructor, ()> {
@@ -275,4 +354,191 @@ impl<'a> Parser<'a> {
             parameters,
         })
     }
+
+    fn parse_case(&mut self, entry: &Field) -> Result<Case, ()> {
+        self.expect_type(entry, &hashset! {CASE.clone()})?;
+
+        let constructor = self.required_attribute(entry, &CASE_CONSTRUCTOR)?;
+        let constructor =
+            self.required_attribute(&constructor, &IDENTIFIER_REFERENCE_IDENTIFIER)?;
+        let constructor = self.parse_identifier(&constructor)?;
+
+        let parameters = self
+            .core
+            .ordered_values(entry, &CASE_PARAMETER)
+            .into_iter()
+            .map(|e| self.parse_parameter(&e.value))
+            .try_collect()?;
+
+        let body = self.required_attribute(entry, &CASE_BODY)?;
+        let body = self.parse_expr(&body)?;
+
+        Ok(Case {
+            constructor,
+            parameters,
+            body,
+        })
+    }
+}
+
+/// Statement of a block, with function bindings grouped for recursion.
+#[derive(Debug)]
+pub(crate) enum BlockItem<'a> {
+    /// Function bindings that refer to each other.
+    Functions(Vec<(&'a Binding, &'a Function)>),
+    Statement(&'a Statement),
+}
+
+/// Group statements of a block. Functions bound by consecutive bindings can refer to each other
+/// (as with `let rec`), so they are split into groups of mutually recursive functions. Groups
+/// come after the groups they refer to.
+pub(crate) fn block_items(stmts: &[Statement]) -> Vec<BlockItem<'_>> {
+    let mut items = Vec::new();
+    let mut functions = Vec::new();
+    for stmt in stmts.iter() {
+        if let Statement::Binding(binding) = stmt {
+            if let ExprKind::Function(f) = &binding.value.kind {
+                functions.push((binding, &**f));
+                continue;
+            }
+        }
+        items.extend(
+            recursive_groups(&functions)
+                .into_iter()
+                .map(BlockItem::Functions),
+        );
+        functions.clear();
+        items.push(BlockItem::Statement(stmt));
+    }
+    items.extend(
+        recursive_groups(&functions)
+            .into_iter()
+            .map(BlockItem::Functions),
+    );
+    items
+}
+
+/// Strongly connected components of the graph of references between `functions` (Tarjan's
+/// algorithm). Each component comes after the components it refers to.
+fn recursive_groups<'a>(
+    functions: &[(&'a Binding, &'a Function)],
+) -> Vec<Vec<(&'a Binding, &'a Function)>> {
+    let edges = functions
+        .iter()
+        .map(|(_binding, f)| {
+            let mut references = HashSet::new();
+            collect_references(&f.body, &mut references);
+            functions
+                .iter()
+                .positions(|(binding, _f)| references.contains(&binding.identifier))
+                .collect()
+        })
+        .collect();
+
+    let n = functions.len();
+    let mut tarjan = Tarjan {
+        edges,
+        index: vec![None; n],
+        lowlink: vec![0; n],
+        stack: Vec::new(),
+        on_stack: vec![false; n],
+        next_index: 0,
+        components: Vec::new(),
+    };
+    for v in 0..n {
+        if tarjan.index[v].is_none() {
+            tarjan.visit(v);
+        }
+    }
+
+    tarjan
+        .components
+        .into_iter()
+        .map(|mut component| {
+            component.sort_unstable();
+            component.into_iter().map(|v| functions[v]).collect()
+        })
+        .collect()
+}
+
+struct Tarjan {
+    edges: Vec<Vec<usize>>,
+    index: Vec<Option<usize>>,
+    lowlink: Vec<usize>,
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    next_index: usize,
+    components: Vec<Vec<usize>>,
+}
+
+impl Tarjan {
+    fn visit(&mut self, v: usize) {
+        self.index[v] = Some(self.next_index);
+        self.lowlink[v] = self.next_index;
+        self.next_index += 1;
+        self.stack.push(v);
+        self.on_stack[v] = true;
+
+        for i in 0..self.edges[v].len() {
+            let w = self.edges[v][i];
+            match self.index[w] {
+                None => {
+                    self.visit(w);
+                    self.lowlink[v] = self.lowlink[v].min(self.lowlink[w]);
+                }
+                Some(index) if self.on_stack[w] => self.lowlink[v] = self.lowlink[v].min(index),
+                Some(_) => {}
+            }
+        }
+
+        if self.index[v] == Some(self.lowlink[v]) {
+            let mut component = Vec::new();
+            loop {
+                let w = self.stack.pop().unwrap();
+                self.on_stack[w] = false;
+                component.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+/// Identifiers `e` refers to.
+fn collect_references(e: &Expr, references: &mut HashSet<Identifier>) {
+    match &e.kind {
+        ExprKind::NumberLiteral(_) | ExprKind::StringLiteral(_) | ExprKind::TypeDef(_) => {}
+        ExprKind::Reference(identifier) => {
+            references.insert(identifier.clone());
+        }
+        ExprKind::App(f, args) => {
+            collect_references(f, references);
+            for arg in args.iter() {
+                collect_references(arg, references);
+            }
+        }
+        ExprKind::Function(f) => collect_references(&f.body, references),
+        ExprKind::Block(stmts) => {
+            for stmt in stmts.iter() {
+                match stmt {
+                    Statement::Binding(binding) => collect_references(&binding.value, references),
+                    Statement::Expr(e) => collect_references(e, references),
+                }
+            }
+        }
+        ExprKind::Access(object, _field) => collect_references(object, references),
+        ExprKind::Match(m) => {
+            collect_references(&m.value, references);
+            for case in m.cases.iter() {
+                collect_references(&case.body, references);
+            }
+        }
+        ExprKind::If(if_) => {
+            collect_references(&if_.condition, references);
+            collect_references(&if_.then, references);
+            collect_references(&if_.else_, references);
+        }
+    }
 }
@@ -0,0 +1,407 @@
+//! Batch runner for `f/RunTest` entities.
+use std::fmt::{Debug, Display};
+use std::io::Write;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use itertools::Itertools;
+
+use meta_core::MetaCore;
+use meta_store::{Field, Store};
+
+use crate::ids;
+use crate::interpreter::{interpret, Error};
+use crate::value::Value;
+use crate::vm::Options;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Outcome {
+    Passed,
+    /// Actual result differs from the expected one.
+    Failed {
+        expected: String,
+    },
+    /// Test has no expected result, so it is not run.
+    Skipped,
+    /// Compiler or VM panicked, or the test did not finish in time.
+    Crashed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub test: Field,
+    /// Identifier of the test, or its id if it has none.
+    pub name: String,
+    /// Result in the format of expected results (see [`describe_result`]).
+    pub actual: Option<String>,
+    pub outcome: Outcome,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub results: Vec<TestResult>,
+}
+
+/// Stack size of the threads tests run on. Compiler passes recurse over the program, so deeply
+/// nested programs need more than the default.
+const TEST_STACK_SIZE: usize = 256 << 20;
+
+/// Run all `f/RunTest` entities in the store and compare their results with expected ones. Each
+/// test has `timeout` to finish, other limits come from `options`.
+///
+/// Every test runs on its own thread, so a test that does not finish in time (including during
+/// compilation) is reported as crashed and abandoned. A stack overflow still aborts the whole
+/// run, as it cannot be caught.
+pub fn run_tests(store: &Store, options: &Options, timeout: Duration) -> Report {
+    let core = MetaCore::new(store);
+    let tests = core
+        .of_type(&ids::RUN_TEST)
+        .into_iter()
+        .map(|datom| datom.entity)
+        .sorted()
+        .collect::<Vec<_>>();
+
+    let results = tests
+        .into_iter()
+        .map(|test| {
+            let name = core
+                .identifier(&test)
+                .map(|datom| datom.value.to_string())
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| test.to_string());
+            let expected = store
+                .value(&test, &ids::RUN_TEST_EXPECTED_RESULT)
+                .map(|datom| datom.value.to_string())
+                .filter(|expected| !expected.is_empty());
+
+            let start = Instant::now();
+            let (actual, outcome) = match expected {
+                None => (None, Outcome::Skipped),
+                Some(expected) => {
+                    let options = Options {
+                        deadline: Some(start + timeout),
+                        ..options.clone()
+                    };
+                    match run_test(store, &test, options, timeout) {
+                        Ok(actual) => {
+                            let outcome = if actual == expected {
+                                Outcome::Passed
+                            } else {
+                                Outcome::Failed { expected }
+                            };
+                            (Some(actual), outcome)
+                        }
+                        Err(message) => (None, Outcome::Crashed(message)),
+                    }
+                }
+            };
+
+            TestResult {
+                test,
+                name,
+                actual,
+                outcome,
+                duration: start.elapsed(),
+            }
+        })
+        .collect();
+
+    Report { results }
+}
+
+/// Run the test on a separate thread and describe its result. Returns the panic message if the
+/// test crashed or did not finish within `timeout`.
+fn run_test(
+    store: &Store,
+    test: &Field,
+    options: Options,
+    timeout: Duration,
+) -> Result<String, String> {
+    let (sender, receiver) = mpsc::channel();
+    let store = store.clone();
+    let test = test.clone();
+    thread::Builder::new()
+        .name(test.to_string())
+        .stack_size(TEST_STACK_SIZE)
+        .spawn(move || {
+            let result = catch_unwind(AssertUnwindSafe(|| interpret(&store, &test, &options)))
+                .map(|result| describe_result(&result))
+                .map_err(panic_message);
+            // the runner does not wait for tests that have timed out
+            let _ = sender.send(result);
+        })
+        .map_err(|err| format!("unable to start test thread: {}", err))?;
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(format!("timed out after {:?}", timeout)),
+        Err(RecvTimeoutError::Disconnected) => Err("test thread exited without a result".into()),
+    }
+}
+
+/// Result of a program in the format of `RUN_TEST_EXPECTED_RESULT`: values are printed as
+/// literals, errors as their kinds (e.g., `RunError(DivisionByZero)`).
+pub fn describe_result(result: &Result<Option<Value>, Error>) -> String {
+    match result {
+        Ok(Some(value)) => value.to_string(),
+        Ok(None) => "()".to_string(),
+        Err(Error::ParseError(errors)) => format!("ParseError({})", first_variant(errors)),
+        Err(Error::TypeError(errors)) => format!("TypeError({})", first_variant(errors)),
+        Err(Error::RunError(error)) => format!("RunError({})", variant(&error.kind)),
+    }
+}
+
+/// Name of the enum variant, without its fields.
+fn variant(value: &impl Debug) -> String {
+    format!("{:?}", value)
+        .chars()
+        .take_while(|c| c.is_alphanumeric())
+        .collect()
+}
+
+fn first_variant(errors: &[impl Debug]) -> String {
+    errors.first().map(variant).unwrap_or_default()
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+impl Report {
+    fn count(&self, f: impl Fn(&Outcome) -> bool) -> usize {
+        self.results
+            .iter()
+            .filter(|result| f(&result.outcome))
+            .count()
+    }
+
+    pub fn passed(&self) -> usize {
+        self.count(|outcome| matches!(outcome, Outcome::Passed))
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|outcome| matches!(outcome, Outcome::Failed { .. }))
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.count(|outcome| matches!(outcome, Outcome::Skipped))
+    }
+
+    pub fn crashed(&self) -> usize {
+        self.count(|outcome| matches!(outcome, Outcome::Crashed(_)))
+    }
+
+    /// Whether no test failed or crashed.
+    pub fn is_success(&self) -> bool {
+        self.failed() == 0 && self.crashed() == 0
+    }
+
+    /// Write the report in JUnit XML format.
+    pub fn write_junit<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        let total_time = self
+            .results
+            .iter()
+            .map(|result| result.duration)
+            .sum::<Duration>();
+        writeln!(w, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            w,
+            r#"<testsuite name="f/RunTest" tests="{}" failures="{}" errors="{}" skipped="{}" time="{:.3}">"#,
+            self.results.len(),
+            self.failed(),
+            self.crashed(),
+            self.skipped(),
+            total_time.as_secs_f64(),
+        )?;
+        for result in self.results.iter() {
+            write!(
+                w,
+                r#"  <testcase classname="f/RunTest" name="{}" time="{:.3}""#,
+                escape_xml(&result.name),
+                result.duration.as_secs_f64(),
+            )?;
+            let (element, message) = match &result.outcome {
+                Outcome::Passed => {
+                    writeln!(w, "/>")?;
+                    continue;
+                }
+                Outcome::Failed { expected } => (
+                    "failure",
+                    format!(
+                        "expected {}, got {}",
+                        expected,
+                        result.actual.as_deref().unwrap_or_default()
+                    ),
+                ),
+                Outcome::Skipped => ("skipped", "no expected result".to_string()),
+                Outcome::Crashed(message) => ("error", message.clone()),
+            };
+            writeln!(w, ">")?;
+            writeln!(
+                w,
+                r#"    <{} message="{}"/>"#,
+                element,
+                escape_xml(&message)
+            )?;
+            writeln!(w, "  </testcase>")?;
+        }
+        writeln!(w, "</testsuite>")
+    }
+}
+
+/// Escape `s` for an attribute value. Characters that XML does not allow at all are replaced with
+/// U+FFFD, whitespace is kept as character references so parsers do not normalize it.
+fn escape_xml(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&apos;"),
+            '\t' => result.push_str("&#9;"),
+            '\n' => result.push_str("&#10;"),
+            '\r' => result.push_str("&#13;"),
+            '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => result.push('\u{fffd}'),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+impl Display for TestResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.outcome {
+            Outcome::Passed => write!(f, "PASS {}", self.name),
+            Outcome::Failed { expected } => write!(
+                f,
+                "FAIL {}: expected {}, got {}",
+                self.name,
+                expected,
+                self.actual.as_deref().unwrap_or_default()
+            ),
+            Outcome::Skipped => write!(f, "SKIP {}: no expected result", self.name),
+            Outcome::Crashed(message) => write!(f, "CRASH {}: {}", self.name, message),
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for result in self.results.iter() {
+            writeln!(f, "{}", result)?;
+        }
+        write!(
+            f,
+            "{} passed, {} failed, {} crashed, {} skipped",
+            self.passed(),
+            self.failed(),
+            self.crashed(),
+            self.skipped()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    static STORE: &str = include_str!("../../store.meta");
+
+    fn result<'a>(report: &'a Report, test: &str) -> &'a TestResult {
+        let test = Field::from(test);
+        report
+            .results
+            .iter()
+            .find(|result| result.test == test)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_run_tests() -> std::io::Result<()> {
+        let store = serde_json::from_reader(Cursor::new(STORE))?;
+
+        let report = run_tests(&store, &Options::default(), Duration::from_secs(1));
+
+        let hello_world = result(&report, "ckgrnm5bt000ixamakqelhqwg");
+        assert_eq!(hello_world.outcome, Outcome::Passed);
+        assert_eq!(hello_world.actual.as_deref(), Some("\"Hello, world!\""));
+        let division_by_zero = result(&report, "ckhj222nx002dnbmap8bs6eg9");
+        assert_eq!(division_by_zero.outcome, Outcome::Passed);
+        assert_eq!(
+            division_by_zero.actual.as_deref(),
+            Some("RunError(DivisionByZero)")
+        );
+        // infinite recursion has no expected result
+        let infinite_recursion = result(&report, "ckh7l3dk20000fxmabieparn0");
+        assert_eq!(infinite_recursion.outcome, Outcome::Skipped);
+
+        assert_eq!(
+            report.results.len(),
+            report.passed() + report.failed() + report.crashed() + report.skipped()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_junit() {
+        let report = Report {
+            results: vec![
+                TestResult {
+                    test: "a".into(),
+                    name: "passing".to_string(),
+                    actual: Some("42".to_string()),
+                    outcome: Outcome::Passed,
+                    duration: Duration::from_millis(2),
+                },
+                TestResult {
+                    test: "c".into(),
+                    name: "crashing".to_string(),
+                    actual: None,
+                    outcome: Outcome::Crashed("line 1\nline\u{0}2".to_string()),
+                    duration: Duration::from_millis(0),
+                },
+                TestResult {
+                    test: "b".into(),
+                    name: "failing".to_string(),
+                    actual: Some("\"<b>\"".to_string()),
+                    outcome: Outcome::Failed {
+                        expected: "42".to_string(),
+                    },
+                    duration: Duration::from_millis(1),
+                },
+            ],
+        };
+
+        let mut xml = Vec::new();
+        report.write_junit(&mut xml).unwrap();
+
+        assert_eq!(
+            String::from_utf8(xml).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuite name="f/RunTest" tests="3" failures="1" errors="1" skipped="0" time="0.003">
+  <testcase classname="f/RunTest" name="passing" time="0.002"/>
+  <testcase classname="f/RunTest" name="crashing" time="0.000">
+    <error message="line 1&#10;line�2"/>
+  </testcase>
+  <testcase classname="f/RunTest" name="failing" time="0.001">
+    <failure message="expected 42, got &quot;&lt;b&gt;&quot;"/>
+  </testcase>
+</testsuite>
+"#
+        );
+        assert!(!report.is_success());
+    }
+}
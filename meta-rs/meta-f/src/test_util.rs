@@ -0,0 +1,147 @@
+//! Builders of parsed expressions for unit tests that do not go through the store.
+use meta_store::Field;
+
+use crate::ids;
+use crate::parser::{
+    Binding, Case, Constructor, Expr, ExprKind, Function, Identifier, If, Match, Parameter,
+    Statement, TypeDef,
+};
+
+pub(crate) fn expr(entity: &str, kind: ExprKind) -> Expr {
+    Expr {
+        entity: entity.into(),
+        kind,
+    }
+}
+
+pub(crate) fn identifier(entry: &str) -> Identifier {
+    Identifier {
+        entry: entry.into(),
+    }
+}
+
+fn parameters(params: &[&str]) -> Vec<Parameter> {
+    params
+        .iter()
+        .map(|p| Parameter { id: identifier(p) })
+        .collect()
+}
+
+pub(crate) fn reference(entity: &str, entry: &str) -> Expr {
+    expr(entity, ExprKind::Reference(identifier(entry)))
+}
+
+pub(crate) fn number(entity: &str, n: i32) -> Expr {
+    expr(entity, ExprKind::NumberLiteral(n))
+}
+
+pub(crate) fn string(entity: &str, s: &str) -> Expr {
+    expr(entity, ExprKind::StringLiteral(s.to_string()))
+}
+
+pub(crate) fn app(entity: &str, f: Expr, args: Vec<Expr>) -> Expr {
+    expr(entity, ExprKind::App(Box::new(f), args))
+}
+
+/// Application of the builtin function `f`.
+pub(crate) fn builtin(entity: &str, f: &Field, args: Vec<Expr>) -> Expr {
+    app(
+        entity,
+        reference(&format!("{}-f", entity), f.as_ref()),
+        args,
+    )
+}
+
+pub(crate) fn function(entity: &str, params: &[&str], body: Expr) -> Expr {
+    let parameters = parameters(params);
+    expr(
+        entity,
+        ExprKind::Function(Box::new(Function { parameters, body })),
+    )
+}
+
+pub(crate) fn binding(entry: &str, value: Expr) -> Statement {
+    Statement::Binding(Binding {
+        identifier: identifier(entry),
+        value,
+    })
+}
+
+pub(crate) fn if_(entity: &str, condition: Expr, then: Expr, else_: Expr) -> Expr {
+    expr(
+        entity,
+        ExprKind::If(Box::new(If {
+            condition,
+            then,
+            else_,
+        })),
+    )
+}
+
+/// Type definition with `(name, parameters)` constructors.
+pub(crate) fn typedef(entity: &str, constructors: &[(&str, &[&str])]) -> Expr {
+    let constructors = constructors
+        .iter()
+        .map(|(constructor, params)| Constructor {
+            identifier: identifier(constructor),
+            parameters: parameters(params),
+        })
+        .collect();
+    expr(entity, ExprKind::TypeDef(TypeDef { constructors }))
+}
+
+pub(crate) fn access(entity: &str, object: Expr, field: &str) -> Expr {
+    expr(
+        entity,
+        ExprKind::Access(Box::new(object), identifier(field)),
+    )
+}
+
+pub(crate) fn case(constructor: &str, params: &[&str], body: Expr) -> Case {
+    Case {
+        constructor: identifier(constructor),
+        parameters: parameters(params),
+        body,
+    }
+}
+
+pub(crate) fn match_(entity: &str, value: Expr, cases: Vec<Case>) -> Expr {
+    expr(entity, ExprKind::Match(Box::new(Match { value, cases })))
+}
+
+/// `name = fn(n) -> if n == 0 then base else other(n - 1)`, one half of the mutually recursive
+/// even/odd pair.
+pub(crate) fn parity(name: &str, base: &Field, other: &str) -> Statement {
+    let n = format!("{}-n", name);
+    binding(
+        name,
+        function(
+            &format!("{}-fn", name),
+            &[&n],
+            if_(
+                &format!("{}-if", name),
+                builtin(
+                    &format!("{}-equal", name),
+                    &ids::NUMBER_EQUAL,
+                    vec![
+                        reference(&format!("{}-n-ref1", name), &n),
+                        number(&format!("{}-0", name), 0),
+                    ],
+                ),
+                reference(&format!("{}-base", name), base.as_ref()),
+                app(
+                    &format!("{}-rec", name),
+                    reference(&format!("{}-other", name), other),
+                    vec![builtin(
+                        &format!("{}-sub", name),
+                        &ids::NUMBER_SUB,
+                        vec![
+                            reference(&format!("{}-n-ref2", name), &n),
+                            number(&format!("{}-1", name), 1),
+                        ],
+                    )],
+                ),
+            ),
+        ),
+    )
+}
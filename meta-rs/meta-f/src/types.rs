@@ -0,0 +1,881 @@
+//! Hindley–Milner type inference for meta-f programs.
+//!
+//! Types of constructor parameters are not annotated, so every constructor parameter becomes a type
+//! parameter of its type definition. They are instantiated with fresh variables at every
+//! constructor use, field access and match, the same way generalized bindings are.
+//!
+//! A parameter that turns out to hold a value of its own type definition (e.g., the tail of a
+//! list) would make the type infinite. Such parameters are recursive: they are given the type of
+//! the value they are part of, and inference is repeated until no new ones are found.
+use std::fmt::Display;
+
+use im::{HashMap, HashSet};
+
+use meta_store::Field;
+
+use crate::compiler::builtins;
+use crate::host::HostFunctions;
+use crate::parser::{
+    block_items, Binding, BlockItem, Case, Constructor, Expr, ExprKind, Function, Identifier, If,
+    Match, RunTest, Statement, TypeDef,
+};
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum Type {
+    Var(usize),
+    Number,
+    Bool,
+    String,
+    Function(Vec<Type>, Box<Type>),
+    /// Record of constructors created by the type definition.
+    TypeDef(Field),
+    /// Value created by one of the constructors of the type definition, with the types of its
+    /// non-recursive constructor parameters.
+    Data(Field, Vec<Type>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    Mismatch {
+        entry: Field,
+        expected: Type,
+        actual: Type,
+    },
+    /// Type would have to contain itself (e.g., a function is applied to itself).
+    InfiniteType {
+        entry: Field,
+        expected: Type,
+        actual: Type,
+    },
+    UnboundIdentifier {
+        entry: Field,
+        identifier: Field,
+    },
+    /// Identifier is neither a constructor nor a constructor parameter.
+    UnknownField {
+        entry: Field,
+        field: Field,
+    },
+    /// Case binds more parameters than its constructor has.
+    ParameterCount {
+        entry: Field,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl Error {
+    /// Entity the error is reported for.
+    pub fn entry(&self) -> &Field {
+        match self {
+            Error::Mismatch { entry, .. }
+            | Error::InfiniteType { entry, .. }
+            | Error::UnboundIdentifier { entry, .. }
+            | Error::UnknownField { entry, .. }
+            | Error::ParameterCount { entry, .. } => entry,
+        }
+    }
+}
+
+/// Infer type of the test expression.
+pub(crate) fn typecheck(
+    test: &RunTest,
+    host_functions: &HostFunctions,
+) -> Result<Type, Vec<Error>> {
+    let env: Env = builtins::types(host_functions)
+        .into_iter()
+        .map(|(identifier, type_)| (identifier, Scheme::mono(type_)))
+        .collect();
+
+    let mut recursive = HashSet::new();
+    loop {
+        let mut infer = Infer::new(recursive.clone());
+        infer.collect_typedefs(&test.expr);
+        let type_ = infer.infer_expr(&env, &test.expr);
+
+        // constructor parameters get the same variables on every run, so the set only grows
+        if infer.recursive != recursive {
+            recursive = infer.recursive;
+        } else if infer.errors.is_empty() {
+            return Ok(infer.apply(&type_));
+        } else {
+            return Err(infer.errors);
+        }
+    }
+}
+
+/// Type with universally quantified variables.
+#[derive(Debug, Clone)]
+struct Scheme {
+    vars: Vec<usize>,
+    type_: Type,
+}
+
+impl Scheme {
+    fn mono(type_: Type) -> Self {
+        Self {
+            vars: Vec::new(),
+            type_,
+        }
+    }
+}
+
+type Env = HashMap<Identifier, Scheme>;
+
+enum UnifyError {
+    Mismatch,
+    InfiniteType,
+}
+
+struct Infer {
+    /// Type variable bindings.
+    substitution: Vec<Option<Type>>,
+    /// Type definition and parameter types of every constructor.
+    constructors: HashMap<Identifier, (Field, Vec<Type>)>,
+    /// Type definition and type of every constructor parameter.
+    parameters: HashMap<Identifier, (Field, Type)>,
+    /// Type variables of all constructor parameters of every type definition.
+    typedefs: HashMap<Field, Vec<usize>>,
+    /// Variables of constructor parameters that hold a value of their own type definition.
+    recursive: HashSet<usize>,
+    errors: Vec<Error>,
+}
+
+impl Infer {
+    fn new(recursive: HashSet<usize>) -> Self {
+        Self {
+            substitution: Vec::new(),
+            constructors: HashMap::new(),
+            parameters: HashMap::new(),
+            typedefs: HashMap::new(),
+            recursive,
+            errors: Vec::new(),
+        }
+    }
+
+    fn fresh(&mut self) -> Type {
+        self.substitution.push(None);
+        Type::Var(self.substitution.len() - 1)
+    }
+
+    /// Assign types to parameters of all constructors.
+    fn collect_typedefs(&mut self, e: &Expr) {
+        match &e.kind {
+            ExprKind::NumberLiteral(_) | ExprKind::StringLiteral(_) | ExprKind::Reference(_) => {}
+            ExprKind::App(f, args) => {
+                self.collect_typedefs(f);
+                for arg in args.iter() {
+                    self.collect_typedefs(arg);
+                }
+            }
+            ExprKind::Function(f) => self.collect_typedefs(&f.body),
+            ExprKind::Block(stmts) => {
+                for stmt in stmts.iter() {
+                    match stmt {
+                        Statement::Binding(Binding { value, .. }) => self.collect_typedefs(value),
+                        Statement::Expr(e) => self.collect_typedefs(e),
+                    }
+                }
+            }
+            ExprKind::TypeDef(TypeDef { constructors }) => {
+                let mut vars = Vec::new();
+                for Constructor {
+                    identifier,
+                    parameters,
+                } in constructors.iter()
+                {
+                    let types = parameters
+                        .iter()
+                        .map(|p| {
+                            let type_ = self.fresh();
+                            if let Type::Var(var) = type_ {
+                                vars.push(var);
+                            }
+                            self.parameters
+                                .insert(p.id.clone(), (e.entity.clone(), type_.clone()));
+                            type_
+                        })
+                        .collect();
+                    self.constructors
+                        .insert(identifier.clone(), (e.entity.clone(), types));
+                }
+                self.typedefs.insert(e.entity.clone(), vars);
+            }
+            ExprKind::Access(object, _) => self.collect_typedefs(object),
+            ExprKind::Match(m) => {
+                self.collect_typedefs(&m.value);
+                for case in m.cases.iter() {
+                    self.collect_typedefs(&case.body);
+                }
+            }
+            ExprKind::If(if_) => {
+                self.collect_typedefs(&if_.condition);
+                self.collect_typedefs(&if_.then);
+                self.collect_typedefs(&if_.else_);
+            }
+        }
+    }
+
+    fn infer_expr(&mut self, env: &Env, e: &Expr) -> Type {
+        match &e.kind {
+            ExprKind::NumberLiteral(_) => Type::Number,
+            ExprKind::StringLiteral(_) => Type::String,
+            ExprKind::Reference(identifier) => match env.get(identifier) {
+                Some(scheme) => self.instantiate(scheme),
+                None => {
+                    self.errors.push(Error::UnboundIdentifier {
+                        entry: e.entity.clone(),
+                        identifier: identifier.entry.clone(),
+                    });
+                    self.fresh()
+                }
+            },
+            ExprKind::App(f, args) => {
+                let f_type = self.infer_expr(env, f);
+                let arg_types = args
+                    .iter()
+                    .map(|arg| self.infer_expr(env, arg))
+                    .collect::<Vec<_>>();
+
+                match self.resolve(&f_type) {
+                    // report mismatching arguments, not the whole function
+                    Type::Function(params, result) if params.len() == args.len() => {
+                        for ((param, arg), arg_type) in params.iter().zip(args).zip(arg_types) {
+                            self.unify_at(&arg.entity, param, &arg_type);
+                        }
+                        *result
+                    }
+                    _ => {
+                        let result = self.fresh();
+                        let expected = Type::Function(arg_types, Box::new(result.clone()));
+                        self.unify_at(&f.entity, &expected, &f_type);
+                        result
+                    }
+                }
+            }
+            ExprKind::Function(f) => {
+                let Function { parameters, body } = &**f;
+                let mut env = env.clone();
+                let params = parameters
+                    .iter()
+                    .map(|p| {
+                        let type_ = self.fresh();
+                        env.insert(p.id.clone(), Scheme::mono(type_.clone()));
+                        type_
+                    })
+                    .collect();
+                let result = self.infer_expr(&env, body);
+                Type::Function(params, Box::new(result))
+            }
+            ExprKind::Block(stmts) => self.infer_block(env, stmts),
+            ExprKind::TypeDef(_) => Type::TypeDef(e.entity.clone()),
+            ExprKind::Access(object, field) => {
+                let object_type = self.infer_expr(env, object);
+                if let Some((typedef, params)) = self.constructors.get(field).cloned() {
+                    self.unify_at(
+                        &object.entity,
+                        &Type::TypeDef(typedef.clone()),
+                        &object_type,
+                    );
+                    let (mapping, data) = self.instantiate_typedef(&typedef);
+                    let params = params.iter().map(|p| self.rename(&mapping, p)).collect();
+                    Type::Function(params, Box::new(data))
+                } else if let Some((typedef, type_)) = self.parameters.get(field).cloned() {
+                    let (mapping, data) = self.instantiate_typedef(&typedef);
+                    self.unify_at(&object.entity, &data, &object_type);
+                    self.rename(&mapping, &type_)
+                } else {
+                    self.errors.push(Error::UnknownField {
+                        entry: e.entity.clone(),
+                        field: field.entry.clone(),
+                    });
+                    self.fresh()
+                }
+            }
+            ExprKind::Match(m) => {
+                let Match { value, cases } = &**m;
+                let value_type = self.infer_expr(env, value);
+                let result = self.fresh();
+                for case in cases.iter() {
+                    self.infer_case(env, e, value, &value_type, case, &result);
+                }
+                result
+            }
+            ExprKind::If(if_) => {
+                let If {
+                    condition,
+                    then,
+                    else_,
+                } = &**if_;
+                let condition_type = self.infer_expr(env, condition);
+                self.unify_at(&condition.entity, &Type::Bool, &condition_type);
+                let result = self.infer_expr(env, then);
+                let else_type = self.infer_expr(env, else_);
+                self.unify_at(&else_.entity, &result, &else_type);
+                result
+            }
+        }
+    }
+
+    fn infer_case(
+        &mut self,
+        env: &Env,
+        match_: &Expr,
+        value: &Expr,
+        value_type: &Type,
+        case: &Case,
+        result: &Type,
+    ) {
+        let Case {
+            constructor,
+            parameters,
+            body,
+        } = case;
+
+        let mut env = env.clone();
+        match self.constructors.get(constructor).cloned() {
+            Some((typedef, params)) => {
+                let (mapping, data) = self.instantiate_typedef(&typedef);
+                self.unify_at(&value.entity, &data, value_type);
+                if parameters.len() > params.len() {
+                    self.errors.push(Error::ParameterCount {
+                        entry: match_.entity.clone(),
+                        expected: params.len(),
+                        actual: parameters.len(),
+                    });
+                }
+                for (p, type_) in parameters.iter().zip(params) {
+                    let type_ = self.rename(&mapping, &type_);
+                    env.insert(p.id.clone(), Scheme::mono(type_));
+                }
+            }
+            None => {
+                self.errors.push(Error::UnknownField {
+                    entry: match_.entity.clone(),
+                    field: constructor.entry.clone(),
+                });
+            }
+        }
+        // bind the rest, so body can still be checked
+        for p in parameters.iter() {
+            if !env.contains_key(&p.id) {
+                let type_ = self.fresh();
+                env.insert(p.id.clone(), Scheme::mono(type_));
+            }
+        }
+
+        let body_type = self.infer_expr(&env, body);
+        self.unify_at(&body.entity, result, &body_type);
+    }
+
+    fn infer_block(&mut self, env: &Env, stmts: &[Statement]) -> Type {
+        let mut env = env.clone();
+        // empty block or block ending with a binding evaluates to 0
+        let mut result = Type::Number;
+        for item in block_items(stmts) {
+            match item {
+                BlockItem::Functions(functions) => {
+                    // functions are generalized only after the whole group is inferred, so they are
+                    // monomorphic within the group
+                    let vars = functions.iter().map(|_| self.fresh()).collect::<Vec<_>>();
+                    let mut rec_env = env.clone();
+                    for ((binding, _f), var) in functions.iter().zip(vars.iter()) {
+                        rec_env.insert(binding.identifier.clone(), Scheme::mono(var.clone()));
+                    }
+                    for ((binding, _f), var) in functions.iter().zip(vars.iter()) {
+                        let type_ = self.infer_expr(&rec_env, &binding.value);
+                        self.unify_at(&binding.value.entity, var, &type_);
+                    }
+                    let schemes = vars
+                        .iter()
+                        .map(|var| self.generalize(&env, var))
+                        .collect::<Vec<_>>();
+                    for ((binding, _f), scheme) in functions.iter().zip(schemes) {
+                        env.insert(binding.identifier.clone(), scheme);
+                    }
+                    result = Type::Number;
+                }
+                BlockItem::Statement(Statement::Binding(Binding { identifier, value })) => {
+                    let type_ = self.infer_expr(&env, value);
+                    let scheme = self.generalize(&env, &type_);
+                    env.insert(identifier.clone(), scheme);
+                    result = Type::Number;
+                }
+                BlockItem::Statement(Statement::Expr(e)) => {
+                    result = self.infer_expr(&env, e);
+                }
+            }
+        }
+        result
+    }
+
+    fn instantiate(&mut self, scheme: &Scheme) -> Type {
+        let mapping = scheme
+            .vars
+            .iter()
+            .map(|var| (*var, self.fresh()))
+            .collect::<HashMap<_, _>>();
+        self.rename(&mapping, &scheme.type_)
+    }
+
+    /// Data type of the type definition with fresh variables for its parameters, and the mapping
+    /// from its parameter variables to them. Recursive parameters are mapped to the data type
+    /// itself.
+    fn instantiate_typedef(&mut self, typedef: &Field) -> (HashMap<usize, Type>, Type) {
+        let vars = self.parameter_vars(typedef);
+        let args = vars.iter().map(|_| self.fresh()).collect::<Vec<_>>();
+        let data = Type::Data(typedef.clone(), args.clone());
+
+        let mut mapping = vars.into_iter().zip(args).collect::<HashMap<_, _>>();
+        for var in self.typedefs.get(typedef).into_iter().flatten() {
+            if self.recursive.contains(var) {
+                mapping.insert(*var, data.clone());
+            }
+        }
+        (mapping, data)
+    }
+
+    /// Variables of non-recursive parameters of the type definition, in order of `Type::Data`
+    /// arguments.
+    fn parameter_vars(&self, typedef: &Field) -> Vec<usize> {
+        self.typedefs
+            .get(typedef)
+            .into_iter()
+            .flatten()
+            .filter(|var| !self.recursive.contains(var))
+            .copied()
+            .collect()
+    }
+
+    fn rename(&self, mapping: &HashMap<usize, Type>, type_: &Type) -> Type {
+        match self.resolve(type_) {
+            Type::Var(var) => mapping.get(&var).cloned().unwrap_or(Type::Var(var)),
+            Type::Function(params, result) => Type::Function(
+                params.iter().map(|p| self.rename(mapping, p)).collect(),
+                Box::new(self.rename(mapping, &result)),
+            ),
+            Type::Data(typedef, args) => Type::Data(
+                typedef,
+                args.iter().map(|arg| self.rename(mapping, arg)).collect(),
+            ),
+            type_ => type_,
+        }
+    }
+
+    /// Quantify over type variables that are not bound in the environment.
+    fn generalize(&self, env: &Env, type_: &Type) -> Scheme {
+        let mut fixed = HashSet::new();
+        for scheme in env.values() {
+            let vars = self.free_vars(&scheme.type_);
+            fixed.extend(vars.relative_complement(scheme.vars.iter().copied().collect()));
+        }
+
+        let vars = self.free_vars(type_).relative_complement(fixed);
+        Scheme {
+            vars: vars.into_iter().collect(),
+            type_: self.apply(type_),
+        }
+    }
+
+    fn free_vars(&self, type_: &Type) -> HashSet<usize> {
+        match self.resolve(type_) {
+            Type::Var(var) => HashSet::unit(var),
+            Type::Function(params, result) => {
+                HashSet::unions(params.iter().map(|p| self.free_vars(p)))
+                    .union(self.free_vars(&result))
+            }
+            Type::Data(_typedef, args) => {
+                HashSet::unions(args.iter().map(|arg| self.free_vars(arg)))
+            }
+            _ => HashSet::new(),
+        }
+    }
+
+    /// Follow variable bindings until the type is not a bound variable.
+    fn resolve(&self, type_: &Type) -> Type {
+        let mut type_ = type_;
+        while let Type::Var(var) = type_ {
+            match &self.substitution[*var] {
+                Some(t) => type_ = t,
+                None => break,
+            }
+        }
+        type_.clone()
+    }
+
+    /// Substitute all bound variables.
+    fn apply(&self, type_: &Type) -> Type {
+        match self.resolve(type_) {
+            Type::Function(params, result) => Type::Function(
+                params.iter().map(|p| self.apply(p)).collect(),
+                Box::new(self.apply(&result)),
+            ),
+            Type::Data(typedef, args) => {
+                Type::Data(typedef, args.iter().map(|arg| self.apply(arg)).collect())
+            }
+            type_ => type_,
+        }
+    }
+
+    fn unify_at(&mut self, entry: &Field, expected: &Type, actual: &Type) {
+        if let Err(err) = self.unify(expected, actual) {
+            let entry = entry.clone();
+            let expected = self.apply(expected);
+            let actual = self.apply(actual);
+            self.errors.push(match err {
+                UnifyError::Mismatch => Error::Mismatch {
+                    entry,
+                    expected,
+                    actual,
+                },
+                UnifyError::InfiniteType => Error::InfiniteType {
+                    entry,
+                    expected,
+                    actual,
+                },
+            });
+        }
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> Result<(), UnifyError> {
+        match (self.resolve(a), self.resolve(b)) {
+            (Type::Var(a), Type::Var(b)) if a == b => Ok(()),
+            (Type::Var(var), type_) | (type_, Type::Var(var)) => {
+                if self.free_vars(&type_).contains(&var) {
+                    self.find_recursive(var, &type_);
+                    return Err(UnifyError::InfiniteType);
+                }
+                self.substitution[var] = Some(type_);
+                Ok(())
+            }
+            (Type::Function(params_a, result_a), Type::Function(params_b, result_b))
+                if params_a.len() == params_b.len() =>
+            {
+                for (a, b) in params_a.iter().zip(params_b.iter()) {
+                    self.unify(a, b)?;
+                }
+                self.unify(&result_a, &result_b)
+            }
+            (Type::Data(typedef_a, args_a), Type::Data(typedef_b, args_b))
+                if typedef_a == typedef_b && args_a.len() == args_b.len() =>
+            {
+                for (a, b) in args_a.iter().zip(args_b.iter()) {
+                    self.unify(a, b)?;
+                }
+                Ok(())
+            }
+            (a, b) if a == b => Ok(()),
+            _ => Err(UnifyError::Mismatch),
+        }
+    }
+
+    /// Mark the constructor parameter as recursive if `var` is its type in `type_`, the data type
+    /// it is part of.
+    fn find_recursive(&mut self, var: usize, type_: &Type) {
+        if let Type::Data(typedef, args) = type_ {
+            let vars = self.parameter_vars(typedef);
+            for (param, arg) in vars.into_iter().zip(args.iter()) {
+                if self.resolve(arg) == Type::Var(var) {
+                    self.recursive.insert(param);
+                }
+            }
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Type::Var(var) => write!(f, "t{}", var),
+            Type::Number => write!(f, "Number"),
+            Type::Bool => write!(f, "Bool"),
+            Type::String => write!(f, "String"),
+            Type::Function(params, result) => {
+                write!(f, "fn(")?;
+                for (i, param) in params.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", param)?;
+                }
+                write!(f, ") -> {}", result)
+            }
+            Type::TypeDef(entity) => write!(f, "type {}", entity.as_ref()),
+            Type::Data(entity, args) => {
+                write!(f, "{}", entity.as_ref())?;
+                if !args.is_empty() {
+                    write!(f, "<")?;
+                    for (i, arg) in args.iter().enumerate() {
+                        if i != 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{}", arg)?;
+                    }
+                    write!(f, ">")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ids;
+    use crate::test_util::*;
+
+    fn typecheck_expr(expr: Expr) -> Result<Type, Vec<Error>> {
+        typecheck(
+            &RunTest {
+                expr,
+                names: HashMap::new(),
+            },
+            &HostFunctions::new(),
+        )
+    }
+
+    #[test]
+    fn test_let_polymorphism() {
+        // { id = fn(x) -> x; id(id)(42) }
+        let program = expr(
+            "block",
+            ExprKind::Block(vec![
+                Statement::Binding(Binding {
+                    identifier: identifier("id"),
+                    value: function("f", &["x"], reference("x-ref", "x")),
+                }),
+                Statement::Expr(expr(
+                    "app1",
+                    ExprKind::App(
+                        Box::new(expr(
+                            "app2",
+                            ExprKind::App(
+                                Box::new(reference("id-ref1", "id")),
+                                vec![reference("id-ref2", "id")],
+                            ),
+                        )),
+                        vec![expr("42", ExprKind::NumberLiteral(42))],
+                    ),
+                )),
+            ]),
+        );
+
+        assert_eq!(Type::Number, typecheck_expr(program).unwrap());
+    }
+
+    #[test]
+    fn test_mutual_recursion() {
+        // { even = fn(n) -> if n == 0 then true else odd(n - 1)
+        //   odd = fn(n) -> if n == 0 then false else even(n - 1)
+        //   even(10) }
+        let program = expr(
+            "block",
+            ExprKind::Block(vec![
+                parity("even", &ids::TRUE, "odd"),
+                parity("odd", &ids::FALSE, "even"),
+                Statement::Expr(app(
+                    "app",
+                    reference("even-ref", "even"),
+                    vec![expr("10", ExprKind::NumberLiteral(10))],
+                )),
+            ]),
+        );
+
+        assert_eq!(Type::Bool, typecheck_expr(program).unwrap());
+    }
+
+    #[test]
+    fn test_forward_reference_is_generalized() {
+        // { f = fn(x) -> id(x); id = fn(x) -> x; id("a"); f(1) }
+        //
+        // id does not refer to f, so it is generalized before f is inferred
+        let program = expr(
+            "block",
+            ExprKind::Block(vec![
+                binding(
+                    "f",
+                    function(
+                        "f-fn",
+                        &["x1"],
+                        app(
+                            "f-app",
+                            reference("id-ref1", "id"),
+                            vec![reference("x1-ref", "x1")],
+                        ),
+                    ),
+                ),
+                binding("id", function("id-fn", &["x2"], reference("x2-ref", "x2"))),
+                Statement::Expr(app(
+                    "app1",
+                    reference("id-ref2", "id"),
+                    vec![expr("a", ExprKind::StringLiteral("a".to_string()))],
+                )),
+                Statement::Expr(app(
+                    "app2",
+                    reference("f-ref", "f"),
+                    vec![expr("1", ExprKind::NumberLiteral(1))],
+                )),
+            ]),
+        );
+
+        assert_eq!(Type::Number, typecheck_expr(program).unwrap());
+    }
+
+    #[test]
+    fn test_mismatch_is_reported_at_argument() {
+        // (fn(x) -> x)("hello") used as a condition
+        let program = expr(
+            "if",
+            ExprKind::If(Box::new(If {
+                condition: expr(
+                    "app",
+                    ExprKind::App(
+                        Box::new(function("f", &["x"], reference("x-ref", "x"))),
+                        vec![expr("s", ExprKind::StringLiteral("hello".to_string()))],
+                    ),
+                ),
+                then: expr("1", ExprKind::NumberLiteral(1)),
+                else_: expr("2", ExprKind::NumberLiteral(2)),
+            })),
+        );
+
+        let errors = typecheck_expr(program).unwrap_err();
+        assert_eq!(1, errors.len());
+        assert!(matches!(
+            &errors[0],
+            Error::Mismatch {
+                expected: Type::Bool,
+                actual: Type::String,
+                ..
+            }
+        ));
+        assert_eq!(&Field::from("app"), errors[0].entry());
+    }
+
+    #[test]
+    fn test_infinite_type() {
+        // fn(f) -> f(f)
+        let program = function(
+            "f",
+            &["f"],
+            expr(
+                "app",
+                ExprKind::App(
+                    Box::new(reference("f-ref1", "f")),
+                    vec![reference("f-ref2", "f")],
+                ),
+            ),
+        );
+
+        let errors = typecheck_expr(program).unwrap_err();
+        assert!(matches!(&errors[0], Error::InfiniteType { .. }));
+    }
+
+    #[test]
+    fn test_typedef_polymorphism() {
+        // { P = type { Pair(first, second) }
+        //   a = P.Pair(1, 2)
+        //   b = P.Pair("a", "b")
+        //   a.first
+        //   b.second }
+        let pair = |entity: &str, first: Expr, second: Expr| {
+            app(
+                entity,
+                access(
+                    &format!("{}-constructor", entity),
+                    reference(&format!("{}-p", entity), "P"),
+                    "Pair",
+                ),
+                vec![first, second],
+            )
+        };
+        let program = expr(
+            "block",
+            ExprKind::Block(vec![
+                binding("P", typedef("typedef", &[("Pair", &["first", "second"])])),
+                binding(
+                    "a",
+                    pair(
+                        "pair1",
+                        expr("1", ExprKind::NumberLiteral(1)),
+                        expr("2", ExprKind::NumberLiteral(2)),
+                    ),
+                ),
+                binding("b", pair("pair2", string("a", "a"), string("b", "b"))),
+                Statement::Expr(access("a-first", reference("a-ref", "a"), "first")),
+                Statement::Expr(access("b-second", reference("b-ref", "b"), "second")),
+            ]),
+        );
+
+        assert_eq!(Type::String, typecheck_expr(program).unwrap());
+    }
+
+    #[test]
+    fn test_recursive_typedef() {
+        // { L = type { Cons(head, tail), Nil() }
+        //   sum = fn(l) -> match l { Cons(h, t) -> h + sum(t); Nil() -> 0 }
+        //   sum(L.Cons(1, L.Nil())) }
+        let sum = function(
+            "sum-fn",
+            &["l"],
+            match_(
+                "match",
+                reference("l-ref", "l"),
+                vec![
+                    case(
+                        "Cons",
+                        &["h", "t"],
+                        app(
+                            "add",
+                            reference("add-ref", ids::NUMBER_ADD.as_ref()),
+                            vec![
+                                reference("h-ref", "h"),
+                                app(
+                                    "sum-rec",
+                                    reference("sum-ref1", "sum"),
+                                    vec![reference("t-ref", "t")],
+                                ),
+                            ],
+                        ),
+                    ),
+                    case("Nil", &[], expr("0", ExprKind::NumberLiteral(0))),
+                ],
+            ),
+        );
+        let constructor = |entity: &str, name: &str| {
+            access(entity, reference(&format!("{}-l", entity), "L"), name)
+        };
+        let program = expr(
+            "block",
+            ExprKind::Block(vec![
+                binding(
+                    "L",
+                    typedef("typedef", &[("Cons", &["head", "tail"]), ("Nil", &[])]),
+                ),
+                binding("sum", sum),
+                Statement::Expr(app(
+                    "app",
+                    reference("sum-ref2", "sum"),
+                    vec![app(
+                        "cons",
+                        constructor("cons-constructor", "Cons"),
+                        vec![
+                            expr("1", ExprKind::NumberLiteral(1)),
+                            app("nil", constructor("nil-constructor", "Nil"), vec![]),
+                        ],
+                    )],
+                )),
+            ]),
+        );
+
+        assert_eq!(Type::Number, typecheck_expr(program).unwrap());
+    }
+
+    #[test]
+    fn test_unbound_identifier() {
+        let errors = typecheck_expr(reference("ref", "unknown")).unwrap_err();
+        assert!(matches!(&errors[0], Error::UnboundIdentifier { .. }));
+        assert_eq!(&Field::from("ref"), errors[0].entry());
+    }
+}
@@ -0,0 +1,22 @@
+use std::fmt::Display;
+
+/// Result of a meta-f program.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Value {
+    Number(i32),
+    Bool(bool),
+    String(String),
+    /// Value that has no meaning outside of the VM (e.g., a record or a function).
+    Opaque,
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::String(s) => write!(f, "{:?}", s),
+            Value::Opaque => write!(f, "<opaque>"),
+        }
+    }
+}
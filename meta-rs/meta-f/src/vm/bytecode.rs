@@ -1,9 +1,9 @@
-use num_enum::{IntoPrimitive, UnsafeFromPrimitive};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 pub(crate) use crate::vm::chunk::DataRef;
 pub(crate) use crate::vm::machine::Reg;
 
-#[derive(Debug, Eq, PartialEq, Copy, Clone, IntoPrimitive, UnsafeFromPrimitive)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone, IntoPrimitive, TryFromPrimitive)]
 #[repr(u8)]
 pub(crate) enum OpCode {
     // u64 = 8 bytes
@@ -30,10 +30,15 @@ pub(crate) enum OpCode {
 
     // 1B opcode, 1B result reg, 2B reserved, 4B value offset (data)
     ConstantValue,
+    // 1B opcode, 1B result reg, 2B reserved, 4B value offset (data)
+    ConstantRef,
 
     // 1B opcode, 1B reg to switch on, 4B N=number of cases, 2B reserved | Nx8 offsets to jump to
     Switch,
 
+    // 1B opcode
+    MatchFailure,
+
     // 1B opcode, 1B reg to jump to, 6B reserved
     JumpReg,
     // 1B opcode, 3B reserved, 4B offset to jump to (signed)
@@ -43,10 +48,45 @@ pub(crate) enum OpCode {
     Offset,
     // 1B opcode, 1B result reg, 1B op1 reg, 1B op2 reg
     Add,
+    // 1B opcode, 1B result reg, 1B op1 reg, 1B op2 reg
+    Sub,
+    // 1B opcode, 1B result reg, 1B op1 reg, 1B op2 reg
+    Mul,
+    // 1B opcode, 1B result reg, 1B op1 reg, 1B op2 reg
+    Div,
+    // 1B opcode, 1B result reg, 1B op1 reg, 1B op2 reg
+    Mod,
+    // 1B opcode, 1B result reg, 1B op reg
+    Neg,
+    // 1B opcode, 1B result reg, 1B op1 reg, 1B op2 reg
+    NumberEqual,
+    // 1B opcode, 1B result reg, 1B op1 reg, 1B op2 reg
+    Less,
+    // 1B opcode, 1B result reg, 1B op1 reg, 1B op2 reg
+    LessEqual,
+    // 1B opcode, 1B result reg, 1B op reg
+    Not,
+    // 1B opcode, 1B result reg, 1B op1 reg, 1B op2 reg
+    And,
+    // 1B opcode, 1B result reg, 1B op1 reg, 1B op2 reg
+    Or,
+    // 1B opcode, 1B result reg, 1B op1 reg, 1B op2 reg
+    StringConcat,
+    // 1B opcode, 1B result reg, 1B op reg
+    StringLength,
+    // 1B opcode, 1B result reg, 1B op1 reg, 1B op2 reg
+    StringEqual,
+    // 1B opcode, 1B result reg, 1B op reg
+    NumberToString,
+    // 1B opcode, 1B result reg, 1B op reg
+    StringToNumber,
     // 1B opcode, 1B result reg, 1B from reg
     Move,
     // 1B opcode, 1B from reg, 1B to reg
     Swap,
+    // 1B opcode, 1B result reg, 2B N=number of arguments, 4B function identifier offset (data) |
+    // N argument regs padded to whole words
+    CallHost,
 }
 
 #[derive(Debug)]
@@ -85,10 +125,16 @@ pub(crate) enum Instruction {
         result: Reg,
         value: DataRef,
     },
+    /// Load address of the data.
+    ConstantRef {
+        result: Reg,
+        value: DataRef,
+    },
     Switch {
         reg: Reg,
         offsets: Vec<i64>,
     },
+    MatchFailure,
     JumpReg {
         reg: Reg,
     },
@@ -105,6 +151,81 @@ pub(crate) enum Instruction {
         op1: Reg,
         op2: Reg,
     },
+    Sub {
+        result: Reg,
+        op1: Reg,
+        op2: Reg,
+    },
+    Mul {
+        result: Reg,
+        op1: Reg,
+        op2: Reg,
+    },
+    Div {
+        result: Reg,
+        op1: Reg,
+        op2: Reg,
+    },
+    Mod {
+        result: Reg,
+        op1: Reg,
+        op2: Reg,
+    },
+    Neg {
+        result: Reg,
+        op: Reg,
+    },
+    NumberEqual {
+        result: Reg,
+        op1: Reg,
+        op2: Reg,
+    },
+    Less {
+        result: Reg,
+        op1: Reg,
+        op2: Reg,
+    },
+    LessEqual {
+        result: Reg,
+        op1: Reg,
+        op2: Reg,
+    },
+    Not {
+        result: Reg,
+        op: Reg,
+    },
+    And {
+        result: Reg,
+        op1: Reg,
+        op2: Reg,
+    },
+    Or {
+        result: Reg,
+        op1: Reg,
+        op2: Reg,
+    },
+    StringConcat {
+        result: Reg,
+        op1: Reg,
+        op2: Reg,
+    },
+    StringLength {
+        result: Reg,
+        op: Reg,
+    },
+    StringEqual {
+        result: Reg,
+        op1: Reg,
+        op2: Reg,
+    },
+    NumberToString {
+        result: Reg,
+        op: Reg,
+    },
+    StringToNumber {
+        result: Reg,
+        op: Reg,
+    },
     Move {
         result: Reg,
         from: Reg,
@@ -113,6 +234,12 @@ pub(crate) enum Instruction {
         from: Reg,
         to: Reg,
     },
+    CallHost {
+        result: Reg,
+        /// String with the identifier of the host function.
+        function: DataRef,
+        args: Vec<Reg>,
+    },
 }
 
 impl Instruction {
@@ -190,6 +317,12 @@ impl Instruction {
                     | ((value.0 as u64) << 32);
                 w.write_all(&instruction.to_ne_bytes())
             }
+            Instruction::ConstantRef { result, value } => {
+                let instruction: u64 = (OpCode::ConstantRef as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((value.0 as u64) << 32);
+                w.write_all(&instruction.to_ne_bytes())
+            }
             Instruction::Switch { reg, offsets } => {
                 let instruction = (OpCode::Switch as u64)
                     | ((reg.0 as u64) << 8)
@@ -200,6 +333,10 @@ impl Instruction {
                 }
                 Ok(())
             }
+            Instruction::MatchFailure => {
+                let instruction: u64 = OpCode::MatchFailure as u64;
+                w.write_all(&instruction.to_ne_bytes())
+            }
             Instruction::JumpReg { reg } => {
                 let instruction = (OpCode::JumpReg as u64) | ((reg.0 as u64) << 8);
                 w.write_all(&instruction.to_ne_bytes())
@@ -226,6 +363,111 @@ impl Instruction {
                     | ((op2.0 as u64) << 24);
                 w.write_all(&instruction.to_ne_bytes())
             }
+            Instruction::Sub { result, op1, op2 } => {
+                let instruction = (OpCode::Sub as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op1.0 as u64) << 16)
+                    | ((op2.0 as u64) << 24);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::Mul { result, op1, op2 } => {
+                let instruction = (OpCode::Mul as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op1.0 as u64) << 16)
+                    | ((op2.0 as u64) << 24);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::Div { result, op1, op2 } => {
+                let instruction = (OpCode::Div as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op1.0 as u64) << 16)
+                    | ((op2.0 as u64) << 24);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::Mod { result, op1, op2 } => {
+                let instruction = (OpCode::Mod as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op1.0 as u64) << 16)
+                    | ((op2.0 as u64) << 24);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::Neg { result, op } => {
+                let instruction =
+                    (OpCode::Neg as u64) | ((result.0 as u64) << 8) | ((op.0 as u64) << 16);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::NumberEqual { result, op1, op2 } => {
+                let instruction = (OpCode::NumberEqual as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op1.0 as u64) << 16)
+                    | ((op2.0 as u64) << 24);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::Less { result, op1, op2 } => {
+                let instruction = (OpCode::Less as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op1.0 as u64) << 16)
+                    | ((op2.0 as u64) << 24);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::LessEqual { result, op1, op2 } => {
+                let instruction = (OpCode::LessEqual as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op1.0 as u64) << 16)
+                    | ((op2.0 as u64) << 24);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::Not { result, op } => {
+                let instruction =
+                    (OpCode::Not as u64) | ((result.0 as u64) << 8) | ((op.0 as u64) << 16);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::And { result, op1, op2 } => {
+                let instruction = (OpCode::And as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op1.0 as u64) << 16)
+                    | ((op2.0 as u64) << 24);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::Or { result, op1, op2 } => {
+                let instruction = (OpCode::Or as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op1.0 as u64) << 16)
+                    | ((op2.0 as u64) << 24);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::StringConcat { result, op1, op2 } => {
+                let instruction = (OpCode::StringConcat as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op1.0 as u64) << 16)
+                    | ((op2.0 as u64) << 24);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::StringLength { result, op } => {
+                let instruction = (OpCode::StringLength as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op.0 as u64) << 16);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::StringEqual { result, op1, op2 } => {
+                let instruction = (OpCode::StringEqual as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op1.0 as u64) << 16)
+                    | ((op2.0 as u64) << 24);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::NumberToString { result, op } => {
+                let instruction = (OpCode::NumberToString as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op.0 as u64) << 16);
+                w.write_all(&instruction.to_ne_bytes())
+            }
+            Instruction::StringToNumber { result, op } => {
+                let instruction = (OpCode::StringToNumber as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((op.0 as u64) << 16);
+                w.write_all(&instruction.to_ne_bytes())
+            }
             Instruction::Move { result, from } => {
                 let instruction =
                     (OpCode::Move as u64) | ((result.0 as u64) << 8) | ((from.0 as u64) << 16);
@@ -236,6 +478,27 @@ impl Instruction {
                     (OpCode::Swap as u64) | ((from.0 as u64) << 8) | ((to.0 as u64) << 16);
                 w.write_all(&instruction.to_ne_bytes())
             }
+            Instruction::CallHost {
+                result,
+                function,
+                args,
+            } => {
+                let instruction: u64 = (OpCode::CallHost as u64)
+                    | ((result.0 as u64) << 8)
+                    | ((args.len() as u64) << 16)
+                    | ((function.0 as u64) << 32);
+                w.write_all(&instruction.to_ne_bytes())?;
+                // arguments are packed into words like other operands, so code is made of words
+                // only
+                for regs in args.chunks(8) {
+                    let word = regs
+                        .iter()
+                        .enumerate()
+                        .fold(0, |word, (i, reg)| word | (reg.0 as u64) << (8 * i));
+                    w.write_all(&word.to_ne_bytes())?;
+                }
+                Ok(())
+            }
         }
     }
 
@@ -247,7 +510,8 @@ impl Instruction {
         r.read_exact(&mut instruction)?;
         let instruction = u64::from_ne_bytes(instruction);
 
-        let opcode = unsafe { OpCode::from_unchecked(instruction as u8) };
+        let opcode = OpCode::try_from_primitive(instruction as u8)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
         Ok(match opcode {
             OpCode::Halt => Instruction::Halt,
             OpCode::HaltReg => {
@@ -309,6 +573,11 @@ impl Instruction {
                 let value = DataRef((instruction >> 32) as u32);
                 Instruction::ConstantValue { result, value }
             }
+            OpCode::ConstantRef => {
+                let result = Reg((instruction >> 8) as u8);
+                let value = DataRef((instruction >> 32) as u32);
+                Instruction::ConstantRef { result, value }
+            }
             OpCode::Switch => {
                 let reg = Reg((instruction >> 8) as u8);
                 let n_offsets = instruction >> 16;
@@ -322,6 +591,7 @@ impl Instruction {
 
                 Instruction::Switch { reg, offsets }
             }
+            OpCode::MatchFailure => Instruction::MatchFailure,
             OpCode::JumpReg => {
                 let reg = Reg((instruction >> 8) as u8);
                 Instruction::JumpReg { reg }
@@ -346,6 +616,97 @@ impl Instruction {
                 let op2 = Reg((instruction >> 24) as u8);
                 Instruction::Add { result, op1, op2 }
             }
+            OpCode::Sub => {
+                let result = Reg((instruction >> 8) as u8);
+                let op1 = Reg((instruction >> 16) as u8);
+                let op2 = Reg((instruction >> 24) as u8);
+                Instruction::Sub { result, op1, op2 }
+            }
+            OpCode::Mul => {
+                let result = Reg((instruction >> 8) as u8);
+                let op1 = Reg((instruction >> 16) as u8);
+                let op2 = Reg((instruction >> 24) as u8);
+                Instruction::Mul { result, op1, op2 }
+            }
+            OpCode::Div => {
+                let result = Reg((instruction >> 8) as u8);
+                let op1 = Reg((instruction >> 16) as u8);
+                let op2 = Reg((instruction >> 24) as u8);
+                Instruction::Div { result, op1, op2 }
+            }
+            OpCode::Mod => {
+                let result = Reg((instruction >> 8) as u8);
+                let op1 = Reg((instruction >> 16) as u8);
+                let op2 = Reg((instruction >> 24) as u8);
+                Instruction::Mod { result, op1, op2 }
+            }
+            OpCode::Neg => {
+                let result = Reg((instruction >> 8) as u8);
+                let op = Reg((instruction >> 16) as u8);
+                Instruction::Neg { result, op }
+            }
+            OpCode::NumberEqual => {
+                let result = Reg((instruction >> 8) as u8);
+                let op1 = Reg((instruction >> 16) as u8);
+                let op2 = Reg((instruction >> 24) as u8);
+                Instruction::NumberEqual { result, op1, op2 }
+            }
+            OpCode::Less => {
+                let result = Reg((instruction >> 8) as u8);
+                let op1 = Reg((instruction >> 16) as u8);
+                let op2 = Reg((instruction >> 24) as u8);
+                Instruction::Less { result, op1, op2 }
+            }
+            OpCode::LessEqual => {
+                let result = Reg((instruction >> 8) as u8);
+                let op1 = Reg((instruction >> 16) as u8);
+                let op2 = Reg((instruction >> 24) as u8);
+                Instruction::LessEqual { result, op1, op2 }
+            }
+            OpCode::Not => {
+                let result = Reg((instruction >> 8) as u8);
+                let op = Reg((instruction >> 16) as u8);
+                Instruction::Not { result, op }
+            }
+            OpCode::And => {
+                let result = Reg((instruction >> 8) as u8);
+                let op1 = Reg((instruction >> 16) as u8);
+                let op2 = Reg((instruction >> 24) as u8);
+                Instruction::And { result, op1, op2 }
+            }
+            OpCode::Or => {
+                let result = Reg((instruction >> 8) as u8);
+                let op1 = Reg((instruction >> 16) as u8);
+                let op2 = Reg((instruction >> 24) as u8);
+                Instruction::Or { result, op1, op2 }
+            }
+            OpCode::StringConcat => {
+                let result = Reg((instruction >> 8) as u8);
+                let op1 = Reg((instruction >> 16) as u8);
+                let op2 = Reg((instruction >> 24) as u8);
+                Instruction::StringConcat { result, op1, op2 }
+            }
+            OpCode::StringLength => {
+                let result = Reg((instruction >> 8) as u8);
+                let op = Reg((instruction >> 16) as u8);
+                Instruction::StringLength { result, op }
+            }
+            OpCode::StringEqual => {
+                let result = Reg((instruction >> 8) as u8);
+                let op1 = Reg((instruction >> 16) as u8);
+                let op2 = Reg((instruction >> 24) as u8);
+                Instruction::StringEqual { result, op1, op2 }
+            }
+            OpCode::NumberToString => {
+                let result = Reg((instruction >> 8) as u8);
+                let op = Reg((instruction >> 16) as u8);
+                Instruction::NumberToString { result, op }
+            }
+            OpCode::StringToNumber => {
+                let result = Reg((instruction >> 8) as u8);
+                let op = Reg((instruction >> 16) as u8);
+                Instruction::StringToNumber { result, op }
+            }
             OpCode::Move => {
                 let result = Reg((instruction >> 8) as u8);
                 let from = Reg((instruction >> 16) as u8);
@@ -356,12 +717,72 @@ impl Instruction {
                 let to = Reg((instruction >> 16) as u8);
                 Instruction::Swap { from, to }
             }
+            OpCode::CallHost => {
+                let result = Reg((instruction >> 8) as u8);
+                let n_args = (instruction >> 16) as u16 as usize;
+                let function = DataRef((instruction >> 32) as u32);
+                let mut args = Vec::with_capacity(n_args);
+                while args.len() < n_args {
+                    let mut word = [0; 8];
+                    r.read_exact(&mut word)?;
+                    let word = u64::from_ne_bytes(word);
+                    let n = (n_args - args.len()).min(8);
+                    args.extend((0..n).map(|i| Reg((word >> (8 * i)) as u8)));
+                }
+                Instruction::CallHost {
+                    result,
+                    function,
+                    args,
+                }
+            }
         })
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_call_host_roundtrip() {
+        // number of arguments and size of the instruction
+        for (n_args, size) in [(0, 8), (3, 16), (8, 16), (9, 24)].iter() {
+            let args = (0..*n_args).map(|i| Reg(i as u8 + 10)).collect::<Vec<_>>();
+            let mut code = Vec::new();
+            Instruction::CallHost {
+                result: Reg(1),
+                function: DataRef(7),
+                args: args.clone(),
+            }
+            .write(&mut code)
+            .unwrap();
+            assert_eq!(code.len(), *size);
+
+            if *n_args > 0 {
+                // first argument is in the lowest byte regardless of byte order
+                let mut word = [0; 8];
+                word.copy_from_slice(&code[8..16]);
+                assert_eq!(u64::from_ne_bytes(word) & 0xff, 10);
+            }
+
+            let mut cursor = Cursor::new(&code);
+            match Instruction::read(&mut cursor).unwrap() {
+                Instruction::CallHost {
+                    result,
+                    function,
+                    args: read_args,
+                } => {
+                    assert_eq!(result, Reg(1));
+                    assert_eq!(function, DataRef(7));
+                    assert_eq!(read_args, args);
+                }
+                instruction => panic!("unexpected {:?}", instruction),
+            }
+            assert_eq!(cursor.position() as usize, code.len());
+        }
+    }
+
     #[test]
     fn test_shr_sign() {
         let original: i64 = -1;
@@ -1,7 +1,10 @@
 use std::io::Cursor;
+use std::ops::Range;
+
+use meta_store::Field;
 
 use crate::vm::bytecode::*;
-use crate::vm::value::Value;
+use crate::vm::value::{string_cells, write_string, Value, ValueTag};
 
 /// Index into data segment.
 #[derive(Debug, Eq, PartialEq, Copy, Clone, Hash)]
@@ -11,6 +14,67 @@ pub(crate) struct DataRef(pub u32);
 pub(crate) struct Chunk {
     code: Vec<u8>,
     data: Vec<Value>,
+    debug_info: DebugInfo,
+}
+
+/// Maps bytecode back to the f program it is compiled from.
+#[derive(Debug, Default)]
+pub(crate) struct DebugInfo {
+    /// Entity the code is compiled from, starting at the position up to the next entry. Sorted by
+    /// position.
+    entities: Vec<(usize, Option<Field>)>,
+    /// Variables held in registers.
+    variables: Vec<Variable>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Variable {
+    /// Code where the register holds the variable.
+    pub range: Range<usize>,
+    pub reg: Reg,
+    /// Entity the variable is created for.
+    pub entity: Field,
+    pub name: Option<String>,
+}
+
+impl DebugInfo {
+    /// Mark code starting at `position` as compiled from `entity`. Positions must not decrease.
+    pub fn set_entity(&mut self, position: usize, entity: Option<Field>) {
+        match self.entities.last_mut() {
+            Some((_, last)) if *last == entity => {}
+            Some((last_position, last)) if *last_position == position => *last = entity,
+            _ => self.entities.push((position, entity)),
+        }
+    }
+
+    /// Entity the instruction at `position` is compiled from.
+    pub fn entity_at(&self, position: usize) -> Option<&Field> {
+        let i = match self.entities.binary_search_by_key(&position, |(p, _)| *p) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        self.entities[i].1.as_ref()
+    }
+
+    pub fn entities(&self) -> &[(usize, Option<Field>)] {
+        &self.entities
+    }
+
+    pub fn variables(&self) -> &[Variable] {
+        &self.variables
+    }
+
+    pub fn add_variable(&mut self, variable: Variable) {
+        self.variables.push(variable);
+    }
+
+    /// Variables held in registers while executing instruction at `position`.
+    pub fn variables_at(&self, position: usize) -> impl Iterator<Item = &Variable> {
+        self.variables
+            .iter()
+            .filter(move |v| v.range.contains(&position))
+    }
 }
 
 impl Chunk {
@@ -18,9 +82,27 @@ impl Chunk {
         Self {
             code: Vec::new(),
             data: Vec::new(),
+            debug_info: DebugInfo::default(),
         }
     }
 
+    /// Chunk with the given contents. Pointers in `data` must point into `data` itself.
+    pub fn from_parts(code: Vec<u8>, data: Vec<Value>, debug_info: DebugInfo) -> Self {
+        Self {
+            code,
+            data,
+            debug_info,
+        }
+    }
+
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
+    }
+
+    pub fn debug_info_mut(&mut self) -> &mut DebugInfo {
+        &mut self.debug_info
+    }
+
     pub fn code(&self) -> &[u8] {
         &self.code
     }
@@ -33,6 +115,23 @@ impl Chunk {
         &self.data[data_ref.0 as usize] as *const Value
     }
 
+    /// The whole data segment.
+    pub fn data_segment(&self) -> &[Value] {
+        &self.data
+    }
+
+    /// Same as [`data`](Self::data), but returns `None` if `data_ref` is out of data segment.
+    pub fn get_data(&self, data_ref: DataRef) -> Option<*const Value> {
+        self.data
+            .get(data_ref.0 as usize)
+            .map(|value| value as *const Value)
+    }
+
+    /// Whether `ptr` points into data segment.
+    pub fn data_contains(&self, ptr: *const Value) -> bool {
+        self.data.as_ptr_range().contains(&ptr)
+    }
+
     pub fn data_mut(&mut self, data_ref: DataRef) -> &mut Value {
         &mut self.data[data_ref.0 as usize]
     }
@@ -44,23 +143,59 @@ impl Chunk {
     }
 
     pub fn alloc_data(&mut self, vals: &[Value]) -> DataRef {
-        // TODO: prepend tag for garbage collector
+        // Data segment is not managed by garbage collector, so no size tag is needed
         let cur = self.data.len() as u32;
         self.data.extend_from_slice(vals);
         DataRef(cur)
     }
 
+    /// Allocate string object in data segment. The returned reference points to the string tag.
+    pub fn alloc_string(&mut self, s: &str) -> DataRef {
+        let n_cells = string_cells(s);
+        // size tag, so that strings look the same as the ones allocated in memory
+        self.data.push(Value::size_tag(n_cells + 1));
+        let cur = self.data.len();
+        self.data.resize(cur + n_cells, Value::invalid(0));
+        write_string(&mut self.data[cur..], s);
+        DataRef(cur as u32)
+    }
+
     pub fn disassemble<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
         writeln!(w, "Data:")?;
-        for (i, value) in self.data.iter().enumerate() {
-            writeln!(w, "{:04} {:?}", i, value)?;
+        let mut i = 0;
+        while i < self.data.len() {
+            let value = self.data[i];
+            if value.tag() == ValueTag::StringTag {
+                let s =
+                    unsafe { Value::from_ptr(self.data(DataRef(i as u32)) as *mut Value).as_str() };
+                writeln!(w, "{:04} {:?} {:?}", i, value, s)?;
+                i += string_cells(s);
+            } else {
+                writeln!(w, "{:04} {:?}", i, value)?;
+                i += 1;
+            }
         }
 
         writeln!(w, "Code:")?;
         let mut cursor = Cursor::new(&self.code);
+        let mut entity = None;
         loop {
             let position = cursor.position();
             if let Ok(instruction) = Instruction::read(&mut cursor) {
+                let position = position as usize;
+                let new_entity = self.debug_info.entity_at(position);
+                if new_entity != entity {
+                    if let Some(e) = new_entity {
+                        writeln!(w, "     ; {:?}", e)?;
+                    }
+                    entity = new_entity;
+                }
+                for v in self.debug_info.variables_at(position) {
+                    if v.range.start == position {
+                        let name = v.name.as_deref().unwrap_or("_");
+                        writeln!(w, "     ; {:?} = {} ({:?})", v.reg, name, v.entity)?;
+                    }
+                }
                 writeln!(w, "{:04} {:?}", position, instruction)?;
             } else {
                 break;
@@ -82,6 +217,7 @@ mod tests {
 
         let number_1 = chunk.alloc_data(&[Value::number(1)]);
         let number_2 = chunk.alloc_data(&[Value::number(2)]);
+        chunk.alloc_string("Hello, world!");
 
         [
             Instruction::ConstantValue {
@@ -106,4 +242,31 @@ mod tests {
 
         chunk.disassemble(&mut std::io::stdout()).unwrap();
     }
+
+    #[test]
+    fn debug_info() {
+        let mut debug_info = DebugInfo::default();
+        let a = Field::from("a");
+        let b = Field::from("b");
+
+        debug_info.set_entity(0, Some(a.clone()));
+        debug_info.set_entity(4, Some(a.clone()));
+        debug_info.set_entity(8, None);
+        debug_info.set_entity(8, Some(b.clone()));
+        debug_info.add_variable(Variable {
+            range: 4..12,
+            reg: Reg(1),
+            entity: b.clone(),
+            name: Some("x".to_string()),
+        });
+
+        assert_eq!(debug_info.entities.len(), 2);
+        assert_eq!(debug_info.entity_at(0), Some(&a));
+        assert_eq!(debug_info.entity_at(7), Some(&a));
+        assert_eq!(debug_info.entity_at(8), Some(&b));
+        assert_eq!(debug_info.entity_at(100), Some(&b));
+        assert_eq!(debug_info.variables_at(3).count(), 0);
+        assert_eq!(debug_info.variables_at(4).count(), 1);
+        assert_eq!(debug_info.variables_at(12).count(), 0);
+    }
 }
@@ -0,0 +1,305 @@
+//! Step debugger for the VM.
+use meta_store::Field;
+
+use crate::vm::bytecode::Reg;
+use crate::vm::machine::{Error, Step, Vm};
+
+/// Where to pause the program.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum Breakpoint {
+    /// Before executing the instruction at the bytecode position.
+    Position(u64),
+    /// When execution enters code compiled from the entity.
+    Entity(Field),
+}
+
+#[derive(Debug)]
+pub enum Status {
+    /// Program can be stepped or resumed.
+    Paused,
+    Halted(Option<crate::Value>),
+    Failed(Error),
+}
+
+/// VM value decoded by its tag.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DebugValue {
+    Number(i32),
+    Bool(bool),
+    Constructor {
+        type_tag: u64,
+        constructor: u16,
+    },
+    String(String),
+    /// Address of a record (or of a field in the middle of one, as closures are). See
+    /// [`Debugger::record`].
+    Pointer(usize),
+    FunctionTag(u8),
+    StringTag(usize),
+    MovedOut(usize),
+    SizeTag(usize),
+    /// Value that has not been initialized.
+    Invalid(i32),
+}
+
+#[derive(Debug, Clone)]
+pub struct Register {
+    pub reg: u8,
+    pub value: DebugValue,
+    /// Entity and name of the variable the register holds at the current position.
+    pub variable: Option<(Field, Option<String>)>,
+}
+
+/// Heap record.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Record {
+    /// Address of the first field.
+    pub address: usize,
+    /// Index of the field the inspected address points to.
+    pub offset: usize,
+    pub fields: Vec<DebugValue>,
+}
+
+/// Runs a program instruction by instruction, pausing at breakpoints.
+pub struct Debugger {
+    vm: Vm,
+    breakpoints: Vec<Breakpoint>,
+    status: Status,
+}
+
+impl Debugger {
+    pub(crate) fn new(vm: Vm) -> Self {
+        Self {
+            vm,
+            breakpoints: Vec::new(),
+            status: Status::Paused,
+        }
+    }
+
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+
+    /// Bytecode position of the next instruction to execute.
+    pub fn position(&self) -> u64 {
+        self.vm.pc()
+    }
+
+    /// Entity the next instruction is compiled from.
+    pub fn entity(&self) -> Option<&Field> {
+        self.vm
+            .chunk()
+            .debug_info()
+            .entity_at(self.position() as usize)
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    /// Returns `true` if the breakpoint was set.
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| b != breakpoint);
+        self.breakpoints.len() != len
+    }
+
+    /// Execute a single instruction.
+    pub fn step(&mut self) -> &Status {
+        if let Status::Paused = self.status {
+            self.status = match self.vm.step() {
+                Ok(Step::Continue) => Status::Paused,
+                Ok(Step::Halt(result)) => {
+                    Status::Halted(result.map(|value| self.vm.to_host(value)))
+                }
+                Err(error) => Status::Failed(error),
+            };
+        }
+        &self.status
+    }
+
+    /// Execute instructions until the code of another entity is reached.
+    pub fn step_entity(&mut self) -> &Status {
+        let entity = self.entity().cloned();
+        while let Status::Paused = self.step() {
+            if self.entity() != entity.as_ref() {
+                break;
+            }
+        }
+        &self.status
+    }
+
+    /// Run until a breakpoint is hit or the program stops. At least one instruction is executed,
+    /// so resuming at a breakpoint does not hit it again.
+    pub fn resume(&mut self) -> &Status {
+        loop {
+            let entity = self.entity().cloned();
+            if let Status::Paused = self.step() {
+                if self.at_breakpoint(entity.as_ref()) {
+                    break;
+                }
+            } else {
+                break;
+            }
+        }
+        &self.status
+    }
+
+    /// Whether the next instruction hits a breakpoint. `previous` is the entity of the previous
+    /// instruction.
+    fn at_breakpoint(&self, previous: Option<&Field>) -> bool {
+        let position = self.position();
+        let entity = self.entity();
+        self.breakpoints.iter().any(|breakpoint| match breakpoint {
+            Breakpoint::Position(p) => *p == position,
+            Breakpoint::Entity(e) => entity == Some(e) && previous != Some(e),
+        })
+    }
+
+    /// Registers that have been written, with variables they hold at the current position.
+    pub fn registers(&self) -> Vec<Register> {
+        let debug_info = self.vm.chunk().debug_info();
+        let variables = debug_info
+            .variables_at(self.position() as usize)
+            .collect::<Vec<_>>();
+        (0..=u8::MAX)
+            .filter_map(|reg| {
+                let value = self.vm.inspect(self.vm.register(Reg(reg)));
+                let variable = variables
+                    .iter()
+                    .find(|variable| variable.reg == Reg(reg))
+                    .map(|variable| (variable.entity.clone(), variable.name.clone()));
+                if value == DebugValue::Invalid(0) && variable.is_none() {
+                    return None;
+                }
+                Some(Register {
+                    reg,
+                    value,
+                    variable,
+                })
+            })
+            .collect()
+    }
+
+    /// Decode heap record `address` points into.
+    pub fn record(&self, address: usize) -> Option<Record> {
+        self.vm.record(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::bytecode::Instruction;
+    use crate::vm::chunk::Chunk;
+    use crate::vm::machine::Options;
+    use crate::vm::value::Value;
+
+    fn debugger(chunk: Chunk) -> Debugger {
+        Debugger::new(Vm::new(chunk, &Options::default()))
+    }
+
+    #[test]
+    fn step_and_inspect() {
+        let mut chunk = Chunk::new();
+        let number_2 = chunk.alloc_data(&[Value::number(2)]);
+        let hello = chunk.alloc_string("hello");
+        [
+            Instruction::ConstantValue {
+                result: Reg(1),
+                value: number_2,
+            },
+            Instruction::AllocReg {
+                result: Reg(2),
+                cells_to_allocate: Reg(1),
+            },
+            Instruction::StoreReg {
+                addr: Reg(2),
+                offset: 0,
+                reg_to_store: Reg(1),
+            },
+            Instruction::ConstantRef {
+                result: Reg(3),
+                value: hello,
+            },
+            Instruction::StoreReg {
+                addr: Reg(2),
+                offset: 1,
+                reg_to_store: Reg(3),
+            },
+            Instruction::HaltReg { reg: Reg(1) },
+        ]
+        .iter()
+        .for_each(|i| {
+            chunk.write(i).unwrap();
+        });
+
+        let mut debugger = debugger(chunk);
+        assert!(debugger.registers().is_empty());
+
+        assert!(matches!(debugger.step(), Status::Paused));
+        let registers = debugger.registers();
+        assert_eq!(registers.len(), 1);
+        assert_eq!(registers[0].reg, 1);
+        assert_eq!(registers[0].value, DebugValue::Number(2));
+
+        for _ in 0..4 {
+            debugger.step();
+        }
+        let registers = debugger.registers();
+        assert_eq!(registers[2].value, DebugValue::String("hello".to_string()));
+        let record = match registers[1].value {
+            DebugValue::Pointer(address) => debugger.record(address).unwrap(),
+            ref value => panic!("expected pointer, got {:?}", value),
+        };
+        assert_eq!(record.offset, 0);
+        assert_eq!(
+            record.fields,
+            vec![
+                DebugValue::Number(2),
+                DebugValue::String("hello".to_string())
+            ]
+        );
+
+        assert!(matches!(
+            debugger.step(),
+            Status::Halted(Some(crate::Value::Number(2)))
+        ));
+        // stepping a halted program does nothing
+        assert!(matches!(debugger.step(), Status::Halted(_)));
+    }
+
+    #[test]
+    fn resume_to_breakpoint() {
+        let mut chunk = Chunk::new();
+        let number_1 = chunk.alloc_data(&[Value::number(1)]);
+        let mut positions = Vec::new();
+        for _ in 0..3 {
+            let position = chunk
+                .write(&Instruction::ConstantValue {
+                    result: Reg(0),
+                    value: number_1,
+                })
+                .unwrap();
+            positions.push(position as u64);
+        }
+        chunk.write(&Instruction::Halt).unwrap();
+
+        let mut debugger = debugger(chunk);
+        debugger.add_breakpoint(Breakpoint::Position(positions[0]));
+        debugger.add_breakpoint(Breakpoint::Position(positions[2]));
+
+        // breakpoint at the current position is not hit
+        assert!(matches!(debugger.resume(), Status::Paused));
+        assert_eq!(debugger.position(), positions[2]);
+
+        assert!(debugger.remove_breakpoint(&Breakpoint::Position(positions[2])));
+        assert!(matches!(debugger.resume(), Status::Halted(None)));
+    }
+}
@@ -0,0 +1,575 @@
+//! On-disk format of compiled chunks.
+//!
+//! All numbers are little-endian. A file consists of:
+//!
+//! - header: magic bytes and format version (u32);
+//! - constant pool: number of data cells (u32) and number of entries (u32) followed by the
+//!   entries. Each entry is a kind byte followed by:
+//!   - `VALUE`: raw scalar cell (u64): a number, constructor, function tag or invalid value;
+//!   - `STRING`: string object (size tag, string tag and bytes), as a string;
+//!   - `POINTER`: index of the data cell the pointer points to (u32), so that the pointer can be
+//!     relocated to wherever the data segment is loaded. It must be a scalar cell or a string
+//!     tag;
+//! - code: number of words (u32) followed by the words (u64). Instructions (including their
+//!   operands) are made of words stored in memory in native byte order, so words are swapped as
+//!   needed;
+//! - debug table: entities (position u64, optional entity) and variables (range start u64, range
+//!   end u64, register u8, entity, optional name).
+//!
+//! Strings are a length (u32) followed by UTF-8 bytes, optional strings are prefixed with a 0/1
+//! byte.
+use std::collections::HashSet;
+use std::io::{Cursor, Read, Write};
+
+use meta_store::Field;
+
+use crate::vm::bytecode::{Instruction, Reg};
+use crate::vm::chunk::{Chunk, DebugInfo, Variable};
+use crate::vm::value::{string_cells, write_string, Value, ValueTag};
+
+const MAGIC: [u8; 8] = *b"\0meta-f\n";
+/// Version 2 added `CallHost`.
+const VERSION: u32 = 2;
+
+const VALUE: u8 = 0;
+const STRING: u8 = 1;
+const POINTER: u8 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// File is not a compiled chunk.
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    /// Constant pool entries don't match the number of data cells, a value is not a scalar, or a
+    /// pointer points outside of the data segment or into a string.
+    InvalidData,
+    InvalidString,
+    /// Code at the position can't be decoded.
+    InvalidInstruction {
+        position: usize,
+    },
+    /// Instruction at the position refers to a cell outside of the data segment or to a cell of
+    /// the wrong kind (e.g., loads string bytes as a value).
+    InvalidDataRef {
+        position: usize,
+    },
+    /// Instruction at the position jumps to the middle of an instruction or outside of code.
+    InvalidJump {
+        position: usize,
+        target: i64,
+    },
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Write `chunk` in the on-disk format. Fails if the data segment contains pointers outside of it
+/// or cells that are only valid in the heap.
+pub(crate) fn write_chunk<W: Write>(chunk: &Chunk, w: &mut W) -> std::io::Result<()> {
+    w.write_all(&MAGIC)?;
+    write_u32(w, VERSION)?;
+
+    let data = chunk.data_segment();
+    let mut entries = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let value = data[i];
+        let mut entry = Vec::new();
+        match value.tag() {
+            // strings in data segment are preceded by size tags (see `Chunk::alloc_string()`)
+            ValueTag::SizeTag
+                if data.get(i + 1).map(|value| value.tag()) == Some(ValueTag::StringTag) =>
+            {
+                let ptr = &data[i + 1] as *const Value as *mut Value;
+                let s = unsafe { Value::from_ptr(ptr).as_str() };
+                entry.push(STRING);
+                write_str(&mut entry, s)?;
+                i += 1 + string_cells(s);
+            }
+            ValueTag::Pointer => {
+                let ptr = value.as_ptr() as *const Value;
+                if !chunk.data_contains(ptr) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("pointer outside of data segment at {}", i),
+                    ));
+                }
+                let index = (ptr as usize - data.as_ptr() as usize) / std::mem::size_of::<Value>();
+                entry.push(POINTER);
+                write_u32(&mut entry, index as u32)?;
+                i += 1;
+            }
+            tag if is_scalar(tag) => {
+                entry.push(VALUE);
+                write_u64(&mut entry, value.to_bits())?;
+                i += 1;
+            }
+            tag => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("unexpected {:?} in data segment at {}", tag, i),
+                ));
+            }
+        }
+        entries.push(entry);
+    }
+    write_u32(w, data.len() as u32)?;
+    write_u32(w, entries.len() as u32)?;
+    for entry in entries.iter() {
+        w.write_all(entry)?;
+    }
+
+    let code = chunk.code();
+    write_u32(w, (code.len() / 8) as u32)?;
+    for word in code.chunks(8) {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(word);
+        write_u64(w, u64::from_ne_bytes(bytes))?;
+    }
+
+    let debug_info = chunk.debug_info();
+    write_u32(w, debug_info.entities().len() as u32)?;
+    for (position, entity) in debug_info.entities() {
+        write_u64(w, *position as u64)?;
+        write_optional_str(w, entity.as_ref().map(|entity| entity.as_ref()))?;
+    }
+    write_u32(w, debug_info.variables().len() as u32)?;
+    for variable in debug_info.variables() {
+        write_u64(w, variable.range.start as u64)?;
+        write_u64(w, variable.range.end as u64)?;
+        w.write_all(&[variable.reg.0])?;
+        write_str(w, variable.entity.as_ref())?;
+        write_optional_str(w, variable.name.as_deref())?;
+    }
+
+    Ok(())
+}
+
+/// Read a chunk written by [`write_chunk`], validating its code.
+pub(crate) fn read_chunk<R: Read>(r: &mut R) -> Result<Chunk, Error> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::InvalidMagic);
+    }
+    let version = read_u32(r)?;
+    if version != VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+
+    let n_cells = read_u32(r)? as usize;
+    let n_entries = read_u32(r)?;
+    let mut data = Vec::new();
+    let mut cells = Vec::new();
+    // pointers are relocated once the data segment has its final address
+    let mut pointers = Vec::new();
+    for _ in 0..n_entries {
+        match read_u8(r)? {
+            VALUE => {
+                let value = Value::from_bits(read_u64(r)?);
+                if !is_scalar(value.tag()) {
+                    return Err(Error::InvalidData);
+                }
+                data.push(value);
+                cells.push(Cell::Scalar);
+            }
+            STRING => {
+                let s = read_str(r)?;
+                let size = string_cells(&s);
+                data.push(Value::size_tag(size + 1));
+                let cur = data.len();
+                data.resize(cur + size, Value::invalid(0));
+                write_string(&mut data[cur..], &s);
+                cells.push(Cell::Object);
+                cells.push(Cell::StringTag);
+                cells.resize(data.len(), Cell::Object);
+            }
+            POINTER => {
+                pointers.push((data.len(), read_u32(r)? as usize));
+                data.push(Value::invalid(0));
+                cells.push(Cell::Scalar);
+            }
+            _ => return Err(Error::InvalidData),
+        }
+        if data.len() > n_cells {
+            return Err(Error::InvalidData);
+        }
+    }
+    if data.len() != n_cells {
+        return Err(Error::InvalidData);
+    }
+    for (cell, index) in pointers {
+        if matches!(cells.get(index), None | Some(Cell::Object)) {
+            return Err(Error::InvalidData);
+        }
+        let ptr = &mut data[index] as *mut Value;
+        data[cell] = Value::from_ptr(ptr);
+    }
+
+    let n_words = read_u32(r)? as usize;
+    let mut code = Vec::new();
+    for _ in 0..n_words {
+        code.extend_from_slice(&read_u64(r)?.to_ne_bytes());
+    }
+
+    let mut debug_info = DebugInfo::default();
+    let n_entities = read_u32(r)?;
+    for _ in 0..n_entities {
+        let position = read_u64(r)? as usize;
+        let entity = read_optional_str(r)?.map(Field::from);
+        debug_info.set_entity(position, entity);
+    }
+    let n_variables = read_u32(r)?;
+    for _ in 0..n_variables {
+        let start = read_u64(r)? as usize;
+        let end = read_u64(r)? as usize;
+        let reg = Reg(read_u8(r)?);
+        let entity = Field::from(read_str(r)?);
+        let name = read_optional_str(r)?;
+        debug_info.add_variable(Variable {
+            range: start..end,
+            reg,
+            entity,
+            name,
+        });
+    }
+
+    let chunk = Chunk::from_parts(code, data, debug_info);
+    validate(&chunk, &cells)?;
+    Ok(chunk)
+}
+
+/// Kind of a data segment cell.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Cell {
+    /// Scalar value or pointer.
+    Scalar,
+    /// String tag of a string object.
+    StringTag,
+    /// Size tag or bytes of a string object, which can't be referred to.
+    Object,
+}
+
+/// Whether values with `tag` can be stored in data segment as is. Pointers and object tags are
+/// dereferenced by the VM, so they are only valid if created by the loader.
+fn is_scalar(tag: ValueTag) -> bool {
+    matches!(
+        tag,
+        ValueTag::Number | ValueTag::Constructor | ValueTag::FunctionTag | ValueTag::Invalid
+    )
+}
+
+/// Check that all code decodes, data references are inside the data segment and point to cells of
+/// the right kind, and constant jumps land at instruction boundaries.
+fn validate(chunk: &Chunk, cells: &[Cell]) -> Result<(), Error> {
+    let code = chunk.code();
+    let mut cursor = Cursor::new(code);
+    let mut starts = HashSet::new();
+    let mut jumps = Vec::new();
+    while (cursor.position() as usize) < code.len() {
+        let position = cursor.position() as usize;
+        let instruction =
+            Instruction::read(&mut cursor).map_err(|_| Error::InvalidInstruction { position })?;
+        starts.insert(position as i64);
+
+        // values are copied out of the data segment, references point into it
+        let valid = match &instruction {
+            Instruction::HaltValue { value }
+            | Instruction::StoreValue { value, .. }
+            | Instruction::ConstantValue { value, .. } => {
+                cells.get(value.0 as usize) == Some(&Cell::Scalar)
+            }
+            Instruction::ConstantRef { value, .. } => matches!(
+                cells.get(value.0 as usize),
+                Some(Cell::Scalar) | Some(Cell::StringTag)
+            ),
+            Instruction::CallHost { function, .. } => {
+                cells.get(function.0 as usize) == Some(&Cell::StringTag)
+            }
+            _ => true,
+        };
+        if !valid {
+            return Err(Error::InvalidDataRef { position });
+        }
+
+        match instruction {
+            Instruction::JumpConst { offset } => {
+                jumps.push((position, position as i64 + offset as i64));
+            }
+            Instruction::Switch { offsets, .. } => {
+                jumps.extend(
+                    offsets
+                        .iter()
+                        .map(|offset| (position, position as i64 + offset)),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    match jumps
+        .into_iter()
+        .find(|(_position, target)| !starts.contains(target))
+    {
+        Some((position, target)) => Err(Error::InvalidJump { position, target }),
+        None => Ok(()),
+    }
+}
+
+fn write_u32<W: Write>(w: &mut W, value: u32) -> std::io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_u64<W: Write>(w: &mut W, value: u64) -> std::io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_str<W: Write>(w: &mut W, s: &str) -> std::io::Result<()> {
+    write_u32(w, s.len() as u32)?;
+    w.write_all(s.as_bytes())
+}
+
+fn write_optional_str<W: Write>(w: &mut W, s: Option<&str>) -> std::io::Result<()> {
+    match s {
+        Some(s) => {
+            w.write_all(&[1])?;
+            write_str(w, s)
+        }
+        None => w.write_all(&[0]),
+    }
+}
+
+fn read_u8<R: Read>(r: &mut R) -> std::io::Result<u8> {
+    let mut bytes = [0; 1];
+    r.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_str<R: Read>(r: &mut R) -> Result<String, Error> {
+    let len = read_u32(r)? as u64;
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    String::from_utf8(bytes).map_err(|_| Error::InvalidString)
+}
+
+fn read_optional_str<R: Read>(r: &mut R) -> Result<Option<String>, Error> {
+    match read_u8(r)? {
+        0 => Ok(None),
+        1 => read_str(r).map(Some),
+        _ => Err(Error::InvalidString),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::machine::{Options, Vm};
+
+    fn roundtrip(chunk: &Chunk) -> Result<Chunk, Error> {
+        let mut bytes = Vec::new();
+        write_chunk(chunk, &mut bytes)?;
+        read_chunk(&mut Cursor::new(bytes))
+    }
+
+    fn hello_chunk() -> Chunk {
+        let mut chunk = Chunk::new();
+        let number_1 = chunk.alloc_data(&[Value::number(1)]);
+        let hello = chunk.alloc_string("Hello, world!");
+        let entity = Field::from("a");
+        chunk.debug_info_mut().set_entity(0, Some(entity.clone()));
+        chunk.debug_info_mut().add_variable(Variable {
+            range: 0..16,
+            reg: Reg(1),
+            entity,
+            name: Some("x".to_string()),
+        });
+        [
+            Instruction::ConstantValue {
+                result: Reg(1),
+                value: number_1,
+            },
+            Instruction::Switch {
+                reg: Reg(1),
+                offsets: vec![24, 32],
+            },
+            Instruction::Halt,
+            Instruction::ConstantRef {
+                result: Reg(2),
+                value: hello,
+            },
+            Instruction::HaltReg { reg: Reg(2) },
+        ]
+        .iter()
+        .for_each(|i| {
+            chunk.write(i).unwrap();
+        });
+        chunk
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let chunk = hello_chunk();
+
+        let loaded = roundtrip(&chunk).unwrap();
+
+        assert_eq!(loaded.code(), chunk.code());
+        assert_eq!(loaded.data_segment(), chunk.data_segment());
+        assert_eq!(
+            loaded.debug_info().entities(),
+            chunk.debug_info().entities()
+        );
+        let variable = &loaded.debug_info().variables()[0];
+        assert_eq!(variable.range, 0..16);
+        assert_eq!(variable.name.as_deref(), Some("x"));
+
+        let mut vm = Vm::new(loaded, &Options::default());
+        let result = vm.run().unwrap().map(|value| vm.to_host(value));
+        assert_eq!(result, Some(crate::Value::String("Hello, world!".into())));
+    }
+
+    #[test]
+    fn test_relocate_pointers() {
+        let mut chunk = Chunk::new();
+        let number_1 = chunk.alloc_data(&[Value::number(1)]);
+        let ptr = chunk.data(number_1) as *mut Value;
+        chunk.alloc_data(&[Value::from_ptr(ptr)]);
+
+        let loaded = roundtrip(&chunk).unwrap();
+
+        let data = loaded.data_segment();
+        assert_eq!(data[1].as_ptr() as *const Value, &data[0] as *const Value);
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let mut bytes = Vec::new();
+        write_chunk(&hello_chunk(), &mut bytes).unwrap();
+
+        let mut magic = bytes.clone();
+        magic[1] = b'M';
+        assert!(matches!(
+            read_chunk(&mut Cursor::new(magic)),
+            Err(Error::InvalidMagic)
+        ));
+
+        bytes[8] = 1;
+        assert!(matches!(
+            read_chunk(&mut Cursor::new(bytes)),
+            Err(Error::UnsupportedVersion(1))
+        ));
+    }
+
+    /// File with a constant pool of `entries` making `n_cells` cells and no code.
+    fn data_file(n_cells: u32, n_entries: u32, entries: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        write_u32(&mut bytes, VERSION).unwrap();
+        write_u32(&mut bytes, n_cells).unwrap();
+        write_u32(&mut bytes, n_entries).unwrap();
+        bytes.extend_from_slice(entries);
+        // no code, entities or variables
+        bytes.extend_from_slice(&[0; 12]);
+        bytes
+    }
+
+    #[test]
+    fn test_invalid_data() {
+        let value = |value: Value| {
+            let mut entry = vec![VALUE];
+            write_u64(&mut entry, value.to_bits()).unwrap();
+            entry
+        };
+        let number = value(Value::number(1));
+        assert!(read_chunk(&mut Cursor::new(data_file(1, 1, &number))).is_ok());
+
+        // cells the VM dereferences can't be forged
+        let forged = [
+            Value::from_ptr(0x1000 as *mut Value),
+            Value::size_tag(1000),
+            Value::string_tag(1000),
+            Value::moved_out(0x1000 as *mut Value),
+        ];
+        for forged in forged.iter() {
+            assert!(matches!(
+                read_chunk(&mut Cursor::new(data_file(1, 1, &value(*forged)))),
+                Err(Error::InvalidData)
+            ));
+        }
+
+        // pointers can't point into the middle of strings
+        let mut entries = vec![STRING];
+        write_str(&mut entries, "hello").unwrap();
+        entries.push(POINTER);
+        write_u32(&mut entries, 2).unwrap();
+        assert!(matches!(
+            read_chunk(&mut Cursor::new(data_file(4, 2, &entries))),
+            Err(Error::InvalidData)
+        ));
+    }
+
+    #[test]
+    fn test_invalid_code() {
+        let mut chunk = Chunk::new();
+        chunk.code_mut().extend_from_slice(&u64::MAX.to_ne_bytes());
+        assert!(matches!(
+            roundtrip(&chunk),
+            Err(Error::InvalidInstruction { position: 0 })
+        ));
+
+        let mut chunk = Chunk::new();
+        chunk
+            .write(&Instruction::ConstantValue {
+                result: Reg(0),
+                value: crate::vm::chunk::DataRef(0),
+            })
+            .unwrap();
+        assert!(matches!(
+            roundtrip(&chunk),
+            Err(Error::InvalidDataRef { position: 0 })
+        ));
+
+        // string bytes can't be loaded as values
+        let mut chunk = Chunk::new();
+        let hello = chunk.alloc_string("hello");
+        chunk
+            .write(&Instruction::ConstantValue {
+                result: Reg(0),
+                value: crate::vm::chunk::DataRef(hello.0 + 1),
+            })
+            .unwrap();
+        assert!(matches!(
+            roundtrip(&chunk),
+            Err(Error::InvalidDataRef { position: 0 })
+        ));
+
+        let mut chunk = Chunk::new();
+        chunk.write(&Instruction::Halt).unwrap();
+        chunk.write(&Instruction::JumpConst { offset: -4 }).unwrap();
+        assert!(matches!(
+            roundtrip(&chunk),
+            Err(Error::InvalidJump {
+                position: 8,
+                target: 4
+            })
+        ));
+    }
+}
@@ -1,29 +1,145 @@
 use std::fmt::Debug;
 use std::io::Cursor;
 use std::ops::{Index, IndexMut};
+use std::time::Instant;
 
 use log::{log_enabled, trace, Level};
 
+use meta_store::Field;
+
+use crate::host::HostFunctions;
 use crate::vm::bytecode::Instruction;
-use crate::vm::chunk::Chunk;
-use crate::vm::memory::Memory;
+use crate::vm::chunk::{Chunk, DataRef};
+use crate::vm::debugger::{DebugValue, Record};
+use crate::vm::memory::{GcStats, Memory};
 use crate::vm::value::*;
 
+/// Runtime error together with the location of the instruction that caused it.
+#[derive(Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    /// Bytecode position of the failing instruction.
+    pub position: u64,
+    /// Entity the failing instruction was compiled from, if known.
+    pub entity: Option<Field>,
+}
+
 #[derive(Debug)]
-pub enum Error {
+pub enum ErrorKind {
     OutOfMemory,
+    /// String can't be converted to a number.
+    InvalidNumber(String),
+    /// No case matches the value.
+    MatchFailure,
+    /// Operand of an instruction has unexpected type.
+    TypeMismatch {
+        expected: &'static str,
+        actual: Value,
+    },
+    /// Result of an arithmetic operation does not fit into a number.
+    Overflow,
+    DivisionByZero,
+    /// Code at the position can't be decoded (e.g., the program runs past the end of code).
+    InvalidInstruction,
+    /// Jump target is outside of code.
+    InvalidJump {
+        target: i64,
+    },
+    /// Pointer (or data reference) points outside of both heap and data segment.
+    InvalidMemoryAccess,
+    /// Instruction budget is exhausted.
+    OutOfFuel,
+    /// Deadline has passed before the program halted.
+    DeadlineExceeded,
+    /// No host function is registered under the identifier.
+    UnknownHostFunction(String),
+    /// Host function failed.
+    HostFunction(String),
+}
+
+/// Limits for program execution.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Maximum number of instructions to execute.
+    pub fuel: Option<u64>,
+    /// Initial heap size in bytes.
+    pub heap_size: usize,
+    /// Heap never grows beyond this size (in bytes). Allocations that would exceed it fail with
+    /// `OutOfMemory`.
+    pub max_heap_size: Option<usize>,
+    /// Time by which the program must halt.
+    pub deadline: Option<Instant>,
+    /// Functions the program can call in addition to builtins.
+    pub host_functions: HostFunctions,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            fuel: None,
+            heap_size: 1024 * 1024, // 1Mb
+            max_heap_size: None,
+            deadline: None,
+            host_functions: HostFunctions::new(),
+        }
+    }
 }
 
+/// Checking the clock is relatively expensive, so the deadline is only checked once per this
+/// number of instructions. Must be a power of two.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
 pub(crate) struct Vm {
     chunk: Chunk,
+    /// Position of the instruction being executed.
+    pc: u64,
     registers: Registers,
     memory: Memory,
+    budget: Budget,
+    host_functions: HostFunctions,
+}
+
+/// Execution limits that are checked on every instruction.
+struct Budget {
+    /// Number of instructions left to execute.
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    /// Number of instructions executed so far.
+    executed: u64,
+}
+
+impl Budget {
+    fn consume(&mut self) -> Result<(), ErrorKind> {
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                return Err(ErrorKind::OutOfFuel);
+            }
+            *fuel -= 1;
+        }
+
+        if let Some(deadline) = self.deadline {
+            if self.executed & (DEADLINE_CHECK_INTERVAL - 1) == 0 && Instant::now() >= deadline {
+                return Err(ErrorKind::DeadlineExceeded);
+            }
+        }
+
+        self.executed += 1;
+        Ok(())
+    }
+}
+
+/// Outcome of executing a single instruction.
+#[derive(Debug)]
+pub(crate) enum Step {
+    Continue,
+    Halt(Option<Value>),
 }
 
 impl Debug for Vm {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Vm")
             .field("chunk", &self.chunk)
+            .field("pc", &self.pc)
             .field("registers", &self.registers)
             .finish()
     }
@@ -65,124 +181,503 @@ impl Debug for Registers {
 }
 
 impl Vm {
-    pub fn new(chunk: Chunk) -> Self {
+    pub fn new(chunk: Chunk, options: &Options) -> Self {
+        let cells = |bytes: usize| bytes / std::mem::size_of::<Value>();
         Self {
             chunk,
+            pc: 0,
             registers: Registers::new(),
-            memory: Memory::new(1024 * 1024 / std::mem::size_of::<Value>()), // 1Mb
+            memory: Memory::new(
+                cells(options.heap_size),
+                options.max_heap_size.map_or(usize::MAX, cells),
+            ),
+            budget: Budget {
+                fuel: options.fuel,
+                deadline: options.deadline,
+                executed: 0,
+            },
+            host_functions: options.host_functions.clone(),
         }
     }
 
+    pub fn gc_stats(&self) -> GcStats {
+        self.memory.stats()
+    }
+
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    /// Position of the next instruction to execute.
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    pub fn register(&self, reg: Reg) -> Value {
+        self.registers[reg]
+    }
+
     pub fn run(&mut self) -> Result<Option<Value>, Error> {
         if log_enabled!(target: "vm", Level::Trace) {
             self.chunk.disassemble(&mut std::io::stderr()).unwrap();
         }
 
-        let mut cursor = Cursor::new(self.chunk.code());
         loop {
-            trace!(target: "vm::registers", "{:?}", &self.registers);
-            let position = cursor.position();
-            trace!(target: "vm", "Position: {}", position);
-            let instruction = Instruction::read(&mut cursor).unwrap();
-            trace!(target: "vm", "Instruction: {:?}", instruction);
-
-            match instruction {
-                Instruction::Halt => {
-                    return Ok(None);
-                }
-                Instruction::HaltReg { reg } => {
-                    return Ok(Some(self.registers[reg]));
-                }
-                Instruction::HaltValue { value } => {
-                    let data = unsafe { *self.chunk.data(value) };
-                    return Ok(Some(data));
+            if let Step::Halt(result) = self.step()? {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Execute a single instruction. After the program halts or fails, the position stays at the
+    /// last executed instruction.
+    pub fn step(&mut self) -> Result<Step, Error> {
+        self.execute().map_err(|kind| Error {
+            kind,
+            position: self.pc,
+            entity: self.chunk.debug_info().entity_at(self.pc as usize).cloned(),
+        })
+    }
+
+    fn execute(&mut self) -> Result<Step, ErrorKind> {
+        let mut cursor = Cursor::new(self.chunk.code());
+        cursor.set_position(self.pc);
+        trace!(target: "vm::registers", "{:?}", &self.registers);
+        let position = self.pc;
+        trace!(target: "vm", "Position: {}", position);
+        self.budget.consume()?;
+        let instruction =
+            Instruction::read(&mut cursor).map_err(|_| ErrorKind::InvalidInstruction)?;
+        trace!(target: "vm", "Instruction: {:?}", instruction);
+
+        match instruction {
+            Instruction::Halt => {
+                return Ok(Step::Halt(None));
+            }
+            Instruction::HaltReg { reg } => {
+                return Ok(Step::Halt(Some(self.registers[reg])));
+            }
+            Instruction::HaltValue { value } => {
+                return Ok(Step::Halt(Some(self.constant(value)?)));
+            }
+            Instruction::AllocConst {
+                result,
+                cells_to_allocate,
+            } => {
+                let ptr = self
+                    .memory
+                    .allocate_cells(cells_to_allocate as usize, &mut self.registers.0);
+                if ptr.is_null() {
+                    return Err(ErrorKind::OutOfMemory);
                 }
-                Instruction::AllocConst {
-                    result,
-                    cells_to_allocate,
-                } => {
-                    let ptr = self.memory.allocate_cells(cells_to_allocate as usize);
-                    if ptr.is_null() {
-                        return Err(Error::OutOfMemory);
-                    }
-                    self.registers[result] = Value::from_ptr(ptr);
+                self.registers[result] = Value::from_ptr(ptr);
+            }
+            Instruction::AllocReg {
+                result,
+                cells_to_allocate,
+            } => {
+                let cells_to_allocate = self.cell_count(cells_to_allocate)?;
+                let ptr = self
+                    .memory
+                    .allocate_cells(cells_to_allocate, &mut self.registers.0);
+                if ptr.is_null() {
+                    return Err(ErrorKind::OutOfMemory);
                 }
-                Instruction::AllocReg {
-                    result,
-                    cells_to_allocate,
-                } => {
-                    let cells_to_allocate = self.registers[cells_to_allocate].as_number() as usize;
-                    let ptr = self.memory.allocate_cells(cells_to_allocate);
-                    if ptr.is_null() {
-                        return Err(Error::OutOfMemory);
+                self.registers[result] = Value::from_ptr(ptr);
+            }
+            Instruction::StoreReg {
+                addr,
+                offset,
+                reg_to_store,
+            } => {
+                let addr = self.heap_address(addr, offset as isize)?;
+                unsafe { *addr = self.registers[reg_to_store] };
+            }
+            Instruction::StoreValue {
+                addr,
+                offset,
+                value,
+            } => {
+                let addr = self.heap_address(addr, offset as isize)?;
+                let value = self.constant(value)?;
+                unsafe { *addr = value };
+            }
+            Instruction::Load {
+                result,
+                addr,
+                offset,
+            } => {
+                let addr = self.address(addr, offset as isize)?;
+                self.registers[result] = unsafe { *addr };
+            }
+            Instruction::ConstantValue { result, value } => {
+                self.registers[result] = self.constant(value)?;
+            }
+            Instruction::ConstantRef { result, value } => {
+                let ptr = self
+                    .chunk
+                    .get_data(value)
+                    .ok_or(ErrorKind::InvalidMemoryAccess)?;
+                self.registers[result] = Value::from_ptr(ptr as *mut Value);
+            }
+            Instruction::Switch { reg, offsets } => {
+                let value = self.registers[reg];
+                let index = match value.tag() {
+                    ValueTag::Constructor => value.as_constructor().1 as usize,
+                    ValueTag::Number => value.as_number() as usize,
+                    _ => {
+                        return Err(ErrorKind::TypeMismatch {
+                            expected: "constructor",
+                            actual: value,
+                        })
                     }
-                    self.registers[result] = Value::from_ptr(ptr);
-                }
-                Instruction::StoreReg {
-                    addr,
-                    offset,
-                    reg_to_store,
-                } => unsafe {
-                    let addr = self.registers[addr].as_ptr();
-                    let addr = addr.offset(offset as isize);
-                    *addr = self.registers[reg_to_store];
-                },
-                Instruction::StoreValue {
-                    addr,
-                    offset,
-                    value,
-                } => unsafe {
-                    let addr = self.registers[addr].as_ptr();
-                    let addr = addr.offset(offset as isize);
-                    let value = *self.chunk.data(value);
-                    *addr = value;
-                },
-                Instruction::Load {
-                    result,
-                    addr,
-                    offset,
-                } => unsafe {
-                    let addr = self.registers[addr].as_ptr();
-                    let addr = addr.offset(offset as isize);
-                    self.registers[result] = *addr;
-                },
-                Instruction::ConstantValue { result, value } => {
-                    let value = unsafe { *self.chunk.data(value) };
-                    self.registers[result] = value;
-                }
-                Instruction::Switch { reg: _, offsets: _ } => {
-                    todo!();
-                }
-                Instruction::JumpReg { reg } => {
-                    let addr = self.registers[reg].as_number();
-                    cursor.set_position(addr as u64);
-                }
-                Instruction::JumpConst { offset } => {
-                    let addr = (position as i64) + (offset as i64);
-                    cursor.set_position(addr as u64);
-                }
-                Instruction::Offset {
-                    result,
-                    op1,
-                    offset,
-                } => {
-                    self.registers[result] = Value::from_ptr(unsafe {
-                        self.registers[op1].as_ptr().offset(offset as isize)
-                    })
-                }
-                Instruction::Add { result, op1, op2 } => {
-                    // TODO: check they are actually numbers
-                    self.registers[result] = Value::number(
-                        self.registers[op1].as_number() + self.registers[op2].as_number(),
-                    );
+                };
+                let offset = offsets.get(index).ok_or(ErrorKind::MatchFailure)?;
+                cursor.set_position(self.jump_target(position as i64 + offset)?);
+            }
+            Instruction::MatchFailure => {
+                return Err(ErrorKind::MatchFailure);
+            }
+            Instruction::JumpReg { reg } => {
+                let addr = self.number(reg)?;
+                cursor.set_position(self.jump_target(addr as i64)?);
+            }
+            Instruction::JumpConst { offset } => {
+                let addr = (position as i64) + (offset as i64);
+                cursor.set_position(self.jump_target(addr)?);
+            }
+            Instruction::Offset {
+                result,
+                op1,
+                offset,
+            } => {
+                // validated when the pointer is dereferenced
+                let ptr = self.pointer(op1)?.wrapping_offset(offset as isize);
+                self.registers[result] = Value::from_ptr(ptr);
+            }
+            Instruction::Add { result, op1, op2 } => {
+                let sum = self.number(op1)?.checked_add(self.number(op2)?);
+                self.registers[result] = Value::number(sum.ok_or(ErrorKind::Overflow)?);
+            }
+            Instruction::Sub { result, op1, op2 } => {
+                let difference = self.number(op1)?.checked_sub(self.number(op2)?);
+                self.registers[result] = Value::number(difference.ok_or(ErrorKind::Overflow)?);
+            }
+            Instruction::Mul { result, op1, op2 } => {
+                let product = self.number(op1)?.checked_mul(self.number(op2)?);
+                self.registers[result] = Value::number(product.ok_or(ErrorKind::Overflow)?);
+            }
+            Instruction::Div { result, op1, op2 } => {
+                let divisor = self.nonzero(op2)?;
+                let quotient = self.number(op1)?.checked_div(divisor);
+                self.registers[result] = Value::number(quotient.ok_or(ErrorKind::Overflow)?);
+            }
+            Instruction::Mod { result, op1, op2 } => {
+                let divisor = self.nonzero(op2)?;
+                let remainder = self.number(op1)?.checked_rem(divisor);
+                self.registers[result] = Value::number(remainder.ok_or(ErrorKind::Overflow)?);
+            }
+            Instruction::Neg { result, op } => {
+                let negated = self.number(op)?.checked_neg();
+                self.registers[result] = Value::number(negated.ok_or(ErrorKind::Overflow)?);
+            }
+            Instruction::NumberEqual { result, op1, op2 } => {
+                let equal = self.number(op1)? == self.number(op2)?;
+                self.registers[result] = Value::boolean(equal);
+            }
+            Instruction::Less { result, op1, op2 } => {
+                let less = self.number(op1)? < self.number(op2)?;
+                self.registers[result] = Value::boolean(less);
+            }
+            Instruction::LessEqual { result, op1, op2 } => {
+                let less_equal = self.number(op1)? <= self.number(op2)?;
+                self.registers[result] = Value::boolean(less_equal);
+            }
+            Instruction::Not { result, op } => {
+                let b = self.boolean(op)?;
+                self.registers[result] = Value::boolean(!b);
+            }
+            Instruction::And { result, op1, op2 } => {
+                let b = self.boolean(op1)? && self.boolean(op2)?;
+                self.registers[result] = Value::boolean(b);
+            }
+            Instruction::Or { result, op1, op2 } => {
+                let b = self.boolean(op1)? || self.boolean(op2)?;
+                self.registers[result] = Value::boolean(b);
+            }
+            Instruction::StringConcat { result, op1, op2 } => {
+                let mut s = self.string(op1)?.to_string();
+                s.push_str(self.string(op2)?);
+                let ptr = self.memory.allocate_string(&s, &mut self.registers.0);
+                if ptr.is_null() {
+                    return Err(ErrorKind::OutOfMemory);
                 }
-                Instruction::Move { result, from } => {
-                    self.registers[result] = self.registers[from];
+                self.registers[result] = Value::from_ptr(ptr);
+            }
+            Instruction::StringLength { result, op } => {
+                let len = self.string(op)?.chars().count();
+                self.registers[result] = Value::number(len as i32);
+            }
+            Instruction::StringEqual { result, op1, op2 } => {
+                let equal = self.string(op1)? == self.string(op2)?;
+                self.registers[result] = Value::boolean(equal);
+            }
+            Instruction::NumberToString { result, op } => {
+                let s = self.number(op)?.to_string();
+                let ptr = self.memory.allocate_string(&s, &mut self.registers.0);
+                if ptr.is_null() {
+                    return Err(ErrorKind::OutOfMemory);
                 }
-                Instruction::Swap { from, to } => {
-                    self.registers.swap(from, to);
+                self.registers[result] = Value::from_ptr(ptr);
+            }
+            Instruction::StringToNumber { result, op } => {
+                let s = self.string(op)?;
+                let number = s
+                    .parse()
+                    .map_err(|_| ErrorKind::InvalidNumber(s.to_string()))?;
+                self.registers[result] = Value::number(number);
+            }
+            Instruction::Move { result, from } => {
+                self.registers[result] = self.registers[from];
+            }
+            Instruction::Swap { from, to } => {
+                self.registers.swap(from, to);
+            }
+            Instruction::CallHost {
+                result,
+                function,
+                args,
+            } => {
+                let identifier = self
+                    .chunk
+                    .get_data(function)
+                    .map(|ptr| Value::from_ptr(ptr as *mut Value))
+                    .filter(|value| self.is_string(*value))
+                    .ok_or(ErrorKind::InvalidMemoryAccess)?;
+                let identifier = unsafe { identifier.as_str() };
+                let function = self
+                    .host_functions
+                    .get(identifier)
+                    .ok_or_else(|| ErrorKind::UnknownHostFunction(identifier.to_string()))?;
+                let args = args
+                    .iter()
+                    .map(|reg| self.to_host(self.registers[*reg]))
+                    .collect::<Vec<_>>();
+                let value = match function.call(&args).map_err(ErrorKind::HostFunction)? {
+                    crate::Value::Number(n) => Value::number(n),
+                    crate::Value::Bool(b) => Value::boolean(b),
+                    crate::Value::String(s) => {
+                        let ptr = self.memory.allocate_string(&s, &mut self.registers.0);
+                        if ptr.is_null() {
+                            return Err(ErrorKind::OutOfMemory);
+                        }
+                        Value::from_ptr(ptr)
+                    }
+                    crate::Value::Opaque => {
+                        return Err(ErrorKind::HostFunction(format!(
+                            "{} returned an opaque value",
+                            identifier
+                        )))
+                    }
+                };
+                self.registers[result] = value;
+            }
+        }
+
+        self.pc = cursor.position();
+        Ok(Step::Continue)
+    }
+
+    /// Number of cells to allocate.
+    fn cell_count(&self, reg: Reg) -> Result<usize, ErrorKind> {
+        let value = self.registers[reg];
+        match self.number(reg)? {
+            n if n >= 0 => Ok(n as usize),
+            _ => Err(ErrorKind::TypeMismatch {
+                expected: "non-negative number",
+                actual: value,
+            }),
+        }
+    }
+
+    fn pointer(&self, reg: Reg) -> Result<*mut Value, ErrorKind> {
+        let value = self.registers[reg];
+        if value.tag() == ValueTag::Pointer {
+            Ok(value.as_ptr())
+        } else {
+            Err(ErrorKind::TypeMismatch {
+                expected: "pointer",
+                actual: value,
+            })
+        }
+    }
+
+    /// Address `offset` cells away from the pointer in `reg` that can be read from.
+    fn address(&self, reg: Reg, offset: isize) -> Result<*mut Value, ErrorKind> {
+        let addr = self.pointer(reg)?.wrapping_offset(offset);
+        if self.memory.contains(addr) || self.chunk.data_contains(addr) {
+            Ok(addr)
+        } else {
+            Err(ErrorKind::InvalidMemoryAccess)
+        }
+    }
+
+    /// Same as [`address`](Self::address), but the address must be in the heap, as data segment
+    /// is read-only.
+    fn heap_address(&self, reg: Reg, offset: isize) -> Result<*mut Value, ErrorKind> {
+        let addr = self.pointer(reg)?.wrapping_offset(offset);
+        if self.memory.contains(addr) {
+            Ok(addr)
+        } else {
+            Err(ErrorKind::InvalidMemoryAccess)
+        }
+    }
+
+    fn constant(&self, data_ref: DataRef) -> Result<Value, ErrorKind> {
+        let ptr = self
+            .chunk
+            .get_data(data_ref)
+            .ok_or(ErrorKind::InvalidMemoryAccess)?;
+        Ok(unsafe { *ptr })
+    }
+
+    fn jump_target(&self, target: i64) -> Result<u64, ErrorKind> {
+        if 0 <= target && (target as usize) < self.chunk.code().len() {
+            Ok(target as u64)
+        } else {
+            Err(ErrorKind::InvalidJump { target })
+        }
+    }
+
+    fn number(&self, reg: Reg) -> Result<i32, ErrorKind> {
+        let value = self.registers[reg];
+        if value.tag() == ValueTag::Number {
+            Ok(value.as_number())
+        } else {
+            Err(ErrorKind::TypeMismatch {
+                expected: "number",
+                actual: value,
+            })
+        }
+    }
+
+    /// Number that can be used as a divisor.
+    fn nonzero(&self, reg: Reg) -> Result<i32, ErrorKind> {
+        match self.number(reg)? {
+            0 => Err(ErrorKind::DivisionByZero),
+            n => Ok(n),
+        }
+    }
+
+    fn boolean(&self, reg: Reg) -> Result<bool, ErrorKind> {
+        let value = self.registers[reg];
+        if value.is_boolean() {
+            Ok(value.as_boolean())
+        } else {
+            Err(ErrorKind::TypeMismatch {
+                expected: "boolean",
+                actual: value,
+            })
+        }
+    }
+
+    fn string(&self, reg: Reg) -> Result<&str, ErrorKind> {
+        let value = self.registers[reg];
+        if self.is_string(value) {
+            Ok(unsafe { value.as_str() })
+        } else {
+            Err(ErrorKind::TypeMismatch {
+                expected: "string",
+                actual: value,
+            })
+        }
+    }
+
+    /// Convert VM value to a value that can outlive the VM.
+    pub fn to_host(&self, value: Value) -> crate::Value {
+        match value.tag() {
+            ValueTag::Number => crate::Value::Number(value.as_number()),
+            ValueTag::Constructor if value.is_boolean() => crate::Value::Bool(value.as_boolean()),
+            ValueTag::Pointer if self.is_string(value) => {
+                crate::Value::String(unsafe { value.as_str() }.to_string())
+            }
+            _ => crate::Value::Opaque,
+        }
+    }
+
+    /// Decode value for debugging.
+    pub fn inspect(&self, value: Value) -> DebugValue {
+        match value.tag() {
+            ValueTag::Pointer if self.is_string(value) => {
+                DebugValue::String(unsafe { value.as_str() }.to_string())
+            }
+            ValueTag::Pointer => DebugValue::Pointer(value.as_ptr() as usize),
+            ValueTag::Number => DebugValue::Number(value.as_number()),
+            ValueTag::Constructor if value.is_boolean() => DebugValue::Bool(value.as_boolean()),
+            ValueTag::Constructor => {
+                let (type_tag, constructor) = value.as_constructor();
+                DebugValue::Constructor {
+                    type_tag,
+                    constructor,
                 }
             }
+            ValueTag::FunctionTag => DebugValue::FunctionTag(value.as_function_tag()),
+            ValueTag::StringTag => DebugValue::StringTag(value.as_string_tag()),
+            ValueTag::MovedOut => DebugValue::MovedOut(value.as_moved_out() as usize),
+            ValueTag::SizeTag => DebugValue::SizeTag(value.as_size_tag()),
+            ValueTag::Invalid => DebugValue::Invalid(value.as_invalid()),
+        }
+    }
+
+    /// Heap record `address` points into. Returns `None` if `address` is outside of the heap or
+    /// points to a string.
+    pub fn record(&self, address: usize) -> Option<Record> {
+        let ptr = address as *mut Value;
+        if !self.memory.contains(ptr) {
+            return None;
+        }
+
+        // Record fields are never size tags, so the closest size tag before the pointer is the
+        // header of the record.
+        let mut header = ptr.wrapping_sub(1);
+        while self.memory.contains(header) && unsafe { *header }.tag() != ValueTag::SizeTag {
+            header = header.wrapping_sub(1);
+        }
+        if !self.memory.contains(header) {
+            return None;
+        }
+
+        let start = header.wrapping_add(1);
+        let size = unsafe { *header }.as_size_tag() - 1;
+        let fields = unsafe { std::slice::from_raw_parts(start, size) };
+        if matches!(fields.first(), Some(field) if field.tag() == ValueTag::StringTag) {
+            return None;
+        }
+
+        Some(Record {
+            address: start as usize,
+            offset: (address - start as usize) / std::mem::size_of::<Value>(),
+            fields: fields.iter().map(|field| self.inspect(*field)).collect(),
+        })
+    }
+
+    fn is_string(&self, value: Value) -> bool {
+        // Objects are preceded by a size tag, so it is safe to look at the first cell of non-empty
+        // ones. Record fields are never size tags, so pointers into the middle of a record (as
+        // closures are) are not mistaken for objects.
+        if value.tag() != ValueTag::Pointer {
+            return false;
+        }
+        let ptr = value.as_ptr();
+        let header = ptr.wrapping_sub(1);
+        if !self.memory.contains(header) && !self.chunk.data_contains(header) {
+            return false;
+        }
+        unsafe {
+            let size = *header;
+            size.tag() == ValueTag::SizeTag
+                && size.as_size_tag() > 1
+                && (*ptr).tag() == ValueTag::StringTag
         }
     }
 }
@@ -203,7 +698,7 @@ mod tests {
             chunk.write(i).unwrap();
         });
 
-        let mut vm = Vm::new(chunk);
+        let mut vm = Vm::new(chunk, &Options::default());
         vm.run().unwrap();
     }
 
@@ -224,7 +719,7 @@ mod tests {
             chunk.write(i).unwrap();
         });
 
-        let mut vm = Vm::new(chunk);
+        let mut vm = Vm::new(chunk, &Options::default());
         vm.run().unwrap();
 
         assert_eq!(Value::number(42), vm.registers[Reg(0)]);
@@ -258,12 +753,324 @@ mod tests {
             chunk.write(i).unwrap();
         });
 
-        let mut vm = Vm::new(chunk);
+        let mut vm = Vm::new(chunk, &Options::default());
         vm.run().unwrap();
 
         assert_eq!(Value::number(3), vm.registers[Reg(3)]);
     }
 
+    #[test]
+    fn run_string_equal() {
+        let mut chunk = Chunk::new();
+
+        let hello_1 = chunk.alloc_string("hello");
+        let hello_2 = chunk.alloc_string("hello");
+
+        [
+            Instruction::ConstantRef {
+                result: Reg(1),
+                value: hello_1,
+            },
+            Instruction::ConstantRef {
+                result: Reg(2),
+                value: hello_2,
+            },
+            Instruction::StringEqual {
+                result: Reg(3),
+                op1: Reg(1),
+                op2: Reg(2),
+            },
+            Instruction::StringConcat {
+                result: Reg(4),
+                op1: Reg(1),
+                op2: Reg(2),
+            },
+            Instruction::HaltReg { reg: Reg(4) },
+        ]
+        .iter()
+        .for_each(|i| {
+            chunk.write(i).unwrap();
+        });
+
+        let mut vm = Vm::new(chunk, &Options::default());
+        let result = vm.run().unwrap().unwrap();
+
+        assert_eq!(Value::boolean(true), vm.registers[Reg(3)]);
+        assert_eq!(
+            crate::Value::String("hellohello".to_string()),
+            vm.to_host(result)
+        );
+    }
+
+    fn run_binary(op1: Value, op2: Value, op: Instruction) -> Result<Option<Value>, Error> {
+        let mut chunk = Chunk::new();
+        let op1 = chunk.alloc_data(&[op1]);
+        let op2 = chunk.alloc_data(&[op2]);
+
+        [
+            Instruction::ConstantValue {
+                result: Reg(1),
+                value: op1,
+            },
+            Instruction::ConstantValue {
+                result: Reg(2),
+                value: op2,
+            },
+            op,
+            Instruction::HaltReg { reg: Reg(0) },
+        ]
+        .iter()
+        .for_each(|i| {
+            chunk.write(i).unwrap();
+        });
+
+        Vm::new(chunk, &Options::default()).run()
+    }
+
+    #[test]
+    fn run_arithmetic() {
+        let div = Instruction::Div {
+            result: Reg(0),
+            op1: Reg(1),
+            op2: Reg(2),
+        };
+        let less = Instruction::Less {
+            result: Reg(0),
+            op1: Reg(1),
+            op2: Reg(2),
+        };
+        assert_eq!(
+            Some(Value::number(-3)),
+            run_binary(Value::number(-7), Value::number(2), div).unwrap()
+        );
+        assert_eq!(
+            Some(Value::boolean(true)),
+            run_binary(Value::number(-7), Value::number(2), less).unwrap()
+        );
+    }
+
+    #[test]
+    fn run_arithmetic_errors() {
+        let mul = || Instruction::Mul {
+            result: Reg(0),
+            op1: Reg(1),
+            op2: Reg(2),
+        };
+        let modulo = Instruction::Mod {
+            result: Reg(0),
+            op1: Reg(1),
+            op2: Reg(2),
+        };
+        assert!(matches!(
+            run_binary(Value::number(1 << 16), Value::number(1 << 16), mul()),
+            Err(Error {
+                kind: ErrorKind::Overflow,
+                ..
+            })
+        ));
+        assert!(matches!(
+            run_binary(Value::number(1), Value::number(0), modulo),
+            Err(Error {
+                kind: ErrorKind::DivisionByZero,
+                ..
+            })
+        ));
+        assert!(matches!(
+            run_binary(Value::number(1), Value::boolean(true), mul()),
+            Err(Error {
+                kind: ErrorKind::TypeMismatch {
+                    expected: "number",
+                    ..
+                },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn run_switch() {
+        let mut chunk = Chunk::new();
+        let constructor = chunk.alloc_data(&[Value::constructor(0, 2)]);
+
+        [
+            Instruction::ConstantValue {
+                result: Reg(0),
+                value: constructor,
+            },
+            Instruction::Switch {
+                reg: Reg(0),
+                offsets: vec![32, 32, 40],
+            },
+            Instruction::Halt,
+            Instruction::MatchFailure,
+        ]
+        .iter()
+        .for_each(|i| {
+            chunk.write(i).unwrap();
+        });
+
+        let mut vm = Vm::new(chunk, &Options::default());
+        assert!(matches!(
+            vm.run(),
+            Err(Error {
+                kind: ErrorKind::MatchFailure,
+                position: 48,
+                ..
+            })
+        ));
+    }
+
+    /// Run `instructions` with `data` at the beginning of data segment.
+    fn run_instructions(
+        data: &[Value],
+        instructions: &[Instruction],
+    ) -> Result<Option<Value>, Error> {
+        let mut chunk = Chunk::new();
+        chunk.alloc_data(data);
+        instructions.iter().for_each(|i| {
+            chunk.write(i).unwrap();
+        });
+
+        Vm::new(chunk, &Options::default()).run()
+    }
+
+    #[test]
+    fn run_invalid_code() {
+        let move_ = || Instruction::Move {
+            result: Reg(0),
+            from: Reg(1),
+        };
+
+        // no halt at the end
+        assert!(matches!(
+            run_instructions(&[], &[move_()]),
+            Err(Error {
+                kind: ErrorKind::InvalidInstruction,
+                position: 8,
+                ..
+            })
+        ));
+        assert!(matches!(
+            run_instructions(&[], &[move_(), Instruction::JumpConst { offset: -16 }]),
+            Err(Error {
+                kind: ErrorKind::InvalidJump { target: -8 },
+                position: 8,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn run_invalid_memory_access() {
+        let data = [Value::number(1)];
+
+        // loading from a number
+        assert!(matches!(
+            run_instructions(
+                &data,
+                &[
+                    Instruction::ConstantValue {
+                        result: Reg(1),
+                        value: DataRef(0),
+                    },
+                    Instruction::Load {
+                        result: Reg(0),
+                        addr: Reg(1),
+                        offset: 0,
+                    },
+                ]
+            ),
+            Err(Error {
+                kind: ErrorKind::TypeMismatch {
+                    expected: "pointer",
+                    ..
+                },
+                ..
+            })
+        ));
+        // reading past the end of a record
+        assert!(matches!(
+            run_instructions(
+                &data,
+                &[
+                    Instruction::AllocConst {
+                        result: Reg(1),
+                        cells_to_allocate: 1,
+                    },
+                    Instruction::Load {
+                        result: Reg(0),
+                        addr: Reg(1),
+                        offset: 1,
+                    },
+                ]
+            ),
+            Err(Error {
+                kind: ErrorKind::InvalidMemoryAccess,
+                position: 8,
+                ..
+            })
+        ));
+        // writing to data segment
+        assert!(matches!(
+            run_instructions(
+                &data,
+                &[
+                    Instruction::ConstantRef {
+                        result: Reg(1),
+                        value: DataRef(0),
+                    },
+                    Instruction::StoreReg {
+                        addr: Reg(1),
+                        offset: 0,
+                        reg_to_store: Reg(1),
+                    },
+                ]
+            ),
+            Err(Error {
+                kind: ErrorKind::InvalidMemoryAccess,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn run_with_empty_heap() {
+        let chunk = || {
+            let mut chunk = Chunk::new();
+            [
+                Instruction::AllocConst {
+                    result: Reg(0),
+                    cells_to_allocate: 2,
+                },
+                Instruction::HaltReg { reg: Reg(0) },
+            ]
+            .iter()
+            .for_each(|i| {
+                chunk.write(i).unwrap();
+            });
+            chunk
+        };
+
+        // heap grows as needed
+        let options = Options {
+            heap_size: 0,
+            ..Options::default()
+        };
+        assert!(Vm::new(chunk(), &options).run().is_ok());
+
+        let options = Options {
+            max_heap_size: Some(0),
+            ..Options::default()
+        };
+        assert!(matches!(
+            Vm::new(chunk(), &options).run(),
+            Err(Error {
+                kind: ErrorKind::OutOfMemory,
+                ..
+            })
+        ));
+    }
+
     #[test]
     fn run_complex() {
         use crate::compiler::cps::Value;
@@ -311,12 +1118,12 @@ mod tests {
                 Box::new([Value::Int(42), Value::Var(Var(4))]),
             )),
         ));
-        let mut gen = VarGen { next_var: 9 };
+        let mut gen = VarGen::new(9);
         let result = closure_conversion(&mut gen, &input);
 
-        let chunk = cps_to_bytecode(&result);
+        let chunk = cps_to_bytecode(&result, &gen.info);
 
-        let mut vm = Vm::new(chunk);
+        let mut vm = Vm::new(chunk, &Options::default());
         vm.run().unwrap();
     }
 }
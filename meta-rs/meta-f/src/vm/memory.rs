@@ -1,45 +1,210 @@
+//! VM heap with a copying garbage collector.
+//!
+//! Every object is prepended with a size tag. Objects are either records of values or strings
+//! (first cell is a string tag followed by raw bytes, which are never scanned). Pointers may point
+//! to the middle of a record (e.g., closures do), but never into a string.
 use std::alloc::Layout;
 use std::mem::{align_of, size_of};
 
-use crate::vm::value::Value;
+use log::debug;
+
+use crate::vm::value::{string_cells, write_string, Value, ValueTag};
+
+/// Garbage collector statistics.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct GcStats {
+    /// Number of garbage collections.
+    pub collections: usize,
+    /// Total number of cells allocated (including size tags).
+    pub allocated_cells: usize,
+    /// Total number of cells copied by garbage collections.
+    pub copied_cells: usize,
+    /// Current heap size in cells.
+    pub heap_cells: usize,
+}
 
 pub(crate) struct Memory {
     memory: *mut Value,
     next: *mut Value,
     limit: *mut Value,
     count: usize,
+    /// Heap never grows beyond this number of cells.
+    max_count: usize,
+    stats: GcStats,
 }
 
 impl Memory {
-    pub fn new(count: usize) -> Self {
-        unsafe {
-            #[allow(clippy::cast_ptr_alignment)]
-            let buf = std::alloc::alloc(Self::layout(count)) as *mut Value;
-            Self {
-                memory: buf,
-                next: buf,
-                limit: buf.add(count),
-                count,
-            }
+    /// Create a heap of `count` cells that can grow up to `max_count` cells. The heap has at least
+    /// one cell, as zero-sized blocks can't be allocated.
+    pub fn new(count: usize, max_count: usize) -> Self {
+        let max_count = std::cmp::max(max_count, 1);
+        let count = std::cmp::min(std::cmp::max(count, 1), max_count);
+        let buf = Self::alloc(count);
+        assert!(!buf.is_null(), "unable to allocate VM memory");
+        Self {
+            memory: buf,
+            next: buf,
+            limit: unsafe { buf.add(count) },
+            count,
+            max_count,
+            stats: GcStats {
+                heap_cells: count,
+                ..GcStats::default()
+            },
         }
     }
 
-    pub fn allocate_cells(&mut self, n_cells: usize) -> *mut Value {
-        unsafe {
-            let n_cells = n_cells + 1; // add one cell for size tag
-            let next = self.next.add(n_cells);
-            if next > self.limit {
-                // TODO: unlikely
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    /// Whether `ptr` points to an allocated cell.
+    pub fn contains(&self, ptr: *const Value) -> bool {
+        self.memory as *const Value <= ptr && ptr < self.next
+    }
+
+    /// Allocate `n_cells` cells, collecting garbage if there is not enough memory. Pointers in
+    /// `roots` are updated to the new locations of objects.
+    ///
+    /// Returns null pointer if out of memory.
+    pub fn allocate_cells(&mut self, n_cells: usize, roots: &mut [Value]) -> *mut Value {
+        let n_cells = n_cells + 1; // add one cell for size tag
+        if self.free() < n_cells {
+            self.collect(roots, self.count);
+
+            // Grow heap if it's more than half full after collection, so collections don't become
+            // too frequent.
+            let needed = self.used() + n_cells;
+            if needed > self.count / 2 && self.count < self.max_count {
+                let count = std::cmp::max(self.count * 2, needed);
+                self.collect(roots, std::cmp::min(count, self.max_count));
+            }
+
+            if self.free() < n_cells {
                 return std::ptr::null_mut();
             }
+        }
 
+        unsafe {
             *self.next = Value::size_tag(n_cells);
             let ptr = self.next.add(1);
-            self.next = next;
+            for i in 0..n_cells - 1 {
+                // the collector might scan the object before all fields are initialized
+                *ptr.add(i) = Value::invalid(0);
+            }
+            self.next = self.next.add(n_cells);
+            self.stats.allocated_cells += n_cells;
             ptr
         }
     }
 
+    /// Allocate a string object. Returns null pointer if out of memory.
+    pub fn allocate_string(&mut self, s: &str, roots: &mut [Value]) -> *mut Value {
+        let n_cells = string_cells(s);
+        let ptr = self.allocate_cells(n_cells, roots);
+        if !ptr.is_null() {
+            write_string(unsafe { std::slice::from_raw_parts_mut(ptr, n_cells) }, s);
+        }
+        ptr
+    }
+
+    /// Copy all objects reachable from `roots` to a new heap of `count` cells (Cheney's
+    /// algorithm). If new heap can't be allocated, the current one is kept.
+    fn collect(&mut self, roots: &mut [Value], count: usize) {
+        let to = Self::alloc(count);
+        if to.is_null() {
+            return;
+        }
+
+        unsafe {
+            let mut next = to;
+            for root in roots.iter_mut() {
+                *root = self.forward(*root, &mut next);
+            }
+
+            let mut scan = to;
+            while scan < next {
+                let n_cells = (*scan).as_size_tag();
+                let object = scan.add(1);
+                if n_cells > 1 && (*object).tag() != ValueTag::StringTag {
+                    for i in 0..n_cells - 1 {
+                        *object.add(i) = self.forward(*object.add(i), &mut next);
+                    }
+                }
+                scan = scan.add(n_cells);
+            }
+
+            let copied = next.offset_from(to) as usize;
+            debug!(
+                "gc: copied {} of {} cells, heap size {} -> {} cells",
+                copied,
+                self.used(),
+                self.count,
+                count
+            );
+
+            std::alloc::dealloc(self.memory as *mut u8, Self::layout(self.count));
+            self.memory = to;
+            self.next = next;
+            self.limit = to.add(count);
+            self.count = count;
+
+            self.stats.collections += 1;
+            self.stats.copied_cells += copied;
+            self.stats.heap_cells = count;
+        }
+    }
+
+    /// Copy the object `value` points to (if it is a pointer into the heap) to `next` and return
+    /// the pointer to the new location. Copied objects are replaced with a `MovedOut` tag, so each
+    /// object is only copied once.
+    unsafe fn forward(&self, value: Value, next: &mut *mut Value) -> Value {
+        if value.tag() != ValueTag::Pointer {
+            return value;
+        }
+        let ptr = value.as_ptr();
+        // Pointer to an empty record at the end of the heap is equal to `next`.
+        if ptr <= self.memory || ptr > self.next {
+            // Not a heap object (e.g., a constant from the chunk data).
+            return value;
+        }
+
+        // Find the object header. Record fields are never size tags, so this skips to the
+        // beginning of the record for pointers into the middle of it.
+        let mut header = ptr.sub(1);
+        while !matches!((*header).tag(), ValueTag::SizeTag | ValueTag::MovedOut) {
+            header = header.sub(1);
+        }
+
+        let new_header = if (*header).tag() == ValueTag::MovedOut {
+            (*header).as_moved_out()
+        } else {
+            let n_cells = (*header).as_size_tag();
+            std::ptr::copy_nonoverlapping(header, *next, n_cells);
+            let new_header = *next;
+            *next = next.add(n_cells);
+            *header = Value::moved_out(new_header);
+            new_header
+        };
+
+        Value::from_ptr(new_header.offset(ptr.offset_from(header)))
+    }
+
+    fn free(&self) -> usize {
+        unsafe { self.limit.offset_from(self.next) as usize }
+    }
+
+    fn used(&self) -> usize {
+        unsafe { self.next.offset_from(self.memory) as usize }
+    }
+
+    fn alloc(count: usize) -> *mut Value {
+        #[allow(clippy::cast_ptr_alignment)]
+        unsafe {
+            std::alloc::alloc(Self::layout(count)) as *mut Value
+        }
+    }
+
     fn layout(count: usize) -> Layout {
         unsafe {
             // Layout::repeat() would be helpful here, but it is nightly-only
@@ -55,3 +220,96 @@ impl Drop for Memory {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Allocate a record of `n` numbers.
+    fn record(memory: &mut Memory, roots: &mut [Value], n: usize) -> Value {
+        let ptr = memory.allocate_cells(n, roots);
+        assert!(!ptr.is_null());
+        for i in 0..n {
+            unsafe { *ptr.add(i) = Value::number(i as i32) };
+        }
+        Value::from_ptr(ptr)
+    }
+
+    #[test]
+    fn test_garbage_is_collected() {
+        let mut memory = Memory::new(64, usize::MAX);
+        let mut roots = [Value::invalid(0); 2];
+
+        roots[0] = record(&mut memory, &mut roots, 3);
+        for _ in 0..100 {
+            roots[1] = record(&mut memory, &mut roots, 7);
+        }
+
+        let stats = memory.stats();
+        assert!(stats.collections > 0);
+        assert_eq!(64, stats.heap_cells);
+        assert_eq!(Value::number(2), unsafe { *roots[0].as_ptr().add(2) });
+        assert_eq!(Value::number(6), unsafe { *roots[1].as_ptr().add(6) });
+    }
+
+    #[test]
+    fn test_heap_grows() {
+        let mut memory = Memory::new(16, usize::MAX);
+        let mut roots = [Value::invalid(0); 8];
+
+        for i in 0..roots.len() {
+            roots[i] = record(&mut memory, &mut roots, 4);
+        }
+
+        assert!(memory.stats().heap_cells >= 8 * 5);
+        for root in roots.iter() {
+            assert_eq!(Value::number(3), unsafe { *root.as_ptr().add(3) });
+        }
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let mut memory = Memory::new(16, 32);
+        let mut roots = [Value::invalid(0); 8];
+
+        for i in 0..6 {
+            roots[i] = record(&mut memory, &mut roots, 4);
+        }
+
+        assert!(memory.allocate_cells(4, &mut roots).is_null());
+        assert_eq!(32, memory.stats().heap_cells);
+    }
+
+    #[test]
+    fn test_shared_and_interior_pointers() {
+        let mut memory = Memory::new(32, usize::MAX);
+        let mut roots = [Value::invalid(0); 3];
+
+        roots[0] = record(&mut memory, &mut roots, 4);
+        let ptr = memory.allocate_cells(2, &mut roots);
+        unsafe {
+            // record pointing to the middle of another record and to itself
+            *ptr = Value::from_ptr(roots[0].as_ptr().add(2));
+            *ptr.add(1) = Value::from_ptr(ptr);
+        }
+        roots[1] = Value::from_ptr(ptr);
+        roots[2] = Value::from_ptr(unsafe { roots[0].as_ptr().add(1) });
+        roots[0] = Value::invalid(0);
+
+        let s = memory.allocate_string("Hello, world!", &mut roots);
+        roots[0] = Value::from_ptr(s);
+
+        memory.collect(&mut roots, 32);
+
+        unsafe {
+            let record = roots[1].as_ptr();
+            assert_eq!(roots[1], *record.add(1));
+            assert_eq!(Value::number(2), *(*record).as_ptr());
+            assert_eq!(Value::number(1), *roots[2].as_ptr());
+            assert_eq!((*record).as_ptr(), roots[2].as_ptr().add(1));
+            assert_eq!("Hello, world!", roots[0].as_str());
+        }
+        // 5 cells of the first record, 3 of the second, and 4 of the string
+        assert_eq!(12, memory.used());
+    }
+}
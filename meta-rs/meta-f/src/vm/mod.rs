@@ -1,5 +1,7 @@
 pub mod bytecode;
 pub mod chunk;
+pub mod debugger;
+pub mod format;
 pub mod machine;
 pub mod memory;
 pub mod value;
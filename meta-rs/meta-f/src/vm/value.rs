@@ -18,9 +18,21 @@ pub enum ValueTag {
     Invalid = 0b111,
 }
 
+/// Type tag of booleans. Types defined in f programs are tagged with compiler variables, which
+/// never get that large.
+pub const BOOL_TYPE_TAG: u64 = (1 << 48) - 1;
+
 #[derive(Eq, PartialEq, Copy, Clone, Hash)]
 pub struct Value(u64);
 impl Value {
+    /// Raw representation of the value.
+    pub(crate) fn to_bits(self) -> u64 {
+        self.0
+    }
+    pub(crate) fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
     pub fn tag(self) -> ValueTag {
         unsafe { ValueTag::from_unchecked(self.0 & 0b111) }
     }
@@ -67,6 +79,19 @@ impl Value {
         (type_tag, constructor)
     }
 
+    /// Booleans are constructors of a builtin type: `false` is the 0-th constructor and `true` is
+    /// the 1-st one, so they can be switched on directly.
+    pub fn boolean(b: bool) -> Self {
+        Self::constructor(BOOL_TYPE_TAG, b as u16)
+    }
+    pub fn is_boolean(self) -> bool {
+        self.tag() == ValueTag::Constructor && self.as_constructor().0 == BOOL_TYPE_TAG
+    }
+    pub fn as_boolean(self) -> bool {
+        assert!(self.is_boolean());
+        self.as_constructor().1 != 0
+    }
+
     pub fn function_tag(n_args: u8) -> Self {
         Self((n_args as u64) << 8 | u64::from(ValueTag::FunctionTag))
     }
@@ -83,6 +108,18 @@ impl Value {
         (self.0 >> 32) as usize
     }
 
+    /// Returns the contents of a string object `self` points to.
+    ///
+    /// # Safety
+    ///
+    /// `self` must point to a live string object and the returned reference must not outlive it.
+    pub(crate) unsafe fn as_str<'a>(self) -> &'a str {
+        let ptr = self.as_ptr();
+        let len = (*ptr).as_string_tag();
+        let bytes = std::slice::from_raw_parts(ptr.add(1) as *const u8, len);
+        std::str::from_utf8_unchecked(bytes)
+    }
+
     pub fn moved_out(ptr: *mut Value) -> Self {
         let ptr = ptr as usize as u64;
         Self(ptr | u64::from(ValueTag::MovedOut))
@@ -93,6 +130,23 @@ impl Value {
     }
 }
 
+/// Number of cells a string object occupies (excluding size tag).
+pub(crate) fn string_cells(s: &str) -> usize {
+    1 + s.as_bytes().chunks(8).len()
+}
+
+/// Write string object to `cells`: string tag followed by UTF-8 bytes padded to whole cells.
+///
+/// `cells` must be at least [`string_cells`] long.
+pub(crate) fn write_string(cells: &mut [Value], s: &str) {
+    cells[0] = Value::string_tag(s.len());
+    for (cell, chunk) in cells[1..].iter_mut().zip(s.as_bytes().chunks(8)) {
+        let mut bytes = [0; 8];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        *cell = Value(u64::from_ne_bytes(bytes));
+    }
+}
+
 impl Debug for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self.tag() {
@@ -137,6 +191,24 @@ mod tests {
         assert_eq!(std::mem::align_of::<Value>(), std::mem::align_of::<f64>());
     }
 
+    #[test]
+    fn string_roundtrip() {
+        let s = "Hello, world!";
+        let mut cells = vec![Value::invalid(0); string_cells(s)];
+        write_string(&mut cells, s);
+
+        assert_eq!(3, cells.len());
+        assert_eq!(s, unsafe { Value::from_ptr(cells.as_mut_ptr()).as_str() });
+    }
+
+    #[test]
+    fn boolean_roundtrip() {
+        assert!(Value::boolean(true).as_boolean());
+        assert!(!Value::boolean(false).as_boolean());
+        assert_eq!((BOOL_TYPE_TAG, 1), Value::boolean(true).as_constructor());
+        assert!(!Value::constructor(0, 1).is_boolean());
+    }
+
     #[test]
     fn value_align_ptr() {
         assert_eq!(
@@ -0,0 +1,305 @@
+//! Compact binary store format.
+//!
+//! A file starts with [`MAGIC`] followed by a single format version byte. The rest is a sequence of
+//! datoms, each encoded as four fields (id, entity, attribute, value).
+//!
+//! Fields are interned in a string table that is built on the fly. A field is encoded as an
+//! unsigned LEB128 number `n`. `n == 0` introduces a new string: its length in bytes (LEB128) and
+//! UTF-8 bytes follow, and the string gets the next free index in the table. Otherwise, `n - 1` is
+//! an index of a previously introduced string. This allows writing and reading datoms one at a
+//! time without keeping the whole store in memory.
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use crate::{Datom, Error, Field, Result, Store};
+
+/// Leading NUL byte guarantees the binary format is never confused with JSON.
+pub const MAGIC: &[u8; 5] = b"\0META";
+pub const VERSION: u8 = 1;
+
+pub struct BinaryWriter<W: Write> {
+    writer: W,
+    strings: HashMap<Field, u64>,
+}
+
+impl<W: Write> BinaryWriter<W> {
+    /// Create a writer and write the file header.
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(BinaryWriter {
+            writer,
+            strings: HashMap::new(),
+        })
+    }
+
+    pub fn write_datom(&mut self, datom: &Datom) -> Result<()> {
+        self.write_field(&datom.id)?;
+        self.write_field(&datom.entity)?;
+        self.write_field(&datom.attribute)?;
+        self.write_field(&datom.value)
+    }
+
+    /// Flush and return the underlying writer.
+    pub fn into_inner(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_field(&mut self, field: &Field) -> Result<()> {
+        if let Some(index) = self.strings.get(field) {
+            return write_varint(&mut self.writer, index + 1);
+        }
+
+        self.strings
+            .insert(field.clone(), self.strings.len() as u64);
+        let s = field.as_ref();
+        write_varint(&mut self.writer, 0)?;
+        write_varint(&mut self.writer, s.len() as u64)?;
+        self.writer.write_all(s.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Reads datoms one by one. Reads are byte-sized, so `reader` should be buffered.
+pub struct BinaryReader<R: Read> {
+    reader: R,
+    strings: Vec<Field>,
+}
+
+impl<R: Read> BinaryReader<R> {
+    /// Create a reader and check the file header.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0; 6];
+        reader.read_exact(&mut header)?;
+        if &header[..5] != MAGIC {
+            return Err(Error::BinaryError("not a binary store".to_string()));
+        }
+        if header[5] != VERSION {
+            return Err(Error::BinaryError(format!(
+                "unsupported format version {}",
+                header[5]
+            )));
+        }
+
+        Ok(BinaryReader {
+            reader,
+            strings: Vec::new(),
+        })
+    }
+
+    /// Read next datom. Returns `None` at the end of input.
+    pub fn read_datom(&mut self) -> Result<Option<Datom>> {
+        let id = match self.read_byte()? {
+            None => return Ok(None),
+            Some(first) => self.read_field(first)?,
+        };
+        let entity = self.read_next_field()?;
+        let attribute = self.read_next_field()?;
+        let value = self.read_next_field()?;
+
+        Ok(Some(Datom::new(id, entity, attribute, value)))
+    }
+
+    fn read_next_field(&mut self) -> Result<Field> {
+        let first = self.read_byte()?.ok_or_else(unexpected_eof)?;
+        self.read_field(first)
+    }
+
+    fn read_field(&mut self, first: u8) -> Result<Field> {
+        let n = self.read_varint(first)?;
+        if n > 0 {
+            return self.strings.get(n as usize - 1).cloned().ok_or_else(|| {
+                Error::BinaryError(format!("string index {} is out of bounds", n - 1))
+            });
+        }
+
+        let first = self.read_byte()?.ok_or_else(unexpected_eof)?;
+        let len = self.read_varint(first)?;
+        let mut bytes = Vec::new();
+        (&mut self.reader).take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(unexpected_eof());
+        }
+        let s = String::from_utf8(bytes).map_err(|e| Error::BinaryError(e.to_string()))?;
+
+        let field = Field::from(s);
+        self.strings.push(field.clone());
+        Ok(field)
+    }
+
+    fn read_varint(&mut self, first: u8) -> Result<u64> {
+        let mut result = 0;
+        let mut shift = 0;
+        let mut byte = first;
+        loop {
+            // the 10th byte has room for a single bit
+            if shift >= 64 || (shift == 63 && byte & 0x7f > 1) {
+                return Err(Error::BinaryError("number is too large".to_string()));
+            }
+            result |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+            shift += 7;
+            byte = self.read_byte()?.ok_or_else(unexpected_eof)?;
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut buf = [0];
+        loop {
+            match self.reader.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(buf[0])),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for BinaryReader<R> {
+    type Item = Result<Datom>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_datom().transpose()
+    }
+}
+
+impl Store {
+    pub fn from_binary<R: Read>(r: R) -> Result<Store> {
+        let datoms = BinaryReader::new(r)?.collect::<Result<Vec<_>>>()?;
+
+        let mut store = Store::new();
+        if !datoms.is_empty() {
+            store.transact(Vec::new(), datoms);
+        }
+
+        Ok(store)
+    }
+
+    /// Write current datoms in binary format. Datoms are sorted, so the output is deterministic.
+    pub fn write_binary<W: Write>(&self, w: W) -> Result<()> {
+        let mut datoms = self.atoms().values().collect::<Vec<_>>();
+        datoms.sort_unstable();
+
+        let mut writer = BinaryWriter::new(w)?;
+        for datom in datoms {
+            writer.write_datom(datom)?;
+        }
+        writer.into_inner()?;
+        Ok(())
+    }
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut n: u64) -> Result<()> {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            writer.write_all(&[byte])?;
+            return Ok(());
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn unexpected_eof() -> Error {
+    Error::BinaryError("unexpected end of input".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    static CORE: &str = include_str!("../../../core.meta");
+
+    fn to_binary(store: &Store) -> Vec<u8> {
+        let mut buf = Vec::new();
+        store.write_binary(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let store = Store::from_str(CORE).unwrap();
+        let buf = to_binary(&store);
+
+        assert_eq!(store, Store::from_binary(buf.as_slice()).unwrap());
+        assert!(buf.len() < serde_json::to_vec(&store).unwrap().len());
+    }
+
+    #[test]
+    fn test_output_is_deterministic() {
+        let buf = to_binary(&Store::from_str(CORE).unwrap());
+        assert_eq!(buf, to_binary(&Store::from_binary(buf.as_slice()).unwrap()));
+    }
+
+    #[test]
+    fn test_interning() {
+        let store = Store::from_str(r#"["10", "10", "10", "10"]"#).unwrap();
+        let mut expected = MAGIC.to_vec();
+        expected.extend_from_slice(&[VERSION, 0, 2, b'1', b'0', 1, 1, 1]);
+        assert_eq!(expected, to_binary(&store));
+    }
+
+    #[test]
+    fn test_long_varint() {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300).unwrap();
+        assert_eq!(vec![0xac, 0x02], buf);
+
+        let mut reader = BinaryReader {
+            reader: &buf[1..],
+            strings: Vec::new(),
+        };
+        assert_eq!(300, reader.read_varint(buf[0]).unwrap());
+    }
+
+    #[test]
+    fn test_overlong_varint() {
+        let read = |buf: &[u8]| {
+            let mut reader = BinaryReader {
+                reader: &buf[1..],
+                strings: Vec::new(),
+            };
+            reader.read_varint(buf[0])
+        };
+
+        let mut buf = Vec::new();
+        write_varint(&mut buf, u64::MAX).unwrap();
+        assert_eq!(u64::MAX, read(&buf).unwrap());
+
+        // 65 bits
+        *buf.last_mut().unwrap() = 0x03;
+        assert!(matches!(read(&buf), Err(Error::BinaryError(_))));
+        // 11 bytes
+        *buf.last_mut().unwrap() = 0x81;
+        buf.push(0x00);
+        assert!(matches!(read(&buf), Err(Error::BinaryError(_))));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let store = Store::from_str(r#"["10", "11", "12", "13"]"#).unwrap();
+        let buf = to_binary(&store);
+
+        assert!(matches!(
+            Store::from_binary(&buf[..buf.len() - 1]),
+            Err(Error::BinaryError(_))
+        ));
+    }
+
+    #[test]
+    fn test_wrong_version() {
+        let mut buf = MAGIC.to_vec();
+        buf.push(VERSION + 1);
+
+        assert!(matches!(
+            Store::from_binary(buf.as_slice()),
+            Err(Error::BinaryError(_))
+        ));
+    }
+}
@@ -0,0 +1,130 @@
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::binary::MAGIC;
+use crate::{Datom, Result, Store};
+
+/// On-disk store formats.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    /// JSON array of datoms, as produced by `Serialize for Store`.
+    Json,
+    /// One JSON datom per line. Allows `;` comments at the end of line.
+    JsonLines,
+    /// See [`crate::binary`].
+    Binary,
+}
+
+impl Format {
+    /// Guess format from the beginning of input without consuming it.
+    ///
+    /// JSON array is distinguished from JSON lines by its first element being an array.
+    pub fn detect<R: BufRead>(r: &mut R) -> Result<Format> {
+        let buf = r.fill_buf()?;
+        if buf.starts_with(MAGIC) {
+            return Ok(Format::Binary);
+        }
+
+        let mut tokens = buf.iter().filter(|c| !c.is_ascii_whitespace());
+        match (tokens.next(), tokens.next()) {
+            (Some(b'['), Some(b'[')) | (Some(b'['), Some(b']')) => Ok(Format::Json),
+            _ => Ok(Format::JsonLines),
+        }
+    }
+
+    /// Format by file extension: `.metab` is binary, `.jsonl` is JSON lines, everything else is
+    /// JSON.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Format {
+        match path.as_ref().extension().and_then(|x| x.to_str()) {
+            Some("metab") => Format::Binary,
+            Some("jsonl") => Format::JsonLines,
+            _ => Format::Json,
+        }
+    }
+}
+
+/// Path of the transaction log saved along with the store at `path` (see [`Store::write_log`]):
+/// the store path with `.log` appended.
+pub fn log_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut path = path.as_ref().as_os_str().to_owned();
+    path.push(".log");
+    PathBuf::from(path)
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Format, String> {
+        match s {
+            "json" => Ok(Format::Json),
+            "jsonl" => Ok(Format::JsonLines),
+            "binary" => Ok(Format::Binary),
+            _ => Err(format!("unknown store format: {}", s)),
+        }
+    }
+}
+
+impl Store {
+    pub fn read<R: BufRead>(r: R, format: Format) -> Result<Store> {
+        match format {
+            Format::Json => Ok(serde_json::from_reader(r)?),
+            Format::JsonLines => Store::from_reader(r),
+            Format::Binary => Store::from_binary(r),
+        }
+    }
+
+    /// Read store detecting its format with [`Format::detect`].
+    pub fn read_any<R: BufRead>(mut r: R) -> Result<(Store, Format)> {
+        let format = Format::detect(&mut r)?;
+        Ok((Store::read(r, format)?, format))
+    }
+
+    pub fn write<W: Write>(&self, mut w: W, format: Format) -> Result<()> {
+        match format {
+            Format::Json => serde_json::to_writer_pretty(&mut w, self)?,
+            Format::JsonLines => {
+                let mut datoms = self.atoms().values().collect::<Vec<&Datom>>();
+                datoms.sort_unstable();
+                for datom in datoms {
+                    serde_json::to_writer(&mut w, datom)?;
+                    w.write_all(b"\n")?;
+                }
+            }
+            Format::Binary => return self.write_binary(w),
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    static CORE: &str = include_str!("../../../core.meta");
+
+    #[test]
+    fn test_roundtrip_all_formats() {
+        let store = Store::from_str(CORE).unwrap();
+        for &format in &[Format::Json, Format::JsonLines, Format::Binary] {
+            let mut buf = Vec::new();
+            store.write(&mut buf, format).unwrap();
+
+            assert_eq!(format, Format::detect(&mut buf.as_slice()).unwrap());
+            assert_eq!(
+                (store.clone(), format),
+                Store::read_any(buf.as_slice()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_detect_core() {
+        assert_eq!(
+            Format::JsonLines,
+            Format::detect(&mut CORE.as_bytes()).unwrap()
+        );
+        assert_eq!(Format::Json, Format::detect(&mut &b"[]"[..]).unwrap());
+    }
+}
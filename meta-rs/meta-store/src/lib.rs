@@ -1,117 +1,291 @@
+pub mod binary;
 mod datom;
+mod format;
+mod loader;
+mod merge;
+mod query;
+mod transaction;
 
 use std::fmt::{Debug, Display};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, Write};
 
-use im::{HashMap, HashSet};
+use im::{HashMap, HashSet, Vector};
+use log::{error, warn};
 use serde::{Deserialize, Serialize};
 
 pub use crate::datom::*;
+pub use crate::format::*;
+pub use crate::loader::*;
+pub use crate::merge::*;
+pub use crate::query::*;
+pub use crate::transaction::*;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 struct Index(HashMap<Field, HashMap<Field, HashSet<Datom>>>);
 
-#[derive(Clone, Eq, PartialEq, Hash)]
-pub struct Store {
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct Indexes {
     atoms: HashMap</* id: */ Field, Datom>,
     eav: Index,
     aev: Index,
     ave: Index,
 }
 
+#[derive(Clone)]
+pub struct Store {
+    current: Indexes,
+    /// Append-only transaction log. Every transaction is stored along with the state of indexes
+    /// right after it was applied. Indexes are persistent, so snapshots share most of their
+    /// structure.
+    log: Vector<(Transaction, Indexes)>,
+    /// Author of new transactions.
+    author: Option<String>,
+}
+
 impl Store {
     pub fn new() -> Store {
         Store {
-            atoms: HashMap::new(),
-            eav: Index::new(),
-            aev: Index::new(),
-            ave: Index::new(),
+            current: Indexes::new(),
+            log: Vector::new(),
+            author: None,
         }
     }
 
+    /// Load store from JSON lines. Malformed lines are skipped and logged; use [`Loader`] to get
+    /// errors for them.
     pub fn from_reader<R>(r: R) -> Result<Store>
     where
         R: std::io::BufRead,
+    {
+        let (store, errors) = Loader::new().load(r)?;
+        for error in errors.iter() {
+            match error.severity {
+                Severity::Error => error!("{}", error),
+                Severity::Warning => warn!("{}", error),
+            }
+        }
+        Ok(store)
+    }
+
+    /// Rebuild store by replaying transaction log. Transaction ids and timestamps are preserved.
+    pub fn from_log<I>(log: I) -> Store
+    where
+        I: IntoIterator<Item = Transaction>,
     {
         let mut store = Store::new();
+        for tx in log {
+            store.apply(tx);
+        }
+        store
+    }
 
+    /// Read transaction log written by [`Store::write_log`].
+    pub fn read_log<R: BufRead>(r: R) -> Result<Vec<Transaction>> {
+        let mut log = Vec::new();
         for line in r.lines() {
             let line = line?;
-            let mut datoms = serde_json::Deserializer::from_str(&line).into_iter::<Datom>();
-            if let Some(Ok(datom)) = datoms.next() {
-                store.add_datom(&datom);
+            if !line.trim().is_empty() {
+                log.push(serde_json::from_str(&line)?);
             }
         }
+        Ok(log)
+    }
+
+    /// Write transaction log as JSON lines, one transaction per line. Store files only contain
+    /// current datoms, so the log is saved separately (see [`log_path`]).
+    pub fn write_log<W: Write>(&self, mut w: W) -> Result<()> {
+        for tx in self.log() {
+            serde_json::to_writer(&mut w, tx)?;
+            w.write_all(b"\n")?;
+        }
+        w.flush()?;
+        Ok(())
+    }
 
-        Ok(store)
+    /// Attach history read with [`Store::read_log`] to the store. The log is replayed, and if it
+    /// does not end with the store's datoms (e.g., the store was merged or edited by hand without
+    /// updating the log), it is dropped with a warning and the store starts a fresh history.
+    pub fn with_history(self, log: Vec<Transaction>) -> Store {
+        let mut store = Store::from_log(log);
+        if store != self {
+            warn!("transaction log does not match the store, starting a new history");
+            return self;
+        }
+        store.author = self.author;
+        store
     }
 
+    /// Author recorded in subsequent transactions.
+    pub fn set_author(&mut self, author: Option<String>) {
+        self.author = author;
+    }
+
+    /// Atomically retract and add datoms. Retractions are applied first, so a datom can be
+    /// replaced with a new datom having the same id.
+    pub fn transact(&mut self, retracted: Vec<Datom>, added: Vec<Datom>) -> TxId {
+        let id = self.last_tx().next();
+        let mut tx = Transaction::new(id, retracted, added);
+        tx.author = self.author.clone();
+        self.apply(tx);
+        id
+    }
+
+    /// Same as [`Store::transact`] but returns datoms that were actually changed.
+    pub fn transact_changes(&mut self, retracted: Vec<Datom>, added: Vec<Datom>) -> ChangeSet {
+        let from = self.last_tx();
+        self.transact(retracted, added);
+        self.changes_since(from)
+    }
+
+    fn apply(&mut self, tx: Transaction) {
+        for datom in tx.retracted.iter() {
+            self.current.remove(datom);
+        }
+        for datom in tx.added.iter() {
+            self.current.add(datom);
+        }
+        self.log.push_back((tx, self.current.clone()));
+    }
+
+    /// Net changes made by transactions after `tx`. Consumers that maintain derived data can
+    /// remember [`Store::last_tx`] and update incrementally using the returned change set.
+    pub fn changes_since(&self, tx: TxId) -> ChangeSet {
+        let start = self
+            .log
+            .iter()
+            .position(|(t, _)| t.id > tx)
+            .unwrap_or_else(|| self.log.len());
+        let before = match start {
+            0 => None,
+            n => self.log.get(n - 1).map(|(_, indexes)| &indexes.atoms),
+        };
+        let is_before = |d: &Datom| matches!(before, Some(atoms) if atoms.get(&d.id) == Some(d));
+        let is_now = |d: &Datom| self.current.atoms.get(&d.id) == Some(d);
+
+        let mut touched = HashSet::new();
+        for (t, _) in self.log.iter().skip(start) {
+            touched.extend(t.retracted.iter().chain(t.added.iter()));
+        }
+
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        for datom in touched {
+            match (is_before(datom), is_now(datom)) {
+                (true, false) => removed.push(datom.clone()),
+                (false, true) => added.push(datom.clone()),
+                _ => {}
+            }
+        }
+        removed.sort_unstable();
+        added.sort_unstable();
+
+        ChangeSet {
+            from: tx,
+            to: self.last_tx(),
+            removed,
+            added,
+        }
+    }
+
+    /// Add a single datom. This is a shorthand for a transaction that adds one datom.
     pub fn add_datom(&mut self, datom: &Datom) {
-        let Datom {
-            id,
-            entity,
-            attribute,
-            value,
-        } = datom;
-        self.atoms.insert(id.clone(), datom.clone());
-        self.eav
-            .add(entity.clone(), attribute.clone(), datom.clone());
-        self.aev
-            .add(attribute.clone(), entity.clone(), datom.clone());
-        self.ave
-            .add(attribute.clone(), value.clone(), datom.clone());
+        self.transact(Vec::new(), vec![datom.clone()]);
     }
 
+    /// Remove a single datom. This is a shorthand for a transaction that retracts one datom.
     pub fn remove_datom(&mut self, datom: &Datom) {
-        let Datom {
-            id,
-            entity,
-            attribute,
-            value,
-        } = datom;
-        self.atoms.remove(id);
-        self.eav.remove(entity.clone(), attribute.clone(), &datom);
-        self.aev.remove(attribute.clone(), entity.clone(), &datom);
-        self.ave.remove(attribute.clone(), value.clone(), &datom);
+        self.transact(vec![datom.clone()], Vec::new());
+    }
+
+    /// Id of the last applied transaction or `TxId(0)` if no transactions were applied.
+    pub fn last_tx(&self) -> TxId {
+        self.log.last().map_or_else(TxId::default, |(tx, _)| tx.id)
+    }
+
+    /// Transactions applied to the store, including the ones of attached history (see
+    /// [`Store::with_history`]).
+    pub fn log(&self) -> impl DoubleEndedIterator<Item = &Transaction> {
+        self.log.iter().map(|(tx, _)| tx)
+    }
+
+    /// Returns the store as it was right after transaction `tx`. The log of the returned store is
+    /// truncated at `tx`.
+    pub fn as_of(&self, tx: TxId) -> Store {
+        let n = self
+            .log
+            .iter()
+            .position(|(t, _)| t.id > tx)
+            .unwrap_or_else(|| self.log.len());
+        let log = self.log.take(n);
+        let current = log
+            .last()
+            .map_or_else(Indexes::new, |(_, indexes)| indexes.clone());
+
+        Store {
+            current,
+            log,
+            author: self.author.clone(),
+        }
+    }
+
+    /// Returns a store with current datoms that were added after transaction `tx`. The returned
+    /// store has no history.
+    pub fn since(&self, tx: TxId) -> Store {
+        let mut current = Indexes::new();
+        for (t, _) in self.log.iter().skip_while(|(t, _)| t.id <= tx) {
+            for datom in t.added.iter() {
+                if self.current.atoms.get(&datom.id) == Some(datom) {
+                    current.add(datom);
+                }
+            }
+        }
+
+        Store {
+            current,
+            log: Vector::new(),
+            author: self.author.clone(),
+        }
     }
 
     pub fn atoms(&self) -> &HashMap<Field, Datom> {
-        &self.atoms
+        &self.current.atoms
     }
 
     #[inline]
     pub fn eav1(&self, e: &Field) -> Option<&HashMap<Field, HashSet<Datom>>> {
-        self.eav.get(e)
+        self.current.eav.get(e)
     }
 
     #[inline]
     pub fn eav2(&self, e: &Field, a: &Field) -> Option<&HashSet<Datom>> {
-        self.eav.get(e)?.get(a)
+        self.current.eav.get(e)?.get(a)
     }
 
     #[inline]
     pub fn aev1(&self, a: &Field) -> Option<&HashMap<Field, HashSet<Datom>>> {
-        self.aev.get(a)
+        self.current.aev.get(a)
     }
 
     #[inline]
     pub fn aev2(&self, a: &Field, e: &Field) -> Option<&HashSet<Datom>> {
-        self.aev.get(a)?.get(e)
+        self.current.aev.get(a)?.get(e)
     }
 
     #[inline]
     pub fn ave1(&self, a: &Field) -> Option<&HashMap<Field, HashSet<Datom>>> {
-        self.ave.get(a)
+        self.current.ave.get(a)
     }
 
     #[inline]
     pub fn ave2(&self, a: &Field, v: &Field) -> Option<&HashSet<Datom>> {
-        self.ave.get(a)?.get(v)
+        self.current.ave.get(a)?.get(v)
     }
 
     pub fn entities(&self) -> HashSet<&Field> {
-        self.eav.0.keys().collect()
+        self.current.eav.0.keys().collect()
     }
 
     #[inline]
@@ -129,7 +303,7 @@ impl Serialize for Store {
     where
         S: serde::Serializer,
     {
-        let mut datoms = self.atoms.values().collect::<Vec<_>>();
+        let mut datoms = self.current.atoms.values().collect::<Vec<_>>();
         datoms.sort_unstable();
         serializer.serialize_newtype_struct("Store", &datoms)
     }
@@ -143,8 +317,8 @@ impl<'de> Deserialize<'de> for Store {
         let datoms = Vec::<Datom>::deserialize(deserializer)?;
 
         let mut store = Self::new();
-        for datom in datoms {
-            store.add_datom(&datom);
+        if !datoms.is_empty() {
+            store.transact(Vec::new(), datoms);
         }
 
         Ok(store)
@@ -153,7 +327,24 @@ impl<'de> Deserialize<'de> for Store {
 
 impl Debug for Store {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Store").field("atoms", &self.atoms).finish()
+        f.debug_struct("Store")
+            .field("atoms", &self.current.atoms)
+            .finish()
+    }
+}
+
+/// Stores are equal if they contain the same datoms. History is not taken into account.
+impl PartialEq for Store {
+    fn eq(&self, other: &Self) -> bool {
+        self.current == other.current
+    }
+}
+
+impl Eq for Store {}
+
+impl Hash for Store {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.current.hash(state);
     }
 }
 
@@ -171,6 +362,46 @@ impl std::str::FromStr for Store {
     }
 }
 
+impl Indexes {
+    fn new() -> Indexes {
+        Indexes {
+            atoms: HashMap::new(),
+            eav: Index::new(),
+            aev: Index::new(),
+            ave: Index::new(),
+        }
+    }
+
+    fn add(&mut self, datom: &Datom) {
+        let Datom {
+            id,
+            entity,
+            attribute,
+            value,
+        } = datom;
+        self.atoms.insert(id.clone(), datom.clone());
+        self.eav
+            .add(entity.clone(), attribute.clone(), datom.clone());
+        self.aev
+            .add(attribute.clone(), entity.clone(), datom.clone());
+        self.ave
+            .add(attribute.clone(), value.clone(), datom.clone());
+    }
+
+    fn remove(&mut self, datom: &Datom) {
+        let Datom {
+            id,
+            entity,
+            attribute,
+            value,
+        } = datom;
+        self.atoms.remove(id);
+        self.eav.remove(entity.clone(), attribute.clone(), datom);
+        self.aev.remove(attribute.clone(), entity.clone(), datom);
+        self.ave.remove(attribute.clone(), value.clone(), datom);
+    }
+}
+
 impl Index {
     pub fn new() -> Index {
         Index(HashMap::new())
@@ -217,6 +448,9 @@ impl Index {
 pub enum Error {
     IoError(std::io::Error),
     JsonError(serde_json::Error),
+    QueryError(QueryError),
+    BinaryError(String),
+    LoadError(LoadError),
 }
 
 impl Display for Error {
@@ -224,6 +458,9 @@ impl Display for Error {
         match self {
             Error::IoError(e) => Display::fmt(e, f),
             Error::JsonError(e) => Display::fmt(e, f),
+            Error::QueryError(e) => Display::fmt(e, f),
+            Error::BinaryError(e) => write!(f, "invalid binary store: {}", e),
+            Error::LoadError(e) => Display::fmt(e, f),
         }
     }
 }
@@ -240,6 +477,18 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+impl From<LoadError> for Error {
+    fn from(err: LoadError) -> Error {
+        Error::LoadError(err)
+    }
+}
+
+impl From<QueryError> for Error {
+    fn from(err: QueryError) -> Error {
+        Error::QueryError(err)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -311,4 +560,176 @@ mod tests {
             store.eav1(&Field::from("0"))
         );
     }
+
+    #[test]
+    fn transact_is_logged() {
+        let mut store = Store::new();
+        let tx1 = store.transact(Vec::new(), vec![("1", "0", "0", "a").into()]);
+        let tx2 = store.transact(
+            vec![("1", "0", "0", "a").into()],
+            vec![("1", "0", "0", "b").into()],
+        );
+
+        assert_eq!(TxId(1), tx1);
+        assert_eq!(TxId(2), tx2);
+        assert_eq!(tx2, store.last_tx());
+        assert_eq!(
+            vec![
+                (vec![], vec![("1", "0", "0", "a").into()]),
+                (
+                    vec![("1", "0", "0", "a").into()],
+                    vec![("1", "0", "0", "b").into()]
+                ),
+            ] as Vec<(Vec<Datom>, Vec<Datom>)>,
+            store
+                .log()
+                .map(|tx| (tx.retracted.clone(), tx.added.clone()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn as_of() {
+        let mut store = Store::from_str(TEST).unwrap();
+        let loaded = store.last_tx();
+        store.remove_datom(&("-9", "0", "4", "Additional comment").into());
+        store.add_datom(&("-10", "0", "4", "New comment").into());
+
+        let old = store.as_of(loaded);
+        assert_eq!(Store::from_str(TEST).unwrap(), old);
+        assert_eq!(loaded, old.last_tx());
+
+        assert_eq!(Store::new(), store.as_of(TxId(0)));
+        assert_eq!(store, store.as_of(store.last_tx()));
+    }
+
+    #[test]
+    fn since() {
+        let mut store = Store::from_str(TEST).unwrap();
+        let loaded = store.last_tx();
+        store.add_datom(&("-10", "0", "4", "New comment").into());
+        store.add_datom(&("-11", "0", "4", "Removed comment").into());
+        store.remove_datom(&("-11", "0", "4", "Removed comment").into());
+
+        let new = store.since(loaded);
+        assert_eq!(
+            Some(&hashset! {("-10", "0", "4", "New comment").into()}),
+            new.eav2(&Field::from("0"), &Field::from("4"))
+        );
+        assert_eq!(1, new.atoms().len());
+    }
+
+    #[test]
+    fn from_log() {
+        let mut store = Store::from_str(TEST).unwrap();
+        store.remove_datom(&("-9", "0", "4", "Additional comment").into());
+
+        let replayed = Store::from_log(store.log().cloned());
+        assert_eq!(store, replayed);
+        assert_eq!(
+            store.log().collect::<Vec<_>>(),
+            replayed.log().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn log_is_persisted() {
+        let mut store = Store::from_str(TEST).unwrap();
+        store.set_author(Some("alice".to_string()));
+        store.remove_datom(&("-9", "0", "4", "Additional comment").into());
+
+        let mut buf = Vec::new();
+        store.write_log(&mut buf).unwrap();
+        let log = Store::read_log(buf.as_slice()).unwrap();
+
+        assert_eq!(store.log().cloned().collect::<Vec<_>>(), log);
+        assert_eq!(
+            vec![None, Some("alice")],
+            log.iter()
+                .map(|tx| tx.author.as_deref())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn with_history() {
+        let mut store = Store::from_str(TEST).unwrap();
+        store.remove_datom(&("-9", "0", "4", "Additional comment").into());
+        let log = store.log().cloned().collect::<Vec<_>>();
+
+        // store file changed without updating the log
+        let mut edited = store.clone();
+        edited.add_datom(&("-10", "0", "4", "New comment").into());
+        let mut loaded = Store::read_any(save(&edited).as_slice()).unwrap().0;
+        loaded.set_author(Some("bob".to_string()));
+
+        let restored = loaded.clone().with_history(log.clone());
+        assert_eq!(edited, restored);
+        assert_eq!(
+            loaded.log().collect::<Vec<_>>(),
+            restored.log().collect::<Vec<_>>()
+        );
+
+        // the log matches the unedited store
+        let mut loaded = Store::read_any(save(&store).as_slice()).unwrap().0;
+        loaded.set_author(Some("bob".to_string()));
+        let mut restored = loaded.with_history(log.clone());
+        assert_eq!(store, restored);
+        assert_eq!(log, restored.log().cloned().collect::<Vec<_>>());
+
+        // history continues with the store's author
+        restored.add_datom(&("-11", "0", "4", "Another comment").into());
+        assert_eq!(
+            Some("bob"),
+            restored.log().last().unwrap().author.as_deref()
+        );
+    }
+
+    fn save(store: &Store) -> Vec<u8> {
+        let mut buf = Vec::new();
+        store.write(&mut buf, Format::JsonLines).unwrap();
+        buf
+    }
+
+    #[test]
+    fn changes_since() {
+        let mut store = Store::from_str(TEST).unwrap();
+        let loaded = store.last_tx();
+        store.remove_datom(&("-9", "0", "4", "Additional comment").into());
+        store.add_datom(&("-10", "0", "4", "New comment").into());
+        store.add_datom(&("-11", "0", "4", "Removed comment").into());
+        store.remove_datom(&("-11", "0", "4", "Removed comment").into());
+
+        let changes = store.changes_since(loaded);
+        assert_eq!(
+            ChangeSet {
+                from: loaded,
+                to: store.last_tx(),
+                removed: vec![("-9", "0", "4", "Additional comment").into()],
+                added: vec![("-10", "0", "4", "New comment").into()],
+            },
+            changes
+        );
+        let entity = Field::from("0");
+        assert_eq!(hashset! {&entity}, changes.entities());
+        assert!(store.changes_since(store.last_tx()).is_empty());
+        assert_eq!(9, store.changes_since(TxId(0)).added.len());
+    }
+
+    #[test]
+    fn transact_changes() {
+        let mut store = Store::from_str(TEST).unwrap();
+        let changes = store.transact_changes(
+            vec![("-100", "0", "4", "Missing").into()],
+            vec![
+                ("-1", "0", "0", "identifier").into(),
+                ("-10", "0", "4", "New comment").into(),
+            ],
+        );
+        assert_eq!(Vec::<Datom>::new(), changes.removed);
+        assert_eq!(
+            vec![Datom::from(("-10", "0", "4", "New comment"))],
+            changes.added
+        );
+    }
 }
@@ -0,0 +1,201 @@
+use std::fmt::Display;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+use crate::{Datom, Error, Result, Store};
+
+/// Malformed datom in a JSON lines store.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LoadError {
+    pub severity: Severity,
+    pub path: Option<PathBuf>,
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column number (in bytes).
+    pub column: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Severity {
+    /// The line is skipped.
+    Error,
+    /// The datom is loaded, but the line is suspicious.
+    Warning,
+}
+
+/// Loader for JSON lines stores.
+///
+/// Blank lines and lines starting with `;` are comments. Every other line must contain a single
+/// datom optionally followed by a `;` comment. Other text after a datom is ignored with a warning.
+/// By default, malformed lines are skipped and reported; in strict mode, loading fails on the first
+/// malformed line.
+#[derive(Debug, Clone, Default)]
+pub struct Loader {
+    path: Option<PathBuf>,
+    strict: bool,
+}
+
+impl Loader {
+    pub fn new() -> Loader {
+        Loader::default()
+    }
+
+    /// Path to include in errors.
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> Loader {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn with_strict(mut self, strict: bool) -> Loader {
+        self.strict = strict;
+        self
+    }
+
+    /// Load store and return errors for all skipped lines along with warnings.
+    pub fn load<R: BufRead>(&self, r: R) -> Result<(Store, Vec<LoadError>)> {
+        let mut datoms = Vec::new();
+        let mut errors = Vec::new();
+
+        for (i, line) in r.lines().enumerate() {
+            match self.parse_line(i + 1, &line?, &mut errors) {
+                Ok(Some(datom)) => datoms.push(datom),
+                Ok(None) => {}
+                Err(err) if self.strict => return Err(Error::LoadError(err)),
+                Err(err) => errors.push(err),
+            }
+        }
+
+        let mut store = Store::new();
+        if !datoms.is_empty() {
+            store.transact(Vec::new(), datoms);
+        }
+
+        Ok((store, errors))
+    }
+
+    /// Parse datom on the line. Warnings are added to `warnings`.
+    fn parse_line(
+        &self,
+        line_number: usize,
+        line: &str,
+        warnings: &mut Vec<LoadError>,
+    ) -> std::result::Result<Option<Datom>, LoadError> {
+        let offset = line.len() - line.trim_start().len();
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with(';') {
+            return Ok(None);
+        }
+
+        let error = |severity: Severity, column: usize, message: String| LoadError {
+            severity,
+            path: self.path.clone(),
+            line: line_number,
+            column: offset + column,
+            message,
+        };
+
+        let mut stream = serde_json::Deserializer::from_str(line).into_iter::<Datom>();
+        let datom = match stream.next() {
+            Some(Ok(datom)) => datom,
+            Some(Err(err)) => {
+                return Err(error(Severity::Error, err.column(), serde_message(&err)))
+            }
+            None => return Ok(None),
+        };
+
+        let end = stream.byte_offset();
+        let rest = line[end..].trim_start();
+        if !rest.is_empty() && !rest.starts_with(';') {
+            let column = line.len() - rest.len() + 1;
+            warnings.push(error(
+                Severity::Warning,
+                column,
+                "trailing characters after datom are ignored".to_string(),
+            ));
+        }
+
+        Ok(Some(datom))
+    }
+}
+
+/// serde_json error message without " at line X column Y" suffix, as the position is reported
+/// separately.
+fn serde_message(err: &serde_json::Error) -> String {
+    let message = err.to_string();
+    match message.rfind(" at line ") {
+        Some(i) => message[..i].to_string(),
+        None => message,
+    }
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{}:", path.display())?;
+        }
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(s: &str) -> (Store, Vec<LoadError>) {
+        Loader::new()
+            .with_path("test.meta")
+            .load(std::io::Cursor::new(s))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_comments_are_not_errors() {
+        let (store, errors) = load(
+            r#"
+; comment
+  ["1", "0", "0", "identifier"] ; trailing comment
+
+            "#,
+        );
+        assert_eq!(Vec::<LoadError>::new(), errors);
+        assert_eq!(1, store.atoms().len());
+    }
+
+    #[test]
+    fn test_malformed_datom() {
+        let (store, errors) = load(
+            r#"["1", "0", "0", "identifier"]
+  ["2", "0", "0" "typo"]
+["3", "0", "0"] x"#,
+        );
+        // datom followed by text is still loaded
+        assert_eq!(2, store.atoms().len());
+        assert_eq!(
+            vec![
+                (Severity::Error, Some(PathBuf::from("test.meta")), 2, 18),
+                (Severity::Warning, Some(PathBuf::from("test.meta")), 3, 17)
+            ],
+            errors
+                .iter()
+                .map(|e| (e.severity, e.path.clone(), e.line, e.column))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("test.meta:2:18: expected `,` or `]`", errors[0].to_string());
+        assert_eq!(
+            "test.meta:3:17: trailing characters after datom are ignored",
+            errors[1].to_string()
+        );
+    }
+
+    #[test]
+    fn test_strict() {
+        let result = Loader::new()
+            .with_strict(true)
+            .load(std::io::Cursor::new("[\"1\", \"0\"]\n"));
+        match result {
+            Err(Error::LoadError(err)) => assert_eq!(1, err.line),
+            _ => panic!("expected load error"),
+        }
+    }
+}
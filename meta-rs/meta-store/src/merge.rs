@@ -0,0 +1,628 @@
+//! Datom-level diff and three-way merge of stores.
+use std::fmt::Display;
+use std::io::{BufRead, Write};
+
+use im::{HashMap, HashSet};
+
+use crate::{Datom, Field, Result, Store};
+
+/// Difference between two stores. Datoms are matched by id, so a datom whose entity, attribute or
+/// value has changed is both removed and added.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Diff {
+    pub removed: Vec<Datom>,
+    pub added: Vec<Datom>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
+
+    /// Apply the diff to JSON lines store `r` and write the result to `w`. Unlike writing the whole
+    /// store, this keeps comments, malformed lines and the order of datoms: lines of removed
+    /// datoms are dropped, changed datoms are replaced in place (keeping trailing comments), and
+    /// new datoms are appended. Datoms are written in the style of the line they replace, and
+    /// appended ones in the style of the last datom of the store.
+    pub fn apply_json_lines<R: BufRead, W: Write>(&self, r: R, mut w: W) -> Result<()> {
+        let removed = self
+            .removed
+            .iter()
+            .map(|d| &d.id)
+            .collect::<HashSet<&Field>>();
+        let mut added = self
+            .added
+            .iter()
+            .map(|d| (&d.id, d))
+            .collect::<HashMap<_, _>>();
+
+        let mut separator = ", ";
+        for line in r.lines() {
+            let line = line?;
+            let start = line.len() - line.trim_start().len();
+            let mut stream =
+                serde_json::Deserializer::from_str(&line[start..]).into_iter::<Datom>();
+            match stream.next() {
+                Some(Ok(datom)) => {
+                    let end = start + stream.byte_offset();
+                    separator = separator_of(&line[start..end]);
+                    if !removed.contains(&datom.id) {
+                        w.write_all(line.as_bytes())?;
+                        w.write_all(b"\n")?;
+                    } else if let Some(new) = added.remove(&datom.id) {
+                        w.write_all(&line.as_bytes()[..start])?;
+                        write_datom(&mut w, new, separator)?;
+                        w.write_all(&line.as_bytes()[end..])?;
+                        w.write_all(b"\n")?;
+                    }
+                }
+                _ => {
+                    w.write_all(line.as_bytes())?;
+                    w.write_all(b"\n")?;
+                }
+            }
+        }
+
+        for datom in self.added.iter() {
+            if added.contains_key(&datom.id) {
+                write_datom(&mut w, datom, separator)?;
+                w.write_all(b"\n")?;
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+/// Separator between fields of the datom written as `text`: `","` if it is written compactly (as by
+/// `serde_json`), `", "` otherwise.
+fn separator_of(text: &str) -> &'static str {
+    let compact = serde_json::from_str::<serde_json::Value>(text)
+        .ok()
+        .and_then(|value| serde_json::to_string(&value).ok());
+    if compact.as_deref() == Some(text) {
+        ","
+    } else {
+        ", "
+    }
+}
+
+fn write_datom<W: Write>(w: &mut W, datom: &Datom, separator: &str) -> Result<()> {
+    w.write_all(b"[")?;
+    for (i, field) in [&datom.id, &datom.entity, &datom.attribute, &datom.value]
+        .iter()
+        .enumerate()
+    {
+        if i != 0 {
+            w.write_all(separator.as_bytes())?;
+        }
+        serde_json::to_writer(&mut *w, field)?;
+    }
+    w.write_all(b"]")?;
+    Ok(())
+}
+
+impl Store {
+    /// Compute datoms that should be removed from and added to `self` to get `other`. Datoms are
+    /// sorted.
+    pub fn diff(&self, other: &Store) -> Diff {
+        let mut diff = Diff::default();
+        for (id, datom) in self.atoms().iter() {
+            if other.atoms().get(id) != Some(datom) {
+                diff.removed.push(datom.clone());
+            }
+        }
+        for (id, datom) in other.atoms().iter() {
+            if self.atoms().get(id) != Some(datom) {
+                diff.added.push(datom.clone());
+            }
+        }
+
+        diff.removed.sort_unstable();
+        diff.added.sort_unstable();
+        diff
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Conflict {
+    /// Both sides changed datom `id` differently. `None` means the datom is absent. Merge result
+    /// contains our version.
+    Datom {
+        id: Field,
+        base: Option<Datom>,
+        ours: Option<Datom>,
+        theirs: Option<Datom>,
+    },
+    /// Both sides set attribute of entity to different values. Reported when neither side has
+    /// multiple values, but the merge result does. Merge result contains all values.
+    Value {
+        entity: Field,
+        attribute: Field,
+        ours: Vec<Datom>,
+        theirs: Vec<Datom>,
+    },
+    /// Both sides placed a different datom after `after`.
+    OrderFork {
+        after: Field,
+        ours: Datom,
+        theirs: Datom,
+    },
+    /// Merge result has a loop of ordering datoms (ids sorted) that is present on neither side.
+    OrderLoop(Vec<Field>),
+}
+
+/// Result of [`Merge::run`].
+#[derive(Debug, Clone)]
+pub struct MergeResult {
+    pub store: Store,
+    pub conflicts: Vec<Conflict>,
+}
+
+/// Three-way merge of stores.
+///
+/// Changes are detected per datom id. If only one side has changed a datom, that change is taken.
+/// Conflicting datom changes are resolved in favor of `ours` and reported.
+pub struct Merge<'a> {
+    base: &'a Store,
+    ours: &'a Store,
+    theirs: &'a Store,
+    order_attribute: Option<Field>,
+}
+
+impl<'a> Merge<'a> {
+    pub fn new(base: &'a Store, ours: &'a Store, theirs: &'a Store) -> Merge<'a> {
+        Merge {
+            base,
+            ours,
+            theirs,
+            order_attribute: None,
+        }
+    }
+
+    /// Attribute that orders datoms by pointing to the previous datom id (`after` in meta.core).
+    /// Enables reporting conflicting orderings.
+    pub fn with_order_attribute(mut self, attribute: Field) -> Merge<'a> {
+        self.order_attribute = Some(attribute);
+        self
+    }
+
+    pub fn run(&self) -> MergeResult {
+        let ours_diff = self.base.diff(self.ours);
+        let theirs_diff = self.base.diff(self.theirs);
+
+        let changed = ours_diff
+            .removed
+            .iter()
+            .chain(ours_diff.added.iter())
+            .chain(theirs_diff.removed.iter())
+            .chain(theirs_diff.added.iter())
+            .map(|d| &d.id)
+            .collect::<HashSet<_>>();
+
+        let mut conflicts = Vec::new();
+        let mut removed = Vec::new();
+        let mut added = Vec::new();
+        for id in changed {
+            let base = self.base.atoms().get(id);
+            let ours = self.ours.atoms().get(id);
+            let theirs = self.theirs.atoms().get(id);
+
+            let result = if ours == theirs || theirs == base {
+                ours
+            } else if ours == base {
+                theirs
+            } else {
+                conflicts.push(Conflict::Datom {
+                    id: id.clone(),
+                    base: base.cloned(),
+                    ours: ours.cloned(),
+                    theirs: theirs.cloned(),
+                });
+                ours
+            };
+
+            if result != base {
+                removed.extend(base.cloned());
+                added.extend(result.cloned());
+            }
+        }
+
+        let mut store = self.base.clone();
+        if !removed.is_empty() || !added.is_empty() {
+            store.transact(removed, added);
+        }
+
+        conflicts.extend(self.value_conflicts(&store, &ours_diff, &theirs_diff));
+        if let Some(order_attribute) = &self.order_attribute {
+            conflicts.extend(order_conflicts(
+                &store,
+                order_attribute,
+                &ours_diff,
+                &theirs_diff,
+            ));
+        }
+
+        conflicts.sort();
+        MergeResult { store, conflicts }
+    }
+
+    fn value_conflicts(
+        &self,
+        merged: &Store,
+        ours_diff: &Diff,
+        theirs_diff: &Diff,
+    ) -> Vec<Conflict> {
+        let touched = |diff: &Diff| {
+            diff.added
+                .iter()
+                .map(|d| (d.entity.clone(), d.attribute.clone()))
+                .collect::<HashSet<_>>()
+        };
+        let ours_touched = touched(ours_diff);
+        let theirs_touched = touched(theirs_diff);
+
+        let mut result = Vec::new();
+        for (entity, attribute) in ours_touched.intersection(theirs_touched) {
+            let values = |store: &Store| {
+                store
+                    .values(&entity, &attribute)
+                    .map_or_else(Vec::new, |values| {
+                        let mut values = values.iter().cloned().collect::<Vec<_>>();
+                        values.sort_unstable();
+                        values
+                    })
+            };
+            let ours = values(self.ours);
+            let theirs = values(self.theirs);
+            let merged_count = merged.values(&entity, &attribute).map_or(0, |x| x.len());
+
+            let value_of = |x: &[Datom]| x.first().map(|d| d.value.clone());
+            if ours.len() <= 1
+                && theirs.len() <= 1
+                && merged_count > 1
+                && value_of(&ours) != value_of(&theirs)
+            {
+                result.push(Conflict::Value {
+                    entity,
+                    attribute,
+                    ours,
+                    theirs,
+                });
+            }
+        }
+        result
+    }
+}
+
+fn order_conflicts(
+    merged: &Store,
+    attribute: &Field,
+    ours_diff: &Diff,
+    theirs_diff: &Diff,
+) -> Vec<Conflict> {
+    let added = |diff: &Diff| {
+        diff.added
+            .iter()
+            .filter(|d| &d.attribute == attribute)
+            .cloned()
+            .collect::<HashSet<Datom>>()
+    };
+    let ours_added = added(ours_diff);
+    let theirs_added = added(theirs_diff);
+
+    let mut result = Vec::new();
+
+    for ours in ours_added.iter().filter(|d| !theirs_added.contains(d)) {
+        let siblings = merged.ave2(attribute, &ours.value).into_iter().flatten();
+        for theirs in siblings.filter(|d| d.entity != ours.entity && theirs_added.contains(d)) {
+            if !ours_added.contains(theirs) {
+                result.push(Conflict::OrderFork {
+                    after: ours.value.clone(),
+                    ours: ours.clone(),
+                    theirs: theirs.clone(),
+                });
+            }
+        }
+    }
+
+    // Follow ordering datoms starting from every new one. A loop is a merge conflict if it
+    // consists of datoms added by both sides.
+    let mut loops = HashSet::new();
+    for start in ours_added.iter().chain(theirs_added.iter()) {
+        let mut path = Vec::new();
+        let mut visited = HashMap::new();
+        let mut datom = start;
+        let cycle_start = loop {
+            if let Some(&i) = visited.get(&datom.entity) {
+                break Some(i);
+            }
+            visited.insert(&datom.entity, path.len());
+            path.push(datom);
+            datom = match merged.value(&datom.value, attribute) {
+                Some(next) => next,
+                None => break None,
+            };
+        };
+
+        if let Some(i) = cycle_start {
+            let cycle = &path[i..];
+            let is_ours = cycle.iter().any(|d| !theirs_added.contains(*d));
+            let is_theirs = cycle.iter().any(|d| !ours_added.contains(*d));
+            if is_ours && is_theirs {
+                let mut ids = cycle.iter().map(|d| d.entity.clone()).collect::<Vec<_>>();
+                ids.sort_unstable();
+                loops.insert(ids);
+            }
+        }
+    }
+    result.extend(loops.into_iter().map(Conflict::OrderLoop));
+
+    result
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fn json<T: serde::Serialize>(x: &T) -> String {
+            serde_json::to_string(x).unwrap_or_default()
+        }
+
+        match self {
+            Conflict::Datom {
+                id,
+                base,
+                ours,
+                theirs,
+            } => write!(
+                f,
+                "datom {:?} changed on both sides: base {}, ours {}, theirs {}",
+                id.as_ref(),
+                json(base),
+                json(ours),
+                json(theirs)
+            ),
+            Conflict::Value {
+                entity,
+                attribute,
+                ours,
+                theirs,
+            } => write!(
+                f,
+                "attribute {:?} of {:?} set on both sides: ours {}, theirs {}",
+                attribute.as_ref(),
+                entity.as_ref(),
+                json(ours),
+                json(theirs)
+            ),
+            Conflict::OrderFork {
+                after,
+                ours,
+                theirs,
+            } => write!(
+                f,
+                "different datoms placed after {:?}: ours {}, theirs {}",
+                after.as_ref(),
+                json(ours),
+                json(theirs)
+            ),
+            Conflict::OrderLoop(ids) => write!(f, "ordering loop: {}", json(ids)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    static BASE: &str = r#"
+      ["1", "e", "name", "foo"]
+      ["2", "e", "tag", "a"]
+      ["3", "l", "item", "x"]
+      ["4", "l", "item", "y"]
+      ["5", "4", "after", "3"]
+    "#;
+
+    fn store(extra: &str) -> Store {
+        Store::from_str(&format!("{}\n{}", BASE, extra)).unwrap()
+    }
+
+    fn merge(ours: &Store, theirs: &Store) -> MergeResult {
+        let base = store("");
+        Merge::new(&base, ours, theirs)
+            .with_order_attribute("after".into())
+            .run()
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = Store::from_str(
+            r#"
+              ["1", "e", "a", "x"]
+              ["2", "e", "b", "y"]
+            "#,
+        )
+        .unwrap();
+        let new = Store::from_str(
+            r#"
+              ["1", "e", "a", "z"]
+              ["3", "e", "c", "w"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            Diff {
+                removed: vec![("1", "e", "a", "x").into(), ("2", "e", "b", "y").into()],
+                added: vec![("1", "e", "a", "z").into(), ("3", "e", "c", "w").into()],
+            },
+            old.diff(&new)
+        );
+        assert!(new.diff(&new).is_empty());
+    }
+
+    #[test]
+    fn test_apply_json_lines() {
+        let input = r#"; comment
+["1", "e", "a", "x"]
+  ["2", "e", "b", "y"] ; changed
+["3", "e", "c", "z"]
+malformed
+"#;
+        let diff = Diff {
+            removed: vec![("2", "e", "b", "y").into(), ("3", "e", "c", "z").into()],
+            added: vec![("2", "e", "b", "w").into(), ("4", "e", "d", "v").into()],
+        };
+
+        let mut output = Vec::new();
+        diff.apply_json_lines(input.as_bytes(), &mut output)
+            .unwrap();
+
+        assert_eq!(
+            r#"; comment
+["1", "e", "a", "x"]
+  ["2", "e", "b", "w"] ; changed
+malformed
+["4", "e", "d", "v"]
+"#,
+            String::from_utf8(output).unwrap()
+        );
+
+        // compact datoms stay compact
+        let input = r#"["1","e","a","x"]
+["2","e","b","y"]
+"#;
+        let mut output = Vec::new();
+        diff.apply_json_lines(input.as_bytes(), &mut output)
+            .unwrap();
+
+        assert_eq!(
+            r#"["1","e","a","x"]
+["2","e","b","w"]
+["4","e","d","v"]
+"#,
+            String::from_utf8(output).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_merge_independent_changes() {
+        let mut ours = store(r#"["10", "e", "comment", "ours"]"#);
+        ours.remove_datom(&("2", "e", "tag", "a").into());
+        let theirs = store(r#"["11", "e", "other", "theirs"]"#);
+
+        let result = merge(&ours, &theirs);
+        assert_eq!(Vec::<Conflict>::new(), result.conflicts);
+        assert_eq!(
+            Store::from_str(
+                r#"
+                  ["1", "e", "name", "foo"]
+                  ["3", "l", "item", "x"]
+                  ["4", "l", "item", "y"]
+                  ["5", "4", "after", "3"]
+                  ["10", "e", "comment", "ours"]
+                  ["11", "e", "other", "theirs"]
+                "#
+            )
+            .unwrap(),
+            result.store
+        );
+    }
+
+    #[test]
+    fn test_merge_datom_conflict() {
+        let mut ours = store("");
+        ours.transact(
+            vec![("1", "e", "name", "foo").into()],
+            vec![("1", "e", "name", "bar").into()],
+        );
+        let mut theirs = store("");
+        theirs.remove_datom(&("1", "e", "name", "foo").into());
+
+        let result = merge(&ours, &theirs);
+        assert_eq!(
+            vec![Conflict::Datom {
+                id: "1".into(),
+                base: Some(("1", "e", "name", "foo").into()),
+                ours: Some(("1", "e", "name", "bar").into()),
+                theirs: None,
+            }],
+            result.conflicts
+        );
+        assert_eq!(
+            Some(&("1", "e", "name", "bar").into()),
+            result.store.atoms().get(&"1".into())
+        );
+    }
+
+    #[test]
+    fn test_merge_value_conflict() {
+        let mut ours = store(r#"["10", "e", "tag", "b"]"#);
+        ours.remove_datom(&("2", "e", "tag", "a").into());
+        let mut theirs = store(r#"["11", "e", "tag", "c"]"#);
+        theirs.remove_datom(&("2", "e", "tag", "a").into());
+
+        assert_eq!(
+            vec![Conflict::Value {
+                entity: "e".into(),
+                attribute: "tag".into(),
+                ours: vec![("10", "e", "tag", "b").into()],
+                theirs: vec![("11", "e", "tag", "c").into()],
+            }],
+            merge(&ours, &theirs).conflicts
+        );
+
+        // the same value is not a conflict
+        let theirs = store(r#"["11", "e", "tag", "b"]"#);
+        let mut theirs = theirs;
+        theirs.remove_datom(&("2", "e", "tag", "a").into());
+        assert_eq!(Vec::<Conflict>::new(), merge(&ours, &theirs).conflicts);
+    }
+
+    #[test]
+    fn test_merge_order_fork() {
+        let ours = store(
+            r#"
+              ["10", "l", "item", "o"]
+              ["11", "10", "after", "3"]
+            "#,
+        );
+        let theirs = store(
+            r#"
+              ["12", "l", "item", "t"]
+              ["13", "12", "after", "3"]
+            "#,
+        );
+
+        assert_eq!(
+            vec![Conflict::OrderFork {
+                after: "3".into(),
+                ours: ("11", "10", "after", "3").into(),
+                theirs: ("13", "12", "after", "3").into(),
+            }],
+            merge(&ours, &theirs).conflicts
+        );
+    }
+
+    #[test]
+    fn test_merge_order_loop() {
+        // ours places y after z, theirs places z after y
+        let ours = store(
+            r#"
+              ["6", "l", "item", "z"]
+              ["10", "4", "after", "6"]
+            "#,
+        );
+        let mut theirs = store(
+            r#"
+              ["6", "l", "item", "z"]
+              ["11", "6", "after", "4"]
+            "#,
+        );
+        theirs.remove_datom(&("5", "4", "after", "3").into());
+
+        assert_eq!(
+            vec![Conflict::OrderLoop(vec!["4".into(), "6".into()])],
+            merge(&ours, &theirs).conflicts
+        );
+    }
+}
@@ -0,0 +1,879 @@
+//! Datalog-style queries over store indexes.
+//!
+//! Queries use a Datomic-like syntax:
+//!
+//! ```text
+//! [:find ?e ?name
+//!  :where [?e "5" ?t]
+//!         [?t "0" "Type"]
+//!         [?e "0" ?name]
+//!         (not [?e "4" _])]
+//! ```
+//!
+//! Pattern terms are either variables (`?x`), a blank (`_`) or a JSON string matching a field
+//! exactly. Rules are defined separately and can be recursive, but not through negation:
+//!
+//! ```text
+//! [[(follows ?a ?b) [?a "16" ?b]]
+//!  [(follows ?a ?c) [?a "16" ?b] (follows ?b ?c)]]
+//! ```
+use std::fmt::Display;
+use std::str::FromStr;
+
+use im::{HashMap, HashSet};
+
+use crate::{Datom, Error, Field, Result, Store};
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Var(pub String);
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum Term {
+    Var(Var),
+    Value(Field),
+    Blank,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Pattern {
+    pub entity: Term,
+    pub attribute: Term,
+    pub value: Term,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum Clause {
+    Pattern(Pattern),
+    Not(Vec<Clause>),
+    Rule(String, Vec<Term>),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Rule {
+    pub name: String,
+    pub params: Vec<Var>,
+    pub body: Vec<Clause>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Query {
+    pub find: Vec<Var>,
+    pub clauses: Vec<Clause>,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum QueryError {
+    Syntax {
+        offset: usize,
+        message: String,
+    },
+    UnboundVariable(Var),
+    UnknownRule(String),
+    ArityMismatch {
+        rule: String,
+        expected: usize,
+    },
+    /// Rule depends on its own negation, so it can't be stratified.
+    NegationCycle(String),
+}
+
+type Binding = HashMap<Var, Field>;
+type Relations = HashMap<String, HashSet<Vec<Field>>>;
+
+impl Var {
+    pub fn new(name: &str) -> Var {
+        Var(name.trim_start_matches('?').to_string())
+    }
+}
+
+impl Term {
+    pub fn var(name: &str) -> Term {
+        Term::Var(Var::new(name))
+    }
+
+    pub fn value<F: Into<Field>>(value: F) -> Term {
+        Term::Value(value.into())
+    }
+
+    fn as_var(&self) -> Option<&Var> {
+        if let Term::Var(var) = self {
+            Some(var)
+        } else {
+            None
+        }
+    }
+
+    fn resolve(&self, binding: &Binding) -> Option<Field> {
+        match self {
+            Term::Var(var) => binding.get(var).cloned(),
+            Term::Value(value) => Some(value.clone()),
+            Term::Blank => None,
+        }
+    }
+
+    fn is_bound(&self, bound: &HashSet<Var>) -> bool {
+        match self {
+            Term::Var(var) => bound.contains(var),
+            Term::Value(_) => true,
+            Term::Blank => false,
+        }
+    }
+}
+
+impl Pattern {
+    pub fn new(entity: Term, attribute: Term, value: Term) -> Pattern {
+        Pattern {
+            entity,
+            attribute,
+            value,
+        }
+    }
+
+    fn terms(&self) -> [&Term; 3] {
+        [&self.entity, &self.attribute, &self.value]
+    }
+}
+
+impl Clause {
+    /// Add rules the clause refers to to `rules`, along with whether they are negated.
+    fn collect_rules<'a>(&'a self, negated: bool, rules: &mut Vec<(&'a str, bool)>) {
+        match self {
+            Clause::Pattern(_) => {}
+            Clause::Not(clauses) => {
+                for clause in clauses.iter() {
+                    clause.collect_rules(true, rules);
+                }
+            }
+            Clause::Rule(name, _args) => rules.push((name, negated)),
+        }
+    }
+
+    fn vars(&self) -> HashSet<Var> {
+        fn term_vars<'a, I: IntoIterator<Item = &'a Term>>(terms: I) -> HashSet<Var> {
+            terms
+                .into_iter()
+                .filter_map(Term::as_var)
+                .cloned()
+                .collect()
+        }
+
+        match self {
+            Clause::Pattern(pattern) => term_vars(pattern.terms().iter().copied()),
+            Clause::Not(clauses) => HashSet::unions(clauses.iter().map(Clause::vars)),
+            Clause::Rule(_name, args) => term_vars(args),
+        }
+    }
+}
+
+impl Query {
+    pub fn new(find: Vec<Var>, clauses: Vec<Clause>) -> Query {
+        Query {
+            find,
+            clauses,
+            rules: Vec::new(),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Query> {
+        let mut parser = Parser::new(s);
+        let query = parser.parse_query()?;
+        parser.expect_end()?;
+        Ok(query)
+    }
+
+    pub fn with_rules(mut self, rules: Vec<Rule>) -> Query {
+        self.rules.extend(rules);
+        self
+    }
+}
+
+impl FromStr for Query {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Query> {
+        Query::parse(s)
+    }
+}
+
+impl Rule {
+    /// Parse a list of rules in `[[(name ?params...) clauses...] ...]` form.
+    pub fn parse_rules(s: &str) -> Result<Vec<Rule>> {
+        let mut parser = Parser::new(s);
+        let rules = parser.parse_rules()?;
+        parser.expect_end()?;
+        Ok(rules)
+    }
+}
+
+impl Store {
+    /// Run `query` against the store. Returns a sorted list of unique tuples, one value for each
+    /// variable in `query.find`.
+    pub fn query(&self, query: &Query) -> Result<Vec<Vec<Field>>> {
+        let eval = Eval::new(self, &query.rules)?;
+
+        let bindings = eval.conjunction(&query.clauses, vec![Binding::new()])?;
+
+        let mut result = bindings
+            .iter()
+            .map(|binding| project(binding, &query.find))
+            .collect::<Result<Vec<_>>>()?;
+        result.sort_unstable();
+        result.dedup();
+        Ok(result)
+    }
+}
+
+struct Eval<'a> {
+    store: &'a Store,
+    rules: HashMap<&'a str, Vec<&'a Rule>>,
+    relations: Relations,
+}
+
+impl<'a> Eval<'a> {
+    fn new(store: &'a Store, rules: &'a [Rule]) -> Result<Self> {
+        let mut by_name = HashMap::<&str, Vec<&Rule>>::new();
+        for rule in rules.iter() {
+            by_name.entry(rule.name.as_str()).or_default().push(rule);
+        }
+
+        let relations = by_name
+            .keys()
+            .map(|name| (name.to_string(), HashSet::new()))
+            .collect();
+
+        let mut eval = Self {
+            store,
+            rules: by_name,
+            relations,
+        };
+        eval.materialize_rules()?;
+        Ok(eval)
+    }
+
+    /// Evaluate rules bottom-up. Rules are evaluated by strata, each until fixpoint, so negated
+    /// rules are complete before they are used.
+    fn materialize_rules(&mut self) -> Result<()> {
+        for stratum in self.strata()? {
+            self.materialize_stratum(&stratum)?;
+        }
+        Ok(())
+    }
+
+    /// Group rules so that every rule comes in the same or a later group than the rules it refers
+    /// to, and in a strictly later group than the rules it negates.
+    fn strata(&self) -> Result<Vec<Vec<&'a Rule>>> {
+        let dependencies = self
+            .rules
+            .iter()
+            .map(|(name, rules)| {
+                let mut dependencies = Vec::new();
+                for rule in rules.iter() {
+                    for clause in rule.body.iter() {
+                        clause.collect_rules(false, &mut dependencies);
+                    }
+                }
+                (*name, dependencies)
+            })
+            .collect::<Vec<_>>();
+
+        let mut strata = self
+            .rules
+            .keys()
+            .map(|name| (*name, 0))
+            .collect::<HashMap<&str, usize>>();
+        loop {
+            let mut changed = false;
+            for (name, dependencies) in dependencies.iter() {
+                for (dependency, negated) in dependencies.iter() {
+                    // unknown rules are reported when evaluated
+                    let dependency = match strata.get(dependency) {
+                        Some(stratum) => stratum + *negated as usize,
+                        None => continue,
+                    };
+                    let stratum = strata.get_mut(name).unwrap();
+                    if dependency > *stratum {
+                        // without negation cycles, there are no more strata than rules
+                        if dependency >= self.rules.len() {
+                            return Err(QueryError::NegationCycle(name.to_string()).into());
+                        }
+                        *stratum = dependency;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let mut result = vec![Vec::new(); strata.values().max().map_or(0, |max| max + 1)];
+        for (name, stratum) in strata {
+            result[stratum].extend(self.rules[name].iter().copied());
+        }
+        Ok(result)
+    }
+
+    fn materialize_stratum(&mut self, rules: &[&Rule]) -> Result<()> {
+        loop {
+            let mut changed = false;
+            for rule in rules.iter() {
+                let tuples = self
+                    .conjunction(&rule.body, vec![Binding::new()])?
+                    .iter()
+                    .map(|binding| project(binding, &rule.params))
+                    .collect::<Result<Vec<_>>>()?;
+
+                let relation = self.relations.entry(rule.name.clone()).or_default();
+                for tuple in tuples {
+                    changed |= relation.insert(tuple).is_none();
+                }
+            }
+
+            if !changed {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Evaluate conjunction of clauses. The planner greedily picks the clause with the most bound
+    /// terms first, and postpones negation until all of its variables are bound by other clauses.
+    fn conjunction(&self, clauses: &[Clause], mut bindings: Vec<Binding>) -> Result<Vec<Binding>> {
+        let mut bound = bindings
+            .first()
+            .map_or_else(HashSet::new, |b| b.keys().cloned().collect());
+        let mut remaining = clauses.iter().collect::<Vec<_>>();
+
+        while !remaining.is_empty() && !bindings.is_empty() {
+            let idx = self.pick_clause(&remaining, &bound);
+            let clause = remaining.remove(idx);
+
+            bindings = match clause {
+                Clause::Pattern(pattern) => bindings
+                    .iter()
+                    .flat_map(|binding| self.match_pattern(pattern, binding))
+                    .collect(),
+                Clause::Rule(name, args) => {
+                    let relation = self.relation(name, args.len())?;
+                    bindings
+                        .iter()
+                        .flat_map(|binding| match_tuples(relation, args, binding))
+                        .collect()
+                }
+                Clause::Not(body) => {
+                    let mut result = Vec::new();
+                    for binding in bindings {
+                        if self.conjunction(body, vec![binding.clone()])?.is_empty() {
+                            result.push(binding);
+                        }
+                    }
+                    result
+                }
+            };
+            bound.extend(clause.vars());
+        }
+
+        Ok(bindings)
+    }
+
+    fn pick_clause(&self, clauses: &[&Clause], bound: &HashSet<Var>) -> usize {
+        let score = |clause: &Clause| -> Option<usize> {
+            match clause {
+                Clause::Pattern(pattern) => {
+                    let [e, a, v] = pattern.terms();
+                    // entity lookups are the most selective, then values, then attributes
+                    Some(
+                        1 + 4 * e.is_bound(bound) as usize
+                            + 2 * v.is_bound(bound) as usize
+                            + a.is_bound(bound) as usize,
+                    )
+                }
+                Clause::Rule(_name, args) => {
+                    Some(args.iter().filter(|arg| arg.is_bound(bound)).count())
+                }
+                Clause::Not(_) => {
+                    let positive_vars = HashSet::unions(
+                        clauses
+                            .iter()
+                            .filter(|c| !matches!(c, Clause::Not(_)))
+                            .map(|c| c.vars()),
+                    );
+                    let pending = clause
+                        .vars()
+                        .into_iter()
+                        .any(|var| !bound.contains(&var) && positive_vars.contains(&var));
+                    // negation only filters, so run it as soon as possible
+                    if pending {
+                        None
+                    } else {
+                        Some(usize::MAX)
+                    }
+                }
+            }
+        };
+
+        clauses
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| score(c).map(|s| (s, i)))
+            // prefer earlier clauses on ties
+            .max_by(|(s1, i1), (s2, i2)| s1.cmp(s2).then(i2.cmp(i1)))
+            .map_or(0, |(_, i)| i)
+    }
+
+    fn match_pattern(&self, pattern: &Pattern, binding: &Binding) -> Vec<Binding> {
+        let e = pattern.entity.resolve(binding);
+        let a = pattern.attribute.resolve(binding);
+        let v = pattern.value.resolve(binding);
+
+        let store = self.store;
+        let candidates: Box<dyn Iterator<Item = &Datom>> = match (&e, &a, &v) {
+            (Some(e), Some(a), _) => Box::new(store.eav2(e, a).into_iter().flatten()),
+            (Some(e), None, _) => Box::new(store.eav1(e).into_iter().flatten().flat_map(|x| x.1)),
+            (None, Some(a), Some(v)) => Box::new(store.ave2(a, v).into_iter().flatten()),
+            (None, Some(a), None) => {
+                Box::new(store.aev1(a).into_iter().flatten().flat_map(|x| x.1))
+            }
+            (None, None, _) => Box::new(store.atoms().values()),
+        };
+
+        candidates
+            .filter_map(|datom| {
+                let mut binding = binding.clone();
+                let fields = [&datom.entity, &datom.attribute, &datom.value];
+                for (term, field) in pattern.terms().iter().zip(fields.iter()) {
+                    if !unify(&mut binding, term, field) {
+                        return None;
+                    }
+                }
+                Some(binding)
+            })
+            .collect()
+    }
+
+    fn relation(&self, name: &str, arity: usize) -> Result<&HashSet<Vec<Field>>> {
+        let rules = self
+            .rules
+            .get(name)
+            .ok_or_else(|| QueryError::UnknownRule(name.to_string()))?;
+        if let Some(rule) = rules.iter().find(|rule| rule.params.len() != arity) {
+            return Err(QueryError::ArityMismatch {
+                rule: name.to_string(),
+                expected: rule.params.len(),
+            }
+            .into());
+        }
+
+        Ok(self
+            .relations
+            .get(name)
+            .expect("relations are created for every rule"))
+    }
+}
+
+fn project(binding: &Binding, vars: &[Var]) -> Result<Vec<Field>> {
+    vars.iter()
+        .map(|var| {
+            binding
+                .get(var)
+                .cloned()
+                .ok_or_else(|| QueryError::UnboundVariable(var.clone()).into())
+        })
+        .collect()
+}
+
+fn match_tuples(relation: &HashSet<Vec<Field>>, args: &[Term], binding: &Binding) -> Vec<Binding> {
+    relation
+        .iter()
+        .filter_map(|tuple| {
+            let mut binding = binding.clone();
+            for (term, field) in args.iter().zip(tuple.iter()) {
+                if !unify(&mut binding, term, field) {
+                    return None;
+                }
+            }
+            Some(binding)
+        })
+        .collect()
+}
+
+fn unify(binding: &mut Binding, term: &Term, field: &Field) -> bool {
+    match term {
+        Term::Blank => true,
+        Term::Value(value) => value == field,
+        Term::Var(var) => match binding.get(var) {
+            Some(value) => value == field,
+            None => {
+                binding.insert(var.clone(), field.clone());
+                true
+            }
+        },
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Token {
+    OpenBracket,
+    CloseBracket,
+    OpenParen,
+    CloseParen,
+    Keyword(String),
+    Var(Var),
+    Blank,
+    String(String),
+    Symbol(String),
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    offset: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Self { input, offset: 0 }
+    }
+
+    fn error<T>(&self, offset: usize, message: impl Display) -> Result<T> {
+        Err(QueryError::Syntax {
+            offset,
+            message: message.to_string(),
+        }
+        .into())
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.input[self.offset..];
+        let trimmed = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        self.offset += rest.len() - trimmed.len();
+    }
+
+    /// Returns the next token and its offset without consuming it.
+    fn peek(&mut self) -> Result<Option<(usize, Token, usize)>> {
+        self.skip_whitespace();
+        let start = self.offset;
+        let rest = &self.input[start..];
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        let simple = match c {
+            '[' => Some(Token::OpenBracket),
+            ']' => Some(Token::CloseBracket),
+            '(' => Some(Token::OpenParen),
+            ')' => Some(Token::CloseParen),
+            _ => None,
+        };
+        if let Some(token) = simple {
+            return Ok(Some((start, token, start + 1)));
+        }
+
+        if c == '"' {
+            let mut escaped = false;
+            for (i, c) in rest.char_indices().skip(1) {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    let end = start + i + 1;
+                    return match serde_json::from_str::<String>(&self.input[start..end]) {
+                        Ok(s) => Ok(Some((start, Token::String(s), end))),
+                        Err(e) => self.error(start, e),
+                    };
+                }
+            }
+            return self.error(start, "unterminated string");
+        }
+
+        let len = rest
+            .find(|c: char| c.is_whitespace() || "[]()\",".contains(c))
+            .unwrap_or(rest.len());
+        let word = &rest[..len];
+        let token = if word == "_" {
+            Token::Blank
+        } else if let Some(keyword) = word.strip_prefix(':') {
+            Token::Keyword(keyword.to_string())
+        } else if word.starts_with('?') && word.len() > 1 {
+            Token::Var(Var::new(word))
+        } else {
+            Token::Symbol(word.to_string())
+        };
+        Ok(Some((start, token, start + len)))
+    }
+
+    fn next(&mut self) -> Result<(usize, Token)> {
+        match self.peek()? {
+            Some((start, token, end)) => {
+                self.offset = end;
+                Ok((start, token))
+            }
+            None => self.error(self.offset, "unexpected end of input"),
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        let (offset, token) = self.next()?;
+        if token == expected {
+            Ok(())
+        } else {
+            self.error(offset, format!("expected {:?}, got {:?}", expected, token))
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<()> {
+        match self.peek()? {
+            None => Ok(()),
+            Some((offset, token, _)) => self.error(offset, format!("unexpected {:?}", token)),
+        }
+    }
+
+    fn next_is(&mut self, expected: &Token) -> Result<bool> {
+        Ok(matches!(self.peek()?, Some((_, token, _)) if &token == expected))
+    }
+
+    fn parse_query(&mut self) -> Result<Query> {
+        self.expect(Token::OpenBracket)?;
+        self.expect(Token::Keyword("find".to_string()))?;
+
+        let mut find = Vec::new();
+        loop {
+            match self.next()? {
+                (_, Token::Var(var)) => find.push(var),
+                (_, Token::Keyword(keyword)) if keyword == "where" => break,
+                (offset, token) => {
+                    return self.error(offset, format!("expected variable, got {:?}", token))
+                }
+            }
+        }
+
+        let clauses = self.parse_clauses(&Token::CloseBracket)?;
+        self.expect(Token::CloseBracket)?;
+
+        Ok(Query::new(find, clauses))
+    }
+
+    fn parse_clauses(&mut self, end: &Token) -> Result<Vec<Clause>> {
+        let mut clauses = Vec::new();
+        while !self.next_is(end)? {
+            clauses.push(self.parse_clause()?);
+        }
+        Ok(clauses)
+    }
+
+    fn parse_clause(&mut self) -> Result<Clause> {
+        match self.next()? {
+            (_, Token::OpenBracket) => {
+                let entity = self.parse_term()?;
+                let attribute = self.parse_term()?;
+                let value = self.parse_term()?;
+                self.expect(Token::CloseBracket)?;
+                Ok(Clause::Pattern(Pattern::new(entity, attribute, value)))
+            }
+            (_, Token::OpenParen) => match self.next()? {
+                (_, Token::Symbol(symbol)) if symbol == "not" => {
+                    let clauses = self.parse_clauses(&Token::CloseParen)?;
+                    self.expect(Token::CloseParen)?;
+                    Ok(Clause::Not(clauses))
+                }
+                (_, Token::Symbol(name)) => {
+                    let mut args = Vec::new();
+                    while !self.next_is(&Token::CloseParen)? {
+                        args.push(self.parse_term()?);
+                    }
+                    self.expect(Token::CloseParen)?;
+                    Ok(Clause::Rule(name, args))
+                }
+                (offset, token) => self.error(offset, format!("expected symbol, got {:?}", token)),
+            },
+            (offset, token) => self.error(offset, format!("expected clause, got {:?}", token)),
+        }
+    }
+
+    fn parse_term(&mut self) -> Result<Term> {
+        match self.next()? {
+            (_, Token::Var(var)) => Ok(Term::Var(var)),
+            (_, Token::Blank) => Ok(Term::Blank),
+            (_, Token::String(s)) => Ok(Term::Value(s.into())),
+            (offset, token) => self.error(offset, format!("expected term, got {:?}", token)),
+        }
+    }
+
+    fn parse_rules(&mut self) -> Result<Vec<Rule>> {
+        self.expect(Token::OpenBracket)?;
+        let mut rules = Vec::new();
+        while !self.next_is(&Token::CloseBracket)? {
+            rules.push(self.parse_rule()?);
+        }
+        self.expect(Token::CloseBracket)?;
+        Ok(rules)
+    }
+
+    fn parse_rule(&mut self) -> Result<Rule> {
+        self.expect(Token::OpenBracket)?;
+        self.expect(Token::OpenParen)?;
+        let name = match self.next()? {
+            (_, Token::Symbol(name)) => name,
+            (offset, token) => {
+                return self.error(offset, format!("expected rule name, got {:?}", token))
+            }
+        };
+        let mut params = Vec::new();
+        loop {
+            match self.next()? {
+                (_, Token::Var(var)) => params.push(var),
+                (_, Token::CloseParen) => break,
+                (offset, token) => {
+                    return self.error(offset, format!("expected variable, got {:?}", token))
+                }
+            }
+        }
+        let body = self.parse_clauses(&Token::CloseBracket)?;
+        self.expect(Token::CloseBracket)?;
+
+        Ok(Rule { name, params, body })
+    }
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QueryError::Syntax { offset, message } => write!(f, "{} at offset {}", message, offset),
+            QueryError::UnboundVariable(var) => write!(f, "unbound variable ?{}", var.0),
+            QueryError::UnknownRule(name) => write!(f, "unknown rule {}", name),
+            QueryError::ArityMismatch { rule, expected } => {
+                write!(f, "rule {} expects {} arguments", rule, expected)
+            }
+            QueryError::NegationCycle(name) => {
+                write!(f, "rule {} depends on its own negation", name)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static TEST: &str = r#"
+        ["0", "0", "identifier"]
+        ["0", "5", "7"]
+        ["1", "0", "Attribute.value-type"]
+        ["1", "5", "7"]
+        ["5", "0", "type"]
+        ["5", "5", "7"]
+        ["5", "4", "Type of the entity"]
+        ["6", "0", "Type"]
+        ["6", "5", "6"]
+        ["7", "0", "Attribute"]
+        ["7", "5", "6"]
+        ["a", "16", "b"]
+        ["b", "16", "c"]
+        ["c", "16", "d"]
+    "#;
+
+    fn query(q: &str) -> Vec<Vec<Field>> {
+        let store = Store::from_str(TEST).unwrap();
+        store.query(&Query::parse(q).unwrap()).unwrap()
+    }
+
+    fn fields(rows: &[&[&str]]) -> Vec<Vec<Field>> {
+        rows.iter()
+            .map(|row| row.iter().map(|&x| x.into()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn query_join() {
+        assert_eq!(
+            fields(&[
+                &["0", "identifier"],
+                &["1", "Attribute.value-type"],
+                &["5", "type"]
+            ]),
+            query(r#"[:find ?e ?name :where [?e "5" ?t] [?t "0" "Attribute"] [?e "0" ?name]]"#)
+        );
+    }
+
+    #[test]
+    fn query_not() {
+        assert_eq!(
+            fields(&[&["0"], &["1"]]),
+            query(r#"[:find ?e :where [?e "5" "7"] (not [?e "4" _])]"#)
+        );
+    }
+
+    #[test]
+    fn query_same_variable() {
+        assert_eq!(fields(&[&["6"]]), query(r#"[:find ?e :where [?e "5" ?e]]"#));
+    }
+
+    #[test]
+    fn query_recursive_rule() {
+        let store = Store::from_str(TEST).unwrap();
+        let rules = Rule::parse_rules(
+            r#"[[(follows ?a ?b) [?a "16" ?b]]
+                [(follows ?a ?c) [?a "16" ?b] (follows ?b ?c)]]"#,
+        )
+        .unwrap();
+        let q = Query::parse(r#"[:find ?x :where (follows "a" ?x)]"#)
+            .unwrap()
+            .with_rules(rules);
+
+        assert_eq!(fields(&[&["b"], &["c"], &["d"]]), store.query(&q).unwrap());
+    }
+
+    #[test]
+    fn query_negated_rule() {
+        // commented is derived from described, so it has to be complete before uncommented uses it
+        let store = Store::from_str(TEST).unwrap();
+        let rules = Rule::parse_rules(
+            r#"[[(uncommented ?e) [?e "5" "7"] (not (commented ?e))]
+                [(commented ?e) (described ?e)]
+                [(described ?e) [?e "4" _]]]"#,
+        )
+        .unwrap();
+        let q = Query::parse(r#"[:find ?e :where (uncommented ?e)]"#)
+            .unwrap()
+            .with_rules(rules);
+
+        assert_eq!(fields(&[&["0"], &["1"]]), store.query(&q).unwrap());
+    }
+
+    #[test]
+    fn query_negation_cycle() {
+        let store = Store::from_str(TEST).unwrap();
+        let rules = Rule::parse_rules(
+            r#"[[(p ?e) [?e "5" "7"] (not (q ?e))]
+                [(q ?e) [?e "5" "7"] (not (p ?e))]]"#,
+        )
+        .unwrap();
+        let q = Query::parse(r#"[:find ?e :where (p ?e)]"#)
+            .unwrap()
+            .with_rules(rules);
+
+        assert!(matches!(
+            store.query(&q),
+            Err(Error::QueryError(QueryError::NegationCycle(_)))
+        ));
+    }
+
+    #[test]
+    fn query_unbound_find() {
+        let store = Store::from_str(TEST).unwrap();
+        let q = Query::parse(r#"[:find ?x :where [?e "0" "Type"]]"#).unwrap();
+        assert!(matches!(
+            store.query(&q),
+            Err(Error::QueryError(QueryError::UnboundVariable(_)))
+        ));
+    }
+
+    #[test]
+    fn query_syntax_error() {
+        assert!(matches!(
+            Query::parse(r#"[:find ?x :where [?x "0"]]"#),
+            Err(Error::QueryError(QueryError::Syntax { offset: 24, .. }))
+        ));
+    }
+}
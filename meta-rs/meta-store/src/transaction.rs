@@ -0,0 +1,82 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use im::HashSet;
+
+use crate::datom::{Datom, Field};
+
+/// Transaction id. Ids are assigned sequentially starting from 1, so `TxId(0)` denotes the empty
+/// store before any transaction.
+#[derive(
+    Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default, Serialize, Deserialize,
+)]
+pub struct TxId(pub u64);
+
+impl TxId {
+    pub fn next(self) -> TxId {
+        TxId(self.0 + 1)
+    }
+}
+
+/// A single entry of the transaction log: a batch of datoms that were retracted and added
+/// together.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Transaction {
+    pub id: TxId,
+    /// Milliseconds since Unix epoch.
+    pub timestamp: u64,
+    /// Who made the transaction (see [`Store::set_author`](crate::Store::set_author)).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub retracted: Vec<Datom>,
+    pub added: Vec<Datom>,
+}
+
+impl Transaction {
+    pub fn new(id: TxId, retracted: Vec<Datom>, added: Vec<Datom>) -> Transaction {
+        Transaction {
+            id,
+            timestamp: now(),
+            author: None,
+            retracted,
+            added,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Net effect of one or more transactions: datoms that appeared in and disappeared from the
+/// store. Retracting a missing datom or adding an existing one is not a change, and a datom added
+/// and retracted again between `from` and `to` is not reported.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ChangeSet {
+    /// Transaction the changes are relative to (exclusive).
+    pub from: TxId,
+    /// Last transaction included in the changes.
+    pub to: TxId,
+    /// Sorted removed datoms.
+    pub removed: Vec<Datom>,
+    /// Sorted added datoms.
+    pub added: Vec<Datom>,
+}
+
+impl ChangeSet {
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty() && self.added.is_empty()
+    }
+
+    pub fn datoms(&self) -> impl Iterator<Item = &Datom> {
+        self.removed.iter().chain(self.added.iter())
+    }
+
+    /// Entities that had any of their datoms added or removed.
+    pub fn entities(&self) -> HashSet<&Field> {
+        self.datoms().map(|d| &d.entity).collect()
+    }
+}
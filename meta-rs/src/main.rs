@@ -1,15 +1,287 @@
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::time::Duration;
 
-use meta_store::Result;
+use meta_core::{ids, MetaCore};
+use meta_store::{log_path, Format, LoadError, Loader, Merge, Result, Severity, Store};
+
+const USAGE: &str = "Usage:
+    meta [FILE]
+    meta validate [--strict] [FILE]
+    meta convert INPUT OUTPUT [json|jsonl|binary]
+    meta diff OLD NEW
+    meta log [FILE]
+    meta merge BASE OURS THEIRS
+    meta test [--junit OUTPUT] [--timeout SECONDS] [FILE]
+    meta compile ENTRY OUTPUT [FILE]
+    meta run PROGRAM";
 
 fn main() -> Result<()> {
     env_logger::init();
 
-    let f = File::open("store.meta")?;
-    let store = serde_json::from_reader(BufReader::new(&f))?;
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args
+        .iter()
+        .map(|x| x.as_str())
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => edit("store.meta")?,
+        ["validate"] => validate("store.meta", false)?,
+        ["validate", "--strict"] => validate("store.meta", true)?,
+        ["validate", "--strict", path] => validate(path, true)?,
+        ["validate", path] => validate(path, false)?,
+        ["convert", input, output] => convert(input, output, Format::from_path(output))?,
+        ["convert", input, output, format] => match format.parse() {
+            Ok(format) => convert(input, output, format)?,
+            Err(err) => usage_error(&err),
+        },
+        ["diff", old, new] => diff(old, new)?,
+        ["log"] => log("store.meta")?,
+        ["log", path] => log(path)?,
+        ["merge", base, ours, theirs] => merge(base, ours, theirs)?,
+        ["test", args @ ..] => test(args)?,
+        ["compile", entry, output] => compile(entry, output, "store.meta")?,
+        ["compile", entry, output, path] => compile(entry, output, path)?,
+        ["run", program] => run(program)?,
+        [path] if !path.starts_with('-') => edit(path)?,
+        _ => usage_error(""),
+    }
+
+    Ok(())
+}
+
+fn usage_error(message: &str) -> ! {
+    if !message.is_empty() {
+        eprintln!("{}", message);
+    }
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+/// Load store in any supported format. Malformed lines of JSON lines stores are returned as
+/// errors (along with warnings), or fail loading in `strict` mode.
+fn load(path: &str, strict: bool) -> Result<(Store, Format, Vec<LoadError>)> {
+    let mut r = BufReader::new(File::open(path)?);
+    let format = Format::detect(&mut r)?;
+    if format != Format::JsonLines {
+        return Ok((Store::read(r, format)?, format, Vec::new()));
+    }
+
+    let (store, errors) = Loader::new().with_path(path).with_strict(strict).load(r)?;
+    Ok((store, format, errors))
+}
+
+/// Load store printing malformed lines as warnings.
+fn load_with_warnings(path: &str) -> Result<(Store, Format)> {
+    let (store, format, errors) = load(path, false)?;
+    for error in errors {
+        eprintln!("warning: {}", error);
+    }
+    Ok((store, format))
+}
+
+/// Load store along with its transaction log (if there is one). New transactions are attributed
+/// to `$META_AUTHOR` or, if it is not set, `$USER`.
+fn load_with_history(path: &str) -> Result<(Store, Format)> {
+    let (store, format) = load_with_warnings(path)?;
+    let mut store = match File::open(log_path(path)) {
+        Ok(f) => store.with_history(Store::read_log(BufReader::new(f))?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => store,
+        Err(err) => return Err(err.into()),
+    };
+    store.set_author(
+        std::env::var("META_AUTHOR")
+            .or_else(|_| std::env::var("USER"))
+            .ok(),
+    );
+    Ok((store, format))
+}
+
+/// Open store in the editor. It is saved back in the same format, and its transaction log is saved
+/// next to it.
+fn edit(path: &str) -> Result<()> {
+    let (store, format) = load_with_history(path)?;
+    meta_editor::main(store, PathBuf::from(path), format);
+    Ok(())
+}
+
+/// Print transactions of the store: who made them, when, and which datoms were removed and added.
+fn log(path: &str) -> Result<()> {
+    let (store, _) = load_with_history(path)?;
+    for tx in store.log() {
+        println!(
+            "tx {} at {} by {}",
+            tx.id.0,
+            tx.timestamp,
+            tx.author.as_deref().unwrap_or("unknown")
+        );
+        for datom in tx.retracted.iter() {
+            println!("- {}", serde_json::to_string(datom)?);
+        }
+        for datom in tx.added.iter() {
+            println!("+ {}", serde_json::to_string(datom)?);
+        }
+    }
+    Ok(())
+}
+
+/// Print load errors, warnings and schema diagnostics for the store and exit with non-zero code if
+/// there are any errors or diagnostics.
+fn validate(path: &str, strict: bool) -> Result<()> {
+    let (store, _, mut errors) = load(path, strict)?;
+    for error in errors.iter() {
+        match error.severity {
+            Severity::Error => println!("{}", error),
+            Severity::Warning => println!("warning: {}", error),
+        }
+    }
+    errors.retain(|error| error.severity == Severity::Error);
+
+    let diagnostics = MetaCore::new(&store).validate();
+    for diagnostic in diagnostics.iter() {
+        println!("{}: {}", path, diagnostic);
+    }
+
+    if !errors.is_empty() || !diagnostics.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn convert(input: &str, output: &str, format: Format) -> Result<()> {
+    let (store, _) = load_with_warnings(input)?;
+    let f = File::create(output)?;
+    store.write(BufWriter::new(f), format)
+}
+
+/// Print datoms removed from `old` and added in `new`. Exits with non-zero code if stores differ.
+fn diff(old: &str, new: &str) -> Result<()> {
+    let (old, _) = load_with_warnings(old)?;
+    let (new, _) = load_with_warnings(new)?;
+
+    let diff = old.diff(&new);
+    for datom in diff.removed.iter() {
+        println!("- {}", serde_json::to_string(datom)?);
+    }
+    for datom in diff.added.iter() {
+        println!("+ {}", serde_json::to_string(datom)?);
+    }
+
+    if !diff.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Three-way merge of stores. The result is written to `ours` in its format. JSON lines stores are
+/// updated in place, so comments and the order of datoms are kept. Conflicts are printed and make
+/// the command exit with non-zero code.
+///
+/// Can be used as a git merge driver:
+///
+/// ```text
+/// git config merge.meta.driver "meta merge %O %A %B"
+/// echo "*.meta merge=meta" >> .gitattributes
+/// ```
+fn merge(base: &str, ours_path: &str, theirs: &str) -> Result<()> {
+    let (base, _) = load_with_warnings(base)?;
+    let (ours, format) = load_with_warnings(ours_path)?;
+    let (theirs, _) = load_with_warnings(theirs)?;
+
+    let result = Merge::new(&base, &ours, &theirs)
+        .with_order_attribute(ids::A_AFTER.clone())
+        .run();
+
+    if format == Format::JsonLines {
+        let original = std::fs::read(ours_path)?;
+        let f = File::create(ours_path)?;
+        ours.diff(&result.store)
+            .apply_json_lines(original.as_slice(), BufWriter::new(f))?;
+    } else {
+        let f = File::create(ours_path)?;
+        result.store.write(BufWriter::new(f), format)?;
+    }
+
+    for conflict in result.conflicts.iter() {
+        println!("{}: {}", ours_path, conflict);
+    }
+    if !result.conflicts.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Run all `f/RunTest` entities of the store and print a report. Exits with non-zero code if any
+/// test fails. With `--junit`, the report is also written in JUnit XML format.
+fn test(args: &[&str]) -> Result<()> {
+    let mut path = "store.meta";
+    let mut junit = None;
+    let mut timeout = Duration::from_secs(10);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--junit" => junit = Some(*args.next().unwrap_or_else(|| usage_error(""))),
+            "--timeout" => {
+                let seconds = args.next().unwrap_or_else(|| usage_error(""));
+                match seconds.parse() {
+                    Ok(seconds) => timeout = Duration::from_secs_f64(seconds),
+                    Err(err) => usage_error(&format!("invalid timeout {}: {}", seconds, err)),
+                }
+            }
+            arg if !arg.starts_with('-') => path = arg,
+            _ => usage_error(""),
+        }
+    }
+
+    let (store, _) = load_with_warnings(path)?;
+    let report = meta_f::run_tests(&store, &meta_f::Options::default(), timeout);
+    println!("{}", report);
+
+    if let Some(output) = junit {
+        let mut f = BufWriter::new(File::create(output)?);
+        report.write_junit(&mut f)?;
+    }
+
+    if !report.is_success() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Compile f entity `entry` of the store into a program file that can be run without the store.
+fn compile(entry: &str, output: &str, path: &str) -> Result<()> {
+    let (store, _) = load_with_warnings(path)?;
+    match meta_f::compile_program(&store, &entry.into(), &meta_f::Options::default()) {
+        Ok(program) => {
+            let mut f = BufWriter::new(File::create(output)?);
+            program.write(&mut f)?;
+        }
+        Err(err) => {
+            eprintln!("{}", meta_f::describe_result(&Err(err)));
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
 
-    meta_editor::main(store);
+/// Run a program compiled with `meta compile` and print its result. Exits with non-zero code if
+/// the program fails.
+fn run(path: &str) -> Result<()> {
+    let mut r = BufReader::new(File::open(path)?);
+    let program = match meta_f::Program::read(&mut r) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("{}: invalid program: {:?}", path, err);
+            std::process::exit(1);
+        }
+    };
 
+    let result = program.run(&meta_f::Options::default());
+    println!("{}", meta_f::describe_result(&result));
+    if result.is_err() {
+        std::process::exit(1);
+    }
     Ok(())
 }